@@ -0,0 +1,22 @@
+; Counts the bytes of a NUL terminated string stored after the code into ECX.
+;
+; Labels are offsets into the code segment while DS starts at 0, so the string is
+; read through a CS override.
+
+bits 16
+
+start:
+    mov si, message
+    xor ecx, ecx
+    cld
+.next:
+    cs lodsb
+    test al, al
+    jz .done
+    inc ecx
+    jmp .next
+.done:
+    hlt
+
+message:
+    db "Hello from the guest", 0
@@ -0,0 +1,19 @@
+; Sums 1 + 2 + ... + ECX into EAX.
+;
+;   hypercalc debug guests/sum.asm
+;   (hypercalc) set ecx 10
+;   (hypercalc) cont
+;   (hypercalc) regs            ; eax = 55
+
+bits 16
+
+start:
+    xor eax, eax
+    test ecx, ecx
+    jz .done
+.next:
+    add eax, ecx
+    dec ecx
+    jnz .next
+.done:
+    hlt
@@ -0,0 +1,2001 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+// A small Intel syntax assembler for guest code, covering the same integer and scalar SSE subset as the disassembler.
+//
+// Source is line based: `label:`, `name equ expr`, an instruction with optional rep/repe/repne/lock prefixes, or one
+// of the directives `bits 16|32|64`, `org addr`, `db/dw/dd/dq items`, `times count <line>` and `align n[, fill]`.
+// Labels starting with a dot are local to the previous global label. Comments start with `;`.
+// Jumps are assembled short when their target is in range and near otherwise.
+
+const MAX_PASSES: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Bits16,
+    Bits32,
+    Bits64
+}
+
+impl Mode {
+
+    /// The default operand and address size in bytes.
+    fn size(self) -> u8 {
+        match self {
+            Mode::Bits16 => 2,
+            Mode::Bits32 => 4,
+            Mode::Bits64 => 8
+        }
+    }
+}
+
+/// The output of the assembler.
+#[derive(Clone, Debug)]
+pub struct AssembledCode {
+    /// The address the first byte was assembled for.
+    pub origin: u64,
+    pub bytes: Vec<u8>,
+    /// Every label and `equ` constant, by name. Local labels appear as `global.local`.
+    pub labels: BTreeMap<String, u64>
+}
+
+impl AssembledCode {
+
+    pub fn address_of(&self, label: &str) -> Option<u64> {
+        self.labels.get(label).copied()
+    }
+}
+
+/// Builds assembler source from Rust, e.g.
+/// `Assembler::new(Mode::Bits16).line("add eax, ecx").line("hlt").assemble()`.
+pub struct Assembler {
+    mode: Mode,
+    origin: u64,
+    source: String
+}
+
+impl Assembler {
+
+    /// Associated function constructor. Starts an empty program in `mode` at origin 0.
+    pub fn new(mode: Mode) -> Self {
+        Assembler {
+            mode: mode,
+            origin: 0,
+            source: String::new()
+        }
+    }
+
+    /// Sets the address the code is assembled for, which is what labels and absolute jump targets resolve to.
+    pub fn origin(mut self, origin: u64) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Appends one or more lines of source.
+    pub fn line(mut self, line: &str) -> Self {
+        self.source.push_str(line);
+        self.source.push('\n');
+        self
+    }
+
+    pub fn label(self, name: &str) -> Self {
+        self.line(&format!("{}:", name))
+    }
+
+    pub fn assemble(&self) -> Result<AssembledCode, String> {
+        assemble(&self.source, self.mode, self.origin)
+    }
+}
+
+/// Assembles `source`. On failure returns a description of the first error, with its line number.
+///
+/// # Arguments
+///
+/// * `source` - The program text.
+/// * `mode` - The mode to assemble for until a `bits` directive says otherwise.
+/// * `origin` - The address of the first byte, until an `org` directive says otherwise.
+pub fn assemble(source: &str, mode: Mode, origin: u64) -> Result<AssembledCode, String> {
+    let statements = parse_source(source)?;
+
+    let mut labels = BTreeMap::new();
+    let mut long_jumps = BTreeSet::new();
+
+    for _ in 0..MAX_PASSES {
+        let pass = run_pass(&statements, mode, origin, &labels, &mut long_jumps, false)?;
+        let converged = !pass.grew && pass.new_labels == labels;
+        labels = pass.new_labels;
+
+        if converged {
+            let pass = run_pass(&statements, mode, origin, &labels, &mut long_jumps, true)?;
+            return Ok(AssembledCode {
+                origin: pass.origin,
+                bytes: pass.bytes,
+                labels: pass.new_labels.into_iter().map(|(name, value)| (name, value as u64)).collect()
+            });
+        }
+    }
+
+    Err(String::from("Label addresses did not settle, check for labels defined in terms of code size"))
+}
+
+/// Assembles the file at `path`, see assemble().
+pub fn assemble_file(path: &str, mode: Mode, origin: u64) -> Result<AssembledCode, String> {
+    let source = fs::read_to_string(path).map_err(|error| format!("Unable to read {}: {}", path, error))?;
+    assemble(&source, mode, origin).map_err(|error_message| format!("{}: {}", path, error_message))
+}
+
+// ---------------------------------------------------------------------------------------------------------------
+// Parsing
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RegisterKind {
+    General,
+    Segment,
+    Xmm
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Register {
+    kind: RegisterKind,
+    /// Width in bytes, 16 for XMM registers.
+    size: u8,
+    /// The encoding, 0 - 15.
+    number: u8,
+    /// spl, bpl, sil and dil only exist with a REX prefix.
+    requires_rex: bool,
+    /// ah, ch, dh and bh can't be encoded with a REX prefix.
+    high_byte: bool
+}
+
+const REGISTERS_8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const REGISTERS_8_REX: [&str; 4] = ["spl", "bpl", "sil", "dil"];
+const REGISTERS_16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const REGISTERS_32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+const REGISTERS_64: [&str; 8] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi"];
+const SEGMENT_REGISTERS: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+
+const SEGMENT_PREFIXES: [u8; 6] = [0x26, 0x2E, 0x36, 0x3E, 0x64, 0x65];
+
+fn general_register(size: u8, number: u8) -> Register {
+    Register {
+        kind: RegisterKind::General,
+        size: size,
+        number: number,
+        requires_rex: false,
+        high_byte: false
+    }
+}
+
+fn parse_register(name: &str) -> Option<Register> {
+    let name = name.to_ascii_lowercase();
+
+    if let Some(number) = REGISTERS_8.iter().position(|register| *register == name) {
+        let mut register = general_register(1, number as u8);
+        register.high_byte = number >= 4;
+        return Some(register);
+    }
+    if let Some(number) = REGISTERS_8_REX.iter().position(|register| *register == name) {
+        let mut register = general_register(1, number as u8 + 4);
+        register.requires_rex = true;
+        return Some(register);
+    }
+    for (size, names) in [(2, &REGISTERS_16), (4, &REGISTERS_32), (8, &REGISTERS_64)].iter() {
+        if let Some(number) = names.iter().position(|register| *register == name) {
+            return Some(general_register(*size, number as u8));
+        }
+    }
+    if let Some(number) = SEGMENT_REGISTERS.iter().position(|register| *register == name) {
+        return Some(Register { kind: RegisterKind::Segment, size: 2, number: number as u8, requires_rex: false, high_byte: false });
+    }
+    if let Some(number) = name.strip_prefix("xmm").and_then(|number| number.parse::<u8>().ok()) {
+        if number < 16 {
+            return Some(Register { kind: RegisterKind::Xmm, size: 16, number: number, requires_rex: false, high_byte: false });
+        }
+    }
+
+    // r8 - r15 with their b/w/d forms
+    let number_end = name.strip_prefix('r')?.find(|c: char| !c.is_ascii_digit()).map(|end| end + 1).unwrap_or(name.len());
+    let number = name.get(1..number_end)?.parse::<u8>().ok()?;
+    if !(8..16).contains(&number) {
+        return None;
+    }
+    let size = match &name[number_end..] {
+        "" => 8,
+        "d" => 4,
+        "w" => 2,
+        "b" | "l" => 1,
+        _ => return None
+    };
+    Some(general_register(size, number))
+}
+
+fn parse_size(keyword: &str) -> Option<u8> {
+    match keyword.to_ascii_lowercase().as_str() {
+        "byte" => Some(1),
+        "word" => Some(2),
+        "dword" => Some(4),
+        "qword" => Some(8),
+        "xmmword" | "oword" => Some(16),
+        _ => None
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Term {
+    Number(i64),
+    Symbol(String),
+    /// `$`, the address of the current instruction.
+    Here
+}
+
+/// A sum of signed terms, e.g. `table + 4 - $`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Expr {
+    terms: Vec<(i64, Term)>
+}
+
+impl Expr {
+
+    /// Whether the value is known while parsing. Anything else may change between passes, so it always gets the
+    /// widest encoding.
+    fn is_constant(&self) -> bool {
+        self.terms.iter().all(|(_, term)| matches!(term, Term::Number(_)))
+    }
+
+    fn evaluate(&self, context: &Context) -> Result<i64, String> {
+        let mut value: i64 = 0;
+        for (sign, term) in self.terms.iter() {
+            let term_value = match term {
+                Term::Number(number) => *number,
+                Term::Here => context.address as i64,
+                Term::Symbol(name) => match context.labels.get(name) {
+                    Some(address) => *address,
+                    None if context.final_pass => return Err(format!("Undefined label {}", name)),
+                    // Not seen yet, the next pass will know
+                    None => context.address as i64
+                }
+            };
+            value = value.wrapping_add(sign.wrapping_mul(term_value));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Memory {
+    size: Option<u8>,
+    segment: Option<u8>,
+    base: Option<Register>,
+    index: Option<Register>,
+    scale: u8,
+    displacement: Option<Expr>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Operand {
+    Register(Register),
+    Memory(Memory),
+    /// An immediate, with its size when one was written (`push dword 5`).
+    Immediate(Expr, Option<u8>)
+}
+
+#[derive(Clone, Debug)]
+enum DataItem {
+    Bytes(Vec<u8>),
+    Value(Expr)
+}
+
+#[derive(Clone, Debug)]
+enum Statement {
+    Label(String),
+    Equ(String, Expr),
+    Instruction { prefixes: Vec<u8>, mnemonic: String, operands: Vec<Operand> },
+    Data(u8, Vec<DataItem>),
+    Times(Expr, Box<Statement>),
+    Align(Expr, Option<Expr>),
+    Bits(Mode),
+    Org(Expr)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    }
+    else if let Some(binary) = lower.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2)
+    }
+    else if let Some(hex) = lower.strip_suffix('h') {
+        // 0FFh style, which must start with a digit to tell it from a label
+        if !hex.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        u64::from_str_radix(hex, 16)
+    }
+    else {
+        lower.parse::<u64>()
+    };
+    parsed.ok().map(|value| value as i64)
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' || first == '.' => {}
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Splits on `separator` outside of quotes and brackets.
+fn split_top_level(text: &str, separator: char) -> Vec<String> {
+    let mut parts = vec!();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut depth = 0;
+
+    for c in text.chars() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ if c == separator && depth == 0 => {
+                    parts.push(current.trim().to_string());
+                    current.clear();
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        current.push(c);
+    }
+    parts.push(current.trim().to_string());
+    parts
+}
+
+struct Parser {
+    /// The last global label, which local labels hang off.
+    scope: String
+}
+
+impl Parser {
+
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.scope, name)
+        }
+        else {
+            String::from(name)
+        }
+    }
+
+    /// Splits an expression into its signed terms.
+    fn split_terms<'a>(&self, text: &'a str) -> Result<Vec<(i64, &'a str)>, String> {
+        let mut terms = vec!();
+        let mut sign = 1;
+        let mut start = 0;
+        let mut in_quote = false;
+
+        for (i, c) in text.char_indices() {
+            if c == '\'' {
+                in_quote = !in_quote;
+                continue;
+            }
+            if in_quote || (c != '+' && c != '-') {
+                continue;
+            }
+            let term = text[start..i].trim();
+            if term.is_empty() {
+                // A unary sign
+                if c == '-' {
+                    sign = -sign;
+                }
+            }
+            else {
+                terms.push((sign, term));
+                sign = if c == '-' { -1 } else { 1 };
+            }
+            start = i + 1;
+        }
+        let term = text[start..].trim();
+        if term.is_empty() {
+            return Err(format!("Incomplete expression {}", text));
+        }
+        terms.push((sign, term));
+        Ok(terms)
+    }
+
+    fn parse_term(&self, text: &str) -> Result<Term, String> {
+        if text == "$" {
+            return Ok(Term::Here);
+        }
+        if text.len() >= 3 && text.starts_with('\'') && text.ends_with('\'') {
+            let chars: Vec<u8> = text[1..text.len() - 1].bytes().collect();
+            let mut value = 0i64;
+            for (i, byte) in chars.iter().enumerate().take(8) {
+                value |= (*byte as i64) << (i * 8);
+            }
+            return Ok(Term::Number(value));
+        }
+        if let Some(number) = parse_number(text) {
+            return Ok(Term::Number(number));
+        }
+        if is_identifier(text) && parse_register(text).is_none() {
+            return Ok(Term::Symbol(self.qualify(text)));
+        }
+        Err(format!("Can't parse {}", text))
+    }
+
+    fn parse_expr(&self, text: &str) -> Result<Expr, String> {
+        let mut terms = vec!();
+        for (sign, term) in self.split_terms(text)? {
+            terms.push((sign, self.parse_term(term)?));
+        }
+        Ok(Expr { terms: terms })
+    }
+
+    fn parse_memory(&self, text: &str, size: Option<u8>) -> Result<Memory, String> {
+        let open = text.find('[').ok_or(format!("Expected [ in {}", text))?;
+        if !text.ends_with(']') {
+            return Err(format!("Expected ] at the end of {}", text));
+        }
+
+        let mut segment = None;
+        let outside = text[..open].trim().trim_end_matches(':').trim();
+        if !outside.is_empty() {
+            segment = Some(self.parse_segment_override(outside)?);
+        }
+
+        let mut inside = text[open + 1..text.len() - 1].trim();
+        if let Some(colon) = inside.find(':') {
+            segment = Some(self.parse_segment_override(inside[..colon].trim())?);
+            inside = inside[colon + 1..].trim();
+        }
+
+        let mut memory = Memory {
+            size: size,
+            segment: segment,
+            base: None,
+            index: None,
+            scale: 1,
+            displacement: None
+        };
+        let mut displacement = vec!();
+
+        for (sign, term) in self.split_terms(inside)? {
+            // reg*scale or scale*reg
+            if let Some((left, right)) = term.split_once('*') {
+                let (register, scale) = match (parse_register(left.trim()), parse_register(right.trim())) {
+                    (Some(register), None) => (register, right.trim()),
+                    (None, Some(register)) => (register, left.trim()),
+                    _ => return Err(format!("Bad scaled index {}", term))
+                };
+                let scale = parse_number(scale).ok_or(format!("Bad scale {}", scale))?;
+                if !matches!(scale, 1 | 2 | 4 | 8) || sign < 0 || memory.index.is_some() {
+                    return Err(format!("Bad scaled index {}", term));
+                }
+                memory.index = Some(register);
+                memory.scale = scale as u8;
+                continue;
+            }
+
+            if let Some(register) = parse_register(term) {
+                if sign < 0 || register.kind != RegisterKind::General {
+                    return Err(format!("Bad address register {}", term));
+                }
+                if memory.base.is_none() {
+                    memory.base = Some(register);
+                }
+                else if memory.index.is_none() {
+                    memory.index = Some(register);
+                }
+                else {
+                    return Err(format!("Too many registers in {}", text));
+                }
+                continue;
+            }
+
+            displacement.push((sign, self.parse_term(term)?));
+        }
+
+        if !displacement.is_empty() {
+            memory.displacement = Some(Expr { terms: displacement });
+        }
+        Ok(memory)
+    }
+
+    fn parse_segment_override(&self, text: &str) -> Result<u8, String> {
+        match parse_register(text) {
+            Some(register) if register.kind == RegisterKind::Segment => Ok(register.number),
+            _ => Err(format!("Bad segment override {}", text))
+        }
+    }
+
+    fn parse_operand(&self, text: &str) -> Result<Operand, String> {
+        let mut text = text.trim();
+        let mut size = None;
+
+        // An optional size keyword, optionally followed by `ptr`
+        if let Some((first, rest)) = text.split_once(char::is_whitespace) {
+            if let Some(keyword_size) = parse_size(first) {
+                size = Some(keyword_size);
+                text = rest.trim();
+                if let Some(rest) = text.strip_prefix("ptr") {
+                    text = rest.trim();
+                }
+            }
+            else if first == "short" || first == "near" {
+                text = rest.trim();
+            }
+        }
+
+        if text.contains('[') {
+            return Ok(Operand::Memory(self.parse_memory(text, size)?));
+        }
+        if let Some(register) = parse_register(text) {
+            return Ok(Operand::Register(register));
+        }
+        Ok(Operand::Immediate(self.parse_expr(text)?, size))
+    }
+
+    fn parse_data(&self, size: u8, text: &str) -> Result<Vec<DataItem>, String> {
+        let mut items = vec!();
+        for item in split_top_level(text, ',') {
+            let is_string = item.len() >= 2 && (item.starts_with('"') && item.ends_with('"') || (size == 1 && item.len() > 3 && item.starts_with('\'') && item.ends_with('\'')));
+            if is_string {
+                let mut bytes: Vec<u8> = item[1..item.len() - 1].bytes().collect();
+                // Strings are padded to a whole number of items
+                while bytes.len() & (size as usize - 1) != 0 {
+                    bytes.push(0);
+                }
+                items.push(DataItem::Bytes(bytes));
+            }
+            else {
+                items.push(DataItem::Value(self.parse_expr(&item)?));
+            }
+        }
+        Ok(items)
+    }
+
+    /// Parses one line with the comment and any labels already removed.
+    fn parse_statement(&self, text: &str) -> Result<Statement, String> {
+        let (word, rest) = match text.split_once(char::is_whitespace) {
+            Some((word, rest)) => (word, rest.trim()),
+            None => (text, "")
+        };
+        let keyword = word.to_ascii_lowercase();
+
+        match keyword.as_str() {
+            "bits" => {
+                return match rest {
+                    "16" => Ok(Statement::Bits(Mode::Bits16)),
+                    "32" => Ok(Statement::Bits(Mode::Bits32)),
+                    "64" => Ok(Statement::Bits(Mode::Bits64)),
+                    _ => Err(format!("bits must be 16, 32 or 64, not {}", rest))
+                };
+            }
+            "org" => return Ok(Statement::Org(self.parse_expr(rest)?)),
+            "db" => return Ok(Statement::Data(1, self.parse_data(1, rest)?)),
+            "dw" => return Ok(Statement::Data(2, self.parse_data(2, rest)?)),
+            "dd" => return Ok(Statement::Data(4, self.parse_data(4, rest)?)),
+            "dq" => return Ok(Statement::Data(8, self.parse_data(8, rest)?)),
+            "align" => {
+                let parts = split_top_level(rest, ',');
+                let fill = match parts.get(1) {
+                    Some(fill) => Some(self.parse_expr(fill)?),
+                    None => None
+                };
+                return Ok(Statement::Align(self.parse_expr(&parts[0])?, fill));
+            }
+            "times" => {
+                let (count, repeated) = rest.split_once(char::is_whitespace).ok_or("times needs a count and a line")?;
+                let count = self.parse_expr(count)?;
+                return Ok(Statement::Times(count, Box::new(self.parse_statement(repeated.trim())?)));
+            }
+            _ => {}
+        }
+
+        // `name equ value`
+        if let Some((equ, value)) = rest.split_once(char::is_whitespace) {
+            if equ.eq_ignore_ascii_case("equ") && is_identifier(word) {
+                return Ok(Statement::Equ(self.qualify(word), self.parse_expr(value.trim())?));
+            }
+        }
+
+        // Prefixes are written as separate words in front of the mnemonic
+        let mut prefixes = vec!();
+        let mut words = text;
+        loop {
+            let (word, rest) = match words.split_once(char::is_whitespace) {
+                Some((word, rest)) => (word, rest.trim()),
+                None => (words, "")
+            };
+            let prefix = match word.to_ascii_lowercase().as_str() {
+                "lock" => 0xF0,
+                "rep" | "repe" | "repz" => 0xF3,
+                "repne" | "repnz" => 0xF2,
+                // Segment overrides for string instructions, `cs lodsb`
+                "es" | "cs" | "ss" | "ds" | "fs" | "gs" if !rest.is_empty() => {
+                    SEGMENT_PREFIXES[parse_register(word).unwrap().number as usize]
+                }
+                _ => break
+            };
+            prefixes.push(prefix);
+            words = rest;
+        }
+
+        let (mnemonic, operand_text) = match words.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+            None => (words, "")
+        };
+        if mnemonic.is_empty() {
+            return Err(String::from("Prefix without an instruction"));
+        }
+
+        let mut operands = vec!();
+        if !operand_text.is_empty() {
+            for operand in split_top_level(operand_text, ',') {
+                operands.push(self.parse_operand(&operand)?);
+            }
+        }
+
+        Ok(Statement::Instruction {
+            prefixes: prefixes,
+            mnemonic: mnemonic.to_ascii_lowercase(),
+            operands: operands
+        })
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+/// Parses the whole source into statements tagged with their line numbers.
+fn parse_source(source: &str) -> Result<Vec<(usize, Statement)>, String> {
+    let mut parser = Parser { scope: String::new() };
+    let mut statements = vec!();
+
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let mut text = strip_comment(line).trim();
+
+        // Any number of `label:` in front of the statement
+        while let Some(colon) = text.find(':') {
+            let name = text[..colon].trim();
+            if !is_identifier(name) || parse_register(name).is_some() {
+                break;
+            }
+            if !name.starts_with('.') {
+                parser.scope = String::from(name);
+            }
+            statements.push((line_number, Statement::Label(parser.qualify(name))));
+            text = text[colon + 1..].trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let statement = parser.parse_statement(text).map_err(|error_message| format!("line {}: {}", line_number, error_message))?;
+        statements.push((line_number, statement));
+    }
+
+    Ok(statements)
+}
+
+// ---------------------------------------------------------------------------------------------------------------
+// Encoding
+
+struct Context<'a> {
+    labels: &'a BTreeMap<String, i64>,
+    /// The address of the current statement.
+    address: u64,
+    mode: Mode,
+    final_pass: bool
+}
+
+fn fits_i8(value: i64) -> bool {
+    (-128..=127).contains(&value)
+}
+
+fn fits_signed(value: i64, size: u8) -> bool {
+    if size >= 8 {
+        return true;
+    }
+    let bits = size as u32 * 8;
+    value >= -(1i64 << (bits - 1)) && value < (1i64 << (bits - 1))
+}
+
+/// Whether `value` fits in `size` bytes read either as signed or unsigned.
+fn fits_size(value: i64, size: u8) -> bool {
+    if size >= 8 {
+        return true;
+    }
+    let bits = size as u32 * 8;
+    value >= -(1i64 << (bits - 1)) && value < (1i64 << bits)
+}
+
+fn little_endian(value: i64, size: u8) -> Vec<u8> {
+    value.to_le_bytes()[..size as usize].to_vec()
+}
+
+#[derive(Default)]
+struct Encoding {
+    /// LOCK, REP and segment override prefixes.
+    prefixes: Vec<u8>,
+    /// The operand size, for the 0x66 prefix and REX.W. None for instructions without a size dependent form.
+    operand_size: Option<u8>,
+    /// Whether the instruction defaults to 64 bit operands in 64 bit mode (push, pop), so needs no REX.W.
+    default_64: bool,
+    address_size: Option<u8>,
+    /// The 66/F2/F3 byte some SSE instructions are selected by, which must come last before REX.
+    mandatory_prefix: Option<u8>,
+    /// REX.W/R/X/B bits.
+    rex: u8,
+    rex_required: bool,
+    rex_forbidden: bool,
+    opcode: Vec<u8>,
+    /// ModRM, SIB and displacement.
+    modrm: Vec<u8>,
+    immediate: Vec<u8>
+}
+
+const REX_W: u8 = 0x8;
+const REX_R: u8 = 0x4;
+const REX_X: u8 = 0x2;
+const REX_B: u8 = 0x1;
+
+impl Encoding {
+
+    fn new(opcode: &[u8]) -> Self {
+        Encoding {
+            opcode: opcode.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn sized(opcode: &[u8], size: u8) -> Self {
+        let mut encoding = Encoding::new(opcode);
+        encoding.operand_size = Some(size);
+        encoding
+    }
+
+    fn use_register(&mut self, register: &Register, rex_bit: u8) {
+        if register.number >= 8 {
+            self.rex |= rex_bit;
+        }
+        self.rex_required |= register.requires_rex;
+        self.rex_forbidden |= register.high_byte;
+    }
+
+    /// Adds the register `number` to the last opcode byte, as in `push r` or `mov r, imm`.
+    fn opcode_register(&mut self, register: &Register) {
+        *self.opcode.last_mut().unwrap() += register.number & 7;
+        self.use_register(register, REX_B);
+    }
+
+    /// Encodes ModRM with `reg` in the reg field and `rm` (a register or memory) in the r/m field.
+    fn modrm(&mut self, reg: u8, rm: &Operand, context: &Context) -> Result<(), String> {
+        match rm {
+            Operand::Register(register) => {
+                self.modrm.push(0xC0 | ((reg & 7) << 3) | (register.number & 7));
+                self.use_register(register, REX_B);
+                if reg >= 8 {
+                    self.rex |= REX_R;
+                }
+                Ok(())
+            }
+            Operand::Memory(memory) => {
+                if reg >= 8 {
+                    self.rex |= REX_R;
+                }
+                self.memory(reg & 7, memory, context)
+            }
+            Operand::Immediate(..) => Err(String::from("Expected a register or memory operand"))
+        }
+    }
+
+    fn modrm_register(&mut self, reg: &Register, rm: &Operand, context: &Context) -> Result<(), String> {
+        self.use_register(reg, REX_R);
+        self.modrm(reg.number & 7, rm, context)
+    }
+
+    fn memory(&mut self, reg: u8, memory: &Memory, context: &Context) -> Result<(), String> {
+        if let Some(segment) = memory.segment {
+            self.prefixes.push(SEGMENT_PREFIXES[segment as usize]);
+        }
+
+        let register_size = memory.base.or(memory.index).map(|register| register.size);
+        let address_size = match register_size {
+            Some(size) => size,
+            None => context.mode.size()
+        };
+        if memory.base.iter().chain(memory.index.iter()).any(|register| register.size != address_size || register.size == 1) {
+            return Err(String::from("Address registers must all be 16, 32 or 64 bit"));
+        }
+        match (context.mode, address_size) {
+            (Mode::Bits64, 2) => return Err(String::from("16 bit addressing isn't available in 64 bit mode")),
+            (Mode::Bits16, 8) | (Mode::Bits32, 8) => return Err(String::from("64 bit addressing needs bits 64")),
+            _ => {}
+        }
+        self.address_size = Some(address_size);
+
+        let (displacement, symbolic) = match &memory.displacement {
+            Some(expr) => (expr.evaluate(context)?, !expr.is_constant()),
+            None => (0, false)
+        };
+
+        if address_size == 2 {
+            self.memory_16(reg, memory, displacement, symbolic, memory.displacement.is_some(), context)
+        }
+        else {
+            self.memory_32(reg, memory, displacement, symbolic, memory.displacement.is_some(), context)
+        }
+    }
+
+    fn memory_16(&mut self, reg: u8, memory: &Memory, displacement: i64, symbolic: bool, has_displacement: bool, context: &Context) -> Result<(), String> {
+        if memory.scale != 1 {
+            return Err(String::from("16 bit addressing has no scaled index"));
+        }
+
+        // bx=3 bp=5 si=6 di=7
+        let mut registers: Vec<u8> = memory.base.iter().chain(memory.index.iter()).map(|register| register.number).collect();
+        registers.sort_unstable();
+        let rm = match registers.as_slice() {
+            [] => None,
+            [3, 6] => Some(0),
+            [3, 7] => Some(1),
+            [5, 6] => Some(2),
+            [5, 7] => Some(3),
+            [6] => Some(4),
+            [7] => Some(5),
+            [5] => Some(6),
+            [3] => Some(7),
+            _ => return Err(String::from("16 bit addresses are [bx|bp + si|di + displacement]"))
+        };
+
+        if context.final_pass && !fits_size(displacement, 2) {
+            return Err(format!("Displacement {:#x} doesn't fit in 16 bits", displacement));
+        }
+
+        match rm {
+            None => {
+                self.modrm.push((reg << 3) | 0x06);
+                self.modrm.extend(little_endian(displacement, 2));
+            }
+            // [bp] has no mod=00 form
+            Some(rm) if !has_displacement && rm != 6 => self.modrm.push((reg << 3) | rm),
+            Some(rm) if !symbolic && fits_i8(displacement) => {
+                self.modrm.push(0x40 | (reg << 3) | rm);
+                self.modrm.push(displacement as u8);
+            }
+            Some(rm) => {
+                self.modrm.push(0x80 | (reg << 3) | rm);
+                self.modrm.extend(little_endian(displacement, 2));
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_32(&mut self, reg: u8, memory: &Memory, displacement: i64, symbolic: bool, has_displacement: bool, context: &Context) -> Result<(), String> {
+        if context.final_pass && !fits_size(displacement, 4) && !(context.mode == Mode::Bits64 && fits_signed(displacement, 4)) {
+            return Err(format!("Displacement {:#x} doesn't fit in 32 bits", displacement));
+        }
+
+        let mut base = memory.base;
+        let mut index = memory.index;
+        let mut scale = memory.scale;
+
+        // [esp] can't be an index, so swap an unscaled esp into the base
+        if let (Some(base_register), Some(index_register)) = (base, index) {
+            if index_register.number == 4 && scale == 1 {
+                base = Some(index_register);
+                index = Some(base_register);
+            }
+        }
+        // A lone scaled register: [eax*2] is encoded as [eax+eax] when possible
+        if base.is_none() && scale == 2 {
+            base = index;
+            scale = 1;
+        }
+        if let Some(index_register) = index {
+            if index_register.number == 4 {
+                return Err(String::from("esp/rsp can't be an index register"));
+            }
+            self.use_register(&index_register, REX_X);
+        }
+        if let Some(base_register) = base {
+            self.use_register(&base_register, REX_B);
+        }
+
+        let scale_bits = match scale {
+            1 => 0,
+            2 => 1,
+            4 => 2,
+            _ => 3
+        };
+
+        let displacement_mode = |base_number: u8| -> (u8, Vec<u8>) {
+            // [ebp]/[r13] have no mod=00 form
+            if !has_displacement && base_number & 7 != 5 {
+                (0x00, vec!())
+            }
+            else if !symbolic && fits_i8(displacement) {
+                (0x40, vec!(displacement as u8))
+            }
+            else {
+                (0x80, little_endian(displacement, 4))
+            }
+        };
+
+        match (base, index) {
+            (None, None) => {
+                if context.mode == Mode::Bits64 {
+                    // mod=00 rm=101 is RIP relative in 64 bit mode, an absolute address needs a SIB
+                    self.modrm.push((reg << 3) | 0x04);
+                    self.modrm.push(0x25);
+                }
+                else {
+                    self.modrm.push((reg << 3) | 0x05);
+                }
+                self.modrm.extend(little_endian(displacement, 4));
+            }
+            (None, Some(index_register)) => {
+                self.modrm.push((reg << 3) | 0x04);
+                self.modrm.push((scale_bits << 6) | ((index_register.number & 7) << 3) | 0x05);
+                self.modrm.extend(little_endian(displacement, 4));
+            }
+            (Some(base_register), None) if base_register.number & 7 != 4 => {
+                let (mode, bytes) = displacement_mode(base_register.number);
+                self.modrm.push(mode | (reg << 3) | (base_register.number & 7));
+                self.modrm.extend(bytes);
+            }
+            (Some(base_register), index_register) => {
+                let (mode, bytes) = displacement_mode(base_register.number);
+                // Index 100 means no index
+                let index_bits = index_register.map(|register| register.number & 7).unwrap_or(4);
+                self.modrm.push(mode | (reg << 3) | 0x04);
+                self.modrm.push((scale_bits << 6) | (index_bits << 3) | (base_register.number & 7));
+                self.modrm.extend(bytes);
+            }
+        }
+        Ok(())
+    }
+
+    fn bytes(self, mode: Mode) -> Result<Vec<u8>, String> {
+        let mut bytes = self.prefixes;
+        let mut rex = self.rex;
+
+        if let Some(size) = self.operand_size {
+            match (mode, size) {
+                (Mode::Bits16, 4) | (Mode::Bits32, 2) | (Mode::Bits64, 2) => bytes.push(0x66),
+                (Mode::Bits16, 8) | (Mode::Bits32, 8) => return Err(String::from("64 bit operands need bits 64")),
+                (Mode::Bits64, 8) if !self.default_64 => rex |= REX_W,
+                (Mode::Bits64, 4) if self.default_64 => return Err(String::from("32 bit operands can't be encoded in 64 bit mode")),
+                _ => {}
+            }
+        }
+
+        if let Some(size) = self.address_size {
+            if matches!((mode, size), (Mode::Bits16, 4) | (Mode::Bits32, 2) | (Mode::Bits64, 4)) {
+                bytes.push(0x67);
+            }
+        }
+
+        if let Some(prefix) = self.mandatory_prefix {
+            bytes.push(prefix);
+        }
+
+        if rex != 0 || self.rex_required {
+            if mode != Mode::Bits64 {
+                return Err(String::from("r8-r15, spl, bpl, sil and dil need bits 64"));
+            }
+            if self.rex_forbidden {
+                return Err(String::from("ah, ch, dh and bh can't be used with a REX prefix"));
+            }
+            bytes.push(0x40 | rex);
+        }
+
+        bytes.extend(self.opcode);
+        bytes.extend(self.modrm);
+        bytes.extend(self.immediate);
+        Ok(bytes)
+    }
+}
+
+const ALU_MNEMONICS: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT_MNEMONICS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+
+/// The condition code of a jcc/setcc/cmovcc suffix.
+fn condition(suffix: &str) -> Option<u8> {
+    let code = match suffix {
+        "o" => 0x0,
+        "no" => 0x1,
+        "b" | "c" | "nae" => 0x2,
+        "ae" | "nc" | "nb" => 0x3,
+        "e" | "z" => 0x4,
+        "ne" | "nz" => 0x5,
+        "be" | "na" => 0x6,
+        "a" | "nbe" => 0x7,
+        "s" => 0x8,
+        "ns" => 0x9,
+        "p" | "pe" => 0xA,
+        "np" | "po" => 0xB,
+        "l" | "nge" => 0xC,
+        "ge" | "nl" => 0xD,
+        "le" | "ng" => 0xE,
+        "g" | "nle" => 0xF,
+        _ => return None
+    };
+    Some(code)
+}
+
+/// An SSE instruction of the `op xmm, xmm/mem` form, and the `op mem, xmm` form for the moves.
+struct SseInstruction {
+    mnemonic: &'static str,
+    prefix: Option<u8>,
+    load: u8,
+    store: Option<u8>,
+    memory_size: u8
+}
+
+const fn sse(mnemonic: &'static str, prefix: Option<u8>, load: u8, store: Option<u8>, memory_size: u8) -> SseInstruction {
+    SseInstruction {
+        mnemonic: mnemonic,
+        prefix: prefix,
+        load: load,
+        store: store,
+        memory_size: memory_size
+    }
+}
+
+const SSE_INSTRUCTIONS: [SseInstruction; 24] = [
+    sse("movss", Some(0xF3), 0x10, Some(0x11), 4),
+    sse("movsd", Some(0xF2), 0x10, Some(0x11), 8),
+    sse("movups", None, 0x10, Some(0x11), 16),
+    sse("movupd", Some(0x66), 0x10, Some(0x11), 16),
+    sse("movaps", None, 0x28, Some(0x29), 16),
+    sse("movapd", Some(0x66), 0x28, Some(0x29), 16),
+    sse("addss", Some(0xF3), 0x58, None, 4),
+    sse("addsd", Some(0xF2), 0x58, None, 8),
+    sse("subss", Some(0xF3), 0x5C, None, 4),
+    sse("subsd", Some(0xF2), 0x5C, None, 8),
+    sse("mulss", Some(0xF3), 0x59, None, 4),
+    sse("mulsd", Some(0xF2), 0x59, None, 8),
+    sse("divss", Some(0xF3), 0x5E, None, 4),
+    sse("divsd", Some(0xF2), 0x5E, None, 8),
+    sse("sqrtss", Some(0xF3), 0x51, None, 4),
+    sse("sqrtsd", Some(0xF2), 0x51, None, 8),
+    sse("comiss", None, 0x2F, None, 4),
+    sse("comisd", Some(0x66), 0x2F, None, 8),
+    sse("ucomiss", None, 0x2E, None, 4),
+    sse("ucomisd", Some(0x66), 0x2E, None, 8),
+    sse("addps", None, 0x58, None, 16),
+    sse("addpd", Some(0x66), 0x58, None, 16),
+    sse("mulps", None, 0x59, None, 16),
+    sse("mulpd", Some(0x66), 0x59, None, 16)
+];
+
+/// Instructions without operands.
+fn simple_instruction(mnemonic: &str, mode: Mode) -> Option<Encoding> {
+    let not_64 = mode != Mode::Bits64;
+    let encoding = match mnemonic {
+        "nop" => Encoding::new(&[0x90]),
+        "pause" => Encoding::new(&[0xF3, 0x90]),
+        "hlt" => Encoding::new(&[0xF4]),
+        "cmc" => Encoding::new(&[0xF5]),
+        "clc" => Encoding::new(&[0xF8]),
+        "stc" => Encoding::new(&[0xF9]),
+        "cli" => Encoding::new(&[0xFA]),
+        "sti" => Encoding::new(&[0xFB]),
+        "cld" => Encoding::new(&[0xFC]),
+        "std" => Encoding::new(&[0xFD]),
+        "sahf" => Encoding::new(&[0x9E]),
+        "lahf" => Encoding::new(&[0x9F]),
+        "int3" => Encoding::new(&[0xCC]),
+        "into" if not_64 => Encoding::new(&[0xCE]),
+        "leave" => Encoding::new(&[0xC9]),
+        "ret" => Encoding::new(&[0xC3]),
+        "daa" if not_64 => Encoding::new(&[0x27]),
+        "das" if not_64 => Encoding::new(&[0x2F]),
+        "aaa" if not_64 => Encoding::new(&[0x37]),
+        "aas" if not_64 => Encoding::new(&[0x3F]),
+        "aam" if not_64 => Encoding::new(&[0xD4, 0x0A]),
+        "aad" if not_64 => Encoding::new(&[0xD5, 0x0A]),
+        "cpuid" => Encoding::new(&[0x0F, 0xA2]),
+        "rdtsc" => Encoding::new(&[0x0F, 0x31]),
+        "rdmsr" => Encoding::new(&[0x0F, 0x32]),
+        "wrmsr" => Encoding::new(&[0x0F, 0x30]),
+        "ud2" => Encoding::new(&[0x0F, 0x0B]),
+        "cbw" => Encoding::sized(&[0x98], 2),
+        "cwde" => Encoding::sized(&[0x98], 4),
+        "cdqe" => Encoding::sized(&[0x98], 8),
+        "cwd" => Encoding::sized(&[0x99], 2),
+        "cdq" => Encoding::sized(&[0x99], 4),
+        "cqo" => Encoding::sized(&[0x99], 8),
+        "iret" => Encoding::sized(&[0xCF], 2),
+        "iretd" => Encoding::sized(&[0xCF], 4),
+        "iretq" => Encoding::sized(&[0xCF], 8),
+        "pusha" if not_64 => Encoding::sized(&[0x60], 2),
+        "pushad" if not_64 => Encoding::sized(&[0x60], 4),
+        "popa" if not_64 => Encoding::sized(&[0x61], 2),
+        "popad" if not_64 => Encoding::sized(&[0x61], 4),
+        "pushf" => Encoding { default_64: true, ..Encoding::sized(&[0x9C], mode.size()) },
+        "popf" => Encoding { default_64: true, ..Encoding::sized(&[0x9D], mode.size()) },
+        "pushfd" if not_64 => Encoding::sized(&[0x9C], 4),
+        "popfd" if not_64 => Encoding::sized(&[0x9D], 4),
+        "pushfq" => Encoding { default_64: true, ..Encoding::sized(&[0x9C], 8) },
+        "popfq" => Encoding { default_64: true, ..Encoding::sized(&[0x9D], 8) },
+        _ => {
+            // String instructions: movsb/movsw/movsd/movsq and friends
+            let (base, suffix) = mnemonic.split_at(mnemonic.len().checked_sub(1)?);
+            let opcode = match base {
+                "movs" => 0xA4,
+                "cmps" => 0xA6,
+                "stos" => 0xAA,
+                "lods" => 0xAC,
+                "scas" => 0xAE,
+                _ => return None
+            };
+            match suffix {
+                "b" => Encoding::new(&[opcode]),
+                "w" => Encoding::sized(&[opcode + 1], 2),
+                "d" => Encoding::sized(&[opcode + 1], 4),
+                "q" => Encoding::sized(&[opcode + 1], 8),
+                _ => return None
+            }
+        }
+    };
+    Some(encoding)
+}
+
+fn operand_size_of(operand: &Operand) -> Option<u8> {
+    match operand {
+        Operand::Register(register) => Some(register.size),
+        Operand::Memory(memory) => memory.size,
+        Operand::Immediate(_, size) => *size
+    }
+}
+
+fn is_register_or_memory(operand: &Operand) -> bool {
+    match operand {
+        Operand::Register(register) => register.kind == RegisterKind::General,
+        Operand::Memory(_) => true,
+        Operand::Immediate(..) => false
+    }
+}
+
+fn general(operand: &Operand) -> Option<Register> {
+    match operand {
+        Operand::Register(register) if register.kind == RegisterKind::General => Some(*register),
+        _ => None
+    }
+}
+
+/// The size of a two operand instruction, from whichever operand has one. Mismatches are errors.
+fn common_size(destination: &Operand, source: &Operand) -> Result<u8, String> {
+    match (operand_size_of(destination), operand_size_of(source)) {
+        (Some(a), Some(b)) if a != b && !matches!(source, Operand::Immediate(..)) => Err(String::from("Operand sizes don't match")),
+        (Some(size), _) => Ok(size),
+        (None, Some(size)) => Ok(size),
+        (None, None) => Err(String::from("Operation size not specified, add byte/word/dword/qword"))
+    }
+}
+
+fn immediate_value(expr: &Expr, size: u8, context: &Context) -> Result<Vec<u8>, String> {
+    let value = expr.evaluate(context)?;
+    if context.final_pass && !fits_size(value, size) {
+        return Err(format!("Immediate {:#x} doesn't fit in {} bytes", value, size));
+    }
+    Ok(little_endian(value, size))
+}
+
+/// Whether an immediate can use a sign extended 8 bit form.
+fn small_immediate(expr: &Expr, size: u8, context: &Context) -> Result<bool, String> {
+    if !expr.is_constant() {
+        return Ok(false);
+    }
+    let value = expr.evaluate(context)?;
+    // 0xFFFFFFF0 as a dword is -16 sign extended
+    let truncated = if size < 8 { (value << (64 - size * 8)) >> (64 - size * 8) } else { value };
+    Ok(fits_i8(value) || (fits_size(value, size) && fits_i8(truncated)))
+}
+
+/// Immediates are at most 32 bits, sign extended to 64 bit operands.
+fn immediate_size(size: u8) -> u8 {
+    size.min(4)
+}
+
+struct Jump {
+    short_opcode: Option<Vec<u8>>,
+    long_opcode: Option<Vec<u8>>
+}
+
+/// Encodes a relative branch, short when allowed and in range. Returns the bytes and whether the long form was
+/// needed.
+fn relative_branch(jump: Jump, target: &Expr, force_long: bool, context: &Context) -> Result<(Vec<u8>, bool), String> {
+    let target = target.evaluate(context)?;
+    let long_size = if context.mode == Mode::Bits16 { 2 } else { 4 };
+
+    if let Some(short_opcode) = &jump.short_opcode {
+        if !force_long || jump.long_opcode.is_none() {
+            let end = (context.address as i64).wrapping_add(short_opcode.len() as i64 + 1);
+            let displacement = target.wrapping_sub(end);
+            if fits_i8(displacement) || jump.long_opcode.is_none() {
+                if context.final_pass && !fits_i8(displacement) {
+                    return Err(format!("Branch target {:#x} is out of short range", target));
+                }
+                let mut bytes = short_opcode.clone();
+                bytes.push(displacement as u8);
+                return Ok((bytes, false));
+            }
+        }
+    }
+
+    let long_opcode = jump.long_opcode.unwrap();
+    let end = (context.address as i64).wrapping_add(long_opcode.len() as i64 + long_size as i64);
+    let displacement = target.wrapping_sub(end);
+    let mut bytes = long_opcode;
+    if long_size == 2 {
+        // Near branches in 16 bit code wrap around the 64K segment
+        bytes.extend(little_endian(displacement, 2));
+    }
+    else {
+        if context.final_pass && !fits_signed(displacement, 4) {
+            return Err(format!("Branch target {:#x} is out of range", target));
+        }
+        bytes.extend(little_endian(displacement, 4));
+    }
+    Ok((bytes, true))
+}
+
+/// Encodes one instruction. `force_long` asks for the near form of a jump an earlier pass found out of short range.
+/// Returns the bytes and whether a jump needed its long form.
+fn encode_instruction(prefixes: &[u8], mnemonic: &str, operands: &[Operand], force_long: bool, context: &Context) -> Result<(Vec<u8>, bool), String> {
+    let mode = context.mode;
+
+    let mut encoding = match (mnemonic, operands) {
+        (_, []) if simple_instruction(mnemonic, mode).is_some() => simple_instruction(mnemonic, mode).unwrap(),
+
+        // Relative branches
+        ("jmp", [Operand::Immediate(target, _)]) | ("call", [Operand::Immediate(target, _)]) => {
+            let jump = if mnemonic == "jmp" {
+                Jump { short_opcode: Some(vec!(0xEB)), long_opcode: Some(vec!(0xE9)) }
+            }
+            else {
+                Jump { short_opcode: None, long_opcode: Some(vec!(0xE8)) }
+            };
+            let (bytes, long) = relative_branch(jump, target, force_long, context)?;
+            let mut prefixed = prefixes.to_vec();
+            prefixed.extend(bytes);
+            return Ok((prefixed, long));
+        }
+        ("loop", [Operand::Immediate(target, _)]) | ("loope", [Operand::Immediate(target, _)]) | ("loopz", [Operand::Immediate(target, _)])
+            | ("loopne", [Operand::Immediate(target, _)]) | ("loopnz", [Operand::Immediate(target, _)])
+            | ("jcxz", [Operand::Immediate(target, _)]) | ("jecxz", [Operand::Immediate(target, _)]) | ("jrcxz", [Operand::Immediate(target, _)]) => {
+            let opcode = match mnemonic {
+                "loopne" | "loopnz" => vec!(0xE0),
+                "loope" | "loopz" => vec!(0xE1),
+                "loop" => vec!(0xE2),
+                // jcxz/jecxz are told apart by the address size
+                "jcxz" if mode != Mode::Bits16 => vec!(0x67, 0xE3),
+                "jecxz" if mode != Mode::Bits32 => vec!(0x67, 0xE3),
+                _ => vec!(0xE3)
+            };
+            let (bytes, _) = relative_branch(Jump { short_opcode: Some(opcode), long_opcode: None }, target, false, context)?;
+            return Ok((bytes, false));
+        }
+        (_, [Operand::Immediate(target, _)]) if mnemonic.starts_with('j') && condition(&mnemonic[1..]).is_some() => {
+            let code = condition(&mnemonic[1..]).unwrap();
+            let jump = Jump { short_opcode: Some(vec!(0x70 + code)), long_opcode: Some(vec!(0x0F, 0x80 + code)) };
+            let (bytes, long) = relative_branch(jump, target, force_long, context)?;
+            return Ok((bytes, long));
+        }
+        ("jmp", [target]) | ("call", [target]) if is_register_or_memory(target) => {
+            let size = operand_size_of(target).unwrap_or(mode.size());
+            let mut encoding = Encoding::sized(&[0xFF], size);
+            encoding.default_64 = true;
+            encoding.modrm(if mnemonic == "call" { 2 } else { 4 }, target, context)?;
+            encoding
+        }
+
+        // The ALU group
+        (_, [destination, source]) if ALU_MNEMONICS.contains(&mnemonic) => {
+            let number = ALU_MNEMONICS.iter().position(|name| *name == mnemonic).unwrap() as u8;
+            let size = common_size(destination, source)?;
+            let byte_form = if size == 1 { 0 } else { 1 };
+
+            match (destination, source) {
+                (_, Operand::Register(register)) if is_register_or_memory(destination) && register.kind == RegisterKind::General => {
+                    let mut encoding = Encoding::sized(&[number * 8 + byte_form], size);
+                    encoding.modrm_register(register, destination, context)?;
+                    encoding
+                }
+                (Operand::Register(register), Operand::Memory(_)) if register.kind == RegisterKind::General => {
+                    let mut encoding = Encoding::sized(&[number * 8 + 2 + byte_form], size);
+                    encoding.modrm_register(register, source, context)?;
+                    encoding
+                }
+                (_, Operand::Immediate(value, _)) if is_register_or_memory(destination) => {
+                    let is_accumulator = matches!(general(destination), Some(register) if register.number == 0);
+                    if size == 1 {
+                        if is_accumulator {
+                            let mut encoding = Encoding::new(&[number * 8 + 4]);
+                            encoding.immediate = immediate_value(value, 1, context)?;
+                            encoding
+                        }
+                        else {
+                            let mut encoding = Encoding::new(&[0x80]);
+                            encoding.modrm(number, destination, context)?;
+                            encoding.immediate = immediate_value(value, 1, context)?;
+                            encoding
+                        }
+                    }
+                    else if small_immediate(value, size, context)? {
+                        let mut encoding = Encoding::sized(&[0x83], size);
+                        encoding.modrm(number, destination, context)?;
+                        encoding.immediate = little_endian(value.evaluate(context)?, 1);
+                        encoding
+                    }
+                    else if is_accumulator {
+                        let mut encoding = Encoding::sized(&[number * 8 + 5], size);
+                        encoding.immediate = immediate_value(value, immediate_size(size), context)?;
+                        encoding
+                    }
+                    else {
+                        let mut encoding = Encoding::sized(&[0x81], size);
+                        encoding.modrm(number, destination, context)?;
+                        encoding.immediate = immediate_value(value, immediate_size(size), context)?;
+                        encoding
+                    }
+                }
+                _ => return Err(format!("Invalid operands for {}", mnemonic))
+            }
+        }
+
+        ("test", [destination, source]) => {
+            let size = common_size(destination, source)?;
+            let byte_form = if size == 1 { 0 } else { 1 };
+            match (destination, source) {
+                (_, Operand::Register(register)) | (Operand::Register(register), _) if register.kind == RegisterKind::General && !matches!(source, Operand::Immediate(..)) => {
+                    let other = if matches!(source, Operand::Register(source_register) if source_register == register) { destination } else { source };
+                    let mut encoding = Encoding::sized(&[0x84 + byte_form], size);
+                    encoding.modrm_register(register, other, context)?;
+                    encoding
+                }
+                (_, Operand::Immediate(value, _)) if is_register_or_memory(destination) => {
+                    if matches!(general(destination), Some(register) if register.number == 0) {
+                        let mut encoding = Encoding::sized(&[0xA8 + byte_form], size);
+                        encoding.immediate = immediate_value(value, immediate_size(size), context)?;
+                        encoding
+                    }
+                    else {
+                        let mut encoding = Encoding::sized(&[0xF6 + byte_form], size);
+                        encoding.modrm(0, destination, context)?;
+                        encoding.immediate = immediate_value(value, immediate_size(size), context)?;
+                        encoding
+                    }
+                }
+                _ => return Err(String::from("Invalid operands for test"))
+            }
+        }
+
+        ("mov", [destination, source]) => {
+            match (destination, source) {
+                (Operand::Register(segment), _) if segment.kind == RegisterKind::Segment => {
+                    if segment.number == 1 {
+                        return Err(String::from("cs can't be loaded with mov"));
+                    }
+                    let mut encoding = Encoding::new(&[0x8E]);
+                    encoding.modrm(segment.number, source, context)?;
+                    encoding
+                }
+                (_, Operand::Register(segment)) if segment.kind == RegisterKind::Segment => {
+                    let mut encoding = Encoding::new(&[0x8C]);
+                    if let Some(register) = general(destination) {
+                        encoding.operand_size = Some(register.size);
+                    }
+                    encoding.modrm(segment.number, destination, context)?;
+                    encoding
+                }
+                (Operand::Register(register), Operand::Immediate(value, _)) if register.kind == RegisterKind::General => {
+                    let size = register.size;
+                    let mut encoding = Encoding::sized(&[if size == 1 { 0xB0 } else { 0xB8 }], size);
+                    encoding.opcode_register(register);
+                    encoding.immediate = immediate_value(value, size, context)?;
+                    encoding
+                }
+                (Operand::Memory(_), Operand::Immediate(value, _)) => {
+                    let size = common_size(destination, source)?;
+                    let mut encoding = Encoding::sized(&[if size == 1 { 0xC6 } else { 0xC7 }], size);
+                    encoding.modrm(0, destination, context)?;
+                    encoding.immediate = immediate_value(value, immediate_size(size), context)?;
+                    encoding
+                }
+                (_, Operand::Register(register)) if is_register_or_memory(destination) && register.kind == RegisterKind::General => {
+                    let size = common_size(destination, source)?;
+                    let mut encoding = Encoding::sized(&[if size == 1 { 0x88 } else { 0x89 }], size);
+                    encoding.modrm_register(register, destination, context)?;
+                    encoding
+                }
+                (Operand::Register(register), Operand::Memory(_)) if register.kind == RegisterKind::General => {
+                    let size = common_size(destination, source)?;
+                    let mut encoding = Encoding::sized(&[if size == 1 { 0x8A } else { 0x8B }], size);
+                    encoding.modrm_register(register, source, context)?;
+                    encoding
+                }
+                _ => return Err(String::from("Invalid operands for mov"))
+            }
+        }
+
+        ("xchg", [first, second]) => {
+            let size = common_size(first, second)?;
+            match (general(first), general(second)) {
+                (Some(a), Some(b)) if size > 1 && (a.number == 0 || b.number == 0) && !(mode == Mode::Bits64 && size == 4 && a.number == 0 && b.number == 0) => {
+                    let other = if a.number == 0 { b } else { a };
+                    let mut encoding = Encoding::sized(&[0x90], size);
+                    encoding.opcode_register(&other);
+                    encoding
+                }
+                (_, Some(register)) => {
+                    let mut encoding = Encoding::sized(&[if size == 1 { 0x86 } else { 0x87 }], size);
+                    encoding.modrm_register(&register, first, context)?;
+                    encoding
+                }
+                (Some(register), _) => {
+                    let mut encoding = Encoding::sized(&[if size == 1 { 0x86 } else { 0x87 }], size);
+                    encoding.modrm_register(&register, second, context)?;
+                    encoding
+                }
+                _ => return Err(String::from("Invalid operands for xchg"))
+            }
+        }
+
+        ("lea", [Operand::Register(register), source @ Operand::Memory(_)]) if register.kind == RegisterKind::General && register.size > 1 => {
+            let mut encoding = Encoding::sized(&[0x8D], register.size);
+            encoding.modrm_register(register, source, context)?;
+            encoding
+        }
+
+        ("inc", [operand]) | ("dec", [operand]) if is_register_or_memory(operand) => {
+            let number = if mnemonic == "inc" { 0 } else { 1 };
+            let size = operand_size_of(operand).ok_or("Operation size not specified, add byte/word/dword/qword")?;
+            match general(operand) {
+                // The one byte forms became REX prefixes in 64 bit mode
+                Some(register) if size > 1 && mode != Mode::Bits64 => {
+                    let mut encoding = Encoding::sized(&[0x40 + number * 8], size);
+                    encoding.opcode_register(&register);
+                    encoding
+                }
+                _ => {
+                    let mut encoding = Encoding::sized(&[if size == 1 { 0xFE } else { 0xFF }], size);
+                    encoding.modrm(number, operand, context)?;
+                    encoding
+                }
+            }
+        }
+
+        ("push", [operand]) | ("pop", [operand]) => {
+            let is_push = mnemonic == "push";
+            match operand {
+                Operand::Register(register) if register.kind == RegisterKind::Segment => {
+                    let opcode = match (register.number, is_push) {
+                        (4, true) => vec!(0x0F, 0xA0),
+                        (4, false) => vec!(0x0F, 0xA1),
+                        (5, true) => vec!(0x0F, 0xA8),
+                        (5, false) => vec!(0x0F, 0xA9),
+                        (1, false) => return Err(String::from("pop cs doesn't exist")),
+                        _ if mode == Mode::Bits64 => return Err(String::from("Only fs and gs can be pushed and popped in 64 bit mode")),
+                        (number, true) => vec!(number * 8 + 0x06),
+                        (number, false) => vec!(number * 8 + 0x07)
+                    };
+                    Encoding::new(&opcode)
+                }
+                Operand::Register(register) if register.kind == RegisterKind::General && register.size > 1 => {
+                    let mut encoding = Encoding::sized(&[if is_push { 0x50 } else { 0x58 }], register.size);
+                    encoding.default_64 = true;
+                    encoding.opcode_register(register);
+                    encoding
+                }
+                Operand::Memory(_) => {
+                    let size = operand_size_of(operand).unwrap_or(mode.size());
+                    let mut encoding = Encoding::sized(&[if is_push { 0xFF } else { 0x8F }], size);
+                    encoding.default_64 = true;
+                    encoding.modrm(if is_push { 6 } else { 0 }, operand, context)?;
+                    encoding
+                }
+                Operand::Immediate(value, size) if is_push => {
+                    let size = size.unwrap_or(mode.size());
+                    if small_immediate(value, size, context)? {
+                        let mut encoding = Encoding::sized(&[0x6A], size);
+                        encoding.default_64 = true;
+                        encoding.immediate = little_endian(value.evaluate(context)?, 1);
+                        encoding
+                    }
+                    else {
+                        let mut encoding = Encoding::sized(&[0x68], size);
+                        encoding.default_64 = true;
+                        encoding.immediate = immediate_value(value, immediate_size(size), context)?;
+                        encoding
+                    }
+                }
+                _ => return Err(format!("Invalid operand for {}", mnemonic))
+            }
+        }
+
+        ("not", [operand]) | ("neg", [operand]) | ("mul", [operand]) | ("imul", [operand]) | ("div", [operand]) | ("idiv", [operand]) if is_register_or_memory(operand) => {
+            let number = match mnemonic {
+                "not" => 2,
+                "neg" => 3,
+                "mul" => 4,
+                "imul" => 5,
+                "div" => 6,
+                _ => 7
+            };
+            let size = operand_size_of(operand).ok_or("Operation size not specified, add byte/word/dword/qword")?;
+            let mut encoding = Encoding::sized(&[if size == 1 { 0xF6 } else { 0xF7 }], size);
+            encoding.modrm(number, operand, context)?;
+            encoding
+        }
+
+        ("imul", [Operand::Register(register), source]) if register.kind == RegisterKind::General && is_register_or_memory(source) => {
+            let mut encoding = Encoding::sized(&[0x0F, 0xAF], register.size);
+            encoding.modrm_register(register, source, context)?;
+            encoding
+        }
+        ("imul", [Operand::Register(register), Operand::Immediate(value, _)]) | ("imul", [Operand::Register(register), _, Operand::Immediate(value, _)])
+            if register.kind == RegisterKind::General && register.size > 1 => {
+            let source = if operands.len() == 3 { &operands[1] } else { &operands[0] };
+            let size = register.size;
+            let mut encoding = if small_immediate(value, size, context)? {
+                let mut encoding = Encoding::sized(&[0x6B], size);
+                encoding.immediate = little_endian(value.evaluate(context)?, 1);
+                encoding
+            }
+            else {
+                let mut encoding = Encoding::sized(&[0x69], size);
+                encoding.immediate = immediate_value(value, immediate_size(size), context)?;
+                encoding
+            };
+            encoding.modrm_register(register, source, context)?;
+            encoding
+        }
+
+        (_, [operand, count]) if SHIFT_MNEMONICS.contains(&mnemonic) && is_register_or_memory(operand) => {
+            let number = SHIFT_MNEMONICS.iter().position(|name| *name == mnemonic).unwrap() as u8;
+            // sal is shl
+            let number = if number == 6 { 4 } else { number };
+            let size = operand_size_of(operand).ok_or("Operation size not specified, add byte/word/dword/qword")?;
+            let byte_form = if size == 1 { 0 } else { 1 };
+
+            let mut encoding = match count {
+                Operand::Register(register) if *register == general_register(1, 1) => Encoding::sized(&[0xD2 + byte_form], size),
+                Operand::Immediate(value, _) if value.is_constant() && value.evaluate(context)? == 1 => Encoding::sized(&[0xD0 + byte_form], size),
+                Operand::Immediate(value, _) => {
+                    let mut encoding = Encoding::sized(&[0xC0 + byte_form], size);
+                    encoding.immediate = immediate_value(value, 1, context)?;
+                    encoding
+                }
+                _ => return Err(format!("The count of {} is cl or an immediate", mnemonic))
+            };
+            encoding.modrm(number, operand, context)?;
+            encoding
+        }
+
+        ("ret", [Operand::Immediate(value, _)]) => {
+            let mut encoding = Encoding::new(&[0xC2]);
+            encoding.immediate = immediate_value(value, 2, context)?;
+            encoding
+        }
+        ("int", [Operand::Immediate(value, _)]) => {
+            let mut encoding = Encoding::new(&[0xCD]);
+            encoding.immediate = immediate_value(value, 1, context)?;
+            encoding
+        }
+        ("aam", [Operand::Immediate(value, _)]) | ("aad", [Operand::Immediate(value, _)]) if mode != Mode::Bits64 => {
+            let mut encoding = Encoding::new(&[if mnemonic == "aam" { 0xD4 } else { 0xD5 }]);
+            encoding.immediate = immediate_value(value, 1, context)?;
+            encoding
+        }
+
+        ("in", [Operand::Register(register), port]) | ("out", [port, Operand::Register(register)])
+            if register.kind == RegisterKind::General && register.number == 0 && register.size <= 4 => {
+            let is_in = mnemonic == "in";
+            let byte_form = if register.size == 1 { 0 } else { 1 };
+            match port {
+                Operand::Register(dx) if *dx == general_register(2, 2) => Encoding::sized(&[if is_in { 0xEC } else { 0xEE } + byte_form], register.size),
+                Operand::Immediate(value, _) => {
+                    let mut encoding = Encoding::sized(&[if is_in { 0xE4 } else { 0xE6 } + byte_form], register.size);
+                    encoding.immediate = immediate_value(value, 1, context)?;
+                    encoding
+                }
+                _ => return Err(String::from("The port is dx or an 8 bit immediate"))
+            }
+        }
+
+        ("movzx", [Operand::Register(register), source]) | ("movsx", [Operand::Register(register), source]) if register.kind == RegisterKind::General && is_register_or_memory(source) => {
+            let source_size = operand_size_of(source).ok_or("Source size not specified, add byte or word")?;
+            let opcode = match (mnemonic, source_size) {
+                ("movzx", 1) => 0xB6,
+                ("movzx", 2) => 0xB7,
+                ("movsx", 1) => 0xBE,
+                ("movsx", 2) => 0xBF,
+                _ => return Err(format!("{} extends a byte or word", mnemonic))
+            };
+            let mut encoding = Encoding::sized(&[0x0F, opcode], register.size);
+            encoding.modrm_register(register, source, context)?;
+            encoding
+        }
+
+        (_, [operand]) if mnemonic.starts_with("set") && condition(&mnemonic[3..]).is_some() && is_register_or_memory(operand) => {
+            let mut encoding = Encoding::new(&[0x0F, 0x90 + condition(&mnemonic[3..]).unwrap()]);
+            encoding.modrm(0, operand, context)?;
+            encoding
+        }
+        (_, [Operand::Register(register), source]) if mnemonic.starts_with("cmov") && condition(&mnemonic[4..]).is_some() && register.kind == RegisterKind::General => {
+            let mut encoding = Encoding::sized(&[0x0F, 0x40 + condition(&mnemonic[4..]).unwrap()], register.size);
+            encoding.modrm_register(register, source, context)?;
+            encoding
+        }
+
+        ("bt", [operand, bit]) | ("bts", [operand, bit]) | ("btr", [operand, bit]) | ("btc", [operand, bit]) if is_register_or_memory(operand) => {
+            let number = match mnemonic {
+                "bt" => 4,
+                "bts" => 5,
+                "btr" => 6,
+                _ => 7
+            };
+            let size = common_size(operand, bit)?;
+            match bit {
+                Operand::Register(register) if register.kind == RegisterKind::General => {
+                    let mut encoding = Encoding::sized(&[0x0F, 0xA3 + (number - 4) * 8], size);
+                    encoding.modrm_register(register, operand, context)?;
+                    encoding
+                }
+                Operand::Immediate(value, _) => {
+                    let mut encoding = Encoding::sized(&[0x0F, 0xBA], size);
+                    encoding.modrm(number, operand, context)?;
+                    encoding.immediate = immediate_value(value, 1, context)?;
+                    encoding
+                }
+                _ => return Err(format!("Invalid operands for {}", mnemonic))
+            }
+        }
+        ("bsf", [Operand::Register(register), source]) | ("bsr", [Operand::Register(register), source]) if register.kind == RegisterKind::General => {
+            let mut encoding = Encoding::sized(&[0x0F, if mnemonic == "bsf" { 0xBC } else { 0xBD }], register.size);
+            encoding.modrm_register(register, source, context)?;
+            encoding
+        }
+        ("bswap", [Operand::Register(register)]) if register.kind == RegisterKind::General && register.size >= 4 => {
+            let mut encoding = Encoding::sized(&[0x0F, 0xC8], register.size);
+            encoding.opcode_register(register);
+            encoding
+        }
+        ("shld", [destination, Operand::Register(register), count]) | ("shrd", [destination, Operand::Register(register), count]) if register.kind == RegisterKind::General => {
+            let base = if mnemonic == "shld" { 0xA4 } else { 0xAC };
+            let mut encoding = match count {
+                Operand::Register(cl) if *cl == general_register(1, 1) => Encoding::sized(&[0x0F, base + 1], register.size),
+                Operand::Immediate(value, _) => {
+                    let mut encoding = Encoding::sized(&[0x0F, base], register.size);
+                    encoding.immediate = immediate_value(value, 1, context)?;
+                    encoding
+                }
+                _ => return Err(format!("The count of {} is cl or an immediate", mnemonic))
+            };
+            encoding.modrm_register(register, destination, context)?;
+            encoding
+        }
+
+        ("ldmxcsr", [operand @ Operand::Memory(_)]) | ("stmxcsr", [operand @ Operand::Memory(_)])
+            | ("fxsave", [operand @ Operand::Memory(_)]) | ("fxrstor", [operand @ Operand::Memory(_)]) => {
+            let number = match mnemonic {
+                "fxsave" => 0,
+                "fxrstor" => 1,
+                "ldmxcsr" => 2,
+                _ => 3
+            };
+            let mut encoding = Encoding::new(&[0x0F, 0xAE]);
+            encoding.modrm(number, operand, context)?;
+            encoding
+        }
+
+        (_, [destination, source]) if SSE_INSTRUCTIONS.iter().any(|instruction| instruction.mnemonic == mnemonic) => {
+            let instruction = SSE_INSTRUCTIONS.iter().find(|instruction| instruction.mnemonic == mnemonic).unwrap();
+            let (prefix, load, store, memory_size) = (instruction.prefix, instruction.load, instruction.store, instruction.memory_size);
+            let check_memory = |operand: &Operand| -> Result<(), String> {
+                match operand {
+                    Operand::Memory(memory) if memory.size.is_some() && memory.size != Some(memory_size) => {
+                        Err(format!("{} takes a {} byte memory operand", mnemonic, memory_size))
+                    }
+                    _ => Ok(())
+                }
+            };
+
+            let mut encoding = match (destination, source) {
+                (Operand::Register(register), _) if register.kind == RegisterKind::Xmm && !matches!(source, Operand::Immediate(..)) => {
+                    check_memory(source)?;
+                    if matches!(source, Operand::Register(source_register) if source_register.kind != RegisterKind::Xmm) {
+                        return Err(format!("{} works on xmm registers", mnemonic));
+                    }
+                    let mut encoding = Encoding::new(&[0x0F, load]);
+                    encoding.modrm_register(register, source, context)?;
+                    encoding
+                }
+                (Operand::Memory(_), Operand::Register(register)) if register.kind == RegisterKind::Xmm && store.is_some() => {
+                    check_memory(destination)?;
+                    let mut encoding = Encoding::new(&[0x0F, store.unwrap()]);
+                    encoding.modrm_register(register, destination, context)?;
+                    encoding
+                }
+                _ => return Err(format!("Invalid operands for {}", mnemonic))
+            };
+            encoding.mandatory_prefix = prefix;
+            encoding
+        }
+
+        _ => {
+            return Err(format!("Unknown instruction or invalid operands: {} with {} operand(s)", mnemonic, operands.len()));
+        }
+    };
+
+    let mut prefixed = prefixes.to_vec();
+    prefixed.append(&mut encoding.prefixes);
+    encoding.prefixes = prefixed;
+    Ok((encoding.bytes(mode)?, false))
+}
+
+/// One pass over the statements, see PassState for what it produces.
+fn run_pass(statements: &[(usize, Statement)], mode: Mode, origin: u64, labels: &BTreeMap<String, i64>, long_jumps: &mut BTreeSet<usize>, final_pass: bool) -> Result<PassState, String> {
+    let mut state = PassState {
+        mode: mode,
+        origin: origin,
+        bytes: vec!(),
+        new_labels: BTreeMap::new(),
+        grew: false
+    };
+
+    for (index, (line_number, statement)) in statements.iter().enumerate() {
+        state.statement(index, statement, labels, long_jumps, final_pass).map_err(|error_message| format!("line {}: {}", line_number, error_message))?;
+    }
+
+    Ok(state)
+}
+
+struct PassState {
+    mode: Mode,
+    origin: u64,
+    bytes: Vec<u8>,
+    /// The labels defined this pass.
+    new_labels: BTreeMap<String, i64>,
+    /// Whether a jump had to switch to its long form.
+    grew: bool
+}
+
+impl PassState {
+
+    /// The address of the next byte. Fails once the code has run past the top of the address space.
+    fn address(&self) -> Result<u64, String> {
+        self.origin.checked_add(self.bytes.len() as u64).ok_or_else(|| String::from("Address past the end of the address space"))
+    }
+
+    fn statement(&mut self, index: usize, statement: &Statement, labels: &BTreeMap<String, i64>, long_jumps: &mut BTreeSet<usize>, final_pass: bool) -> Result<(), String> {
+        let context = Context {
+            labels: labels,
+            address: self.address()?,
+            mode: self.mode,
+            final_pass: final_pass
+        };
+
+        match statement {
+            Statement::Label(name) => {
+                if self.new_labels.insert(name.clone(), self.address()? as i64).is_some() {
+                    return Err(format!("Label {} is defined twice", name));
+                }
+            }
+            Statement::Equ(name, value) => {
+                let value = value.evaluate(&context)?;
+                if self.new_labels.insert(name.clone(), value).is_some() {
+                    return Err(format!("Label {} is defined twice", name));
+                }
+            }
+            Statement::Bits(mode) => self.mode = *mode,
+            Statement::Org(address) => {
+                if !self.bytes.is_empty() {
+                    return Err(String::from("org must come before any code or data"));
+                }
+                if !address.is_constant() {
+                    return Err(String::from("org needs a constant address"));
+                }
+                self.origin = address.evaluate(&context)? as u64;
+            }
+            Statement::Data(size, items) => {
+                for item in items.iter() {
+                    match item {
+                        DataItem::Bytes(bytes) => self.bytes.extend_from_slice(bytes),
+                        DataItem::Value(expr) => {
+                            let value = expr.evaluate(&context)?;
+                            if final_pass && !fits_size(value, *size) {
+                                return Err(format!("{:#x} doesn't fit in {} bytes", value, size));
+                            }
+                            self.bytes.extend(little_endian(value, *size));
+                        }
+                    }
+                }
+            }
+            Statement::Align(alignment, fill) => {
+                if !alignment.is_constant() {
+                    return Err(String::from("align needs a constant"));
+                }
+                let alignment = alignment.evaluate(&context)? as u64;
+                if alignment == 0 || !alignment.is_power_of_two() {
+                    return Err(String::from("align needs a power of two"));
+                }
+                // Padding defaults to NOPs, so code can run through it
+                let fill = match fill {
+                    Some(fill) => fill.evaluate(&context)? as u8,
+                    None => 0x90
+                };
+                let padding = self.address()?.wrapping_neg() & (alignment - 1);
+                if self.origin.checked_add(self.bytes.len() as u64 + padding).is_none() {
+                    return Err(String::from("Address past the end of the address space"));
+                }
+                self.bytes.resize(self.bytes.len() + padding as usize, fill);
+            }
+            Statement::Times(count, repeated) => {
+                if !count.is_constant() {
+                    return Err(String::from("times needs a constant count"));
+                }
+                let count = count.evaluate(&context)?;
+                if count < 0 {
+                    return Err(String::from("times needs a positive count"));
+                }
+                for _ in 0..count {
+                    self.statement(index, repeated, labels, long_jumps, final_pass)?;
+                }
+            }
+            Statement::Instruction { prefixes, mnemonic, operands } => {
+                let force_long = long_jumps.contains(&index);
+                let (bytes, long) = encode_instruction(prefixes, mnemonic, operands, force_long, &context)?;
+                if long && !force_long {
+                    long_jumps.insert(index);
+                    self.grew = true;
+                }
+                self.bytes.extend(bytes);
+            }
+        }
+        self.address()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(mode: Mode, source: &str) -> Vec<u8> {
+        match assemble(source, mode, 0) {
+            Ok(code) => code.bytes,
+            Err(error_message) => panic!("{}: {}", source, error_message)
+        }
+    }
+
+    fn error(mode: Mode, source: &str) -> String {
+        match assemble(source, mode, 0) {
+            Ok(code) => panic!("{} assembled to {:02x?}", source, code.bytes),
+            Err(error_message) => error_message
+        }
+    }
+
+    #[test]
+    fn encodings_16() {
+        let cases: [(&str, &[u8]); 10] = [
+            ("mov ax, 0x1234", &[0xB8, 0x34, 0x12]),
+            ("mov eax, 1", &[0x66, 0xB8, 0x01, 0x00, 0x00, 0x00]),
+            ("add ax, cx", &[0x01, 0xC8]),
+            ("mov [bx+si+4], al", &[0x88, 0x40, 0x04]),
+            ("mov bp, [bp]", &[0x8B, 0x6E, 0x00]),
+            ("mov word [0x1c00], 0x55", &[0xC7, 0x06, 0x00, 0x1C, 0x55, 0x00]),
+            ("mov eax, [esi]", &[0x66, 0x67, 0x8B, 0x06]),
+            ("push 5", &[0x6A, 0x05]),
+            ("int 0x10", &[0xCD, 0x10]),
+            ("rep movsb", &[0xF3, 0xA4])
+        ];
+        for (source, expected) in cases.iter() {
+            assert_eq!(bytes(Mode::Bits16, source), *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn encodings_32() {
+        let cases: [(&str, &[u8]); 12] = [
+            ("mov eax, [esp+8]", &[0x8B, 0x44, 0x24, 0x08]),
+            ("lea ecx, [eax+ebx*4+0x100]", &[0x8D, 0x8C, 0x98, 0x00, 0x01, 0x00, 0x00]),
+            ("mov eax, [0x1000]", &[0x8B, 0x05, 0x00, 0x10, 0x00, 0x00]),
+            ("mov dword [ebp], 7", &[0xC7, 0x45, 0x00, 0x07, 0x00, 0x00, 0x00]),
+            ("add ecx, 1", &[0x83, 0xC1, 0x01]),
+            ("add eax, 0x1000", &[0x05, 0x00, 0x10, 0x00, 0x00]),
+            ("and ecx, 0xfffffff0", &[0x83, 0xE1, 0xF0]),
+            ("mov ax, 1", &[0x66, 0xB8, 0x01, 0x00]),
+            ("shl edx, 1", &[0xD1, 0xE2]),
+            ("movzx eax, byte [esi]", &[0x0F, 0xB6, 0x06]),
+            ("addss xmm1, xmm2", &[0xF3, 0x0F, 0x58, 0xCA]),
+            ("movsd xmm0, [eax]", &[0xF2, 0x0F, 0x10, 0x00])
+        ];
+        for (source, expected) in cases.iter() {
+            assert_eq!(bytes(Mode::Bits32, source), *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn short_jumps_widen() {
+        // In range stays short, forwards and backwards
+        assert_eq!(bytes(Mode::Bits32, "jmp end\ntimes 127 nop\nend:")[..2], [0xEB, 0x7F]);
+        assert_eq!(bytes(Mode::Bits32, "top: nop\njnz top"), [0x90, 0x75, 0xFD]);
+
+        // One byte further needs the near form, which moves the labels after it
+        let code = assemble("jmp end\ntimes 128 nop\nend: jz end", Mode::Bits32, 0).unwrap();
+        assert_eq!(code.bytes[..5], [0xE9, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(code.address_of("end"), Some(133));
+        assert_eq!(code.bytes[133..], [0x74, 0xFE]);
+
+        let code = bytes(Mode::Bits16, "jz end\ntimes 200 nop\nend:");
+        assert_eq!(code[..4], [0x0F, 0x84, 0xC8, 0x00]);
+
+        assert_eq!(error(Mode::Bits32, "loop end\ntimes 200 nop\nend:"), "line 1: Branch target 0xca is out of short range");
+    }
+
+    #[test]
+    fn local_labels() {
+        let code = assemble("first:\n.loop: nop\njmp .loop\nsecond:\n.loop: nop\njmp .loop\njmp first.loop", Mode::Bits32, 0x100).unwrap();
+        assert_eq!(code.address_of("first.loop"), Some(0x100));
+        assert_eq!(code.address_of("second.loop"), Some(0x103));
+        assert_eq!(code.address_of(".loop"), None);
+        assert_eq!(code.bytes, [0x90, 0xEB, 0xFD, 0x90, 0xEB, 0xFD, 0xEB, 0xF8]);
+
+        assert_eq!(error(Mode::Bits32, "first:\n.loop: nop\nsecond:\njmp .loop"), "line 4: Undefined label second.loop");
+    }
+
+    #[test]
+    fn directives() {
+        let code = assemble("org 0x7c00\nstart: db 1, 'ab'\nalign 4\ndw start\ntimes 2 db 0xcc\nvalue equ 3\ndd value", Mode::Bits16, 0).unwrap();
+        assert_eq!(code.origin, 0x7C00);
+        assert_eq!(code.bytes, [0x01, 0x61, 0x62, 0x90, 0x00, 0x7C, 0xCC, 0xCC, 0x03, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(Mode::Bits16, "mov ax, 0x12345"), "line 1: Immediate 0x12345 doesn't fit in 2 bytes");
+        assert_eq!(error(Mode::Bits32, "a: nop\na: nop"), "line 2: Label a is defined twice");
+        assert_eq!(error(Mode::Bits32, "jmp nowhere"), "line 1: Undefined label nowhere");
+        assert_eq!(error(Mode::Bits32, "align 3"), "line 1: align needs a power of two");
+        assert_eq!(error(Mode::Bits32, "mov eax, r8d"), "line 1: r8-r15, spl, bpl, sil and dil need bits 64");
+        assert_eq!(error(Mode::Bits32, "mov ax, ecx"), "line 1: Operand sizes don't match");
+        assert_eq!(error(Mode::Bits32, "frobnicate eax"), "line 1: Unknown instruction or invalid operands: frobnicate with 1 operand(s)");
+        assert_eq!(error(Mode::Bits32, "nop\norg 0x100"), "line 2: org must come before any code or data");
+    }
+
+    #[test]
+    fn address_space_overflow() {
+        for mode in [Mode::Bits16, Mode::Bits32, Mode::Bits64].iter() {
+            assert_eq!(error(*mode, "org 0xffffffffffffffff\nnop"), "line 2: Address past the end of the address space");
+            assert_eq!(error(*mode, "org 0xfffffffffffffffe\ntimes 4 nop"), "line 2: Address past the end of the address space");
+            assert_eq!(error(*mode, "org 0xfffffffffffffff1\nalign 16"), "line 2: Address past the end of the address space");
+        }
+        assert_eq!(assemble("nop\nnop", Mode::Bits32, u64::MAX - 1).map(|code| code.bytes).unwrap_err(),
+            "line 2: Address past the end of the address space");
+    }
+}
@@ -0,0 +1,444 @@
+use std::fmt;
+
+use crate::assembler::{Assembler, Mode};
+use crate::bignum::Layout;
+use crate::calculator::CalcVm;
+use crate::vcpu_regs::*;
+
+// Exact decimal arithmetic with the x86 decimal adjust instructions, run as 32 bit guest code. Sums and differences
+// work on packed BCD (two digits a byte) with ADC/SBB followed by DAA/DAS; products and quotients on unpacked BCD (a
+// digit a byte) with MUL and AAM, AAA to add up the partial products, and AAD before each DIV. The host lines up the
+// decimal points, keeps the signs and checks the guest's answer against its own i128 arithmetic when that can hold it.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BcdOp {
+    Add,
+    Sub,
+    Mul,
+    /// Division by a single digit, truncated to the dividend's decimal places, with a remainder.
+    Div
+}
+
+impl BcdOp {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add" | "+" => Some(BcdOp::Add),
+            "sub" | "-" => Some(BcdOp::Sub),
+            "mul" | "*" => Some(BcdOp::Mul),
+            "div" | "/" => Some(BcdOp::Div),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BcdOp::Add => "+",
+            BcdOp::Sub => "-",
+            BcdOp::Mul => "*",
+            BcdOp::Div => "/"
+        }
+    }
+}
+
+/// A fixed point decimal number, `digits` × 10^-`scale`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Decimal {
+    pub negative: bool,
+    /// Least significant first, 0 to 9 each, without leading zeros.
+    pub digits: Vec<u8>,
+    /// How many of the digits are after the decimal point.
+    pub scale: usize
+}
+
+impl Decimal {
+
+    /// Associated function constructor. Strips leading zeros and the sign of zero.
+    pub fn new(negative: bool, mut digits: Vec<u8>, scale: usize) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        Decimal {
+            negative: negative && !digits.is_empty(),
+            digits: digits,
+            scale: scale
+        }
+    }
+
+    /// Parses an optionally negative decimal number with an optional fraction, e.g. `-1234.50`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let trimmed = text.trim();
+        let (negative, unsigned) = match trimmed.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed)
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, fraction),
+            None => (unsigned, "")
+        };
+
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(format!("{} is not a decimal number", trimmed));
+        }
+        let mut digits = vec!();
+        for c in integer.chars().chain(fraction.chars()).rev() {
+            match c.to_digit(10) {
+                Some(digit) => digits.push(digit as u8),
+                None => return Err(format!("{} is not a decimal number", trimmed))
+            }
+        }
+        Ok(Decimal::new(negative, digits, fraction.len()))
+    }
+
+    /// The same value with `scale` decimal places, which must be at least the current ones.
+    fn with_scale(&self, scale: usize) -> Self {
+        let mut digits = vec![0; scale - self.scale];
+        digits.extend_from_slice(&self.digits);
+        Decimal::new(self.negative, digits, scale)
+    }
+
+    /// The same value with trailing zero decimal places dropped.
+    fn trimmed(&self) -> Self {
+        let zeros = self.digits.iter().take(self.scale).take_while(|digit| **digit == 0).count();
+        let zeros = if self.digits.is_empty() { self.scale } else { zeros };
+        Decimal::new(self.negative, self.digits[zeros.min(self.digits.len())..].to_vec(), self.scale - zeros)
+    }
+
+    /// `digits` as a signed integer, None if it doesn't fit in an i128.
+    fn to_i128(&self) -> Option<i128> {
+        let mut value: i128 = 0;
+        for digit in self.digits.iter().rev() {
+            value = value.checked_mul(10)?.checked_add(*digit as i128)?;
+        }
+        Some(if self.negative { -value } else { value })
+    }
+
+    fn from_i128(value: i128, scale: usize) -> Self {
+        let mut magnitude = value.unsigned_abs();
+        let mut digits = vec!();
+        while magnitude != 0 {
+            digits.push((magnitude % 10) as u8);
+            magnitude /= 10;
+        }
+        Decimal::new(value < 0, digits, scale)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut text = String::new();
+        if self.negative {
+            text.push('-');
+        }
+        // At least one digit in front of the point
+        let width = self.digits.len().max(self.scale + 1);
+        for index in (0..width).rev() {
+            text.push((b'0' + self.digits.get(index).copied().unwrap_or(0)) as char);
+            if index == self.scale && self.scale != 0 {
+                text.push('.');
+            }
+        }
+        write!(f, "{}", text)
+    }
+}
+
+/// `digits` (least significant first) packed two to a byte, low digit in the low nibble, zero extended to `length`
+/// bytes.
+fn pack(digits: &[u8], length: usize) -> Vec<u8> {
+    let mut bytes = vec![0; length];
+    for (index, digit) in digits.iter().enumerate() {
+        bytes[index / 2] |= digit << (4 * (index % 2));
+    }
+    bytes
+}
+
+fn unpack(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().flat_map(|byte| [byte & 0xF, byte >> 4]).collect()
+}
+
+/// Assembles the routine for 32 bit code to the start of the code segment and runs it from a fresh register state,
+/// returning EAX and EFLAGS as it halted.
+fn run_routine(calc_vm: &mut CalcVm, assembler: Assembler) -> Result<(u32, u32), String> {
+    calc_vm.load_code(&assembler.line("hlt").assemble()?.bytes)?;
+    calc_vm.reset_cpu_state();
+    calc_vm.use_32bit_code();
+    calc_vm.run()?;
+    let cpu_state = &calc_vm.vcpu().cpu_state;
+    Ok((cpu_state.gpr32(REG_RAX), cpu_state.eflags()))
+}
+
+/// ADC + DAA (or SBB + DAS) over `length` bytes of packed BCD at `a` and `b` into `result`, leaving the decimal
+/// carry or borrow out in CF.
+fn packed_chain(instruction: &str, adjust: &str, a: u32, b: u32, result: u32, length: usize) -> Assembler {
+    Assembler::new(Mode::Bits32)
+        .line(&format!("mov esi, {:#x}", a))
+        .line(&format!("mov ebx, {:#x}", b))
+        .line(&format!("mov edi, {:#x}", result))
+        .line(&format!("mov ecx, {}", length))
+        .line("clc")
+        .label("chain")
+        .line("mov al, [esi]")
+        .line(&format!("{} al, [ebx]", instruction))
+        .line(adjust)
+        .line("mov [edi], al")
+        // LEA and LOOP leave CF alone
+        .line("lea esi, [esi+1]")
+        .line("lea ebx, [ebx+1]")
+        .line("lea edi, [edi+1]")
+        .line("loop chain")
+}
+
+/// The digits of |a| + |b| (`add` true) or |a| - |b|, and the carry or borrow out. Both are padded to the same length.
+fn packed_magnitudes(calc_vm: &mut CalcVm, add: bool, a: &[u8], b: &[u8]) -> Result<(Vec<u8>, bool), String> {
+    let length = a.len().max(b.len()).max(1).div_ceil(2);
+    let mut layout = Layout::new();
+    let (a_offset, b_offset, result) = (layout.bytes(length)?, layout.bytes(length)?, layout.bytes(length)?);
+    calc_vm.write_data(a_offset, &pack(a, length))?;
+    calc_vm.write_data(b_offset, &pack(b, length))?;
+
+    let routine = if add {
+        packed_chain("adc", "daa", a_offset, b_offset, result, length)
+    }
+    else {
+        packed_chain("sbb", "das", a_offset, b_offset, result, length)
+    };
+    let (_, eflags) = run_routine(calc_vm, routine)?;
+
+    Ok((unpack(&calc_vm.read_data(result, length)), eflags & EFLAGS_CF != 0))
+}
+
+/// The digits of |a| × |b| by unpacked schoolbook multiplication: every digit product is split into tens and units
+/// by AAM and added into the result with AAA, carrying the tens in DL.
+fn mul_magnitudes(calc_vm: &mut CalcVm, a: &[u8], b: &[u8]) -> Result<Vec<u8>, String> {
+    let (a_length, b_length) = (a.len().max(1), b.len().max(1));
+    let mut layout = Layout::new();
+    let a_offset = layout.bytes(a_length)?;
+    let b_offset = layout.bytes(b_length)?;
+    let result = layout.bytes(a_length + b_length)?;
+    let mut a_digits = a.to_vec();
+    a_digits.resize(a_length, 0);
+    let mut b_digits = b.to_vec();
+    b_digits.resize(b_length, 0);
+    calc_vm.write_data(a_offset, &a_digits)?;
+    calc_vm.write_data(b_offset, &b_digits)?;
+    calc_vm.write_data(result, &vec![0; a_length + b_length])?;
+
+    let routine = Assembler::new(Mode::Bits32)
+        .line(&format!("mov ebx, {:#x}", b_offset))
+        .line(&format!("mov edi, {:#x}", result))
+        .label("outer")
+        .line("mov ebp, edi")
+        .line(&format!("mov esi, {:#x}", a_offset))
+        .line(&format!("mov ecx, {}", a_length))
+        .line("xor dl, dl")
+        .label("inner")
+        .line("mov al, [esi]")
+        .line("mul byte [ebx]")
+        // AH = tens, AL = units, then the digit already there and the carry go on top
+        .line("aam")
+        .line("add al, [edi]")
+        .line("aaa")
+        .line("add al, dl")
+        .line("aaa")
+        .line("mov [edi], al")
+        .line("mov dl, ah")
+        .line("inc esi")
+        .line("inc edi")
+        .line("loop inner")
+        .line("mov [edi], dl")
+        .line("lea edi, [ebp+1]")
+        .line("inc ebx")
+        .line(&format!("cmp ebx, {:#x}", b_offset + b_length as u32))
+        .line("jne outer");
+    run_routine(calc_vm, routine)?;
+
+    Ok(calc_vm.read_data(result, a_length + b_length))
+}
+
+/// The digits of |a| / `divisor` and the remainder, by unpacked long division from the top digit: AAD folds the
+/// running remainder (AH) and the next digit (AL) into a binary AL for DIV. A zero divisor raises #DE in the guest.
+fn div_magnitudes(calc_vm: &mut CalcVm, a: &[u8], divisor: u8) -> Result<(Vec<u8>, u8), String> {
+    let length = a.len().max(1);
+    let mut layout = Layout::new();
+    let (a_offset, quotient) = (layout.bytes(length)?, layout.bytes(length)?);
+    let mut digits = a.to_vec();
+    digits.resize(length, 0);
+    calc_vm.write_data(a_offset, &digits)?;
+
+    let routine = Assembler::new(Mode::Bits32)
+        .line(&format!("mov esi, {:#x}", a_offset + length as u32 - 1))
+        .line(&format!("mov edi, {:#x}", quotient + length as u32 - 1))
+        .line(&format!("mov ecx, {}", length))
+        .line(&format!("mov bl, {}", divisor))
+        .line("xor ah, ah")
+        .label("next_digit")
+        .line("mov al, [esi]")
+        .line("aad")
+        .line("div bl")
+        .line("mov [edi], al")
+        .line("dec esi")
+        .line("dec edi")
+        .line("loop next_digit");
+    let (eax, _) = run_routine(calc_vm, routine)?;
+
+    Ok((calc_vm.read_data(quotient, length), (eax >> 8) as u8))
+}
+
+/// a + b with signs: an addition or a subtraction of the magnitudes, whose borrow tells which was larger.
+fn add(calc_vm: &mut CalcVm, a: &Decimal, b: &Decimal) -> Result<Decimal, String> {
+    let scale = a.scale.max(b.scale);
+    let (a, b) = (a.with_scale(scale), b.with_scale(scale));
+
+    if a.negative == b.negative {
+        let (mut sum, carry) = packed_magnitudes(calc_vm, true, &a.digits, &b.digits)?;
+        if carry {
+            sum.push(1);
+        }
+        return Ok(Decimal::new(a.negative, sum, scale));
+    }
+
+    let (difference, borrowed) = packed_magnitudes(calc_vm, false, &a.digits, &b.digits)?;
+    if !borrowed {
+        Ok(Decimal::new(a.negative, difference, scale))
+    }
+    else {
+        let (difference, _) = packed_magnitudes(calc_vm, false, &b.digits, &a.digits)?;
+        Ok(Decimal::new(b.negative, difference, scale))
+    }
+}
+
+/// Runs `a op b` in the guest. Returns the result, and for BcdOp::Div the remainder. On failure, including a divisor
+/// that isn't a single digit, returns a description of what went wrong.
+pub fn run_bcd_op(calc_vm: &mut CalcVm, op: BcdOp, a: &Decimal, b: &Decimal) -> Result<(Decimal, Option<Decimal>), String> {
+    match op {
+        BcdOp::Add => Ok((add(calc_vm, a, b)?, None)),
+        BcdOp::Sub => Ok((add(calc_vm, a, &Decimal::new(!b.negative, b.digits.clone(), b.scale))?, None)),
+        BcdOp::Mul => {
+            let product = mul_magnitudes(calc_vm, &a.digits, &b.digits)?;
+            Ok((Decimal::new(a.negative != b.negative, product, a.scale + b.scale), None))
+        }
+        BcdOp::Div => {
+            let divisor = b.trimmed();
+            if divisor.scale != 0 || divisor.digits.len() > 1 {
+                return Err(format!("Can only divide by a single digit (AAD works on one), not {}", b));
+            }
+            let (quotient, remainder) = div_magnitudes(calc_vm, &a.digits, divisor.digits.first().copied().unwrap_or(0))?;
+            Ok((Decimal::new(a.negative != b.negative, quotient, a.scale), Some(Decimal::new(a.negative, vec!(remainder), a.scale))))
+        }
+    }
+}
+
+/// What run_bcd_op() should return, worked out with i128 arithmetic on the host. None if the operands or the result
+/// don't fit, or for a division by zero.
+pub fn host_result(op: BcdOp, a: &Decimal, b: &Decimal) -> Option<(Decimal, Option<Decimal>)> {
+    match op {
+        BcdOp::Add | BcdOp::Sub => {
+            let scale = a.scale.max(b.scale);
+            let (a, b) = (a.with_scale(scale).to_i128()?, b.with_scale(scale).to_i128()?);
+            let result = if op == BcdOp::Add { a.checked_add(b)? } else { a.checked_sub(b)? };
+            Some((Decimal::from_i128(result, scale), None))
+        }
+        BcdOp::Mul => Some((Decimal::from_i128(a.to_i128()?.checked_mul(b.to_i128()?)?, a.scale + b.scale), None)),
+        BcdOp::Div => {
+            let divisor = b.trimmed().to_i128()?;
+            if divisor == 0 {
+                return None;
+            }
+            let dividend = a.to_i128()?;
+            Some((Decimal::from_i128(dividend / divisor, a.scale), Some(Decimal::from_i128(dividend % divisor, a.scale))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(text: &str) -> Decimal {
+        Decimal::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!(decimal("-1234.50"), Decimal::new(true, vec!(0, 5, 4, 3, 2, 1), 2));
+        assert_eq!(decimal("-1234.50").to_string(), "-1234.50");
+        assert_eq!(decimal(" 42 "), Decimal::new(false, vec!(2, 4), 0));
+        assert_eq!(decimal("7.").to_string(), "7");
+        assert_eq!(decimal("0.001").to_string(), "0.001");
+        assert_eq!(decimal("123.456").to_string(), "123.456");
+    }
+
+    #[test]
+    fn missing_integer_part() {
+        assert_eq!(decimal(".5"), Decimal::new(false, vec!(5), 1));
+        assert_eq!(decimal("-.5"), Decimal::new(true, vec!(5), 1));
+        assert_eq!(decimal("-.5").to_string(), "-0.5");
+        assert_eq!(decimal(".05").to_string(), "0.05");
+    }
+
+    #[test]
+    fn leading_zeros_and_negative_zero() {
+        assert_eq!(decimal("000123.4"), decimal("123.4"));
+        assert_eq!(decimal("0007").to_string(), "7");
+        assert_eq!(decimal("-0.050").to_string(), "-0.050");
+
+        // Zero keeps its decimal places but never a sign
+        for (text, shown) in [("-0", "0"), ("-000", "0"), ("-0.00", "0.00"), ("-.0", "0.0"), ("0", "0")] {
+            let zero = decimal(text);
+            assert!(zero.digits.is_empty() && !zero.negative, "{}", text);
+            assert_eq!(zero.to_string(), shown);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        for text in ["", "-", ".", "-.", "1.2.3", "+1", "--1", "1e5", "0x10", "1_000", "1 000"] {
+            assert_eq!(Decimal::parse(text), Err(format!("{} is not a decimal number", text.trim())), "{}", text);
+        }
+    }
+
+    #[test]
+    fn trailing_decimal_places_are_trimmed() {
+        assert_eq!(decimal("1.500").trimmed(), Decimal::new(false, vec!(5, 1), 1));
+        assert_eq!(decimal("-2.000").trimmed(), Decimal::new(true, vec!(2), 0));
+        assert_eq!(decimal("100").trimmed(), Decimal::new(false, vec!(0, 0, 1), 0));
+        assert_eq!(decimal("0.000").trimmed(), Decimal::default());
+        assert_eq!(decimal("0.020").trimmed().to_string(), "0.02");
+    }
+
+    #[test]
+    fn scales_line_up() {
+        assert_eq!(decimal("1.5").with_scale(3), decimal("1.500"));
+        assert_eq!(decimal("-.5").with_scale(1), decimal("-0.5"));
+        assert_eq!(decimal("0").with_scale(2).to_string(), "0.00");
+
+        let result = |op, a, b| host_result(op, &decimal(a), &decimal(b)).map(|(result, remainder)| {
+            (result.to_string(), remainder.map(|remainder| remainder.to_string()))
+        });
+        assert_eq!(result(BcdOp::Add, "1.5", "2.25"), Some((String::from("3.75"), None)));
+        assert_eq!(result(BcdOp::Add, "-.5", "0.25"), Some((String::from("-0.25"), None)));
+        assert_eq!(result(BcdOp::Sub, "10", "0.001"), Some((String::from("9.999"), None)));
+        assert_eq!(result(BcdOp::Sub, "1.10", "1.1"), Some((String::from("0.00"), None)));
+        assert_eq!(result(BcdOp::Mul, "-1.5", "-.5"), Some((String::from("0.75"), None)));
+        assert_eq!(result(BcdOp::Mul, "2.50", "4"), Some((String::from("10.00"), None)));
+    }
+
+    #[test]
+    fn host_division() {
+        let result = |a, b| host_result(BcdOp::Div, &decimal(a), &decimal(b)).map(|(result, remainder)| {
+            (result.to_string(), remainder.unwrap().to_string())
+        });
+        assert_eq!(result("7", "2"), Some((String::from("3"), String::from("1"))));
+        assert_eq!(result("-7", "2"), Some((String::from("-3"), String::from("-1"))));
+        assert_eq!(result("7.25", "3.0"), Some((String::from("2.41"), String::from("0.02"))));
+        assert_eq!(result("1", "0.0"), None);
+    }
+
+    #[test]
+    fn host_result_overflow() {
+        let huge = "9".repeat(40);
+        assert_eq!(host_result(BcdOp::Add, &decimal(&huge), &decimal("1")), None);
+        assert_eq!(host_result(BcdOp::Mul, &decimal(&"9".repeat(20)), &decimal(&"9".repeat(20))), None);
+        assert!(host_result(BcdOp::Add, &decimal(&"9".repeat(38)), &decimal("1")).is_some());
+    }
+}
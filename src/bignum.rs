@@ -0,0 +1,515 @@
+use std::fmt;
+
+use crate::assembler::{Assembler, Mode};
+use crate::calculator::CalcVm;
+use crate::variables::VARIABLES_BASE;
+use crate::vcpu_regs::EFLAGS_CF;
+
+// Arbitrary precision integers computed in the guest. The host only converts between text and limbs (little endian
+// u32s) and handles the signs; the magnitudes are laid out as limb arrays in the data segment and guest loops do the
+// arithmetic: ADC and SBB chains for addition and subtraction, a MUL schoolbook product and a shift and subtract long
+// division. Each routine is assembled for the lengths and addresses of its operands and run in one VM entry.
+
+/// The data segment range the limb arrays are placed in, between the stack and the variables.
+pub const BIGNUM_AREA: u32 = 0x1000;
+pub const BIGNUM_AREA_END: u32 = VARIABLES_BASE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BigOp {
+    Add,
+    Sub,
+    Mul,
+    /// Truncating division, the remainder takes the dividend's sign.
+    DivMod
+}
+
+impl BigOp {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add" | "+" => Some(BigOp::Add),
+            "sub" | "-" => Some(BigOp::Sub),
+            "mul" | "*" => Some(BigOp::Mul),
+            "divmod" | "div" | "/" => Some(BigOp::DivMod),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BigOp::Add => "+",
+            BigOp::Sub => "-",
+            BigOp::Mul => "*",
+            BigOp::DivMod => "/"
+        }
+    }
+}
+
+/// A sign and magnitude integer. Zero has no limbs and is never negative.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct BigInt {
+    pub negative: bool,
+    /// Least significant first, without leading zero limbs.
+    pub limbs: Vec<u32>
+}
+
+impl BigInt {
+
+    /// Associated function constructor. Strips leading zero limbs and the sign of zero.
+    pub fn new(negative: bool, mut limbs: Vec<u32>) -> Self {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        BigInt {
+            negative: negative && !limbs.is_empty(),
+            limbs: limbs
+        }
+    }
+
+    /// Parses an optionally negative decimal number, or a hex one with `0x`. Underscores between digits are ignored.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let trimmed = text.trim();
+        let (negative, unsigned) = match trimmed.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed)
+        };
+        let (radix, digits) = match unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+            Some(hex) => (16, hex),
+            None => (10, unsigned)
+        };
+
+        let mut limbs: Vec<u32> = vec!();
+        let mut seen_digit = false;
+        for c in digits.chars() {
+            if c == '_' {
+                continue;
+            }
+            let digit = match c.to_digit(radix) {
+                Some(digit) => digit,
+                None => return Err(format!("{} is not a {} number", text.trim(), if radix == 16 { "hex" } else { "decimal" }))
+            };
+            seen_digit = true;
+
+            // limbs = limbs * radix + digit
+            let mut carry = digit as u64;
+            for limb in limbs.iter_mut() {
+                let value = *limb as u64 * radix as u64 + carry;
+                *limb = value as u32;
+                carry = value >> 32;
+            }
+            if carry != 0 {
+                limbs.push(carry as u32);
+            }
+        }
+        if !seen_digit {
+            return Err(format!("{} is not a number", text.trim()));
+        }
+
+        Ok(BigInt::new(negative, limbs))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    /// The magnitude in hex with a `0x` prefix and the sign in front.
+    pub fn to_hex(&self) -> String {
+        let mut text = String::from(if self.negative { "-0x" } else { "0x" });
+        match self.limbs.split_last() {
+            Some((top, rest)) => {
+                text.push_str(&format!("{:x}", top));
+                for limb in rest.iter().rev() {
+                    text.push_str(&format!("{:08x}", limb));
+                }
+            }
+            None => text.push('0')
+        }
+        text
+    }
+
+    /// The magnitude in decimal with the sign in front.
+    pub fn to_decimal(&self) -> String {
+        // Peel off nine digits at a time, from the bottom
+        const CHUNK: u64 = 1_000_000_000;
+        let mut limbs = self.limbs.clone();
+        let mut chunks = vec!();
+        while !limbs.is_empty() {
+            let mut remainder = 0u64;
+            for limb in limbs.iter_mut().rev() {
+                let value = (remainder << 32) | *limb as u64;
+                *limb = (value / CHUNK) as u32;
+                remainder = value % CHUNK;
+            }
+            chunks.push(remainder as u32);
+            while limbs.last() == Some(&0) {
+                limbs.pop();
+            }
+        }
+
+        let mut text = String::from(if self.negative { "-" } else { "" });
+        match chunks.split_last() {
+            Some((top, rest)) => {
+                text.push_str(&top.to_string());
+                for chunk in rest.iter().rev() {
+                    text.push_str(&format!("{:09}", chunk));
+                }
+            }
+            None => text.push('0')
+        }
+        text
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_decimal())
+    }
+}
+
+/// Hands out consecutive arrays in the bignum area.
+pub struct Layout {
+    next: u32
+}
+
+impl Layout {
+
+    /// Associated function constructor. Starts at the bottom of the area.
+    pub fn new() -> Self {
+        Layout {
+            next: BIGNUM_AREA
+        }
+    }
+
+    /// The DS offset of a new array of `limbs` limbs. On failure returns a description of how much didn't fit.
+    pub fn array(&mut self, limbs: usize) -> Result<u32, String> {
+        self.bytes(limbs * 4)
+    }
+
+    /// The DS offset of a new array of `count` bytes. On failure returns a description of how much didn't fit.
+    pub fn bytes(&mut self, count: usize) -> Result<u32, String> {
+        let offset = self.next;
+        let end = offset as usize + count;
+        if end > BIGNUM_AREA_END as usize {
+            return Err(format!("The operands need {} bytes of guest memory, there are {}", end - BIGNUM_AREA as usize,
+                BIGNUM_AREA_END - BIGNUM_AREA));
+        }
+        self.next = end as u32;
+        Ok(offset)
+    }
+}
+
+fn limb_bytes(limbs: &[u32]) -> Vec<u8> {
+    limbs.iter().flat_map(|limb| limb.to_le_bytes()).collect()
+}
+
+fn read_limbs(calc_vm: &mut CalcVm, offset: u32, count: usize) -> Vec<u32> {
+    let bytes = calc_vm.read_data(offset, count * 4);
+    bytes.chunks(4).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect()
+}
+
+/// `limbs` zero extended to `length`.
+fn padded(limbs: &[u32], length: usize) -> Vec<u32> {
+    let mut padded = limbs.to_vec();
+    padded.resize(length, 0);
+    padded
+}
+
+/// Assembles the routine to the start of the code segment and runs it from a fresh register state, returning the
+/// EFLAGS it halted with.
+fn run_routine(calc_vm: &mut CalcVm, assembler: Assembler) -> Result<u32, String> {
+    calc_vm.load_code(&assembler.line("hlt").assemble()?.bytes)?;
+    calc_vm.reset_cpu_state();
+    calc_vm.run()?;
+    Ok(calc_vm.vcpu().cpu_state.eflags())
+}
+
+/// The ADC (`adc`) or SBB (`sbb`) chain over two equally long arrays, leaving the carry or borrow out in CF.
+fn carry_chain(instruction: &str, a: u32, b: u32, result: u32, length: usize) -> Assembler {
+    Assembler::new(Mode::Bits16)
+        .line(&format!("mov si, {:#x}", a))
+        .line(&format!("mov bx, {:#x}", b))
+        .line(&format!("mov di, {:#x}", result))
+        .line(&format!("mov cx, {}", length))
+        .line("clc")
+        .label("chain")
+        .line("mov eax, [si]")
+        .line(&format!("{} eax, [bx]", instruction))
+        .line("mov [di], eax")
+        // LEA and LOOP leave CF alone
+        .line("lea si, [si+4]")
+        .line("lea bx, [bx+4]")
+        .line("lea di, [di+4]")
+        .line("loop chain")
+}
+
+/// |a| + |b|
+fn add_magnitudes(calc_vm: &mut CalcVm, a: &[u32], b: &[u32]) -> Result<Vec<u32>, String> {
+    let length = a.len().max(b.len()).max(1);
+    let mut layout = Layout::new();
+    let (a_offset, b_offset, result) = (layout.array(length)?, layout.array(length)?, layout.array(length)?);
+    calc_vm.write_data(a_offset, &limb_bytes(&padded(a, length)))?;
+    calc_vm.write_data(b_offset, &limb_bytes(&padded(b, length)))?;
+
+    let eflags = run_routine(calc_vm, carry_chain("adc", a_offset, b_offset, result, length))?;
+
+    let mut sum = read_limbs(calc_vm, result, length);
+    if eflags & EFLAGS_CF != 0 {
+        sum.push(1);
+    }
+    Ok(sum)
+}
+
+/// |a| - |b| and whether it borrowed, i.e. |a| < |b| and the difference is the two's complement of the magnitude.
+fn sub_magnitudes(calc_vm: &mut CalcVm, a: &[u32], b: &[u32]) -> Result<(Vec<u32>, bool), String> {
+    let length = a.len().max(b.len()).max(1);
+    let mut layout = Layout::new();
+    let (a_offset, b_offset, result) = (layout.array(length)?, layout.array(length)?, layout.array(length)?);
+    calc_vm.write_data(a_offset, &limb_bytes(&padded(a, length)))?;
+    calc_vm.write_data(b_offset, &limb_bytes(&padded(b, length)))?;
+
+    let eflags = run_routine(calc_vm, carry_chain("sbb", a_offset, b_offset, result, length))?;
+
+    Ok((read_limbs(calc_vm, result, length), eflags & EFLAGS_CF != 0))
+}
+
+/// |a| * |b|, schoolbook: for every limb of b, a times it is added into the product at that limb's position.
+fn mul_magnitudes(calc_vm: &mut CalcVm, a: &[u32], b: &[u32]) -> Result<Vec<u32>, String> {
+    let (a_length, b_length) = (a.len().max(1), b.len().max(1));
+    let mut layout = Layout::new();
+    let a_offset = layout.array(a_length)?;
+    let b_offset = layout.array(b_length)?;
+    let result = layout.array(a_length + b_length)?;
+    calc_vm.write_data(a_offset, &limb_bytes(&padded(a, a_length)))?;
+    calc_vm.write_data(b_offset, &limb_bytes(&padded(b, b_length)))?;
+    calc_vm.write_data(result, &vec![0; (a_length + b_length) * 4])?;
+
+    run_routine(calc_vm, mul_routine(a_offset, a_length, b_offset, b_length, result))?;
+
+    Ok(read_limbs(calc_vm, result, a_length + b_length))
+}
+
+/// The schoolbook product of the `a_length` limbs at `a` and the `b_length` limbs at `b` into the zeroed array at
+/// `result`.
+fn mul_routine(a: u32, a_length: usize, b: u32, b_length: usize, result: u32) -> Assembler {
+    Assembler::new(Mode::Bits16)
+        .line(&format!("mov bx, {:#x}", b))
+        .line(&format!("mov di, {:#x}", result))
+        .label("outer")
+        .line("push di")
+        .line(&format!("mov si, {:#x}", a))
+        .line(&format!("mov cx, {}", a_length))
+        // EBP carries the high half of each partial product into the next limb
+        .line("xor ebp, ebp")
+        .label("inner")
+        .line("mov eax, [si]")
+        .line("mul dword [bx]")
+        .line("add eax, [di]")
+        .line("adc edx, 0")
+        .line("add eax, ebp")
+        .line("adc edx, 0")
+        .line("mov [di], eax")
+        .line("mov ebp, edx")
+        .line("add si, 4")
+        .line("add di, 4")
+        .line("loop inner")
+        .line("mov [di], ebp")
+        .line("pop di")
+        .line("add di, 4")
+        .line("add bx, 4")
+        .line(&format!("cmp bx, {:#x}", b + b_length as u32 * 4))
+        .line("jne outer")
+}
+
+/// |a| / |b| and |a| % |b| by binary long division: the dividend is shifted into the remainder a bit at a time and
+/// the divisor subtracted whenever it fits, which is the quotient bit. |b| must not be zero.
+fn divmod_magnitudes(calc_vm: &mut CalcVm, a: &[u32], b: &[u32]) -> Result<(Vec<u32>, Vec<u32>), String> {
+    let a_length = a.len().max(1);
+    // One limb more than the divisor, as the shifted remainder can be up to twice the divisor
+    let b_length = b.len() + 1;
+    let mut layout = Layout::new();
+    let dividend = layout.array(a_length)?;
+    let divisor = layout.array(b_length)?;
+    let remainder = layout.array(b_length)?;
+    let trial = layout.array(b_length)?;
+    let quotient = layout.array(a_length)?;
+    calc_vm.write_data(dividend, &limb_bytes(&padded(a, a_length)))?;
+    calc_vm.write_data(divisor, &limb_bytes(&padded(b, b_length)))?;
+    calc_vm.write_data(remainder, &vec![0; b_length * 4])?;
+    calc_vm.write_data(quotient, &vec![0; a_length * 4])?;
+
+    run_routine(calc_vm, divmod_routine(dividend, a_length, divisor, b_length, remainder, trial, quotient))?;
+
+    Ok((read_limbs(calc_vm, quotient, a_length), read_limbs(calc_vm, remainder, b_length)))
+}
+
+/// The long division of the `a_length` limbs at `dividend` (shifted out in the process) by the `b_length` limbs at
+/// `divisor` into the zeroed arrays at `quotient` and `remainder`. `trial` is scratch space as long as the divisor.
+fn divmod_routine(dividend: u32, a_length: usize, divisor: u32, b_length: usize, remainder: u32, trial: u32, quotient: u32)
+    -> Assembler {
+    Assembler::new(Mode::Bits16)
+        .line(&format!("mov ebp, {}", a_length * 32))
+        .label("next_bit")
+        // The dividend's top bit goes through CF into the bottom of the remainder
+        .line(&format!("mov si, {:#x}", dividend))
+        .line(&format!("mov cx, {}", a_length))
+        .line("clc")
+        .label("shift_dividend")
+        .line("rcl dword [si], 1")
+        .line("lea si, [si+4]")
+        .line("loop shift_dividend")
+        .line(&format!("mov si, {:#x}", remainder))
+        .line(&format!("mov cx, {}", b_length))
+        .label("shift_remainder")
+        .line("rcl dword [si], 1")
+        .line("lea si, [si+4]")
+        .line("loop shift_remainder")
+        // trial = remainder - divisor, CF set if it borrowed
+        .line(&format!("mov si, {:#x}", remainder))
+        .line(&format!("mov bx, {:#x}", divisor))
+        .line(&format!("mov di, {:#x}", trial))
+        .line(&format!("mov cx, {}", b_length))
+        .line("clc")
+        .label("subtract")
+        .line("mov eax, [si]")
+        .line("sbb eax, [bx]")
+        .line("mov [di], eax")
+        .line("lea si, [si+4]")
+        .line("lea bx, [bx+4]")
+        .line("lea di, [di+4]")
+        .line("loop subtract")
+        .line("jc no_fit")
+        // It fit: remainder = trial. MOV, LEA and LOOP keep CF clear
+        .line(&format!("mov si, {:#x}", trial))
+        .line(&format!("mov di, {:#x}", remainder))
+        .line(&format!("mov cx, {}", b_length))
+        .label("copy")
+        .line("mov eax, [si]")
+        .line("mov [di], eax")
+        .line("lea si, [si+4]")
+        .line("lea di, [di+4]")
+        .line("loop copy")
+        .label("no_fit")
+        // The quotient bit is the inverted borrow
+        .line("cmc")
+        .line(&format!("mov si, {:#x}", quotient))
+        .line(&format!("mov cx, {}", a_length))
+        .label("shift_quotient")
+        .line("rcl dword [si], 1")
+        .line("lea si, [si+4]")
+        .line("loop shift_quotient")
+        .line("dec ebp")
+        .line("jnz next_bit")
+}
+
+/// a + b for signed operands, as an addition or a subtraction of the magnitudes.
+fn add(calc_vm: &mut CalcVm, a: &BigInt, b: &BigInt) -> Result<BigInt, String> {
+    if a.negative == b.negative {
+        return Ok(BigInt::new(a.negative, add_magnitudes(calc_vm, &a.limbs, &b.limbs)?));
+    }
+
+    // Opposite signs: the larger magnitude wins, which the guest's borrow tells
+    let (difference, borrowed) = sub_magnitudes(calc_vm, &a.limbs, &b.limbs)?;
+    if !borrowed {
+        Ok(BigInt::new(a.negative, difference))
+    }
+    else {
+        let (difference, _) = sub_magnitudes(calc_vm, &b.limbs, &a.limbs)?;
+        Ok(BigInt::new(b.negative, difference))
+    }
+}
+
+/// Runs `a op b` in the guest. Returns the result, and for BigOp::DivMod the remainder. On failure, including a
+/// division by zero or operands too large for guest memory, returns a description of what went wrong.
+pub fn run_big_op(calc_vm: &mut CalcVm, op: BigOp, a: &BigInt, b: &BigInt) -> Result<(BigInt, Option<BigInt>), String> {
+    match op {
+        BigOp::Add => Ok((add(calc_vm, a, b)?, None)),
+        BigOp::Sub => {
+            let negated = BigInt::new(!b.negative, b.limbs.clone());
+            Ok((add(calc_vm, a, &negated)?, None))
+        }
+        BigOp::Mul => {
+            let product = mul_magnitudes(calc_vm, &a.limbs, &b.limbs)?;
+            Ok((BigInt::new(a.negative != b.negative, product), None))
+        }
+        BigOp::DivMod => {
+            if b.is_zero() {
+                return Err(String::from("Division by zero"));
+            }
+            let (quotient, remainder) = divmod_magnitudes(calc_vm, &a.limbs, &b.limbs)?;
+            Ok((BigInt::new(a.negative != b.negative, quotient), Some(BigInt::new(a.negative, remainder))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_decimal_and_hex() {
+        assert_eq!(BigInt::parse("0"), Ok(BigInt::default()));
+        assert_eq!(BigInt::parse("4294967296"), Ok(BigInt::new(false, vec!(0, 1))));
+        assert_eq!(BigInt::parse("-4294967297"), Ok(BigInt::new(true, vec!(1, 1))));
+        assert_eq!(BigInt::parse(" 0x1_0000_0000 "), Ok(BigInt::new(false, vec!(0, 1))));
+        assert_eq!(BigInt::parse("-0XFFFFFFFF"), Ok(BigInt::new(true, vec!(u32::MAX))));
+        assert_eq!(BigInt::parse("1_000"), Ok(BigInt::new(false, vec!(1000))));
+    }
+
+    #[test]
+    fn leading_zeros_and_negative_zero() {
+        assert_eq!(BigInt::parse("000000000000000000000000042"), Ok(BigInt::new(false, vec!(42))));
+        assert_eq!(BigInt::parse("0x0000000000000000ff"), Ok(BigInt::new(false, vec!(0xff))));
+        assert_eq!(BigInt::new(false, vec!(7, 0, 0)).limbs, vec!(7));
+
+        // Zero has no sign however it is written
+        for text in ["-0", "-000", "-0x0", "0x00000000_00000000"] {
+            let zero = BigInt::parse(text).unwrap();
+            assert_eq!(zero, BigInt::default(), "{}", text);
+            assert!(zero.is_zero() && !zero.negative);
+            assert_eq!(zero.to_decimal(), "0");
+            assert_eq!(zero.to_hex(), "0x0");
+        }
+        assert_eq!(BigInt::new(true, vec!(0)), BigInt::default());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(BigInt::parse(""), Err(String::from(" is not a number")));
+        assert_eq!(BigInt::parse("-"), Err(String::from("- is not a number")));
+        assert_eq!(BigInt::parse("0x"), Err(String::from("0x is not a number")));
+        assert_eq!(BigInt::parse("_"), Err(String::from("_ is not a number")));
+        assert_eq!(BigInt::parse("12a"), Err(String::from("12a is not a decimal number")));
+        assert_eq!(BigInt::parse("0xfg"), Err(String::from("0xfg is not a hex number")));
+        assert_eq!(BigInt::parse("+1"), Err(String::from("+1 is not a decimal number")));
+        assert_eq!(BigInt::parse("--1"), Err(String::from("--1 is not a decimal number")));
+        assert_eq!(BigInt::parse("-.5"), Err(String::from("-.5 is not a decimal number")));
+    }
+
+    #[test]
+    fn decimal_chunks_are_padded() {
+        assert_eq!(BigInt::parse("999999999").unwrap().to_decimal(), "999999999");
+        assert_eq!(BigInt::parse("1000000000").unwrap().to_decimal(), "1000000000");
+        assert_eq!(BigInt::parse("1000000001").unwrap().to_decimal(), "1000000001");
+        assert_eq!(BigInt::parse("1000000000000000000").unwrap().to_decimal(), "1000000000000000000");
+        assert_eq!(BigInt::parse("-1000000000000000000").unwrap().to_decimal(), "-1000000000000000000");
+        assert_eq!(BigInt::parse("1000000000000000000000000000").unwrap().to_decimal(), "1000000000000000000000000000");
+        assert_eq!(BigInt::new(false, vec!(0, 1)).to_string(), "4294967296");
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let numbers = [
+            "1", "-1", "4294967295", "18446744073709551616", "-340282366920938463463374607431768211455",
+            "123456789012345678901234567890123456789012345678901234567890"
+        ];
+        for text in numbers {
+            let number = BigInt::parse(text).unwrap();
+            assert_eq!(number.to_decimal(), text);
+            assert_eq!(BigInt::parse(&number.to_hex()), Ok(number));
+        }
+
+        // Only the top limb is left unpadded
+        assert_eq!(BigInt::new(false, vec!(0xabc, 1)).to_hex(), "0x100000abc");
+        assert_eq!(BigInt::new(true, vec!(0, 0, 0x10)).to_hex(), "-0x100000000000000000");
+    }
+}
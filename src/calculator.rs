@@ -0,0 +1,524 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::slice;
+
+#[cfg(windows)]
+use winapi::shared::ntdef::HANDLE;
+#[cfg(windows)]
+use winapi::um::memoryapi::{CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS, FILE_MAP_COPY};
+#[cfg(windows)]
+use winapi::um::winnt::PAGE_READWRITE;
+#[cfg(windows)]
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+#[cfg(windows)]
+use winapi::um::errhandlingapi::GetLastError;
+#[cfg(not(windows))]
+use crate::win32_unsupported::{CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS, FILE_MAP_COPY,
+    PAGE_READWRITE, CloseHandle, INVALID_HANDLE_VALUE, GetLastError, HANDLE};
+
+use crate::haxm::{HaxmDevice, HaxmVCPU, VcpuExit};
+use crate::port_io::PortIoBus;
+use crate::cmos::{Cmos, RtcClock, CMOS_INDEX_PORT};
+use crate::cpuid::{CpuidConfig, HYPERCALC_SIGNATURE};
+use crate::disasm::{self, CodeSize, Instruction, MAX_INSTRUCTION_LENGTH};
+use crate::debugger::Debugger;
+use crate::trace::Tracer;
+use crate::haxm_interface_windows::vmx_msr;
+use crate::state_file::{CpuState, FpuState, MsrState, VcpuSnapshot, SAVED_MSRS};
+use crate::snapshot::{MemoryRegion, VmSnapshot};
+use crate::guest_fault::{self, GuestFault, CODE32_SELECTOR, FAULT_HALT, MAILBOX};
+use crate::state_dump::AR_DB;
+
+pub const RAM_SIZE: u32 = 0x4000;
+
+/*
+    Physical Memory (processor linear address space) layout for a pseudo flat model:
+    [0x0000 - 0x1fff] [Data segment]
+    [0x2000 - 0x3fff] [Code segment]
+
+    SS covers the data segment too and the stack grows down from ESP 0x1000. The segment limits and the initial ESP
+    are part of the starting state in fixtures/initial_state.json.
+
+    Expression variables live below the descriptor tables, see variables:
+    [0x1c00 - 0x1dff] [Variables, a dword each]
+
+    The top of each segment belongs to the exception handling, see guest_fault:
+    [0x1e00 - 0x1fff] [GDT, fault mailbox, IDT]
+    [0x3c00 - 0x3fff] [Fault handler and per vector stubs]
+
+    Below the stubs is room for the number theory routines, see number_theory:
+    [0x3000 - 0x3bff] [Routine library, when installed]
+*/
+pub const DATA_BASE: u32 = 0x0000;
+pub const CODE_BASE: u32 = 0x2000;
+
+/// A single vCPU HAXM VM laid out the way the calculator expects. Guest code runs from CS:0 (linear CODE_BASE) and
+/// addresses its operands relative to DS (linear DATA_BASE).
+///
+/// Guest RAM is a view of a pagefile backed section, so a set up VM can fork() children that share it copy-on-write.
+pub struct CalcVm {
+    pub device: HaxmDevice,
+    pub io_bus: PortIoBus,
+    /// When set, run() single-steps the guest and records every instruction.
+    pub tracer: Option<Tracer>,
+    /// What reset_cpu_state() goes back to, fixtures/initial_state.json unless replaced.
+    pub initial_state: CpuState,
+    hva: *mut u8,
+    /// The section holding guest RAM, null for forks: they map their parent's and can't be forked themselves.
+    section: HANDLE
+}
+
+/// A child VM made by CalcVm::fork(), used like a CalcVm. It borrows its parent for as long as it exists: the child
+/// maps the parent's RAM and uses its HAXM device handle, so the parent can't change or drop them underneath it.
+pub struct Fork<'a> {
+    vm: CalcVm,
+    parent: PhantomData<&'a mut CalcVm>
+}
+
+impl Deref for Fork<'_> {
+    type Target = CalcVm;
+
+    fn deref(&self) -> &CalcVm {
+        &self.vm
+    }
+}
+
+impl DerefMut for Fork<'_> {
+    fn deref_mut(&mut self) -> &mut CalcVm {
+        &mut self.vm
+    }
+}
+
+/// Why CalcVm.run() failed.
+#[derive(Debug)]
+pub enum RunError {
+    /// The guest took an exception and its handler halted.
+    Fault(GuestFault),
+    /// Anything else: HAXM failed, or the guest stopped without halting.
+    Failed(String)
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Fault(fault) => write!(f, "{}", fault),
+            RunError::Failed(error_message) => write!(f, "{}", error_message)
+        }
+    }
+}
+
+impl From<String> for RunError {
+    fn from(error_message: String) -> Self {
+        RunError::Failed(error_message)
+    }
+}
+
+impl From<RunError> for String {
+    fn from(error: RunError) -> Self {
+        error.to_string()
+    }
+}
+
+/// The devices every CalcVm has, with `cmos` as its RTC.
+fn io_bus_with(cmos: Cmos) -> PortIoBus {
+    let mut io_bus = PortIoBus::new();
+    io_bus.register(CMOS_INDEX_PORT, 2, Box::new(cmos));
+    io_bus
+}
+
+/// The devices every CalcVm starts with, the RTC on the host's clock.
+fn default_io_bus() -> PortIoBus {
+    io_bus_with(Cmos::new(RtcClock::Host))
+}
+
+/// Creates a VM on an opened HAXM device, backs its RAM with `hva` and gives it vCPU 0 with a tunnel. On failure
+/// returns a description of the step that failed.
+fn create_vm(haxm_device: &mut HaxmDevice, hva: *mut u8) -> Result<(), String> {
+    if let Err(last_error) = haxm_device.new_vm() {
+        return Err(format!("Unable to create a new VM. GetLastError: {}", last_error));
+    }
+
+    let calc_vm = &mut haxm_device.vms[0];
+
+    if let Some(last_error) = calc_vm.alloc_ram(hva as u64, RAM_SIZE) {
+        return Err(format!("Unable to allocate memory for the VM. GetLastError: {}", last_error));
+    }
+
+    if let Some(last_error) = calc_vm.set_ram(0, RAM_SIZE, hva as u64) {
+        return Err(format!("Unable to set memory for the VM. GetLastError: {}", last_error));
+    }
+
+    if let Some(last_error) = calc_vm.new_cpu(0) {
+        return Err(format!("Unable to create a vCPU for the VM. GetLastError: {}", last_error));
+    }
+
+    if let Some(last_error) = calc_vm.vcpus[0].setup_vcpu_tunnel() {
+        return Err(format!("Unable to setup vCPU channel for vCPU: {}. GetLastError: {}", calc_vm.vcpus[0].id, last_error));
+    }
+    Ok(())
+}
+
+impl CalcVm {
+
+    /// Associated function constructor. Opens HAXM, creates the VM, its RAM and vCPU 0 and attaches the emulated
+    /// devices. On failure returns a description of the step that failed.
+    pub fn new() -> Result<Self, String> {
+        unsafe {
+            let mut haxm_device = HaxmDevice::new();
+
+            if let Err(last_error) = haxm_device.initialize() {
+                return Err(format!("Unable to initialize HAXM device. GetLastError: {}", last_error));
+            }
+
+            let section = CreateFileMappingW(INVALID_HANDLE_VALUE, ptr::null_mut(), PAGE_READWRITE, 0, RAM_SIZE, ptr::null());
+            if section.is_null() {
+                return Err(format!("Unable to allocate memory. Call to CreateFileMappingW failed. GetLastError: {}", GetLastError()));
+            }
+
+            let hva = MapViewOfFile(section, FILE_MAP_ALL_ACCESS, 0, 0, RAM_SIZE as usize);
+            if hva.is_null() {
+                let last_error = GetLastError();
+                CloseHandle(section);
+                return Err(format!("Unable to allocate memory. Call to MapViewOfFile failed. GetLastError: {}", last_error));
+            }
+
+            // From here on dropping the CalcVm cleans up whatever has been created
+            let mut new_calc_vm = CalcVm {
+                device: haxm_device,
+                io_bus: default_io_bus(),
+                tracer: None,
+                initial_state: VcpuSnapshot::initial().cpu,
+                hva: hva as *mut u8,
+                section: section
+            };
+            create_vm(&mut new_calc_vm.device, new_calc_vm.hva)?;
+
+            // Unused memory is NOPs, so stray jumps into it slide instead of executing garbage
+            new_calc_vm.memory().fill(0x90);
+            let (code_limit, data_limit) = (new_calc_vm.initial_state.cs.limit, new_calc_vm.initial_state.ds.limit);
+            guest_fault::install(new_calc_vm.memory(), DATA_BASE, CODE_BASE, code_limit, data_limit)?;
+            new_calc_vm.reset_cpu_state();
+
+            Ok(new_calc_vm)
+        }
+    }
+
+    /// Creates a child VM that starts out identical to this one: its RAM is a copy-on-write view of this VM's, and it
+    /// gets a copy of vCPU 0's registers, FPU state and MSRs, the CPUID table and the device state. Nothing the child
+    /// does is visible here. The child still reads every page it hasn't written from this VM's memory and shares its
+    /// HAXM device handle, so it borrows this VM until it is dropped. Children can't be forked further.
+    ///
+    /// Whether the children really share pages is up to HAXM: a driver that locks guest RAM for writing makes Windows
+    /// copy each page up front. The children still start from this VM's memory without it being rebuilt.
+    /// On failure returns a description of the step that failed.
+    pub fn fork(&mut self) -> Result<Fork<'_>, String> {
+        if self.section.is_null() {
+            return Err(String::from("Only a VM created by CalcVm::new() can be forked, this one is a fork itself"));
+        }
+
+        unsafe {
+            let hva = MapViewOfFile(self.section, FILE_MAP_COPY, 0, 0, RAM_SIZE as usize);
+            if hva.is_null() {
+                return Err(format!("Unable to map a copy of the VM's memory. GetLastError: {}", GetLastError()));
+            }
+
+            let mut haxm_device = HaxmDevice::new();
+            haxm_device.device_handle = self.device.device_handle;
+
+            let mut child = CalcVm {
+                device: haxm_device,
+                io_bus: default_io_bus(),
+                tracer: None,
+                initial_state: self.initial_state.clone(),
+                hva: hva as *mut u8,
+                section: ptr::null_mut()
+            };
+            create_vm(&mut child.device, child.hva)?;
+
+            load_vcpu_state(child.vcpu(), &save_vcpu_state(self.vcpu())?)?;
+            // Older HAXM drivers have no CPUID IOCTLs, the child then sees the driver's default table like its parent
+            if let Ok(entries) = self.vcpu().get_cpuid() {
+                child.set_cpuid(&CpuidConfig::from_entries(entries))?;
+            }
+            child.io_bus.restore_states(&self.io_bus.save_states())?;
+
+            Ok(Fork {
+                vm: child,
+                parent: PhantomData
+            })
+        }
+    }
+
+    /// vCPU 0, the only one the calculator uses.
+    pub fn vcpu(&mut self) -> &mut HaxmVCPU {
+        &mut self.device.vms[0].vcpus[0]
+    }
+
+    /// All of guest physical memory.
+    pub fn memory(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.hva, RAM_SIZE as usize) }
+    }
+
+    /// Copies `code` to the start of the code segment, where execution begins. On failure returns a description of
+    /// why the code doesn't fit.
+    pub fn load_code(&mut self, code: &[u8]) -> Result<(), String> {
+        self.write_code(0, code)
+    }
+
+    /// Copies `code` into the code segment at CS:`offset`. On failure returns a description of why the code doesn't
+    /// fit.
+    pub fn write_code(&mut self, offset: u32, code: &[u8]) -> Result<(), String> {
+        self.write_segment("CS", CODE_BASE, RAM_SIZE - CODE_BASE, offset, code)
+    }
+
+    /// Copies `data` into the data segment at DS:`offset`. On failure returns a description of why the data doesn't
+    /// fit.
+    pub fn write_data(&mut self, offset: u32, data: &[u8]) -> Result<(), String> {
+        self.write_segment("DS", DATA_BASE, CODE_BASE - DATA_BASE, offset, data)
+    }
+
+    /// Copies `bytes` to `segment`:`offset`, where the segment starts at linear `base` and is `size` bytes long.
+    fn write_segment(&mut self, segment: &str, base: u32, size: u32, offset: u32, bytes: &[u8]) -> Result<(), String> {
+        match (offset as usize).checked_add(bytes.len()) {
+            Some(end) if end <= size as usize => {}
+            _ => return Err(format!("{:#x} bytes at {}:{:#x} don't fit in the segment, it is {:#x} bytes", bytes.len(), segment, offset, size))
+        }
+        let start = (base + offset) as usize;
+        self.memory()[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Reads `length` bytes of the data segment from DS:`offset`.
+    pub fn read_data(&mut self, offset: u32, length: usize) -> Vec<u8> {
+        let start = (DATA_BASE + offset) as usize;
+        self.memory()[start..start + length].to_vec()
+    }
+
+    /// Decodes the instruction at CS:EIP of the local register state, the one the guest executes next.
+    pub fn current_instruction(&mut self) -> Instruction {
+        let cpu_state = &self.vcpu().cpu_state;
+        let address = cpu_state.cs.base + cpu_state.eip() as u64;
+        let code_size = CodeSize::of_segment(&cpu_state.cs);
+
+        let start = (address as usize).min(RAM_SIZE as usize);
+        let end = (start + MAX_INSTRUCTION_LENGTH).min(RAM_SIZE as usize);
+        disasm::decode(&self.memory()[start..end], address, code_size)
+    }
+
+    /// Replaces vCPU 0's CPUID table with `config`. On failure returns a description of what went wrong.
+    pub fn set_cpuid(&mut self, config: &CpuidConfig) -> Result<(), String> {
+        let vcpu = self.vcpu();
+        match vcpu.set_cpuid(config.entries()) {
+            None => Ok(()),
+            Some(last_error) => Err(format!("Unable to set vCPU {} CPUID. GetLastError: {}", vcpu.id, last_error))
+        }
+    }
+
+    /// Changes the CPUID table HAXM reports for vCPU 0 with `configure`, e.g. to hide features. On failure returns a
+    /// description of what went wrong.
+    pub fn update_cpuid<F: FnOnce(CpuidConfig) -> CpuidConfig>(&mut self, configure: F) -> Result<(), String> {
+        let vcpu = self.vcpu();
+        let entries = match vcpu.get_cpuid() {
+            Ok(entries) => entries,
+            Err(last_error) => return Err(format!("Unable to get vCPU {} CPUID. GetLastError: {}", vcpu.id, last_error))
+        };
+
+        self.set_cpuid(&configure(CpuidConfig::from_entries(entries)))
+    }
+
+    /// Adds the HyperCalc signature to the hypervisor leaf (0x40000000) on top of the CPUID table HAXM reports, so
+    /// guests can tell they are running under HyperCalc.
+    pub fn advertise_hypervisor(&mut self) -> Result<(), String> {
+        self.update_cpuid(|config| config.hypervisor_signature(HYPERCALC_SIGNATURE))
+    }
+
+    /// Replaces the RTC/CMOS, e.g. with one on a fixed clock or with preseeded NVRAM. Whatever the guest set on the
+    /// old one is lost.
+    pub fn set_cmos(&mut self, cmos: Cmos) {
+        self.io_bus = io_bus_with(cmos);
+    }
+
+    /// Puts vCPU 0's register state back to the calculator's starting point, initial_state. By default that is 16 bit
+    /// protected mode with CS and DS covering the code and data halves of memory, paging off and EIP at the start of
+    /// the code segment. Only the local copy is changed, run() sends it to HAXM.
+    pub fn reset_cpu_state(&mut self) {
+        let initial_state = self.initial_state.clone();
+        initial_state.to_vcpu_state(&mut self.vcpu().cpu_state);
+    }
+
+    /// Switches the local register state to the 32 bit flavour of the code segment (CODE32_SELECTOR, CS.D set), for
+    /// guest code assembled with Mode::Bits32. Same base and limit, and reset_cpu_state() goes back to 16 bit.
+    pub fn use_32bit_code(&mut self) {
+        let cs = &mut self.vcpu().cpu_state.cs;
+        cs.selector = CODE32_SELECTOR;
+        cs.anon_union.ar = cs.ar() | AR_DB;
+    }
+
+    /// Captures vCPU 0's registers (the local copy, current after every run), FPU state and the SAVED_MSRS.
+    /// On failure returns a description of what went wrong.
+    pub fn save_state(&mut self) -> Result<VcpuSnapshot, String> {
+        save_vcpu_state(self.vcpu())
+    }
+
+    /// Makes `snapshot` vCPU 0's state. The registers go to the local copy, run() sends them to HAXM; the FPU state
+    /// and MSRs, when the snapshot has them, are set right away. On failure returns a description of what went wrong.
+    pub fn load_state(&mut self, snapshot: &VcpuSnapshot) -> Result<(), String> {
+        load_vcpu_state(self.vcpu(), snapshot)
+    }
+
+    /// Captures the whole VM: every vCPU like save_state(), all of RAM and the devices on the I/O bus.
+    /// On failure returns a description of what went wrong.
+    pub fn snapshot(&mut self) -> Result<VmSnapshot, String> {
+        let mut vcpus = vec!();
+        for vcpu in self.device.vms[0].vcpus.iter_mut() {
+            vcpus.push(save_vcpu_state(vcpu)?);
+        }
+
+        let memory = vec!(MemoryRegion { guest_physical: 0, bytes: self.memory().to_vec() });
+        Ok(VmSnapshot::new(vcpus, memory, self.io_bus.save_states()))
+    }
+
+    /// Puts the VM back into the state snapshot() captured, typically in a freshly created CalcVm. On failure returns
+    /// a description of what doesn't fit, the VM may then be partly restored.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) -> Result<(), String> {
+        let vcpu_count = self.device.vms[0].vcpus.len();
+        if snapshot.vcpus.len() != vcpu_count {
+            return Err(format!("The snapshot has {} vCPUs, the VM {}", snapshot.vcpus.len(), vcpu_count));
+        }
+
+        for region in snapshot.memory.iter() {
+            let start = region.guest_physical as usize;
+            let end = match start.checked_add(region.bytes.len()).filter(|end| *end <= RAM_SIZE as usize) {
+                Some(end) => end,
+                None => return Err(format!("Snapshot memory at {:#x} + {:#x} is outside of guest RAM", region.guest_physical, region.bytes.len()))
+            };
+            self.memory()[start..end].copy_from_slice(&region.bytes);
+        }
+
+        for (vcpu, vcpu_snapshot) in self.device.vms[0].vcpus.iter_mut().zip(snapshot.vcpus.iter()) {
+            load_vcpu_state(vcpu, vcpu_snapshot)?;
+        }
+
+        self.io_bus.restore_states(&snapshot.devices)
+    }
+
+    /// Sends the local register state to vCPU 0, runs it until it exits to us and reads the registers back.
+    /// On failure returns a description of what went wrong.
+    pub fn run_until_exit(&mut self) -> Result<VcpuExit, String> {
+        let vcpu = &mut self.device.vms[0].vcpus[0];
+
+        if let Some(last_error) = vcpu.set_regs() {
+            return Err(format!("Unable to set vCPU {} registers. GetLastError: {}", vcpu.id, last_error));
+        }
+
+        let exit = vcpu.run_until_exit(&mut self.io_bus);
+
+        if let Some(last_error) = vcpu.get_regs() {
+            return Err(format!("Unable to get vCPU {} registers. GetLastError: {}", vcpu.id, last_error));
+        }
+
+        match exit {
+            Ok(exit) => Ok(exit),
+            Err(last_error) => Err(format!("Unable to run vCPU {}. GetLastError: {}", vcpu.id, last_error))
+        }
+    }
+
+    /// The exception the guest took, if it is halted in the fault handler.
+    pub fn guest_fault(&mut self) -> Option<GuestFault> {
+        let cpu_state = &self.vcpu().cpu_state;
+        if cpu_state.cs.base != CODE_BASE as u64 || cpu_state.eip() != FAULT_HALT + 1 {
+            return None;
+        }
+        Some(GuestFault::from_mailbox(&self.read_data(MAILBOX, 16), CODE_BASE as u64))
+    }
+
+    /// Like run_until_exit(), but the guest is expected to run to its HLT. If it takes an exception returns the
+    /// RunError::Fault its handler recorded, if it stops for any other reason a description of what happened. With a
+    /// tracer set the guest is single-stepped and every instruction recorded.
+    pub fn run(&mut self) -> Result<(), RunError> {
+        let exit = match self.tracer.take() {
+            Some(mut tracer) => {
+                let mut debugger = Debugger::new(self);
+                let exit = tracer.run(&mut debugger);
+                debugger.detach();
+                self.tracer = Some(tracer);
+                exit?
+            }
+            None => self.run_until_exit()?
+        };
+
+        match exit {
+            VcpuExit::Halt => match self.guest_fault() {
+                Some(fault) => Err(RunError::Fault(fault)),
+                None => Ok(())
+            },
+            exit => {
+                let instruction = self.current_instruction();
+                Err(RunError::Failed(format!("vCPU {} stopped without halting: {:?} at {}", self.vcpu().id, exit, instruction)))
+            }
+        }
+    }
+}
+
+impl Drop for CalcVm {
+
+    /// Closes the vCPUs and the VM, which makes HAXM destroy them, then releases guest RAM. The HAXM device handle is
+    /// only closed by the VM that opened it.
+    fn drop(&mut self) {
+        unsafe {
+            for vm in self.device.vms.iter() {
+                for vcpu in vm.vcpus.iter() {
+                    CloseHandle(vcpu.vcpu_handle);
+                }
+                CloseHandle(vm.vm_handle);
+            }
+
+            UnmapViewOfFile(self.hva as *const _);
+            if !self.section.is_null() {
+                CloseHandle(self.section);
+                CloseHandle(self.device.device_handle);
+            }
+        }
+    }
+}
+
+fn save_vcpu_state(vcpu: &mut HaxmVCPU) -> Result<VcpuSnapshot, String> {
+    if let Some(last_error) = vcpu.get_fpu() {
+        return Err(format!("Unable to get vCPU {} FPU state. GetLastError: {}", vcpu.id, last_error));
+    }
+
+    let mut msrs: Vec<vmx_msr> = SAVED_MSRS.iter().map(|index| vmx_msr { entry: *index, value: 0 }).collect();
+    let read = match vcpu.get_msrs(&mut msrs) {
+        Ok(read) => read,
+        Err(last_error) => return Err(format!("Unable to get vCPU {} MSRs. GetLastError: {}", vcpu.id, last_error))
+    };
+    if read < msrs.len() {
+        return Err(format!("HAXM refused to read MSR {:#x}", { msrs[read].entry }));
+    }
+
+    let msrs = msrs.iter().map(|msr| MsrState { index: msr.entry, value: msr.value }).collect();
+    Ok(VcpuSnapshot::new(CpuState::from_vcpu_state(&vcpu.cpu_state), Some(FpuState::from_fx_layout(&vcpu.fpu_state)), msrs))
+}
+
+fn load_vcpu_state(vcpu: &mut HaxmVCPU, snapshot: &VcpuSnapshot) -> Result<(), String> {
+    snapshot.cpu.to_vcpu_state(&mut vcpu.cpu_state);
+
+    if let Some(fpu) = &snapshot.fpu {
+        vcpu.fpu_state = fpu.to_fx_layout();
+        if let Some(last_error) = vcpu.set_fpu() {
+            return Err(format!("Unable to set vCPU {} FPU state. GetLastError: {}", vcpu.id, last_error));
+        }
+    }
+
+    let msrs = snapshot.vmx_msrs();
+    let written = match vcpu.set_msrs(&msrs) {
+        Ok(written) => written,
+        Err(last_error) => return Err(format!("Unable to set vCPU {} MSRs. GetLastError: {}", vcpu.id, last_error))
+    };
+    if written < msrs.len() {
+        return Err(format!("HAXM refused to write MSR {:#x}", { msrs[written].entry }));
+    }
+    Ok(())
+}
@@ -0,0 +1,485 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::port_io::PortIoDevice;
+
+// An MC146818 compatible RTC/CMOS, the same chip (or at least the same register interface) PC firmware reads
+// the date and time from. Only the register file is emulated, the periodic/alarm/update interrupts are not
+// since nothing injects IRQ8 into the guest.
+
+pub const CMOS_INDEX_PORT: u16 = 0x70;
+pub const CMOS_DATA_PORT: u16  = 0x71;
+
+pub const CMOS_REG_SECONDS: u8        = 0x00;
+pub const CMOS_REG_MINUTES: u8        = 0x02;
+pub const CMOS_REG_HOURS: u8          = 0x04;
+pub const CMOS_REG_WEEKDAY: u8        = 0x06;
+pub const CMOS_REG_DAY_OF_MONTH: u8   = 0x07;
+pub const CMOS_REG_MONTH: u8          = 0x08;
+pub const CMOS_REG_YEAR: u8           = 0x09;
+pub const CMOS_REG_STATUS_A: u8       = 0x0A;
+pub const CMOS_REG_STATUS_B: u8       = 0x0B;
+pub const CMOS_REG_STATUS_C: u8       = 0x0C;
+pub const CMOS_REG_STATUS_D: u8       = 0x0D;
+pub const CMOS_REG_CENTURY: u8        = 0x32;
+
+pub const CMOS_NVRAM_SIZE: usize = 128;
+
+const STATUS_A_UIP: u8          = 0x80;
+const STATUS_B_24_HOUR: u8      = 0x02;
+const STATUS_B_BINARY: u8       = 0x04;
+const STATUS_D_VALID_RAM: u8    = 0x80;
+const HOURS_PM: u8              = 0x80;
+const INDEX_NMI_DISABLE: u8     = 0x80;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// Where the RTC gets "now" from.
+pub enum RtcClock {
+    /// The host's wall-clock time (UTC).
+    Host,
+    /// A frozen point in time, in seconds since the Unix epoch. Useful to keep guests deterministic.
+    Fixed(i64)
+}
+
+impl RtcClock {
+
+    /// Builds a frozen clock from a calendar date and time.
+    pub fn fixed(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        let date_time = DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second
+        };
+        RtcClock::Fixed(date_time.to_unix())
+    }
+
+    /// Parses `host`, or a UTC date and time such as `2024-02-29T13:45:07` for a frozen clock. On failure returns a
+    /// description of what is wrong with `text`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text == "host" {
+            return Ok(RtcClock::Host);
+        }
+
+        let invalid = || format!("{} is not a date and time such as 2024-02-29T13:45:07", text);
+        let (date, time) = text.split_once('T').ok_or_else(invalid)?;
+        let date: Vec<&str> = date.split('-').collect();
+        let time: Vec<&str> = time.split(':').collect();
+        if date.len() != 3 || time.len() != 3 {
+            return Err(invalid());
+        }
+        let year = date[0].parse::<i64>().map_err(|_| invalid())?;
+        let mut fields = [0u8; 5];
+        for (field, text) in fields.iter_mut().zip(date[1..].iter().chain(time.iter())) {
+            *field = text.parse::<u8>().map_err(|_| invalid())?;
+        }
+
+        // Out of range fields (the 30th of February, hour 24) come back as a different time
+        let [month, day, hour, minute, second] = fields;
+        let clock = RtcClock::fixed(year, month, day, hour, minute, second);
+        let expected = DateTime { year: year, month: month, day: day, hour: hour, minute: minute, second: second };
+        if DateTime::from_unix(clock.now()) != expected {
+            return Err(invalid());
+        }
+        Ok(clock)
+    }
+
+    /// Returns the current time of this clock in seconds since the Unix epoch.
+    pub fn now(&self) -> i64 {
+        match self {
+            RtcClock::Host => {
+                match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(elapsed) => elapsed.as_secs() as i64,
+                    Err(before_epoch) => -(before_epoch.duration().as_secs() as i64)
+                }
+            }
+            RtcClock::Fixed(seconds) => *seconds
+        }
+    }
+}
+
+/// A broken down UTC calendar time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8
+}
+
+impl DateTime {
+
+    /// Converts seconds since the Unix epoch to a calendar time.
+    pub fn from_unix(seconds: i64) -> Self {
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let second_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: (second_of_day / 3600) as u8,
+            minute: ((second_of_day / 60) % 60) as u8,
+            second: (second_of_day % 60) as u8
+        }
+    }
+
+    /// Converts the calendar time to seconds since the Unix epoch.
+    pub fn to_unix(self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY
+            + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// Day of the week, 1 (Sunday) through 7 (Saturday), which is what the RTC's weekday register holds.
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((days_from_civil(self.year, self.month, self.day) + 4).rem_euclid(7) + 1) as u8
+    }
+}
+
+// Both conversions are Howard Hinnant's proleptic Gregorian calendar algorithms:
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = (if month_index < 10 { month_index + 3 } else { month_index - 9 }) as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// What a snapshot keeps of the CMOS. A host clock stays a host clock, with the guest's offset from it.
+#[derive(Serialize, Deserialize)]
+struct CmosState {
+    index: u8,
+    nmi_disabled: bool,
+    nvram: Vec<u8>,
+    /// The frozen time of an RtcClock::Fixed, None for RtcClock::Host.
+    fixed_clock: Option<i64>,
+    offset: i64
+}
+
+pub struct Cmos {
+    index: u8,
+    nmi_disabled: bool,
+    nvram: [u8; CMOS_NVRAM_SIZE],
+    clock: RtcClock,
+    /// Seconds added to `clock` after the guest writes the time registers.
+    offset: i64
+}
+
+impl Cmos {
+
+    /// Associated function constructor. Constructs an RTC reporting time from `clock`, in BCD and 24 hour mode
+    /// like most firmware leaves it.
+    pub fn new(clock: RtcClock) -> Self {
+        let mut nvram = [0u8; CMOS_NVRAM_SIZE];
+        nvram[CMOS_REG_STATUS_A as usize] = 0x26; // 32.768kHz time base, 1024Hz periodic rate
+        nvram[CMOS_REG_STATUS_B as usize] = STATUS_B_24_HOUR;
+        nvram[CMOS_REG_STATUS_D as usize] = STATUS_D_VALID_RAM;
+
+        Cmos {
+            index: 0,
+            nmi_disabled: false,
+            nvram: nvram,
+            clock: clock,
+            offset: 0
+        }
+    }
+
+    /// Copies `bytes` into NVRAM starting at `offset`, so guests find configuration already in place.
+    /// Offsets below 0x0E overlap the clock and status registers, the time registers are always computed from the
+    /// clock on read.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The CMOS register index of the first byte.
+    /// * `bytes` - The data. Anything past the end of the 128 byte NVRAM is ignored.
+    pub fn preseed_nvram(&mut self, offset: u8, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            let index = offset as usize + i;
+            if index >= CMOS_NVRAM_SIZE {
+                break;
+            }
+            self.nvram[index] = *byte;
+        }
+    }
+
+    /// Replaces the clock and discards any time the guest has set.
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.clock = clock;
+        self.offset = 0;
+    }
+
+    /// The time the guest currently sees.
+    pub fn now(&self) -> DateTime {
+        DateTime::from_unix(self.clock.now() + self.offset)
+    }
+
+    fn status_b(&self) -> u8 {
+        self.nvram[CMOS_REG_STATUS_B as usize]
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.status_b() & STATUS_B_BINARY != 0 {
+            value
+        }
+        else {
+            to_bcd(value)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.status_b() & STATUS_B_BINARY != 0 {
+            value
+        }
+        else {
+            from_bcd(value)
+        }
+    }
+
+    fn encode_hours(&self, hour: u8) -> u8 {
+        if self.status_b() & STATUS_B_24_HOUR != 0 {
+            return self.encode(hour);
+        }
+
+        let hour_12 = match hour % 12 {
+            0 => 12,
+            hour_12 => hour_12
+        };
+        let pm = if hour >= 12 { HOURS_PM } else { 0 };
+        self.encode(hour_12) | pm
+    }
+
+    fn decode_hours(&self, value: u8) -> u8 {
+        if self.status_b() & STATUS_B_24_HOUR != 0 {
+            return self.decode(value);
+        }
+
+        let hour_12 = self.decode(value & !HOURS_PM) % 12;
+        if value & HOURS_PM != 0 {
+            hour_12 + 12
+        }
+        else {
+            hour_12
+        }
+    }
+
+    /// Reads a CMOS register the same way the guest would through port 0x71.
+    pub fn read_register(&mut self, index: u8) -> u8 {
+        let now = self.now();
+
+        match index {
+            CMOS_REG_SECONDS => self.encode(now.second),
+            CMOS_REG_MINUTES => self.encode(now.minute),
+            CMOS_REG_HOURS => self.encode_hours(now.hour),
+            CMOS_REG_WEEKDAY => self.encode(now.weekday()),
+            CMOS_REG_DAY_OF_MONTH => self.encode(now.day),
+            CMOS_REG_MONTH => self.encode(now.month),
+            CMOS_REG_YEAR => self.encode(now.year.rem_euclid(100) as u8),
+            CMOS_REG_CENTURY => self.encode(now.year.div_euclid(100).rem_euclid(100) as u8),
+            // The clock never ticks behind the guest's back, so an update is never in progress
+            CMOS_REG_STATUS_A => self.nvram[index as usize] & !STATUS_A_UIP,
+            CMOS_REG_STATUS_C => {
+                // Interrupt flags clear on read
+                let flags = self.nvram[index as usize];
+                self.nvram[index as usize] = 0;
+                flags
+            }
+            _ => self.nvram[index as usize % CMOS_NVRAM_SIZE]
+        }
+    }
+
+    /// Writes a CMOS register the same way the guest would through port 0x71. Writes to the time registers move the
+    /// guest's clock, the host clock is never touched.
+    pub fn write_register(&mut self, index: u8, value: u8) {
+        let mut now = self.now();
+
+        match index {
+            CMOS_REG_SECONDS => now.second = self.decode(value),
+            CMOS_REG_MINUTES => now.minute = self.decode(value),
+            CMOS_REG_HOURS => now.hour = self.decode_hours(value),
+            CMOS_REG_DAY_OF_MONTH => now.day = self.decode(value),
+            CMOS_REG_MONTH => now.month = self.decode(value),
+            CMOS_REG_YEAR => now.year = now.year.div_euclid(100) * 100 + self.decode(value) as i64,
+            CMOS_REG_CENTURY => now.year = self.decode(value) as i64 * 100 + now.year.rem_euclid(100),
+            // The weekday is derived from the date
+            CMOS_REG_WEEKDAY => return,
+            // Read only
+            CMOS_REG_STATUS_C | CMOS_REG_STATUS_D => return,
+            _ => {
+                self.nvram[index as usize % CMOS_NVRAM_SIZE] = value;
+                return;
+            }
+        }
+
+        self.offset = now.to_unix() - self.clock.now();
+    }
+}
+
+impl PortIoDevice for Cmos {
+
+    fn io_in(&mut self, port: u16, _size: u8) -> u32 {
+        if port == CMOS_DATA_PORT {
+            self.read_register(self.index) as u32
+        }
+        else {
+            // The index port is write only on real hardware
+            0xFF
+        }
+    }
+
+    fn io_out(&mut self, port: u16, _size: u8, value: u32) {
+        let value = value as u8;
+
+        if port == CMOS_INDEX_PORT {
+            self.nmi_disabled = value & INDEX_NMI_DISABLE != 0;
+            self.index = value & !INDEX_NMI_DISABLE;
+        }
+        else if port == CMOS_DATA_PORT {
+            self.write_register(self.index, value);
+        }
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let state = CmosState {
+            index: self.index,
+            nmi_disabled: self.nmi_disabled,
+            nvram: self.nvram.to_vec(),
+            fixed_clock: match self.clock {
+                RtcClock::Host => None,
+                RtcClock::Fixed(seconds) => Some(seconds)
+            },
+            offset: self.offset
+        };
+        bincode::serialize(&state).ok()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let state: CmosState = bincode::deserialize(state).map_err(|e| format!("Invalid CMOS state: {}", e))?;
+        if state.nvram.len() != CMOS_NVRAM_SIZE {
+            return Err(format!("Invalid CMOS state: {} bytes of NVRAM", state.nvram.len()));
+        }
+
+        self.index = state.index;
+        self.nmi_disabled = state.nmi_disabled;
+        self.nvram.copy_from_slice(&state.nvram);
+        self.clock = match state.fixed_clock {
+            Some(seconds) => RtcClock::Fixed(seconds),
+            None => RtcClock::Host
+        };
+        self.offset = state.offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::port_io::PortIoBus;
+
+    /// A Thursday afternoon in a leap year's February.
+    fn cmos() -> Cmos {
+        Cmos::new(RtcClock::fixed(2024, 2, 29, 13, 45, 7))
+    }
+
+    fn time_registers(cmos: &mut Cmos) -> [u8; 8] {
+        [CMOS_REG_SECONDS, CMOS_REG_MINUTES, CMOS_REG_HOURS, CMOS_REG_WEEKDAY, CMOS_REG_DAY_OF_MONTH, CMOS_REG_MONTH,
+            CMOS_REG_YEAR, CMOS_REG_CENTURY].map(|index| cmos.read_register(index))
+    }
+
+    #[test]
+    fn bcd_time() {
+        assert_eq!(time_registers(&mut cmos()), [0x07, 0x45, 0x13, 0x05, 0x29, 0x02, 0x24, 0x20]);
+    }
+
+    #[test]
+    fn binary_time() {
+        let mut cmos = cmos();
+        cmos.write_register(CMOS_REG_STATUS_B, STATUS_B_24_HOUR | STATUS_B_BINARY);
+        assert_eq!(time_registers(&mut cmos), [7, 45, 13, 5, 29, 2, 24, 20]);
+    }
+
+    #[test]
+    fn twelve_hour_mode() {
+        let mut cmos = cmos();
+        cmos.write_register(CMOS_REG_STATUS_B, 0);
+        assert_eq!(cmos.read_register(CMOS_REG_HOURS), HOURS_PM | 0x01);
+
+        cmos.write_register(CMOS_REG_STATUS_B, STATUS_B_BINARY);
+        assert_eq!(cmos.read_register(CMOS_REG_HOURS), HOURS_PM | 1);
+
+        cmos.set_clock(RtcClock::fixed(2024, 2, 29, 0, 30, 0));
+        assert_eq!(cmos.read_register(CMOS_REG_HOURS), 12);
+    }
+
+    #[test]
+    fn guest_writes_keep_an_offset() {
+        let mut cmos = cmos();
+        cmos.write_register(CMOS_REG_MINUTES, 0x50);
+        cmos.write_register(CMOS_REG_DAY_OF_MONTH, 0x01);
+        assert_eq!(cmos.now(), DateTime { year: 2024, month: 2, day: 1, hour: 13, minute: 50, second: 7 });
+        assert_eq!(time_registers(&mut cmos), [0x07, 0x50, 0x13, 0x05, 0x01, 0x02, 0x24, 0x20]);
+        assert_eq!(cmos.offset, 5 * 60 - 28 * SECONDS_PER_DAY);
+        assert!(matches!(cmos.clock, RtcClock::Fixed(_)));
+
+        cmos.set_clock(RtcClock::fixed(2024, 2, 29, 13, 45, 7));
+        assert_eq!(cmos.read_register(CMOS_REG_MINUTES), 0x45);
+    }
+
+    #[test]
+    fn parse_clock() {
+        assert!(matches!(RtcClock::parse("host"), Ok(RtcClock::Host)));
+        assert!(matches!(RtcClock::parse("2024-02-29T13:45:07"), Ok(RtcClock::Fixed(1709214307))));
+        assert!(matches!(RtcClock::parse("1969-12-31T23:59:59"), Ok(RtcClock::Fixed(-1))));
+
+        for text in ["", "now", "2024-02-29", "2024-02-29T13:45", "2023-02-29T00:00:00", "2024-13-01T00:00:00",
+            "2024-01-01T24:00:00", "2024-01-01T00:60:00", "2024-01-00T00:00:00", "2024-01-01T00:00:0x"] {
+            assert_eq!(RtcClock::parse(text).err(), Some(format!("{} is not a date and time such as 2024-02-29T13:45:07", text)));
+        }
+    }
+
+    #[test]
+    fn preseeded_nvram_reads_through_the_ports() {
+        let mut cmos = cmos();
+        cmos.preseed_nvram(0x10, &[0xaa, 0xbb]);
+        let mut io_bus = PortIoBus::new();
+        io_bus.register(CMOS_INDEX_PORT, 2, Box::new(cmos));
+
+        io_bus.io_out(CMOS_INDEX_PORT, 1, 0x10);
+        assert_eq!(io_bus.io_in(CMOS_DATA_PORT, 1), 0xaa);
+        io_bus.io_out(CMOS_INDEX_PORT, 1, INDEX_NMI_DISABLE as u32 | 0x11);
+        assert_eq!(io_bus.io_in(CMOS_DATA_PORT, 1), 0xbb);
+        io_bus.io_out(CMOS_INDEX_PORT, 1, CMOS_REG_SECONDS as u32);
+        assert_eq!(io_bus.io_in(CMOS_DATA_PORT, 1), 0x07);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::haxm_interface_windows::hax_cpuid_entry;
+
+pub const CPUID_LEAF_VENDOR: u32     = 0x0;
+pub const CPUID_LEAF_FEATURES: u32   = 0x1;
+pub const CPUID_LEAF_HYPERVISOR: u32 = 0x40000000;
+
+// Leaf 1 ECX feature bits
+pub const CPUID_1_ECX_SSE3: u32       = 1 << 0;
+pub const CPUID_1_ECX_SSSE3: u32      = 1 << 9;
+pub const CPUID_1_ECX_SSE4_1: u32     = 1 << 19;
+pub const CPUID_1_ECX_SSE4_2: u32     = 1 << 20;
+pub const CPUID_1_ECX_POPCNT: u32     = 1 << 23;
+pub const CPUID_1_ECX_AVX: u32        = 1 << 28;
+pub const CPUID_1_ECX_HYPERVISOR: u32 = 1 << 31;
+
+// Leaf 1 EDX feature bits
+pub const CPUID_1_EDX_FPU: u32  = 1 << 0;
+pub const CPUID_1_EDX_TSC: u32  = 1 << 4;
+pub const CPUID_1_EDX_MSR: u32  = 1 << 5;
+pub const CPUID_1_EDX_CX8: u32  = 1 << 8;
+pub const CPUID_1_EDX_APIC: u32 = 1 << 9;
+pub const CPUID_1_EDX_SEP: u32  = 1 << 11;
+pub const CPUID_1_EDX_CMOV: u32 = 1 << 15;
+pub const CPUID_1_EDX_MMX: u32  = 1 << 23;
+pub const CPUID_1_EDX_FXSR: u32 = 1 << 24;
+pub const CPUID_1_EDX_SSE: u32  = 1 << 25;
+pub const CPUID_1_EDX_SSE2: u32 = 1 << 26;
+
+/// What a guest finds in EBX, ECX and EDX of leaf 0x40000000 when running under HyperCalc.
+pub const HYPERCALC_SIGNATURE: &[u8; 12] = b"HyperCalc\0\0\0";
+
+/// The leaf 1 feature bits by name, as ECX and EDX masks.
+const FEATURES: [(&str, u32, u32); 17] = [
+    ("sse3", CPUID_1_ECX_SSE3, 0), ("ssse3", CPUID_1_ECX_SSSE3, 0), ("sse4_1", CPUID_1_ECX_SSE4_1, 0),
+    ("sse4_2", CPUID_1_ECX_SSE4_2, 0), ("popcnt", CPUID_1_ECX_POPCNT, 0), ("avx", CPUID_1_ECX_AVX, 0),
+    ("fpu", 0, CPUID_1_EDX_FPU), ("tsc", 0, CPUID_1_EDX_TSC), ("msr", 0, CPUID_1_EDX_MSR), ("cx8", 0, CPUID_1_EDX_CX8),
+    ("apic", 0, CPUID_1_EDX_APIC), ("sep", 0, CPUID_1_EDX_SEP), ("cmov", 0, CPUID_1_EDX_CMOV),
+    ("mmx", 0, CPUID_1_EDX_MMX), ("fxsr", 0, CPUID_1_EDX_FXSR), ("sse", 0, CPUID_1_EDX_SSE), ("sse2", 0, CPUID_1_EDX_SSE2)
+];
+
+/// Turns a comma separated list of feature names, e.g. `sse4_2,popcnt`, into the ECX and EDX masks
+/// CpuidConfig.mask_features() takes. On failure returns a description of the name that isn't known.
+pub fn feature_masks(names: &str) -> Result<(u32, u32), String> {
+    let (mut ecx_mask, mut edx_mask) = (0, 0);
+    for name in names.split(',').map(|name| name.trim()) {
+        match FEATURES.iter().find(|(feature, _, _)| feature.eq_ignore_ascii_case(name)) {
+            Some((_, ecx, edx)) => {
+                ecx_mask |= ecx;
+                edx_mask |= edx;
+            }
+            None => return Err(format!("Unknown CPUID feature {}", name))
+        }
+    }
+    Ok((ecx_mask, edx_mask))
+}
+
+fn register_from_bytes(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// A CPUID table to hand to HaxmVCPU.set_cpuid(). Usually started from HaxmVCPU.get_cpuid() so only the leaves that
+/// matter are changed.
+pub struct CpuidConfig {
+    entries: Vec<hax_cpuid_entry>
+}
+
+impl CpuidConfig {
+
+    /// Associated function constructor. Constructs a table from existing leaves.
+    pub fn from_entries(entries: Vec<hax_cpuid_entry>) -> Self {
+        CpuidConfig {
+            entries: entries
+        }
+    }
+
+    pub fn entries(&self) -> &[hax_cpuid_entry] {
+        &self.entries
+    }
+
+    fn leaf_mut(&mut self, function: u32, index: u32) -> &mut hax_cpuid_entry {
+        if let Some(position) = self.entries.iter().position(|entry| entry.function == function && entry.index == index) {
+            return &mut self.entries[position];
+        }
+
+        self.entries.push(hax_cpuid_entry {
+            function: function,
+            index: index,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            pad: [0; 3]
+        });
+        self.entries.last_mut().unwrap()
+    }
+
+    /// Sets the 12 character vendor string of leaf 0, e.g. "GenuineIntel". The maximum standard leaf in EAX is kept.
+    pub fn vendor(mut self, vendor: &[u8; 12]) -> Self {
+        let leaf = self.leaf_mut(CPUID_LEAF_VENDOR, 0);
+        if leaf.eax == 0 {
+            leaf.eax = CPUID_LEAF_FEATURES;
+        }
+        // The vendor string is spread over EBX, EDX, ECX in that order
+        leaf.ebx = register_from_bytes(&vendor[0..4]);
+        leaf.edx = register_from_bytes(&vendor[4..8]);
+        leaf.ecx = register_from_bytes(&vendor[8..12]);
+        self
+    }
+
+    /// Clears feature bits of leaf 1 so guests take their fallback paths.
+    ///
+    /// # Arguments
+    ///
+    /// * `ecx_mask` - The CPUID_1_ECX_* bits to hide.
+    /// * `edx_mask` - The CPUID_1_EDX_* bits to hide.
+    pub fn mask_features(mut self, ecx_mask: u32, edx_mask: u32) -> Self {
+        let leaf = self.leaf_mut(CPUID_LEAF_FEATURES, 0);
+        leaf.ecx &= !ecx_mask;
+        leaf.edx &= !edx_mask;
+        self
+    }
+
+    /// Sets the hypervisor present bit of leaf 1 and reports `signature` from leaf 0x40000000, the convention guests
+    /// use to detect which hypervisor they run under.
+    pub fn hypervisor_signature(mut self, signature: &[u8; 12]) -> Self {
+        self.leaf_mut(CPUID_LEAF_FEATURES, 0).ecx |= CPUID_1_ECX_HYPERVISOR;
+
+        let leaf = self.leaf_mut(CPUID_LEAF_HYPERVISOR, 0);
+        // EAX is the highest hypervisor leaf, this is the only one
+        leaf.eax = CPUID_LEAF_HYPERVISOR;
+        leaf.ebx = register_from_bytes(&signature[0..4]);
+        leaf.ecx = register_from_bytes(&signature[4..8]);
+        leaf.edx = register_from_bytes(&signature[8..12]);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_names() {
+        assert_eq!(feature_masks("sse4_2, POPCNT,sse2"), Ok((CPUID_1_ECX_SSE4_2 | CPUID_1_ECX_POPCNT, CPUID_1_EDX_SSE2)));
+        assert_eq!(feature_masks("avx512"), Err(String::from("Unknown CPUID feature avx512")));
+        assert_eq!(feature_masks(""), Err(String::from("Unknown CPUID feature ")));
+    }
+
+    #[test]
+    fn vendor_and_masked_features() {
+        let config = CpuidConfig::from_entries(vec!())
+            .hypervisor_signature(HYPERCALC_SIGNATURE)
+            .vendor(b"AuthenticAMD")
+            .mask_features(CPUID_1_ECX_HYPERVISOR | CPUID_1_ECX_AVX, CPUID_1_EDX_SSE2);
+
+        let leaves: Vec<(u32, u32, u32, u32, u32)> = config.entries().iter()
+            .map(|entry| (entry.function, entry.eax, entry.ebx, entry.ecx, entry.edx)).collect();
+        assert_eq!(leaves, [
+            (CPUID_LEAF_FEATURES, 0, 0, 0, 0),
+            (CPUID_LEAF_HYPERVISOR, CPUID_LEAF_HYPERVISOR, 0x65707948, 0x6c614372, 0x63),
+            (CPUID_LEAF_VENDOR, CPUID_LEAF_FEATURES, 0x68747541, 0x444d4163, 0x69746e65)
+        ]);
+    }
+}
@@ -0,0 +1,360 @@
+use crate::calculator::{CalcVm, RAM_SIZE};
+use crate::disasm::{self, CodeSize, Instruction, MAX_INSTRUCTION_LENGTH};
+use crate::guest_debug::*;
+use crate::haxm::VcpuExit;
+use crate::gdbstub::{BreakpointType, GdbTarget, StopReason, I386_CS, I386_EFLAGS, I386_EIP, I386_REGISTER_COUNT};
+use crate::haxm_interface_windows::vcpu_state_t;
+use crate::snapshot::VmSnapshot;
+
+/// Maps `cpu_state` to gdb's i386 registers. EIP is reported as the linear address CS.base + EIP, the segments as their
+/// selectors.
+pub fn i386_registers(cpu_state: &vcpu_state_t) -> [u32; I386_REGISTER_COUNT] {
+    let mut registers = [0u32; I386_REGISTER_COUNT];
+
+    // gdb's order of the general registers is the encoding order, the same as vcpu_regs::REG_*
+    for (index, register) in registers.iter_mut().take(8).enumerate() {
+        *register = cpu_state.gpr32(index);
+    }
+    registers[I386_EIP] = (cpu_state.cs.base + cpu_state.eip() as u64) as u32;
+    registers[I386_EFLAGS] = cpu_state.eflags();
+
+    let segments = [&cpu_state.cs, &cpu_state.ss, &cpu_state.ds, &cpu_state.es, &cpu_state.fs, &cpu_state.gs];
+    for (i, segment) in segments.iter().enumerate() {
+        registers[I386_CS + i] = segment.selector as u32;
+    }
+    registers
+}
+
+/// The inverse of i386_registers(). Segment registers are left alone, a new selector would need its descriptor loaded
+/// with it.
+pub fn set_i386_registers(cpu_state: &mut vcpu_state_t, registers: &[u32; I386_REGISTER_COUNT]) {
+    for (index, register) in registers.iter().take(8).enumerate() {
+        cpu_state.set_gpr32(index, *register);
+    }
+    let eip = (registers[I386_EIP] as u64).wrapping_sub(cpu_state.cs.base) as u32;
+    cpu_state.set_eip(eip);
+    cpu_state.set_eflags(registers[I386_EFLAGS]);
+}
+
+/// Drives a CalcVm one instruction or one breakpoint at a time. Addresses are linear, which is also guest physical
+/// since the calculator never enables paging.
+pub struct Debugger<'a> {
+    pub calc_vm: &'a mut CalcVm,
+    software_breakpoints: SoftwareBreakpoints,
+    hw_breakpoints: [Option<HwBreakpoint>; HW_BREAKPOINT_COUNT]
+}
+
+impl<'a> Debugger<'a> {
+
+    /// Associated function constructor. The guest is left where it is, nothing runs until step() or cont().
+    pub fn new(calc_vm: &'a mut CalcVm) -> Self {
+        Debugger {
+            calc_vm: calc_vm,
+            software_breakpoints: SoftwareBreakpoints::new(),
+            hw_breakpoints: [None; HW_BREAKPOINT_COUNT]
+        }
+    }
+
+    /// The linear address of the next instruction, CS.base + EIP.
+    pub fn pc(&mut self) -> u64 {
+        let cpu_state = &self.calc_vm.vcpu().cpu_state;
+        cpu_state.cs.base + cpu_state.eip() as u64
+    }
+
+    /// Reads guest memory as the guest sees it, i.e. without the INT3s of software breakpoints. Returns None if the
+    /// range is outside of RAM.
+    pub fn read_memory(&mut self, address: u64, length: usize) -> Option<Vec<u8>> {
+        match address.checked_add(length as u64) {
+            Some(end) if end <= RAM_SIZE as u64 => {}
+            _ => return None
+        }
+
+        let mut bytes = self.calc_vm.memory()[address as usize..address as usize + length].to_vec();
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            if let Some(original_byte) = self.software_breakpoints.original_byte(address + i as u64) {
+                *byte = original_byte;
+            }
+        }
+        Some(bytes)
+    }
+
+    /// Decodes up to `count` instructions from `address` with CS's default operand size. Returns None if `address` is
+    /// outside of RAM.
+    pub fn disassemble(&mut self, address: u64, count: usize) -> Option<Vec<Instruction>> {
+        if address >= RAM_SIZE as u64 {
+            return None;
+        }
+
+        let code_size = CodeSize::of_segment(&self.calc_vm.vcpu().cpu_state.cs);
+        // Enough bytes for `count` instructions of the maximum length, or whatever RAM is left
+        let length = count.saturating_mul(MAX_INSTRUCTION_LENGTH).min(RAM_SIZE as usize - address as usize);
+        let bytes = self.read_memory(address, length)?;
+        Some(disasm::disassemble(&bytes, address, code_size, count))
+    }
+
+    /// The instruction at pc(), the one step() executes next.
+    pub fn current_instruction(&mut self) -> Option<Instruction> {
+        let pc = self.pc();
+        self.disassemble(pc, 1)?.into_iter().next()
+    }
+
+    /// Writes guest memory, keeping any software breakpoints in the range. Returns false if the range is outside of
+    /// RAM.
+    pub fn write_memory(&mut self, address: u64, data: &[u8]) -> bool {
+        match address.checked_add(data.len() as u64) {
+            Some(end) if end <= RAM_SIZE as u64 => {}
+            _ => return false
+        }
+
+        let covered: Vec<u64> = (address..address + data.len() as u64).filter(|address| self.software_breakpoints.contains(*address)).collect();
+        let memory = self.calc_vm.memory();
+        for breakpoint in covered.iter() {
+            self.software_breakpoints.remove(memory, *breakpoint);
+        }
+        memory[address as usize..address as usize + data.len()].copy_from_slice(data);
+        for breakpoint in covered.iter() {
+            self.software_breakpoints.insert(memory, *breakpoint);
+        }
+        true
+    }
+
+    pub fn insert_software_breakpoint(&mut self, address: u64) -> bool {
+        let memory = self.calc_vm.memory();
+        self.software_breakpoints.insert(memory, address)
+    }
+
+    pub fn remove_software_breakpoint(&mut self, address: u64) -> bool {
+        let memory = self.calc_vm.memory();
+        self.software_breakpoints.remove(memory, address)
+    }
+
+    pub fn software_breakpoints(&self) -> Vec<u64> {
+        self.software_breakpoints.addresses()
+    }
+
+    /// Puts `breakpoint` in a free debug register. Returns the DRn used, or None if all four are taken.
+    pub fn insert_hw_breakpoint(&mut self, breakpoint: HwBreakpoint) -> Option<usize> {
+        let slot = self.hw_breakpoints.iter().position(|slot| slot.is_none())?;
+        self.hw_breakpoints[slot] = Some(breakpoint);
+        Some(slot)
+    }
+
+    /// Frees the debug register holding a `kind` breakpoint on `address`. Returns false if there was none.
+    pub fn remove_hw_breakpoint(&mut self, address: u64, kind: HwBreakpointKind) -> bool {
+        let found = self.hw_breakpoints.iter().position(|slot| {
+            matches!(slot, Some(breakpoint) if breakpoint.address == address && breakpoint.kind == kind)
+        });
+
+        match found {
+            Some(slot) => {
+                self.hw_breakpoints[slot] = None;
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn hw_breakpoints(&self) -> &[Option<HwBreakpoint>; HW_BREAKPOINT_COUNT] {
+        &self.hw_breakpoints
+    }
+
+    fn run_with(&mut self, config: DebugConfig) -> Result<VcpuExit, String> {
+        let vcpu = self.calc_vm.vcpu();
+        if let Some(last_error) = vcpu.set_debug(&config) {
+            return Err(format!("Unable to set vCPU {} debug state. GetLastError: {}", vcpu.id, last_error));
+        }
+
+        self.calc_vm.run_until_exit()
+    }
+
+    /// Executes the instruction at pc() with every breakpoint on pc() lifted, so a breakpoint we just stopped at
+    /// doesn't fire again straight away.
+    fn step_over_breakpoints(&mut self) -> Result<VcpuExit, String> {
+        let pc = self.pc();
+
+        let mut config = DebugConfig {
+            single_step: true,
+            software_breakpoints: true,
+            hw_breakpoints: self.hw_breakpoints
+        };
+        for slot in config.hw_breakpoints.iter_mut() {
+            if matches!(slot, Some(breakpoint) if breakpoint.kind == HwBreakpointKind::Execute && breakpoint.address == pc) {
+                *slot = None;
+            }
+        }
+
+        let was_inserted = self.remove_software_breakpoint(pc);
+        let exit = self.run_with(config);
+        if was_inserted {
+            self.insert_software_breakpoint(pc);
+        }
+        exit
+    }
+
+    fn at_breakpoint(&mut self) -> bool {
+        let pc = self.pc();
+        self.software_breakpoints.contains(pc) || self.hw_breakpoints.iter().any(|slot| {
+            matches!(slot, Some(breakpoint) if breakpoint.kind == HwBreakpointKind::Execute && breakpoint.address == pc)
+        })
+    }
+
+    /// Executes one instruction. On failure returns a description of what went wrong.
+    pub fn step(&mut self) -> Result<VcpuExit, String> {
+        self.step_over_breakpoints()
+    }
+
+    /// Runs until a breakpoint, HLT or anything else stops the guest. On failure returns a description of what went
+    /// wrong.
+    pub fn cont(&mut self) -> Result<VcpuExit, String> {
+        if self.at_breakpoint() {
+            match self.step_over_breakpoints()? {
+                VcpuExit::Debug(DebugExit { kind: DebugExitKind::SingleStep, .. }) => {}
+                exit => return Ok(exit)
+            }
+        }
+
+        let config = DebugConfig {
+            single_step: false,
+            software_breakpoints: true,
+            hw_breakpoints: self.hw_breakpoints
+        };
+        self.run_with(config)
+    }
+
+    /// Snapshots the VM as the guest sees it, without the INT3s of software breakpoints.
+    pub fn snapshot(&mut self) -> Result<VmSnapshot, String> {
+        let mut snapshot = self.calc_vm.snapshot()?;
+        for region in snapshot.memory.iter_mut() {
+            for (i, byte) in region.bytes.iter_mut().enumerate() {
+                if let Some(original_byte) = self.software_breakpoints.original_byte(region.guest_physical + i as u64) {
+                    *byte = original_byte;
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Restores a snapshot underneath the breakpoints, which stay set at the same addresses.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) -> Result<(), String> {
+        let breakpoints = self.software_breakpoints.addresses();
+        let memory = self.calc_vm.memory();
+        self.software_breakpoints.remove_all(memory);
+
+        let restored = self.calc_vm.restore(snapshot);
+        for address in breakpoints.iter() {
+            self.insert_software_breakpoint(*address);
+        }
+        restored
+    }
+
+    /// Removes every breakpoint from the guest and turns debugging off.
+    pub fn detach(&mut self) {
+        let memory = self.calc_vm.memory();
+        self.software_breakpoints.remove_all(memory);
+        self.hw_breakpoints = [None; HW_BREAKPOINT_COUNT];
+
+        let vcpu = self.calc_vm.vcpu();
+        if let Some(last_error) = vcpu.set_debug(&DebugConfig::default()) {
+            eprintln!("Unable to turn off debugging on vCPU {}. GetLastError: {}", vcpu.id, last_error);
+        }
+    }
+}
+
+impl<'a> GdbTarget for Debugger<'a> {
+
+    fn read_registers(&mut self) -> [u32; I386_REGISTER_COUNT] {
+        i386_registers(&self.calc_vm.vcpu().cpu_state)
+    }
+
+    fn write_registers(&mut self, registers: &[u32; I386_REGISTER_COUNT]) {
+        set_i386_registers(&mut self.calc_vm.vcpu().cpu_state, registers);
+    }
+
+    fn read_memory(&mut self, address: u64, length: usize) -> Option<Vec<u8>> {
+        Debugger::read_memory(self, address, length)
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> bool {
+        Debugger::write_memory(self, address, data)
+    }
+
+    fn resume(&mut self, step: bool) -> StopReason {
+        let exit = if step { self.step() } else { self.cont() };
+
+        match exit {
+            Ok(VcpuExit::Debug(exit)) => {
+                match exit.kind {
+                    DebugExitKind::SingleStep => StopReason::Step,
+                    DebugExitKind::SoftwareBreakpoint => StopReason::Breakpoint,
+                    DebugExitKind::HwBreakpoint(_) => StopReason::HwBreakpoint,
+                    DebugExitKind::Watchpoint(slot) => {
+                        let access = matches!(self.hw_breakpoints[slot], Some(breakpoint) if breakpoint.kind == HwBreakpointKind::ReadWrite);
+                        StopReason::Watchpoint { address: exit.address, access: access }
+                    }
+                }
+            }
+            Ok(VcpuExit::Halt) => StopReason::Halted,
+            Ok(exit) => {
+                eprintln!("vCPU {} stopped: {:?}", self.calc_vm.vcpu().id, exit);
+                StopReason::Crashed
+            }
+            Err(error_message) => {
+                eprintln!("{}", error_message);
+                StopReason::Crashed
+            }
+        }
+    }
+
+    fn insert_breakpoint(&mut self, kind: BreakpointType, address: u64, length: u64) -> bool {
+        let (kind, length) = match kind {
+            BreakpointType::Software => return self.insert_software_breakpoint(address),
+            BreakpointType::Hardware => (HwBreakpointKind::Execute, 1),
+            BreakpointType::WriteWatchpoint => (HwBreakpointKind::Write, length),
+            BreakpointType::AccessWatchpoint => (HwBreakpointKind::ReadWrite, length)
+        };
+
+        // Data breakpoints cover 1, 2, 4 or 8 naturally aligned bytes
+        if !matches!(length, 1 | 2 | 4 | 8) || address & (length - 1) != 0 {
+            return false;
+        }
+
+        self.insert_hw_breakpoint(HwBreakpoint { address: address, kind: kind, length: length as u8 }).is_some()
+    }
+
+    fn remove_breakpoint(&mut self, kind: BreakpointType, address: u64, _length: u64) -> bool {
+        match kind {
+            BreakpointType::Software => self.remove_software_breakpoint(address),
+            BreakpointType::Hardware => self.remove_hw_breakpoint(address, HwBreakpointKind::Execute),
+            BreakpointType::WriteWatchpoint => self.remove_hw_breakpoint(address, HwBreakpointKind::Write),
+            BreakpointType::AccessWatchpoint => self.remove_hw_breakpoint(address, HwBreakpointKind::ReadWrite)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_file::VcpuSnapshot;
+    use crate::vcpu_regs::*;
+
+    #[test]
+    fn i386_register_mapping() {
+        let mut cpu_state = VcpuSnapshot::initial().cpu.vcpu_state();
+        cpu_state.set_gpr32(REG_RDX, 0x1234);
+        cpu_state.set_eip(0x10);
+
+        let mut registers = i386_registers(&cpu_state);
+        assert_eq!(registers[2], 0x1234);
+        assert_eq!(registers[4], 0x1000);
+        assert_eq!(registers[I386_EIP], 0x2010);
+        assert_eq!(registers[I386_CS], 8);
+
+        registers[I386_EIP] = 0x2020;
+        registers[0] = 42;
+        registers[I386_CS] = 0x18;
+        set_i386_registers(&mut cpu_state, &registers);
+        assert_eq!(cpu_state.eip(), 0x20);
+        assert_eq!(cpu_state.gpr32(REG_RAX), 42);
+        assert_eq!(cpu_state.cs.selector, 8);
+    }
+}
@@ -0,0 +1,983 @@
+use std::cell::Cell;
+use std::fmt;
+
+use crate::haxm_interface_windows::segment_desc_t;
+
+// Decodes the protected mode integer and scalar SSE subset HyperCalc guests use into Intel syntax.
+// Opcode reference: Intel SDM Vol. 2, Appendix A.
+
+/// The longest an x86 instruction may be.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// The default operand and address size of the code being decoded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CodeSize {
+    Bits16,
+    Bits32
+}
+
+impl CodeSize {
+
+    /// The default size of code running from `cs`, picked by its descriptor's D bit.
+    pub fn of_segment(cs: &segment_desc_t) -> Self {
+        let ar = unsafe { cs.anon_union.ar };
+        if ar & (1 << 14) != 0 {
+            CodeSize::Bits32
+        }
+        else {
+            CodeSize::Bits16
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Instruction {
+    /// Where the instruction was decoded from. Branch targets are shown relative to the same address space.
+    pub address: u64,
+    pub bytes: Vec<u8>,
+    /// Intel syntax, e.g. `add eax, ecx`. `(bad)` if the bytes didn't decode.
+    pub text: String,
+    /// The memory the instruction reads or writes through its ModRM or moffs operand. None for `lea` and implicit
+    /// accesses like push or the string instructions.
+    pub memory: Option<MemoryOperand>
+}
+
+/// A ModRM memory operand, enough to work out the address it touches from the registers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryOperand {
+    /// The segment register in encoding order (es, cs, ss, ds, fs, gs), the default one when there's no override.
+    pub segment: u8,
+    /// General purpose registers in encoding order (eax, ecx, edx, ...).
+    pub base: Option<u8>,
+    pub index: Option<u8>,
+    pub scale: u8,
+    pub displacement: i64,
+    /// 2 or 4 bytes, offsets wrap around at this size.
+    pub address_size: u8,
+    /// How many bytes are accessed.
+    pub size: usize
+}
+
+impl MemoryOperand {
+
+    /// The offset into the segment, given the 32 bit general purpose registers in encoding order.
+    pub fn offset(&self, registers: &[u32; 8]) -> u64 {
+        let base = self.base.map(|register| registers[register as usize] as u64).unwrap_or(0);
+        let index = self.index.map(|register| registers[register as usize] as u64 * self.scale as u64).unwrap_or(0);
+        let mask = if self.address_size == 2 { 0xFFFF } else { 0xFFFF_FFFF };
+        base.wrapping_add(index).wrapping_add(self.displacement as u64) & mask
+    }
+}
+
+impl Instruction {
+
+    pub fn length(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "{:08x}: {:<24} {}", self.address, bytes.join(" "), self.text)
+    }
+}
+
+const BAD: &str = "(bad)";
+
+const REGS_8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const REGS_16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const REGS_32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+const SEGMENT_REGS: [&str; 8] = ["es", "cs", "ss", "ds", "fs", "gs", "?", "?"];
+const ADDRESS_16: [&str; 8] = ["bx+si", "bx+di", "bp+si", "bp+di", "si", "di", "bp", "bx"];
+/// The registers of ADDRESS_16 as (base, index).
+const ADDRESS_16_REGISTERS: [(u8, Option<u8>); 8] = [(3, Some(6)), (3, Some(7)), (5, Some(6)), (5, Some(7)), (6, None), (7, None), (5, None), (3, None)];
+
+const SEGMENT_SS: u8 = 2;
+const SEGMENT_DS: u8 = 3;
+
+const ALU_OPS: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT_OPS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const GROUP_3_OPS: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+const CONDITIONS: [&str; 16] = ["o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g"];
+
+/// Operand widths in bytes, plus 16 for an XMM register or memory operand.
+const XMM: u8 = 16;
+
+/// The size of the FXSAVE/FXRSTOR image.
+const FXSAVE_AREA_SIZE: usize = 512;
+
+fn size_name(size: u8) -> &'static str {
+    match size {
+        1 => "byte",
+        2 => "word",
+        4 => "dword",
+        8 => "qword",
+        _ => "xmmword"
+    }
+}
+
+fn register_name(register: u8, size: u8) -> String {
+    match size {
+        1 => String::from(REGS_8[register as usize]),
+        2 => String::from(REGS_16[register as usize]),
+        4 => String::from(REGS_32[register as usize]),
+        _ => format!("xmm{}", register)
+    }
+}
+
+fn hex(value: u64) -> String {
+    format!("{:#x}", value)
+}
+
+fn signed_hex(value: i64) -> String {
+    if value < 0 {
+        format!("-{:#x}", value.unsigned_abs())
+    }
+    else {
+        format!("+{:#x}", value)
+    }
+}
+
+struct ModRM {
+    mode: u8,
+    reg: u8,
+    rm: u8,
+    /// The formatted memory operand without its size, e.g. `[bx+si+0x10]`, when mode != 3.
+    memory: String
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    address: u64,
+    operand_size: u8,
+    address_size: u8,
+    segment: Option<&'static str>,
+    /// An F2 or F3 prefix, which is a REP prefix or selects an SSE form depending on the opcode.
+    repeat: Option<u8>,
+    operand_size_prefix: bool,
+    lock: bool,
+    /// The memory operand of the last modrm(), its size is filled in when it's formatted.
+    memory_operand: Option<MemoryOperand>,
+    memory_size: Cell<usize>
+}
+
+impl<'a> Decoder<'a> {
+
+    fn byte(&mut self) -> Option<u8> {
+        if self.position >= MAX_INSTRUCTION_LENGTH {
+            return None;
+        }
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn immediate(&mut self, size: u8) -> Option<u64> {
+        let mut value = 0u64;
+        for i in 0..size {
+            value |= (self.byte()? as u64) << (i * 8);
+        }
+        Some(value)
+    }
+
+    /// An immediate of `size` bytes sign extended to the operand size, as used by e.g. `83 /0 ib`.
+    fn signed_immediate(&mut self, size: u8) -> Option<i64> {
+        let value = self.immediate(size)?;
+        let shift = 64 - size as u32 * 8;
+        Some(((value << shift) as i64) >> shift)
+    }
+
+    fn modrm(&mut self) -> Option<ModRM> {
+        let byte = self.byte()?;
+        let mode = byte >> 6;
+        let reg = (byte >> 3) & 7;
+        let rm = byte & 7;
+
+        let memory = if mode == 3 {
+            String::new()
+        }
+        else if self.address_size == 2 {
+            let (base, displacement) = match (mode, rm) {
+                (0, 6) => (None, Some(self.immediate(2)? as i64)),
+                (0, _) => (Some(ADDRESS_16[rm as usize]), None),
+                (1, _) => (Some(ADDRESS_16[rm as usize]), Some(self.signed_immediate(1)?)),
+                _ => (Some(ADDRESS_16[rm as usize]), Some(self.signed_immediate(2)?))
+            };
+            let (base_register, index_register) = match base {
+                Some(_) => (Some(ADDRESS_16_REGISTERS[rm as usize].0), ADDRESS_16_REGISTERS[rm as usize].1),
+                None => (None, None)
+            };
+            self.record_memory(base_register, index_register, 1, displacement.unwrap_or(0));
+            self.format_memory(base.map(String::from), displacement)
+        }
+        else {
+            let mut base = Some(String::from(REGS_32[rm as usize]));
+            let mut registers = (Some(rm), None, 1);
+            if rm == 4 {
+                let sib = self.byte()?;
+                let scale = 1 << (sib >> 6);
+                let index = (sib >> 3) & 7;
+                let sib_base = sib & 7;
+
+                let base_name = if sib_base == 5 && mode == 0 { None } else { Some(REGS_32[sib_base as usize]) };
+                // Index 4 (esp) means no index
+                base = match (base_name, index) {
+                    (Some(base_name), 4) => Some(String::from(base_name)),
+                    (Some(base_name), _) if scale == 1 => Some(format!("{}+{}", base_name, REGS_32[index as usize])),
+                    (Some(base_name), _) => Some(format!("{}+{}*{}", base_name, REGS_32[index as usize], scale)),
+                    (None, 4) => None,
+                    (None, _) if scale == 1 => Some(String::from(REGS_32[index as usize])),
+                    (None, _) => Some(format!("{}*{}", REGS_32[index as usize], scale))
+                };
+                registers = (if base_name.is_some() { Some(sib_base) } else { None }, if index == 4 { None } else { Some(index) }, scale);
+                if sib_base == 5 && mode == 0 {
+                    let displacement = self.signed_immediate(4)?;
+                    self.record_memory(registers.0, registers.1, scale, displacement);
+                    return Some(ModRM { mode: mode, reg: reg, rm: rm, memory: self.format_memory(base, Some(displacement)) });
+                }
+            }
+
+            let displacement = match (mode, rm) {
+                (0, 5) => {
+                    base = None;
+                    registers.0 = None;
+                    Some(self.immediate(4)? as i64)
+                }
+                (0, _) => None,
+                (1, _) => Some(self.signed_immediate(1)?),
+                _ => Some(self.signed_immediate(4)?)
+            };
+            self.record_memory(registers.0, registers.1, registers.2, displacement.unwrap_or(0));
+            self.format_memory(base, displacement)
+        };
+
+        Some(ModRM { mode: mode, reg: reg, rm: rm, memory: memory })
+    }
+
+    fn record_memory(&mut self, base: Option<u8>, index: Option<u8>, scale: u8, displacement: i64) {
+        // bp, ebp and esp based addresses default to the stack segment
+        let stack_based = matches!((self.address_size, base), (2, Some(5)) | (4, Some(4)) | (4, Some(5)));
+        let segment = match self.segment {
+            Some(name) => SEGMENT_REGS.iter().position(|segment| *segment == name).unwrap_or(SEGMENT_DS as usize) as u8,
+            None if stack_based => SEGMENT_SS,
+            None => SEGMENT_DS
+        };
+
+        self.memory_operand = Some(MemoryOperand {
+            segment: segment,
+            base: base,
+            index: index,
+            scale: scale,
+            displacement: displacement,
+            address_size: self.address_size,
+            size: 0
+        });
+    }
+
+    fn format_memory(&self, base: Option<String>, displacement: Option<i64>) -> String {
+        let segment = match self.segment {
+            Some(segment) => format!("{}:", segment),
+            None => String::new()
+        };
+        match (base, displacement) {
+            (Some(base), Some(0)) | (Some(base), None) => format!("{}[{}]", segment, base),
+            (Some(base), Some(displacement)) => format!("{}[{}{}]", segment, base, signed_hex(displacement)),
+            (None, displacement) => format!("{}[{}]", segment, hex(displacement.unwrap_or(0) as u64 & self.address_mask()))
+        }
+    }
+
+    fn address_mask(&self) -> u64 {
+        if self.address_size == 2 { 0xFFFF } else { 0xFFFF_FFFF }
+    }
+
+    /// The r/m operand: a register of `size`, or memory of `size`.
+    fn rm_operand(&self, modrm: &ModRM, size: u8) -> String {
+        if modrm.mode == 3 {
+            register_name(modrm.rm, size)
+        }
+        else {
+            self.memory_size.set(size as usize);
+            format!("{} ptr {}", size_name(size), modrm.memory)
+        }
+    }
+
+    fn relative_target(&mut self, size: u8) -> Option<String> {
+        let displacement = self.signed_immediate(size)?;
+        let next = self.address.wrapping_add(self.position as u64);
+        Some(hex(next.wrapping_add(displacement as u64)))
+    }
+
+    fn sized_mnemonic(&self, word: &str, dword: &str) -> String {
+        String::from(if self.operand_size == 2 { word } else { dword })
+    }
+
+    /// `word ` or `dword ` when a 0x66 prefix changed how much an immediate push stores, so it doesn't read as the
+    /// default for the mode.
+    fn push_size(&self) -> String {
+        if self.operand_size_prefix { format!("{} ", size_name(self.operand_size)) } else { String::new() }
+    }
+
+    fn decode_two_byte(&mut self) -> Option<String> {
+        let opcode = self.byte()?;
+        let size = self.operand_size;
+
+        let text = match opcode {
+            0x0B => String::from("ud2"),
+            0x30 => String::from("wrmsr"),
+            0x31 => String::from("rdtsc"),
+            0x32 => String::from("rdmsr"),
+            0xA2 => String::from("cpuid"),
+            0xA0 => String::from("push fs"),
+            0xA1 => String::from("pop fs"),
+            0xA8 => String::from("push gs"),
+            0xA9 => String::from("pop gs"),
+            0x20 | 0x22 => {
+                // Always register to register, whatever the mod bits say
+                let byte = self.byte()?;
+                let control = (byte >> 3) & 7;
+                if !matches!(control, 0 | 2 | 3 | 4) {
+                    return None;
+                }
+                let register = register_name(byte & 7, 4);
+                if opcode == 0x20 {
+                    format!("mov {}, cr{}", register, control)
+                }
+                else {
+                    format!("mov cr{}, {}", control, register)
+                }
+            }
+            0x10 | 0x11 | 0x28 | 0x29 | 0x2E | 0x2F | 0x51 | 0x58 | 0x59 | 0x5C | 0x5E => {
+                // F3 = scalar single, F2 = scalar double, 66 = packed double, none = packed single
+                let suffix = match (self.repeat, self.operand_size_prefix) {
+                    (Some(0xF3), _) => "ss",
+                    (Some(0xF2), _) => "sd",
+                    (None, true) => "pd",
+                    (None, false) => "ps",
+                    _ => return None
+                };
+                let (name, operand_size) = match (opcode, suffix) {
+                    (0x10, _) | (0x11, _) => (format!("mov{}", if suffix.starts_with('s') { suffix } else if suffix == "pd" { "upd" } else { "ups" }), XMM),
+                    (0x28, "ps") | (0x28, "pd") | (0x29, "ps") | (0x29, "pd") => (format!("mova{}", suffix), XMM),
+                    (0x28, _) | (0x29, _) => return None,
+                    (0x2E, "ps") | (0x2E, "pd") => (format!("ucomis{}", &suffix[1..]), XMM),
+                    (0x2F, "ps") | (0x2F, "pd") => (format!("comis{}", &suffix[1..]), XMM),
+                    (0x2E, _) | (0x2F, _) => return None,
+                    (0x51, _) => (format!("sqrt{}", suffix), XMM),
+                    (0x58, _) => (format!("add{}", suffix), XMM),
+                    (0x59, _) => (format!("mul{}", suffix), XMM),
+                    (0x5C, _) => (format!("sub{}", suffix), XMM),
+                    _ => (format!("div{}", suffix), XMM)
+                };
+                self.repeat = None;
+
+                // Scalar memory operands are only as wide as the scalar
+                let memory_size = match suffix {
+                    "ss" => 4,
+                    "sd" => 8,
+                    _ if opcode == 0x2E || opcode == 0x2F => if suffix == "ps" { 4 } else { 8 },
+                    _ => operand_size
+                };
+                let modrm = self.modrm()?;
+                let register = register_name(modrm.reg, XMM);
+                let memory = if modrm.mode == 3 { register_name(modrm.rm, XMM) } else { self.rm_operand(&modrm, memory_size) };
+                if opcode == 0x11 || opcode == 0x29 {
+                    format!("{} {}, {}", name, memory, register)
+                }
+                else {
+                    format!("{} {}, {}", name, register, memory)
+                }
+            }
+            0xAE => {
+                let modrm = self.modrm()?;
+                if modrm.mode == 3 {
+                    return None;
+                }
+                if modrm.reg < 2 {
+                    self.memory_size.set(FXSAVE_AREA_SIZE);
+                }
+                match modrm.reg {
+                    0 => format!("fxsave {}", modrm.memory),
+                    1 => format!("fxrstor {}", modrm.memory),
+                    2 => format!("ldmxcsr {}", self.rm_operand(&modrm, 4)),
+                    3 => format!("stmxcsr {}", self.rm_operand(&modrm, 4)),
+                    _ => return None
+                }
+            }
+            0x40..=0x4F => {
+                let modrm = self.modrm()?;
+                format!("cmov{} {}, {}", CONDITIONS[(opcode & 0xF) as usize], register_name(modrm.reg, size), self.rm_operand(&modrm, size))
+            }
+            0x80..=0x8F => {
+                let target = self.relative_target(size)?;
+                format!("j{} {}", CONDITIONS[(opcode & 0xF) as usize], target)
+            }
+            0x90..=0x9F => {
+                let modrm = self.modrm()?;
+                format!("set{} {}", CONDITIONS[(opcode & 0xF) as usize], self.rm_operand(&modrm, 1))
+            }
+            0xA3 | 0xAB | 0xB3 | 0xBB => {
+                let name = match opcode { 0xA3 => "bt", 0xAB => "bts", 0xB3 => "btr", _ => "btc" };
+                let modrm = self.modrm()?;
+                format!("{} {}, {}", name, self.rm_operand(&modrm, size), register_name(modrm.reg, size))
+            }
+            0xBA => {
+                let modrm = self.modrm()?;
+                let name = match modrm.reg { 4 => "bt", 5 => "bts", 6 => "btr", 7 => "btc", _ => return None };
+                let operand = self.rm_operand(&modrm, size);
+                format!("{} {}, {}", name, operand, hex(self.immediate(1)?))
+            }
+            0xA4 | 0xA5 | 0xAC | 0xAD => {
+                let name = if opcode < 0xA8 { "shld" } else { "shrd" };
+                let modrm = self.modrm()?;
+                let count = if opcode & 1 == 0 { hex(self.immediate(1)?) } else { String::from("cl") };
+                format!("{} {}, {}, {}", name, self.rm_operand(&modrm, size), register_name(modrm.reg, size), count)
+            }
+            0xAF => {
+                let modrm = self.modrm()?;
+                format!("imul {}, {}", register_name(modrm.reg, size), self.rm_operand(&modrm, size))
+            }
+            0xB6 | 0xB7 | 0xBE | 0xBF => {
+                let name = if opcode < 0xB8 { "movzx" } else { "movsx" };
+                let source_size = if opcode & 1 == 0 { 1 } else { 2 };
+                let modrm = self.modrm()?;
+                format!("{} {}, {}", name, register_name(modrm.reg, size), self.rm_operand(&modrm, source_size))
+            }
+            0xBC | 0xBD => {
+                let name = if opcode == 0xBC { "bsf" } else { "bsr" };
+                let modrm = self.modrm()?;
+                format!("{} {}, {}", name, register_name(modrm.reg, size), self.rm_operand(&modrm, size))
+            }
+            0xC8..=0xCF => format!("bswap {}", register_name(opcode & 7, 4)),
+            _ => return None
+        };
+        Some(text)
+    }
+
+    /// The string instructions, which take a size suffix instead of operands.
+    fn string_instruction(&mut self, name: &str, opcode: u8) -> String {
+        let suffix = if opcode & 1 == 0 {
+            "b"
+        }
+        else if self.operand_size == 2 {
+            "w"
+        }
+        else {
+            "d"
+        };
+
+        // REPE/REPNE only mean something to the comparing forms
+        let compares = name == "cmps" || name == "scas";
+        let prefix = match (self.repeat, compares) {
+            (Some(0xF3), false) => "rep ",
+            (Some(0xF3), true) => "repe ",
+            (Some(0xF2), true) => "repne ",
+            _ => ""
+        };
+        self.repeat = None;
+        format!("{}{}{}", prefix, name, suffix)
+    }
+
+    fn decode(&mut self) -> Option<String> {
+        // Prefixes
+        let opcode = loop {
+            let byte = self.byte()?;
+            match byte {
+                0x66 => {
+                    self.operand_size = 6 - self.operand_size;
+                    self.operand_size_prefix = true;
+                }
+                0x67 => self.address_size = 6 - self.address_size,
+                0x26 => self.segment = Some("es"),
+                0x2E => self.segment = Some("cs"),
+                0x36 => self.segment = Some("ss"),
+                0x3E => self.segment = Some("ds"),
+                0x64 => self.segment = Some("fs"),
+                0x65 => self.segment = Some("gs"),
+                0xF0 => self.lock = true,
+                0xF2 | 0xF3 => self.repeat = Some(byte),
+                _ => break byte
+            }
+        };
+
+        let size = self.operand_size;
+        let accumulator = register_name(0, size);
+
+        let text = match opcode {
+            0x0F => self.decode_two_byte()?,
+            // The classic ALU block: op r/m8, r8 / op r/m, r / op r8, r/m8 / op r, r/m / op al, imm8 / op eax, imm
+            0x00..=0x3F if opcode & 7 < 6 => {
+                let name = ALU_OPS[(opcode >> 3) as usize];
+                match opcode & 7 {
+                    0..=3 => {
+                        let operand_size = if opcode & 1 == 0 { 1 } else { size };
+                        let modrm = self.modrm()?;
+                        let register = register_name(modrm.reg, operand_size);
+                        let rm = self.rm_operand(&modrm, operand_size);
+                        if opcode & 2 == 0 {
+                            format!("{} {}, {}", name, rm, register)
+                        }
+                        else {
+                            format!("{} {}, {}", name, register, rm)
+                        }
+                    }
+                    4 => format!("{} al, {}", name, hex(self.immediate(1)?)),
+                    _ => format!("{} {}, {}", name, accumulator, hex(self.immediate(size)?))
+                }
+            }
+            0x06 | 0x0E | 0x16 | 0x1E => format!("push {}", SEGMENT_REGS[(opcode >> 3) as usize]),
+            0x07 | 0x17 | 0x1F => format!("pop {}", SEGMENT_REGS[(opcode >> 3) as usize]),
+            0x27 => String::from("daa"),
+            0x2F => String::from("das"),
+            0x37 => String::from("aaa"),
+            0x3F => String::from("aas"),
+            0x40..=0x47 => format!("inc {}", register_name(opcode & 7, size)),
+            0x48..=0x4F => format!("dec {}", register_name(opcode & 7, size)),
+            0x50..=0x57 => format!("push {}", register_name(opcode & 7, size)),
+            0x58..=0x5F => format!("pop {}", register_name(opcode & 7, size)),
+            0x60 => self.sized_mnemonic("pusha", "pushad"),
+            0x61 => self.sized_mnemonic("popa", "popad"),
+            0x68 => format!("push {}{}", self.push_size(), hex(self.immediate(size)?)),
+            0x6A => format!("push {}{}", self.push_size(),
+                hex(self.signed_immediate(1)? as u64 & if size == 2 { 0xFFFF } else { 0xFFFF_FFFF })),
+            0x69 | 0x6B => {
+                let modrm = self.modrm()?;
+                let immediate = if opcode == 0x69 { self.immediate(size)? as i64 } else { self.signed_immediate(1)? };
+                format!("imul {}, {}, {}", register_name(modrm.reg, size), self.rm_operand(&modrm, size), signed_hex(immediate).trim_start_matches('+'))
+            }
+            0x70..=0x7F => {
+                let target = self.relative_target(1)?;
+                format!("j{} {}", CONDITIONS[(opcode & 0xF) as usize], target)
+            }
+            0x80 | 0x81 | 0x83 => {
+                let operand_size = if opcode == 0x80 { 1 } else { size };
+                let modrm = self.modrm()?;
+                let immediate = if opcode == 0x83 {
+                    self.signed_immediate(1)? as u64 & if size == 2 { 0xFFFF } else { 0xFFFF_FFFF }
+                }
+                else {
+                    self.immediate(operand_size)?
+                };
+                format!("{} {}, {}", ALU_OPS[modrm.reg as usize], self.rm_operand(&modrm, operand_size), hex(immediate))
+            }
+            0x84..=0x87 => {
+                let name = if opcode < 0x86 { "test" } else { "xchg" };
+                let operand_size = if opcode & 1 == 0 { 1 } else { size };
+                let modrm = self.modrm()?;
+                format!("{} {}, {}", name, self.rm_operand(&modrm, operand_size), register_name(modrm.reg, operand_size))
+            }
+            0x88..=0x8B => {
+                let operand_size = if opcode & 1 == 0 { 1 } else { size };
+                let modrm = self.modrm()?;
+                let register = register_name(modrm.reg, operand_size);
+                let rm = self.rm_operand(&modrm, operand_size);
+                if opcode & 2 == 0 {
+                    format!("mov {}, {}", rm, register)
+                }
+                else {
+                    format!("mov {}, {}", register, rm)
+                }
+            }
+            0x8C => {
+                let modrm = self.modrm()?;
+                let operand_size = if modrm.mode == 3 { size } else { 2 };
+                format!("mov {}, {}", self.rm_operand(&modrm, operand_size), SEGMENT_REGS[modrm.reg as usize])
+            }
+            0x8D => {
+                let modrm = self.modrm()?;
+                if modrm.mode == 3 {
+                    return None;
+                }
+                // Only the address is computed, nothing is accessed
+                self.memory_operand = None;
+                format!("lea {}, {}", register_name(modrm.reg, size), modrm.memory)
+            }
+            0x8E => {
+                let modrm = self.modrm()?;
+                format!("mov {}, {}", SEGMENT_REGS[modrm.reg as usize], self.rm_operand(&modrm, 2))
+            }
+            0x8F => {
+                let modrm = self.modrm()?;
+                format!("pop {}", self.rm_operand(&modrm, size))
+            }
+            0x90 if self.repeat == Some(0xF3) => {
+                self.repeat = None;
+                String::from("pause")
+            }
+            0x90 => String::from("nop"),
+            0x91..=0x97 => format!("xchg {}, {}", accumulator, register_name(opcode & 7, size)),
+            0x98 => self.sized_mnemonic("cbw", "cwde"),
+            0x99 => self.sized_mnemonic("cwd", "cdq"),
+            0x9C => self.sized_mnemonic("pushf", "pushfd"),
+            0x9D => self.sized_mnemonic("popf", "popfd"),
+            0x9E => String::from("sahf"),
+            0x9F => String::from("lahf"),
+            0xA0..=0xA3 => {
+                let operand_size = if opcode & 1 == 0 { 1 } else { size };
+                let offset = self.immediate(self.address_size)?;
+                self.record_memory(None, None, 1, offset as i64);
+                self.memory_size.set(operand_size as usize);
+                let memory = format!("{} ptr {}", size_name(operand_size), self.format_memory(None, Some(offset as i64)));
+                let register = register_name(0, operand_size);
+                if opcode & 2 == 0 {
+                    format!("mov {}, {}", register, memory)
+                }
+                else {
+                    format!("mov {}, {}", memory, register)
+                }
+            }
+            0xA4 | 0xA5 => self.string_instruction("movs", opcode),
+            0xA6 | 0xA7 => self.string_instruction("cmps", opcode),
+            0xAA | 0xAB => self.string_instruction("stos", opcode),
+            0xAC | 0xAD => self.string_instruction("lods", opcode),
+            0xAE | 0xAF => self.string_instruction("scas", opcode),
+            0xA8 => format!("test al, {}", hex(self.immediate(1)?)),
+            0xA9 => format!("test {}, {}", accumulator, hex(self.immediate(size)?)),
+            0xB0..=0xB7 => format!("mov {}, {}", register_name(opcode & 7, 1), hex(self.immediate(1)?)),
+            0xB8..=0xBF => format!("mov {}, {}", register_name(opcode & 7, size), hex(self.immediate(size)?)),
+            0xC0 | 0xC1 | 0xD0..=0xD3 => {
+                let operand_size = if opcode & 1 == 0 { 1 } else { size };
+                let modrm = self.modrm()?;
+                let count = match opcode {
+                    0xC0 | 0xC1 => hex(self.immediate(1)?),
+                    0xD0 | 0xD1 => String::from("1"),
+                    _ => String::from("cl")
+                };
+                format!("{} {}, {}", SHIFT_OPS[modrm.reg as usize], self.rm_operand(&modrm, operand_size), count)
+            }
+            0xC2 => format!("ret {}", hex(self.immediate(2)?)),
+            0xC3 => String::from("ret"),
+            0xC6 | 0xC7 => {
+                let operand_size = if opcode == 0xC6 { 1 } else { size };
+                let modrm = self.modrm()?;
+                if modrm.reg != 0 {
+                    return None;
+                }
+                format!("mov {}, {}", self.rm_operand(&modrm, operand_size), hex(self.immediate(operand_size)?))
+            }
+            0xC9 => String::from("leave"),
+            0xCC => String::from("int3"),
+            0xCD => format!("int {}", hex(self.immediate(1)?)),
+            0xCE => String::from("into"),
+            0xCF => self.sized_mnemonic("iret", "iretd"),
+            0xD4 | 0xD5 => {
+                let name = if opcode == 0xD4 { "aam" } else { "aad" };
+                let base = self.immediate(1)?;
+                // Base 10 is the documented form and is written without an operand
+                if base == 10 { String::from(name) } else { format!("{} {}", name, hex(base)) }
+            }
+            0xE0..=0xE3 => {
+                let name = match opcode {
+                    0xE0 => "loopne",
+                    0xE1 => "loope",
+                    0xE2 => "loop",
+                    _ => if self.address_size == 2 { "jcxz" } else { "jecxz" }
+                };
+                let target = self.relative_target(1)?;
+                format!("{} {}", name, target)
+            }
+            0xE4 => format!("in al, {}", hex(self.immediate(1)?)),
+            0xE5 => format!("in {}, {}", accumulator, hex(self.immediate(1)?)),
+            0xE6 => format!("out {}, al", hex(self.immediate(1)?)),
+            0xE7 => format!("out {}, {}", hex(self.immediate(1)?), accumulator),
+            0xE8 => format!("call {}", self.relative_target(size)?),
+            0xE9 => format!("jmp {}", self.relative_target(size)?),
+            0xEA => {
+                let offset = self.immediate(size)?;
+                let selector = self.immediate(2)?;
+                format!("jmp {}:{}", hex(selector), hex(offset))
+            }
+            0xEB => format!("jmp {}", self.relative_target(1)?),
+            0xEC => String::from("in al, dx"),
+            0xED => format!("in {}, dx", accumulator),
+            0xEE => String::from("out dx, al"),
+            0xEF => format!("out dx, {}", accumulator),
+            0xF4 => String::from("hlt"),
+            0xF5 => String::from("cmc"),
+            0xF6 | 0xF7 => {
+                let operand_size = if opcode == 0xF6 { 1 } else { size };
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, operand_size);
+                if modrm.reg < 2 {
+                    format!("test {}, {}", rm, hex(self.immediate(operand_size)?))
+                }
+                else {
+                    format!("{} {}", GROUP_3_OPS[modrm.reg as usize], rm)
+                }
+            }
+            0xF8 => String::from("clc"),
+            0xF9 => String::from("stc"),
+            0xFA => String::from("cli"),
+            0xFB => String::from("sti"),
+            0xFC => String::from("cld"),
+            0xFD => String::from("std"),
+            0xFE => {
+                let modrm = self.modrm()?;
+                match modrm.reg {
+                    0 => format!("inc {}", self.rm_operand(&modrm, 1)),
+                    1 => format!("dec {}", self.rm_operand(&modrm, 1)),
+                    _ => return None
+                }
+            }
+            0xFF => {
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, size);
+                match modrm.reg {
+                    0 => format!("inc {}", rm),
+                    1 => format!("dec {}", rm),
+                    2 => format!("call {}", rm),
+                    4 => format!("jmp {}", rm),
+                    6 => format!("push {}", rm),
+                    _ => return None
+                }
+            }
+            _ => return None
+        };
+
+        // A REP prefix nothing consumed is shown as written
+        let text = match self.repeat {
+            Some(0xF3) => format!("rep {}", text),
+            Some(_) => format!("repne {}", text),
+            None => text
+        };
+        Some(if self.lock { format!("lock {}", text) } else { text })
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`. If it doesn't decode (or is cut off) the result is a one byte
+/// `(bad)`, so callers can always make progress.
+///
+/// # Arguments
+///
+/// * `bytes` - The code, at least MAX_INSTRUCTION_LENGTH bytes of it when available.
+/// * `address` - The address `bytes` starts at, used to show branch targets.
+/// * `code_size` - The default operand and address size, see CodeSize::of_segment().
+pub fn decode(bytes: &[u8], address: u64, code_size: CodeSize) -> Instruction {
+    let default_size = match code_size {
+        CodeSize::Bits16 => 2,
+        CodeSize::Bits32 => 4
+    };
+
+    let mut decoder = Decoder {
+        bytes: bytes,
+        position: 0,
+        address: address,
+        operand_size: default_size,
+        address_size: default_size,
+        segment: None,
+        repeat: None,
+        operand_size_prefix: false,
+        lock: false,
+        memory_operand: None,
+        memory_size: Cell::new(0)
+    };
+
+    match decoder.decode() {
+        Some(text) => {
+            let memory = decoder.memory_operand.map(|memory| MemoryOperand { size: decoder.memory_size.get(), ..memory });
+            Instruction {
+                address: address,
+                bytes: bytes[..decoder.position].to_vec(),
+                text: text,
+                memory: memory
+            }
+        }
+        None => Instruction {
+            address: address,
+            bytes: bytes.iter().take(1).copied().collect(),
+            text: String::from(BAD),
+            memory: None
+        }
+    }
+}
+
+/// Decodes up to `count` consecutive instructions, stopping early at the end of `bytes`.
+pub fn disassemble(bytes: &[u8], address: u64, code_size: CodeSize, count: usize) -> Vec<Instruction> {
+    let mut instructions = vec!();
+    let mut offset = 0;
+
+    while instructions.len() < count && offset < bytes.len() {
+        let instruction = decode(&bytes[offset..], address + offset as u64, code_size);
+        offset += instruction.length().max(1);
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{self, Mode};
+
+    const ORIGIN: u64 = 0x1000;
+
+    /// Assembles each line on its own and checks it decodes back to the same text and length.
+    fn round_trip(mode: Mode, code_size: CodeSize, lines: &[&str]) {
+        for line in lines.iter() {
+            let code = match assembler::assemble(line, mode, ORIGIN) {
+                Ok(code) => code,
+                Err(error_message) => panic!("{}: {}", line, error_message)
+            };
+            let instruction = decode(&code.bytes, ORIGIN, code_size);
+            assert_eq!(instruction.text, *line, "{:02x?}", code.bytes);
+            assert_eq!(instruction.length(), code.bytes.len(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn round_trip_32() {
+        round_trip(Mode::Bits32, CodeSize::Bits32, &[
+            "add eax, ecx",
+            "sub dword ptr [ebx+0x10], 0x5",
+            "and ecx, 0xfffffff0",
+            "cmp al, 0x7f",
+            "test eax, 0x100",
+            "xor byte ptr [ecx+edx*2-0x8], bl",
+            "mov eax, dword ptr [esp+0x8]",
+            "mov ecx, dword ptr fs:[0x10]",
+            "mov byte ptr [esi], 0x41",
+            "mov word ptr [eax], 0x1234",
+            "mov ax, ds",
+            "mov ds, ax",
+            "lea ecx, [eax+ebx*4+0x100]",
+            "xchg eax, edx",
+            "inc ecx",
+            "dec byte ptr [eax]",
+            "not edx",
+            "neg dword ptr [ebp-0x4]",
+            "idiv ecx",
+            "imul eax, ecx",
+            "imul eax, ecx, 0xa",
+            "shl edx, 0x3",
+            "shr eax, 1",
+            "sar eax, cl",
+            "movzx eax, byte ptr [esi]",
+            "movsx ecx, word ptr [eax]",
+            "cmovne eax, ebx",
+            "sete al",
+            "bt eax, ecx",
+            "bts dword ptr [eax], 0x3",
+            "bsf eax, ecx",
+            "bswap eax",
+            "shld eax, edx, 0x4",
+            "shrd eax, edx, cl",
+            "push 0x12345678",
+            "push 0x5",
+            "push word 0x5",
+            "push es",
+            "pop ebx",
+            "pop dword ptr [eax]",
+            "pushad",
+            "popfd",
+            "cwde",
+            "cdq",
+            "in al, dx",
+            "out 0x70, al",
+            "int 0x21",
+            "ret 0x8",
+            "ret",
+            "iretd",
+            "hlt",
+            "cpuid",
+            "rdmsr",
+            "pause",
+            "rep movsb",
+            "repe cmpsb",
+            "rep stosd",
+            "lock add dword ptr [eax], ecx",
+            "call dword ptr [eax]",
+            "jmp eax",
+            "jmp 0x1010",
+            "jne 0x2000",
+            "call 0x1100",
+            "loop 0x1000",
+            "jecxz 0x1005",
+            "addss xmm1, xmm2",
+            "sqrtss xmm3, dword ptr [esp+0x4]",
+            "movsd qword ptr [eax], xmm0",
+            "comisd xmm0, qword ptr [ebx]",
+            "movaps xmm0, xmm1",
+            "mulpd xmm2, xmmword ptr [ecx]",
+            "ldmxcsr dword ptr [eax]",
+            "fxsave [eax]"
+        ]);
+    }
+
+    #[test]
+    fn round_trip_16() {
+        round_trip(Mode::Bits16, CodeSize::Bits16, &[
+            "mov ax, 0x1234",
+            "mov eax, 0x1",
+            "xor ax, ax",
+            "add word ptr [bx+si+0x4], ax",
+            "mov bp, word ptr [bp]",
+            "mov word ptr [0x1c00], 0x55",
+            "mov eax, dword ptr [esi]",
+            "movzx ax, byte ptr [bx]",
+            "push 0x5",
+            "push dword 0x12345678",
+            "in ax, dx",
+            "out dx, al",
+            "int 0x10",
+            "rep movsw",
+            "lodsb",
+            "aam",
+            "aad 0x10",
+            "jmp 0x1010",
+            "je 0x200",
+            "call 0x300"
+        ]);
+    }
+
+    #[test]
+    fn control_registers() {
+        assert_eq!(decode(&[0x0F, 0x20, 0xC0], 0, CodeSize::Bits32).text, "mov eax, cr0");
+        assert_eq!(decode(&[0x0F, 0x22, 0xDA], 0, CodeSize::Bits16).text, "mov cr3, edx");
+        // The mod bits are ignored, there is no memory form
+        let instruction = decode(&[0x0F, 0x20, 0x20], 0, CodeSize::Bits32);
+        assert_eq!((instruction.text.as_str(), instruction.length(), instruction.memory), ("mov eax, cr4", 3, None));
+        assert_eq!(decode(&[0x0F, 0x20, 0xC8], 0, CodeSize::Bits32).text, BAD);
+    }
+
+    #[test]
+    fn far_jump() {
+        let instruction = decode(&[0xEA, 0x00, 0x20, 0x08, 0x00], 0, CodeSize::Bits16);
+        assert_eq!((instruction.text.as_str(), instruction.length()), ("jmp 0x8:0x2000", 5));
+        let instruction = decode(&[0xEA, 0x78, 0x56, 0x34, 0x12, 0x10, 0x00], 0, CodeSize::Bits32);
+        assert_eq!((instruction.text.as_str(), instruction.length()), ("jmp 0x10:0x12345678", 7));
+        let instruction = decode(&[0x66, 0xEA, 0x00, 0x20, 0x08, 0x00], 0, CodeSize::Bits32);
+        assert_eq!((instruction.text.as_str(), instruction.length()), ("jmp 0x8:0x2000", 6));
+    }
+
+    #[test]
+    fn moffs_memory_operands() {
+        let instruction = decode(&[0xA1, 0x00, 0x1C], 0, CodeSize::Bits16);
+        assert_eq!(instruction.text, "mov ax, word ptr [0x1c00]");
+        assert_eq!(instruction.memory, Some(MemoryOperand {
+            segment: SEGMENT_DS,
+            base: None,
+            index: None,
+            scale: 1,
+            displacement: 0x1C00,
+            address_size: 2,
+            size: 2
+        }));
+
+        let instruction = decode(&[0x64, 0xA2, 0x10, 0x00, 0x00, 0x00], 0, CodeSize::Bits32);
+        assert_eq!(instruction.text, "mov byte ptr fs:[0x10], al");
+        let memory = instruction.memory.unwrap();
+        assert_eq!((memory.segment, memory.size, memory.offset(&[0; 8])), (4, 1, 0x10));
+    }
+
+    #[test]
+    fn modrm_memory_operands() {
+        // [ebp-4] defaults to SS
+        let memory = decode(&[0x8B, 0x45, 0xFC], 0, CodeSize::Bits32).memory.unwrap();
+        assert_eq!((memory.segment, memory.base, memory.size), (SEGMENT_SS, Some(5), 4));
+        assert_eq!(memory.offset(&[0, 0, 0, 0, 0, 0x100, 0, 0]), 0xFC);
+
+        assert_eq!(decode(&[0x8D, 0x04, 0x98], 0, CodeSize::Bits32).memory, None);
+        assert_eq!(decode(&[0x8B], 0, CodeSize::Bits32).text, BAD);
+    }
+}
@@ -0,0 +1,676 @@
+use crate::assembler::{Assembler, Mode};
+use crate::calculator::{CalcVm, RunError};
+use crate::guest_fault::STUBS_OFFSET;
+use crate::number_theory::{self, Library, Routine, LIBRARY_OFFSET};
+use crate::radix;
+use crate::variables::{Variables, ANS, VARIABLES_BASE};
+use crate::vcpu_regs::*;
+
+// Infix expressions such as `(3 + 4) * 12 - 7 / 2` or `0xff & ~'a'`, compiled to guest code that evaluates the whole expression in
+// one VM entry. The code keeps the value being computed in EAX and parks left operands on the guest stack (SS:ESP,
+// below 0x1000 in the data segment) while the right operand is evaluated. The result comes back in EAX.
+//
+// Values are 32 bit two's complement: `/`, `%` and `>>` are signed, everything else wraps. Dividing by zero (or
+// i32::MIN by -1) raises #DE in the guest, which evaluate() reports as the guest fault.
+//
+// Functions such as `gcd(84, 36)` CALL the number theory routines, installed next to the code, with the arguments
+// taken as unsigned. A routine that has no result, e.g. `fact(13)` overflowing, makes the code halt at a HLT of its
+// own, which evaluate() tells from the end of the expression by EIP.
+//
+// Variables are memory operands: `x * 3` reads x with `mov ecx, dword [x's address]`, and `x = ...` ends the code
+// with a store to the same dword, as does every statement to `ans`.
+
+/// The deepest the guest stack may get: the stack grows down from ESP 0x1000 to the bottom of the data segment.
+const MAX_STACK_DEPTH: usize = 0x1000 / 4;
+/// How deeply an expression may nest. Parsing it and generating its code recurse once per level, and that has to fit
+/// in a 1 MiB host stack even in a debug build, so this is well below what the guest stack would allow.
+const MAX_NESTING: usize = MAX_STACK_DEPTH / 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnaryOp {
+    Neg,
+    Not
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr
+}
+
+impl BinaryOp {
+
+    /// The operator written as in an expression, e.g. `+` or `<<`.
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "+" => Some(BinaryOp::Add),
+            "-" => Some(BinaryOp::Sub),
+            "*" => Some(BinaryOp::Mul),
+            "/" => Some(BinaryOp::Div),
+            "%" => Some(BinaryOp::Mod),
+            "&" => Some(BinaryOp::And),
+            "|" => Some(BinaryOp::Or),
+            "^" => Some(BinaryOp::Xor),
+            "<<" => Some(BinaryOp::Shl),
+            ">>" => Some(BinaryOp::Shr),
+            _ => None
+        }
+    }
+
+    /// Binding strength, higher binds tighter. All binary operators are left associative.
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::Xor => 2,
+            BinaryOp::And => 3,
+            BinaryOp::Shl | BinaryOp::Shr => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 6
+        }
+    }
+
+    /// The instructions computing `EAX op ECX` into EAX.
+    pub fn instructions(&self) -> &'static [&'static str] {
+        match self {
+            BinaryOp::Add => &["add eax, ecx"],
+            BinaryOp::Sub => &["sub eax, ecx"],
+            BinaryOp::Mul => &["imul eax, ecx"],
+            BinaryOp::Div => &["cdq", "idiv ecx"],
+            BinaryOp::Mod => &["cdq", "idiv ecx", "mov eax, edx"],
+            BinaryOp::And => &["and eax, ecx"],
+            BinaryOp::Or => &["or eax, ecx"],
+            BinaryOp::Xor => &["xor eax, ecx"],
+            BinaryOp::Shl => &["shl eax, cl"],
+            BinaryOp::Shr => &["sar eax, cl"]
+        }
+    }
+}
+
+/// A parsed expression.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Expr {
+    Number(u32),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// A number theory routine applied to Routine::arity() arguments.
+    Call(Routine, Vec<Expr>),
+    /// A variable, read from its dword in guest memory.
+    Variable(String)
+}
+
+impl Expr {
+
+    /// How many values the guest code for this expression keeps on the stack at most.
+    fn stack_depth(&self) -> usize {
+        match self {
+            Expr::Number(_) | Expr::Variable(_) => 0,
+            Expr::Unary(_, operand) => operand.stack_depth(),
+            Expr::Binary(_, left, right) => {
+                if right.is_operand() {
+                    left.stack_depth()
+                }
+                else {
+                    left.stack_depth().max(1 + right.stack_depth())
+                }
+            }
+            // Every argument but the last waits on the stack, then the routine needs its share
+            Expr::Call(_, args) => args.iter().enumerate()
+                .map(|(index, arg)| index + arg.stack_depth())
+                .fold(number_theory::STACK_USE.div_ceil(4), usize::max)
+        }
+    }
+
+    /// Whether the expression is a constant or a variable, which an instruction can take as its operand directly.
+    fn is_operand(&self) -> bool {
+        matches!(self, Expr::Number(_) | Expr::Variable(_))
+    }
+
+    /// The first variable the expression uses that isn't in `variables`.
+    fn unknown_variable(&self, variables: &Variables) -> Option<&str> {
+        match self {
+            Expr::Number(_) => None,
+            Expr::Variable(name) => if variables.address(name).is_none() { Some(name) } else { None },
+            Expr::Unary(_, operand) => operand.unknown_variable(variables),
+            Expr::Binary(_, left, right) => left.unknown_variable(variables).or_else(|| right.unknown_variable(variables)),
+            Expr::Call(_, args) => args.iter().find_map(|arg| arg.unknown_variable(variables))
+        }
+    }
+}
+
+/// A line of input: an expression, and the variable its value is assigned to if it is written `name = expression`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Statement {
+    pub target: Option<String>,
+    pub expr: Expr
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+    Number(u32),
+    Variable(String),
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    /// `-` can be either, the parser decides.
+    Minus,
+    Function(Routine),
+    Comma,
+    Assign,
+    Open,
+    Close
+}
+
+/// Splits `text` into tokens, each with the column (from 1) it starts at.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec!();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let column = index + 1;
+        let c = chars[index];
+        let next = chars.get(index + 1).copied();
+
+        if c.is_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        // Any literal radix::parse_u64() reads: digits, letters, `_` and `#` up to the next operator, or a quoted
+        // character
+        if c.is_ascii_digit() || c == '\'' {
+            let start = index;
+            if c == '\'' {
+                index += 1;
+                while index < chars.len() && chars[index] != '\'' {
+                    index += if chars[index] == '\\' { 2 } else { 1 };
+                }
+                index = (index + 1).min(chars.len());
+            }
+            else {
+                while index < chars.len() && (chars[index].is_ascii_alphanumeric() || chars[index] == '_' || chars[index] == '#') {
+                    index += 1;
+                }
+            }
+            let literal: String = chars[start..index].iter().collect();
+            match radix::parse_u64(&literal) {
+                Ok(value) if value <= u32::MAX as u64 => tokens.push((Token::Number(value as u32), column)),
+                Ok(_) => return Err(format!("{} at column {} doesn't fit in 32 bits", literal, column)),
+                Err(error_message) => return Err(format!("{} at column {}", error_message, column))
+            }
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = index;
+            while index < chars.len() && (chars[index].is_ascii_alphanumeric() || chars[index] == '_') {
+                index += 1;
+            }
+            let name: String = chars[start..index].iter().collect();
+            match Routine::from_name(&name) {
+                Some(routine) => tokens.push((Token::Function(routine), column)),
+                None => tokens.push((Token::Variable(name), column))
+            }
+            continue;
+        }
+
+        let (token, length) = match (c, next) {
+            ('<', Some('<')) => (Token::Binary(BinaryOp::Shl), 2),
+            ('>', Some('>')) => (Token::Binary(BinaryOp::Shr), 2),
+            ('+', _) => (Token::Binary(BinaryOp::Add), 1),
+            ('-', _) => (Token::Minus, 1),
+            ('*', _) => (Token::Binary(BinaryOp::Mul), 1),
+            ('/', _) => (Token::Binary(BinaryOp::Div), 1),
+            ('%', _) => (Token::Binary(BinaryOp::Mod), 1),
+            ('&', _) => (Token::Binary(BinaryOp::And), 1),
+            ('|', _) => (Token::Binary(BinaryOp::Or), 1),
+            ('^', _) => (Token::Binary(BinaryOp::Xor), 1),
+            ('~', _) => (Token::Unary(UnaryOp::Not), 1),
+            (',', _) => (Token::Comma, 1),
+            ('=', _) => (Token::Assign, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            _ => return Err(format!("Unexpected '{}' at column {}", c, column))
+        };
+        tokens.push((token, column));
+        index += length;
+    }
+
+    Ok(tokens)
+}
+
+/// Precedence climbing over the token list.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// Reported for errors at the end of the input.
+    end_column: usize,
+    /// How many parse_binary() and parse_unary() calls are under way.
+    depth: usize
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.position).map(|(token, _)| token.clone())
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.position).map(|(_, column)| *column).unwrap_or(self.end_column)
+    }
+
+    fn nests_too_deeply(column: usize) -> String {
+        format!("The expression nests too deeply at column {}, {} levels at most", column, MAX_NESTING)
+    }
+
+    /// Counts a level of recursion, refusing to go past MAX_NESTING before the recursion can exhaust the host's
+    /// stack. Every level that returns Ok undoes it.
+    fn enter(&mut self) -> Result<(), String> {
+        if self.depth >= MAX_NESTING {
+            return Err(Parser::nests_too_deeply(self.column()));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// An expression whose binary operators all bind at least as tightly as `min_precedence`, with the height of its
+    /// tree.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<(Expr, usize), String> {
+        self.enter()?;
+        let (mut left, mut height) = self.parse_unary()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Binary(op)) => op,
+                Some(Token::Minus) => BinaryOp::Sub,
+                _ => break
+            };
+            if op.precedence() < min_precedence {
+                break;
+            }
+            let column = self.column();
+            self.position += 1;
+
+            // Left associative: the right operand only takes operators binding tighter than this one
+            let (right, right_height) = self.parse_binary(op.precedence() + 1)?;
+            // A chain such as `1 - 2 - 3` deepens the tree without recursing, so it is limited here
+            height = height.max(right_height) + 1;
+            if height > MAX_NESTING {
+                return Err(Parser::nests_too_deeply(column));
+            }
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        self.depth -= 1;
+        Ok((left, height))
+    }
+
+    /// A number, a parenthesized expression, a function call or a unary operator applied to one, with the height of
+    /// its tree.
+    fn parse_unary(&mut self) -> Result<(Expr, usize), String> {
+        self.enter()?;
+        let column = self.column();
+        let token = self.peek();
+        self.position += 1;
+
+        let parsed = match token {
+            Some(Token::Number(value)) => (Expr::Number(value), 1),
+            Some(Token::Variable(name)) => (Expr::Variable(name), 1),
+            Some(Token::Minus) => {
+                let (operand, height) = self.parse_unary()?;
+                (Expr::Unary(UnaryOp::Neg, Box::new(operand)), height + 1)
+            }
+            Some(Token::Binary(BinaryOp::Add)) => self.parse_unary()?,
+            Some(Token::Unary(op)) => {
+                let (operand, height) = self.parse_unary()?;
+                (Expr::Unary(op, Box::new(operand)), height + 1)
+            }
+            Some(Token::Open) => {
+                let inner = self.parse_binary(0)?;
+                if self.peek() != Some(Token::Close) {
+                    return Err(format!("Expected ')' at column {}", self.column()));
+                }
+                self.position += 1;
+                inner
+            }
+            Some(Token::Function(routine)) => self.parse_call(routine, column)?,
+            _ => return Err(format!("Expected a number at column {}", column))
+        };
+        self.depth -= 1;
+        Ok(parsed)
+    }
+
+    /// The parenthesized arguments of `routine`, whose name is at `column`.
+    fn parse_call(&mut self, routine: Routine, column: usize) -> Result<(Expr, usize), String> {
+        if self.peek() != Some(Token::Open) {
+            return Err(format!("Expected '(' after {} at column {}", routine.name(), self.column()));
+        }
+        self.position += 1;
+
+        let mut args = vec!();
+        let mut height = 0;
+        loop {
+            let (arg, arg_height) = self.parse_binary(0)?;
+            args.push(arg);
+            height = height.max(arg_height);
+            match self.peek() {
+                Some(Token::Comma) => self.position += 1,
+                Some(Token::Close) => break,
+                _ => return Err(format!("Expected ',' or ')' at column {}", self.column()))
+            }
+        }
+        self.position += 1;
+
+        if args.len() != routine.arity() {
+            return Err(format!("{} at column {} takes {} argument{}, not {}", routine.name(), column, routine.arity(),
+                if routine.arity() == 1 { "" } else { "s" }, args.len()));
+        }
+        Ok((Expr::Call(routine, args), height + 1))
+    }
+
+    /// The rest of the input as one expression.
+    fn parse_to_end(&mut self) -> Result<Expr, String> {
+        let (expr, _) = self.parse_binary(0)?;
+        match self.peek() {
+            None => {}
+            Some(Token::Close) => return Err(format!("Unmatched ')' at column {}", self.column())),
+            Some(Token::Assign) => return Err(format!("Only a variable can be assigned to, at column {}", self.column())),
+            Some(_) => return Err(format!("Expected an operator at column {}", self.column()))
+        }
+        Ok(expr)
+    }
+}
+
+/// Parses an infix expression or an assignment `name = expression`. On failure returns a description of the problem
+/// and the column it is at.
+pub fn parse_statement(text: &str) -> Result<Statement, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+        end_column: text.chars().count() + 1,
+        depth: 0
+    };
+    let target = match (parser.tokens.first(), parser.tokens.get(1)) {
+        (Some((Token::Variable(name), _)), Some((Token::Assign, _))) => Some(name.clone()),
+        _ => None
+    };
+    if target.is_some() {
+        parser.position = 2;
+    }
+
+    Ok(Statement {
+        target: target,
+        expr: parser.parse_to_end()?
+    })
+}
+
+/// Guest code for an expression.
+pub struct Program {
+    pub bytes: Vec<u8>,
+    /// The routines the code calls, to be installed before it runs. None if it calls none.
+    pub library: Option<Library>,
+    /// The EIP the code halts with when a call has no result, and the routine that hadn't.
+    failures: Vec<(u32, Routine)>
+}
+
+/// What emit() reads variables from and the calls it has generated so far.
+struct CallSites<'a> {
+    library: &'a Library,
+    variables: &'a Variables,
+    used: bool,
+    /// The routine of every call that can fail, in order. Call n jumps to the HLT labelled `failed<n>`.
+    failures: Vec<Routine>
+}
+
+impl CallSites<'_> {
+
+    /// The memory operand of variable `name`, which compile() has checked is defined.
+    fn operand(&self, name: &str) -> String {
+        format!("dword [{:#x}]", self.variables.address(name).unwrap_or(VARIABLES_BASE))
+    }
+}
+
+/// Appends the code leaving the value of `expr` in EAX.
+fn emit(expr: &Expr, assembler: Assembler, call_sites: &mut CallSites) -> Assembler {
+    match expr {
+        Expr::Number(value) => assembler.line(&format!("mov eax, {:#x}", value)),
+        Expr::Variable(name) => assembler.line(&format!("mov eax, {}", call_sites.operand(name))),
+        Expr::Unary(op, operand) => {
+            let assembler = emit(operand, assembler, call_sites);
+            match op {
+                UnaryOp::Neg => assembler.line("neg eax"),
+                UnaryOp::Not => assembler.line("not eax")
+            }
+        }
+        Expr::Binary(op, left, right) => {
+            let mut assembler = emit(left, assembler, call_sites);
+            // Constants and variables go straight into ECX, anything else needs EAX and the left operand saved meanwhile
+            if let Expr::Number(value) = **right {
+                assembler = assembler.line(&format!("mov ecx, {:#x}", value));
+            }
+            else if let Expr::Variable(name) = &**right {
+                assembler = assembler.line(&format!("mov ecx, {}", call_sites.operand(name)));
+            }
+            else {
+                assembler = emit(right, assembler.line("push eax"), call_sites)
+                    .line("mov ecx, eax")
+                    .line("pop eax");
+            }
+            for line in op.instructions().iter() {
+                assembler = assembler.line(line);
+            }
+            assembler
+        }
+        Expr::Call(routine, args) => {
+            let mut assembler = assembler;
+            for (index, arg) in args.iter().enumerate() {
+                if index != 0 {
+                    assembler = assembler.line("push eax");
+                }
+                assembler = emit(arg, assembler, call_sites);
+            }
+            // Into EAX, ECX and EBX, the last argument is in EAX already
+            assembler = match args.len() {
+                2 => assembler.line("mov ecx, eax").line("pop eax"),
+                3 => assembler.line("mov ebx, eax").line("pop ecx").line("pop eax"),
+                _ => assembler
+            };
+
+            call_sites.used = true;
+            assembler = assembler.line(&format!("call {:#x}", call_sites.library.entry(*routine)));
+            if routine.failure().is_some() {
+                assembler = assembler.line(&format!("jc failed{}", call_sites.failures.len()));
+                call_sites.failures.push(*routine);
+            }
+            assembler
+        }
+    }
+}
+
+/// Compiles `expr` to guest code that ends in HLT with the value in EAX, having stored it at each DS offset in
+/// `stores`. On failure returns a description of why the expression can't run in the guest.
+pub fn compile(expr: &Expr, variables: &Variables, stores: &[u32]) -> Result<Program, String> {
+    if expr.stack_depth() > MAX_STACK_DEPTH {
+        return Err(format!("The expression nests too deeply for the {} entry guest stack", MAX_STACK_DEPTH));
+    }
+    if let Some(name) = expr.unknown_variable(variables) {
+        return Err(format!("Unknown variable {}", name));
+    }
+
+    let library = Library::new()?;
+    let mut call_sites = CallSites {
+        library: &library,
+        variables: variables,
+        used: false,
+        failures: vec!()
+    };
+    let mut assembler = emit(expr, Assembler::new(Mode::Bits16), &mut call_sites);
+    for address in stores {
+        assembler = assembler.line(&format!("mov [{:#x}], eax", address));
+    }
+    assembler = assembler.line("hlt");
+    for index in 0..call_sites.failures.len() {
+        assembler = assembler.label(&format!("failed{}", index)).line("hlt");
+    }
+    let code = assembler.assemble()?;
+
+    let mut failures = vec!();
+    for (index, routine) in call_sites.failures.iter().enumerate() {
+        match code.address_of(&format!("failed{}", index)) {
+            Some(address) => failures.push((address as u32 + 1, *routine)),
+            None => return Err(format!("No HLT for call {}", index))
+        }
+    }
+    // The code has to end below the routines it calls, and always below the fault handler
+    let (limit, above) = if call_sites.used { (LIBRARY_OFFSET, "the routines") } else { (STUBS_OFFSET, "the fault handler") };
+    if code.bytes.len() > limit as usize {
+        return Err(format!("The expression's code takes {} bytes and would overwrite {} at {:#x}", code.bytes.len(), above, limit));
+    }
+
+    Ok(Program {
+        bytes: code.bytes,
+        library: if call_sites.used { Some(library) } else { None },
+        failures: failures
+    })
+}
+
+/// Evaluates `expr` in the guest: compiles it, loads it at the start of the code segment with the routines it calls
+/// and runs it from a fresh register state, which stores the value at each DS offset in `stores`. On failure returns
+/// the exception the guest took, e.g. #DE for a division by zero, or a description of what else went wrong, including
+/// a function without a result.
+pub fn evaluate(calc_vm: &mut CalcVm, expr: &Expr, variables: &Variables, stores: &[u32]) -> Result<u32, RunError> {
+    let program = compile(expr, variables, stores)?;
+    calc_vm.load_code(&program.bytes)?;
+    if let Some(library) = &program.library {
+        library.install(calc_vm)?;
+    }
+    calc_vm.reset_cpu_state();
+    calc_vm.run()?;
+
+    let cpu_state = &calc_vm.vcpu().cpu_state;
+    if let Some((_, routine)) = program.failures.iter().find(|(eip, _)| *eip == cpu_state.eip()) {
+        return Err(RunError::Failed(format!("{}(): {}", routine.name(), routine.failure().unwrap_or("no result"))));
+    }
+    Ok(cpu_state.gpr32(REG_RAX))
+}
+
+/// Executes `statement` in the guest, which stores the value in `ans` and in the variable assigned to, defining that
+/// when it is new. Nothing is stored or defined if the evaluation fails.
+pub fn execute(calc_vm: &mut CalcVm, statement: &Statement, variables: &mut Variables) -> Result<u32, RunError> {
+    let mut stores = vec!();
+    if let Some(target) = &statement.target {
+        stores.push(variables.address_for(target)?);
+    }
+    if let Some(ans) = variables.address(ANS) {
+        stores.push(ans);
+    }
+
+    let value = evaluate(calc_vm, &statement.expr, variables, &stores)?;
+    if let Some(target) = &statement.target {
+        variables.define(target)?;
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Expr {
+        match parse_statement(text) {
+            Ok(statement) => statement.expr,
+            Err(error_message) => panic!("{}: {}", text, error_message)
+        }
+    }
+
+    fn number(value: u32) -> Box<Expr> {
+        Box::new(Expr::Number(value))
+    }
+
+    fn binary(op: BinaryOp, left: Box<Expr>, right: Box<Expr>) -> Box<Expr> {
+        Box::new(Expr::Binary(op, left, right))
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(parse("2 - 3 - 4"), *binary(BinaryOp::Sub, binary(BinaryOp::Sub, number(2), number(3)), number(4)));
+        assert_eq!(parse("2 - (3 - 4)"), *binary(BinaryOp::Sub, number(2), binary(BinaryOp::Sub, number(3), number(4))));
+        assert_eq!(parse("8 / 4 * 2 % 3"),
+            *binary(BinaryOp::Mod, binary(BinaryOp::Mul, binary(BinaryOp::Div, number(8), number(4)), number(2)), number(3)));
+
+        // | ^ & << + * from loosest to tightest
+        let tightest = binary(BinaryOp::Add, number(1), binary(BinaryOp::Mul, number(2), number(3)));
+        assert_eq!(parse("1 | 2 ^ 3 & 4 << 1 + 2 * 3"), *binary(BinaryOp::Or, number(1),
+            binary(BinaryOp::Xor, number(2), binary(BinaryOp::And, number(3), binary(BinaryOp::Shl, number(4), tightest)))));
+        assert_eq!(parse("1 * 2 + 3 << 4 & 5 ^ 6 | 7"), *binary(BinaryOp::Or, binary(BinaryOp::Xor, binary(BinaryOp::And,
+            binary(BinaryOp::Shl, binary(BinaryOp::Add, binary(BinaryOp::Mul, number(1), number(2)), number(3)), number(4)),
+            number(5)), number(6)), number(7)));
+    }
+
+    #[test]
+    fn unary_operators() {
+        let neg = |operand: Box<Expr>| Box::new(Expr::Unary(UnaryOp::Neg, operand));
+        assert_eq!(parse("-2 * 3"), *binary(BinaryOp::Mul, neg(number(2)), number(3)));
+        assert_eq!(parse("2 - -3"), *binary(BinaryOp::Sub, number(2), neg(number(3))));
+        assert_eq!(parse("--2"), *neg(neg(number(2))));
+        assert_eq!(parse("+2"), Expr::Number(2));
+        assert_eq!(parse("-(1 + 2)"), *neg(binary(BinaryOp::Add, number(1), number(2))));
+        assert_eq!(parse("~x & 0xff"),
+            *binary(BinaryOp::And, Box::new(Expr::Unary(UnaryOp::Not, Box::new(Expr::Variable(String::from("x"))))), number(0xFF)));
+    }
+
+    #[test]
+    fn calls_and_literals() {
+        assert_eq!(parse("gcd(84, 6 * 6)"), Expr::Call(Routine::Gcd, vec!(Expr::Number(84), *binary(BinaryOp::Mul, number(6), number(6)))));
+        assert_eq!(parse("0x10 + 0b11 + 'a'"), *binary(BinaryOp::Add, binary(BinaryOp::Add, number(16), number(3)), number(97)));
+    }
+
+    #[test]
+    fn assignment() {
+        assert_eq!(parse_statement("x = y * 2"), Ok(Statement {
+            target: Some(String::from("x")),
+            expr: *binary(BinaryOp::Mul, Box::new(Expr::Variable(String::from("y"))), number(2))
+        }));
+        assert_eq!(parse_statement("x"), Ok(Statement { target: None, expr: Expr::Variable(String::from("x")) }));
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("1 + (2", "Expected ')' at column 7"),
+            ("1 + 2)", "Unmatched ')' at column 6"),
+            ("1 2", "Expected an operator at column 3"),
+            ("1 +", "Expected a number at column 4"),
+            ("x =", "Expected a number at column 4"),
+            ("3 = 4", "Only a variable can be assigned to, at column 3"),
+            ("1 $ 2", "Unexpected '$' at column 3"),
+            ("2 * gcd 4", "Expected '(' after gcd at column 9"),
+            ("gcd(1 2)", "Expected ',' or ')' at column 7"),
+            ("1 + gcd(1)", "gcd at column 5 takes 2 arguments, not 1"),
+            ("fact(1, 2)", "fact at column 1 takes 1 argument, not 2"),
+            ("0x100000000", "0x100000000 at column 1 doesn't fit in 32 bits")
+        ];
+        for (text, expected) in cases.iter() {
+            assert_eq!(parse_statement(text), Err(String::from(*expected)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nests_too_deeply = |column: usize| Err(format!("The expression nests too deeply at column {}, {} levels at most", column, MAX_NESTING));
+
+        assert!(parse_statement(&format!("{}1{}", "(".repeat(100), ")".repeat(100))).is_ok());
+        assert_eq!(parse_statement(&format!("{}1", "-".repeat(1000))), nests_too_deeply(MAX_NESTING));
+        assert!(parse_statement(&format!("{}1{}", "(".repeat(1000), ")".repeat(1000))).unwrap_err().contains("nests too deeply"));
+        // A long chain of one operator doesn't recurse but still nests in the tree
+        assert!(parse_statement(&format!("{}1", "1-".repeat(MAX_NESTING - 1))).is_ok());
+        assert_eq!(parse_statement(&format!("{}1", "1-".repeat(MAX_NESTING))), nests_too_deeply(2 * MAX_NESTING));
+    }
+}
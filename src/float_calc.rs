@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+
+use crate::assembler::{Assembler, Mode};
+use crate::calculator::CalcVm;
+use crate::fpu::*;
+use crate::vcpu_regs::{EFLAGS_CF, EFLAGS_PF, EFLAGS_ZF};
+
+// The operands go in through XMM0 and XMM1 of the FXSAVE image and the result comes back in XMM0, next to the
+// MXCSR the guest left behind.
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FloatOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// Square root of the first operand, the second is ignored.
+    Sqrt,
+    /// Ordered compare (COMISS/COMISD), so NaN operands raise invalid.
+    Compare
+}
+
+impl FloatOp {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add" | "+" => Some(FloatOp::Add),
+            "sub" | "-" => Some(FloatOp::Sub),
+            "mul" | "*" => Some(FloatOp::Mul),
+            "div" | "/" => Some(FloatOp::Div),
+            "sqrt" => Some(FloatOp::Sqrt),
+            "cmp" => Some(FloatOp::Compare),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            FloatOp::Add => "+",
+            FloatOp::Sub => "-",
+            FloatOp::Mul => "*",
+            FloatOp::Div => "/",
+            FloatOp::Sqrt => "sqrt",
+            FloatOp::Compare => "cmp"
+        }
+    }
+}
+
+/// The scalar SSE width an operation runs at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Precision {
+    Single,
+    Double
+}
+
+#[derive(Debug)]
+pub struct FloatResult {
+    /// Raw bits of the guest's result, an f32 in the low 32 bits for Precision::Single. Zero for FloatOp::Compare.
+    pub bits: u64,
+    /// Raw bits the host computed for the same operation.
+    pub host_bits: u64,
+    /// For FloatOp::Compare, the guest's ordering decoded from ZF/PF/CF, None when unordered.
+    pub ordering: Option<Ordering>,
+    /// For FloatOp::Compare, the host's ordering.
+    pub host_ordering: Option<Ordering>,
+    /// The sticky MXCSR exception flags (fpu::MXCSR_*) the guest raised.
+    pub exceptions: u32
+}
+
+impl FloatResult {
+
+    /// Whether the guest and host agree, bit for bit.
+    pub fn matches_host(&self) -> bool {
+        self.bits == self.host_bits && self.ordering == self.host_ordering
+    }
+
+    pub fn value(&self, precision: Precision) -> f64 {
+        match precision {
+            Precision::Single => f32::from_bits(self.bits as u32) as f64,
+            Precision::Double => f64::from_bits(self.bits)
+        }
+    }
+}
+
+/// Names of the IEEE exceptions set in `mxcsr`.
+pub fn exception_names(mxcsr: u32) -> Vec<&'static str> {
+    let mut names = vec!();
+    let flags = [
+        (MXCSR_INVALID, "invalid"),
+        (MXCSR_DENORMAL, "denormal"),
+        (MXCSR_DIVIDE_BY_ZERO, "divide-by-zero"),
+        (MXCSR_OVERFLOW, "overflow"),
+        (MXCSR_UNDERFLOW, "underflow"),
+        (MXCSR_PRECISION, "inexact")
+    ];
+    for (flag, name) in flags.iter() {
+        if mxcsr & flag != 0 {
+            names.push(*name);
+        }
+    }
+    names
+}
+
+/// Builds the guest code for `op`, which works on XMM0 and XMM1 and leaves its result in XMM0.
+fn guest_code(op: FloatOp, precision: Precision) -> Result<Vec<u8>, String> {
+    let suffix = match precision {
+        Precision::Single => "s",
+        Precision::Double => "d"
+    };
+
+    let operation = match op {
+        FloatOp::Add => format!("adds{} xmm0, xmm1", suffix),
+        FloatOp::Sub => format!("subs{} xmm0, xmm1", suffix),
+        FloatOp::Mul => format!("muls{} xmm0, xmm1", suffix),
+        FloatOp::Div => format!("divs{} xmm0, xmm1", suffix),
+        FloatOp::Sqrt => format!("sqrts{} xmm0, xmm0", suffix),
+        FloatOp::Compare => format!("comis{} xmm0, xmm1", suffix)
+    };
+
+    let code = Assembler::new(Mode::Bits16)
+        .line(&operation)
+        .line("hlt")
+        .assemble()?;
+    Ok(code.bytes)
+}
+
+fn host_bits(op: FloatOp, precision: Precision, a: f64, b: f64) -> u64 {
+    match precision {
+        Precision::Single => {
+            let (a, b) = (a as f32, b as f32);
+            let result = match op {
+                FloatOp::Add => a + b,
+                FloatOp::Sub => a - b,
+                FloatOp::Mul => a * b,
+                FloatOp::Div => a / b,
+                FloatOp::Sqrt => a.sqrt(),
+                FloatOp::Compare => return 0
+            };
+            result.to_bits() as u64
+        }
+        Precision::Double => {
+            let result = match op {
+                FloatOp::Add => a + b,
+                FloatOp::Sub => a - b,
+                FloatOp::Mul => a * b,
+                FloatOp::Div => a / b,
+                FloatOp::Sqrt => a.sqrt(),
+                FloatOp::Compare => return 0
+            };
+            result.to_bits()
+        }
+    }
+}
+
+/// Runs `a op b` on the guest's SSE unit and checks it against the host.
+///
+/// # Arguments
+///
+/// * `calc_vm` - The VM to run in. Its code and the vCPU's FPU state are overwritten.
+/// * `op` - The operation.
+/// * `precision` - Whether the single precision instructions are used. Single operands should already be f32 values,
+///   parsed with `parse::<f32>()`: rounding a parsed f64 again to f32 can land on a different f32.
+/// * `a` - The first operand.
+/// * `b` - The second operand. Ignored by FloatOp::Sqrt.
+pub fn run_float_op(calc_vm: &mut CalcVm, op: FloatOp, precision: Precision, a: f64, b: f64) -> Result<FloatResult, String> {
+    calc_vm.load_code(&guest_code(op, precision)?)?;
+
+    calc_vm.reset_cpu_state();
+    {
+        let vcpu = calc_vm.vcpu();
+
+        // SSE needs CR0.EM clear and CR0.MP set (SDM Vol. 3 13.1.3), plus CR4.OSFXSR. With CR4.OSXMMEXCPT set an
+        // unmasked SIMD exception would be a #XM rather than a #UD, though the default MXCSR masks them all.
+        vcpu.cpu_state.cr0 = (vcpu.cpu_state.cr0 | CR0_MP) & !(CR0_EM | CR0_TS);
+        vcpu.cpu_state.cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+
+        vcpu.fpu_state.reset();
+        match precision {
+            Precision::Single => {
+                vcpu.fpu_state.set_xmm_f32(0, a as f32);
+                vcpu.fpu_state.set_xmm_f32(1, b as f32);
+            }
+            Precision::Double => {
+                vcpu.fpu_state.set_xmm_f64(0, a);
+                vcpu.fpu_state.set_xmm_f64(1, b);
+            }
+        }
+        if let Some(last_error) = vcpu.set_fpu() {
+            return Err(format!("Unable to set vCPU {} FPU state. GetLastError: {}", vcpu.id, last_error));
+        }
+    }
+
+    calc_vm.run()?;
+
+    let vcpu = calc_vm.vcpu();
+    if let Some(last_error) = vcpu.get_fpu() {
+        return Err(format!("Unable to get vCPU {} FPU state. GetLastError: {}", vcpu.id, last_error));
+    }
+    let mut bits = match precision {
+        Precision::Single => vcpu.fpu_state.xmm_f32(0).to_bits() as u64,
+        Precision::Double => vcpu.fpu_state.xmm_f64(0).to_bits()
+    };
+    let exceptions = vcpu.fpu_state.mxcsr_exceptions();
+
+    let (ordering, host_ordering) = if op == FloatOp::Compare {
+        bits = 0;
+        // COMIS* reports unordered as ZF=PF=CF=1, greater as all clear, less as CF and equal as ZF
+        let eflags = calc_vm.vcpu().cpu_state.eflags();
+        let ordering = if eflags & EFLAGS_PF != 0 {
+            None
+        }
+        else if eflags & EFLAGS_ZF != 0 {
+            Some(Ordering::Equal)
+        }
+        else if eflags & EFLAGS_CF != 0 {
+            Some(Ordering::Less)
+        }
+        else {
+            Some(Ordering::Greater)
+        };
+
+        let host_ordering = match precision {
+            Precision::Single => (a as f32).partial_cmp(&(b as f32)),
+            Precision::Double => a.partial_cmp(&b)
+        };
+        (ordering, host_ordering)
+    }
+    else {
+        (None, None)
+    };
+
+    Ok(FloatResult {
+        bits: bits,
+        host_bits: host_bits(op, precision, a, b),
+        ordering: ordering,
+        host_ordering: host_ordering,
+        exceptions: exceptions
+    })
+}
@@ -0,0 +1,168 @@
+use crate::haxm_interface_windows::fx_layout;
+
+// Typed access to the FXSAVE image HAXM exchanges through HAX_VCPU_IOCTL_GET_FPU/HAX_VCPU_IOCTL_SET_FPU.
+// Intel SDM Vol. 1 10.5.1 "FXSAVE Area" describes the layout.
+
+// MXCSR bits
+pub const MXCSR_INVALID: u32            = 1 << 0;
+pub const MXCSR_DENORMAL: u32           = 1 << 1;
+pub const MXCSR_DIVIDE_BY_ZERO: u32     = 1 << 2;
+pub const MXCSR_OVERFLOW: u32           = 1 << 3;
+pub const MXCSR_UNDERFLOW: u32          = 1 << 4;
+pub const MXCSR_PRECISION: u32          = 1 << 5;
+pub const MXCSR_EXCEPTION_FLAGS: u32    = 0x3F;
+/// The power-on MXCSR: all exceptions masked, round to nearest.
+pub const MXCSR_DEFAULT: u32            = 0x1F80;
+
+/// Where the x87 status word keeps TOP, the physical register number of ST(0).
+pub const FSW_TOP_SHIFT: u16 = 11;
+
+/// The power-on x87 control word: all exceptions masked, 64 bit precision, round to nearest.
+pub const FCW_DEFAULT: u16 = 0x037F;
+
+pub const XMM_REGISTER_COUNT: usize = 16;
+pub const X87_REGISTER_COUNT: usize = 8;
+
+/// An x87 80 bit double extended precision register.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct X87Register(pub [u8; 10]);
+
+impl X87Register {
+
+    /// The 64 bit significand, including the explicit integer bit.
+    pub fn significand(self) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.0[0..8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// The sign bit and 15 bit biased exponent.
+    pub fn sign_exponent(self) -> u16 {
+        u16::from_le_bytes([self.0[8], self.0[9]])
+    }
+
+    /// Converts to the nearest f64.
+    pub fn to_f64(self) -> f64 {
+        let negative = self.sign_exponent() & 0x8000 != 0;
+        let exponent = (self.sign_exponent() & 0x7FFF) as i32;
+        let significand = self.significand();
+
+        let magnitude = if exponent == 0x7FFF {
+            if significand << 1 == 0 {
+                f64::INFINITY
+            }
+            else {
+                f64::NAN
+            }
+        }
+        else {
+            // significand * 2^(exponent - bias - 63), scaled in steps so the intermediate never over/underflows
+            let mut magnitude = significand as f64;
+            let mut power = exponent - 16383 - 63;
+            while power > 0 {
+                let step = power.min(1000);
+                magnitude *= 2f64.powi(step);
+                power -= step;
+            }
+            while power < 0 {
+                let step = power.max(-1000);
+                magnitude *= 2f64.powi(step);
+                power -= step;
+            }
+            magnitude
+        };
+
+        if negative {
+            -magnitude
+        }
+        else {
+            magnitude
+        }
+    }
+}
+
+impl fx_layout {
+
+    /// Returns ST(`index`). FXSAVE stores the x87 registers in stack order, so index 0 is the top of the stack.
+    pub fn st(&self, index: usize) -> X87Register {
+        let mut register = [0u8; 10];
+        register.copy_from_slice(&self.st_mm[index][0..10]);
+        X87Register(register)
+    }
+
+    /// The physical register number currently at the top of the x87 stack.
+    pub fn top(&self) -> u8 {
+        ((self.fsw >> FSW_TOP_SHIFT) & 7) as u8
+    }
+
+    /// Whether ST(`index`) holds a value. FXSAVE keeps an abridged tag word, one bit per physical register.
+    pub fn is_st_valid(&self, index: usize) -> bool {
+        let physical = (self.top() as usize + index) % X87_REGISTER_COUNT;
+        self.ftw & (1 << physical) != 0
+    }
+
+    fn xmm_bytes(&self, index: usize) -> &[u8; 16] {
+        if index < 8 {
+            &self.mmx_1[index]
+        }
+        else {
+            &self.mmx_2[index - 8]
+        }
+    }
+
+    fn xmm_bytes_mut(&mut self, index: usize) -> &mut [u8; 16] {
+        if index < 8 {
+            &mut self.mmx_1[index]
+        }
+        else {
+            &mut self.mmx_2[index - 8]
+        }
+    }
+
+    /// Returns the full 128 bits of XMM`index`.
+    pub fn xmm(&self, index: usize) -> u128 {
+        u128::from_le_bytes(*self.xmm_bytes(index))
+    }
+
+    /// Sets the full 128 bits of XMM`index`.
+    pub fn set_xmm(&mut self, index: usize, value: u128) {
+        *self.xmm_bytes_mut(index) = value.to_le_bytes();
+    }
+
+    /// Returns the low scalar single of XMM`index`, what the *SS instructions operate on.
+    pub fn xmm_f32(&self, index: usize) -> f32 {
+        f32::from_bits(self.xmm(index) as u32)
+    }
+
+    /// Sets the low scalar single of XMM`index`, leaving the upper bits alone like MOVSS from a register does.
+    pub fn set_xmm_f32(&mut self, index: usize, value: f32) {
+        let upper = self.xmm(index) & !(u32::MAX as u128);
+        self.set_xmm(index, upper | value.to_bits() as u128);
+    }
+
+    /// Returns the low scalar double of XMM`index`, what the *SD instructions operate on.
+    pub fn xmm_f64(&self, index: usize) -> f64 {
+        f64::from_bits(self.xmm(index) as u64)
+    }
+
+    /// Sets the low scalar double of XMM`index`, leaving the upper bits alone like MOVSD from a register does.
+    pub fn set_xmm_f64(&mut self, index: usize, value: f64) {
+        let upper = self.xmm(index) & !(u64::MAX as u128);
+        self.set_xmm(index, upper | value.to_bits() as u128);
+    }
+
+    /// The six sticky SSE exception flags (MXCSR_INVALID through MXCSR_PRECISION) raised so far.
+    pub fn mxcsr_exceptions(&self) -> u32 {
+        self.mxcsr & MXCSR_EXCEPTION_FLAGS
+    }
+
+    /// Puts the image in the state FNINIT and a power-on MXCSR would leave it, with every register cleared.
+    pub fn reset(&mut self) {
+        unsafe {
+            *self = std::mem::zeroed();
+        }
+        self.fcw = FCW_DEFAULT;
+        self.mxcsr = MXCSR_DEFAULT;
+        self.mxcsr_mask = 0xFFFF;
+    }
+}
@@ -0,0 +1,590 @@
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+
+// A GDB remote serial protocol stub (https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html).
+// The stub doesn't know about the hypervisor, it drives anything implementing GdbTarget (debugger::Debugger maps HAXM's
+// registers to gdb's), so the packet handling can be exercised with a scripted client and a fake target on any host.
+
+/// The general registers at the start of gdb's i386 'g' packet: eax, ecx, edx, ebx, esp, ebp, esi, edi, eip, eflags,
+/// cs, ss, ds, es, fs and gs. gdb accepts a reply that stops after them and treats the FPU/SSE registers that would
+/// follow as unavailable.
+pub const I386_REGISTER_COUNT: usize = 16;
+pub const I386_EIP: usize = 8;
+pub const I386_EFLAGS: usize = 9;
+pub const I386_CS: usize = 10;
+
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const PACKET_SIZE: usize = 0x4000;
+/// The most bytes an `m` reply carries: two hex digits each, and the packet's `$` and `#xx` must fit too.
+const MAX_READ_LENGTH: usize = (PACKET_SIZE - 4) / 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BreakpointType {
+    Software,
+    Hardware,
+    WriteWatchpoint,
+    AccessWatchpoint
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    /// A single step completed.
+    Step,
+    Breakpoint,
+    HwBreakpoint,
+    Watchpoint { address: u64, access: bool },
+    /// The guest executed HLT. Its state can still be inspected, resuming it again reports an exit.
+    Halted,
+    /// The guest can't continue, e.g. after a triple fault.
+    Crashed
+}
+
+/// What the stub needs from the thing being debugged. Addresses are linear, and the program counter gdb sees (register
+/// I386_EIP) is the linear address of the next instruction, so it lines up with memory and breakpoint addresses.
+pub trait GdbTarget {
+    fn read_registers(&mut self) -> [u32; I386_REGISTER_COUNT];
+    fn write_registers(&mut self, registers: &[u32; I386_REGISTER_COUNT]);
+    fn read_memory(&mut self, address: u64, length: usize) -> Option<Vec<u8>>;
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> bool;
+    /// Runs the guest for one instruction, or until something stops it.
+    fn resume(&mut self, step: bool) -> StopReason;
+    fn insert_breakpoint(&mut self, kind: BreakpointType, address: u64, length: u64) -> bool;
+    fn remove_breakpoint(&mut self, kind: BreakpointType, address: u64, length: u64) -> bool;
+}
+
+/// What the connection should do after a packet was handled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StubAction {
+    Reply(String),
+    /// Reply, then close the connection.
+    ReplyAndClose(String),
+    Close
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Wraps `data` as `$data#checksum`, escaping the characters the protocol reserves.
+pub fn frame_packet(data: &str) -> Vec<u8> {
+    let mut escaped = vec!();
+    for byte in data.bytes() {
+        if byte == b'$' || byte == b'#' || byte == b'}' || byte == b'*' {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        }
+        else {
+            escaped.push(byte);
+        }
+    }
+
+    let mut packet = vec!(b'$');
+    packet.extend_from_slice(&escaped);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&escaped)).as_bytes());
+    packet
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() & 1 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex_u64(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// The size in bytes of register `index` in gdb's i386 description: the general registers, st0-st7, the x87 control
+/// registers (fctrl to fop), xmm0-xmm7 and mxcsr. None past the end.
+fn i386_register_size(index: u64) -> Option<usize> {
+    match index {
+        0..=15 => Some(4),
+        16..=23 => Some(10),
+        24..=31 => Some(4),
+        32..=39 => Some(16),
+        40 => Some(4),
+        _ => None
+    }
+}
+
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Step => format!("S{:02x}", SIGTRAP),
+        StopReason::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+        StopReason::HwBreakpoint => format!("T{:02x}hwbreak:;", SIGTRAP),
+        StopReason::Watchpoint { address, access } => {
+            let kind = if access { "awatch" } else { "watch" };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, address)
+        }
+        StopReason::Halted => format!("S{:02x}", SIGTRAP),
+        StopReason::Crashed => format!("X{:02x}", SIGSEGV)
+    }
+}
+
+/// Protocol state for one debugging session.
+pub struct GdbStub<T: GdbTarget> {
+    pub target: T,
+    last_stop: StopReason,
+    halted: bool
+}
+
+impl<T: GdbTarget> GdbStub<T> {
+
+    /// Associated function constructor. The guest is considered stopped (as if by SIGTRAP) when gdb attaches.
+    pub fn new(target: T) -> Self {
+        GdbStub {
+            target: target,
+            last_stop: StopReason::Step,
+            halted: false
+        }
+    }
+
+    fn resume(&mut self, step: bool, address: &str) -> StubAction {
+        if self.halted {
+            // A halted guest has nowhere left to go, tell gdb the "process" exited
+            return StubAction::ReplyAndClose(String::from("W00"));
+        }
+
+        if !address.is_empty() {
+            match parse_hex_u64(address) {
+                Some(address) => {
+                    let mut registers = self.target.read_registers();
+                    registers[I386_EIP] = address as u32;
+                    self.target.write_registers(&registers);
+                }
+                None => return StubAction::Reply(String::from("E01"))
+            }
+        }
+
+        self.last_stop = self.target.resume(step);
+        match self.last_stop {
+            StopReason::Halted => self.halted = true,
+            StopReason::Crashed => return StubAction::ReplyAndClose(stop_reply(self.last_stop)),
+            _ => {}
+        }
+        StubAction::Reply(stop_reply(self.last_stop))
+    }
+
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> StubAction {
+        let fields: Vec<&str> = arguments.split(',').collect();
+        if fields.len() < 3 {
+            return StubAction::Reply(String::from("E01"));
+        }
+
+        let kind = match fields[0] {
+            "0" => BreakpointType::Software,
+            "1" => BreakpointType::Hardware,
+            "2" => BreakpointType::WriteWatchpoint,
+            "4" => BreakpointType::AccessWatchpoint,
+            // x86 has no read-only watchpoints
+            _ => return StubAction::Reply(String::new())
+        };
+
+        let (address, length) = match (parse_hex_u64(fields[1]), parse_hex_u64(fields[2])) {
+            (Some(address), Some(length)) => (address, length),
+            _ => return StubAction::Reply(String::from("E01"))
+        };
+
+        let was_successful = if insert {
+            self.target.insert_breakpoint(kind, address, length)
+        }
+        else {
+            self.target.remove_breakpoint(kind, address, length)
+        };
+
+        if was_successful {
+            StubAction::Reply(String::from("OK"))
+        }
+        else {
+            StubAction::Reply(String::from("E0e"))
+        }
+    }
+
+    /// Handles one packet's payload (without the `$` and checksum) and says what to answer.
+    pub fn handle_packet(&mut self, packet: &str) -> StubAction {
+        let command = packet.chars().next().unwrap_or(' ');
+        let arguments = packet.get(1..).unwrap_or("");
+
+        match command {
+            '?' => StubAction::Reply(stop_reply(self.last_stop)),
+            'g' => {
+                let registers = self.target.read_registers();
+                let bytes: Vec<u8> = registers.iter().flat_map(|register| register.to_le_bytes()).collect();
+                StubAction::Reply(to_hex(&bytes))
+            }
+            'G' => {
+                match from_hex(arguments) {
+                    Some(bytes) if bytes.len() >= I386_REGISTER_COUNT * 4 => {
+                        let mut registers = [0u32; I386_REGISTER_COUNT];
+                        for (i, register) in registers.iter_mut().enumerate() {
+                            *register = u32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+                        }
+                        self.target.write_registers(&registers);
+                        StubAction::Reply(String::from("OK"))
+                    }
+                    _ => StubAction::Reply(String::from("E01"))
+                }
+            }
+            'p' => {
+                match parse_hex_u64(arguments) {
+                    Some(index) if (index as usize) < I386_REGISTER_COUNT => {
+                        let registers = self.target.read_registers();
+                        StubAction::Reply(to_hex(&registers[index as usize].to_le_bytes()))
+                    }
+                    // Registers we don't model (FPU/SSE) are reported as unavailable, an x for each hex digit
+                    Some(index) => match i386_register_size(index) {
+                        Some(size) => StubAction::Reply("xx".repeat(size)),
+                        None => StubAction::Reply(String::from("E01"))
+                    },
+                    None => StubAction::Reply(String::from("E01"))
+                }
+            }
+            'P' => {
+                let (index, value) = match arguments.split_once('=') {
+                    Some(parts) => parts,
+                    None => return StubAction::Reply(String::from("E01"))
+                };
+                match (parse_hex_u64(index), from_hex(value)) {
+                    (Some(index), Some(value)) if (index as usize) < I386_REGISTER_COUNT && value.len() == 4 => {
+                        let mut registers = self.target.read_registers();
+                        registers[index as usize] = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                        self.target.write_registers(&registers);
+                        StubAction::Reply(String::from("OK"))
+                    }
+                    _ => StubAction::Reply(String::from("E01"))
+                }
+            }
+            'm' => {
+                let parsed = arguments.split_once(',').and_then(|(address, length)| Some((parse_hex_u64(address)?, parse_hex_u64(length)?)));
+                match parsed {
+                    Some((address, length)) => {
+                        // Stay within the PacketSize advertised, gdb asks again for the rest of a short read
+                        let length = length.min(MAX_READ_LENGTH as u64) as usize;
+                        match self.target.read_memory(address, length) {
+                            Some(bytes) => StubAction::Reply(to_hex(&bytes)),
+                            None => StubAction::Reply(String::from("E0e"))
+                        }
+                    }
+                    None => StubAction::Reply(String::from("E01"))
+                }
+            }
+            'M' => {
+                let parsed = arguments.split_once(':').and_then(|(location, data)| {
+                    let (address, length) = location.split_once(',')?;
+                    Some((parse_hex_u64(address)?, parse_hex_u64(length)?, from_hex(data)?))
+                });
+                match parsed {
+                    Some((address, length, data)) if data.len() as u64 == length => {
+                        if self.target.write_memory(address, &data) {
+                            StubAction::Reply(String::from("OK"))
+                        }
+                        else {
+                            StubAction::Reply(String::from("E0e"))
+                        }
+                    }
+                    _ => StubAction::Reply(String::from("E01"))
+                }
+            }
+            'c' => self.resume(false, arguments),
+            's' => self.resume(true, arguments),
+            'Z' => self.breakpoint(true, arguments),
+            'z' => self.breakpoint(false, arguments),
+            // There is exactly one thread, whatever gdb selects is fine
+            'H' | 'T' => StubAction::Reply(String::from("OK")),
+            'k' => StubAction::Close,
+            'D' => StubAction::ReplyAndClose(String::from("OK")),
+            'q' => {
+                if packet.starts_with("qSupported") {
+                    StubAction::Reply(format!("PacketSize={:x};swbreak+;hwbreak+", PACKET_SIZE))
+                }
+                else if packet == "qAttached" {
+                    StubAction::Reply(String::from("1"))
+                }
+                else if packet == "qC" {
+                    StubAction::Reply(String::from("QC1"))
+                }
+                else if packet == "qfThreadInfo" {
+                    StubAction::Reply(String::from("m1"))
+                }
+                else if packet == "qsThreadInfo" {
+                    StubAction::Reply(String::from("l"))
+                }
+                else {
+                    StubAction::Reply(String::new())
+                }
+            }
+            // Anything else (vCont, X, qXfer...) is unsupported, which gdb answers by falling back to the basic packets
+            _ => StubAction::Reply(String::new())
+        }
+    }
+}
+
+/// Reads one packet from `stream`, acknowledging it. Returns None once the client disconnects.
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    let mut byte = [0u8; 1];
+
+    loop {
+        // Skip acks and anything else until the start of a packet
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut data = vec!();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+
+        let mut received_checksum = [0u8; 2];
+        stream.read_exact(&mut received_checksum)?;
+        let received_checksum = std::str::from_utf8(&received_checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if received_checksum == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+
+            // Undo the escaping frame_packet() applies
+            let mut unescaped = vec!();
+            let mut bytes = data.into_iter();
+            while let Some(byte) = bytes.next() {
+                if byte == b'}' {
+                    if let Some(escaped) = bytes.next() {
+                        unescaped.push(escaped ^ 0x20);
+                    }
+                }
+                else {
+                    unescaped.push(byte);
+                }
+            }
+            return Ok(Some(String::from_utf8_lossy(&unescaped).into_owned()));
+        }
+
+        // Ask for a retransmission
+        stream.write_all(b"-")?;
+    }
+}
+
+fn send_packet<S: Read + Write>(stream: &mut S, data: &str) -> io::Result<()> {
+    let packet = frame_packet(data);
+    let mut ack = [0u8; 1];
+
+    loop {
+        stream.write_all(&packet)?;
+        stream.flush()?;
+
+        if stream.read(&mut ack)? == 0 || ack[0] == b'+' {
+            return Ok(());
+        }
+    }
+}
+
+/// Serves one gdb session over `stream` until gdb detaches, kills the target or disconnects.
+pub fn serve<T: GdbTarget, S: Read + Write>(stub: &mut GdbStub<T>, stream: &mut S) -> io::Result<()> {
+    while let Some(packet) = read_packet(stream)? {
+        match stub.handle_packet(&packet) {
+            StubAction::Reply(reply) => send_packet(stream, &reply)?,
+            StubAction::ReplyAndClose(reply) => {
+                send_packet(stream, &reply)?;
+                break;
+            }
+            StubAction::Close => break
+        }
+    }
+    Ok(())
+}
+
+/// Waits for gdb on 127.0.0.1:`port` (`target remote :port`) and serves one session.
+pub fn serve_tcp<T: GdbTarget>(stub: &mut GdbStub<T>, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for gdb on 127.0.0.1:{}", port);
+    let (mut stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    serve(stub, &mut stream)
+}
+
+/// Waits for gdb on the Unix socket at `path` (`target remote path`) and serves one session.
+#[cfg(unix)]
+pub fn serve_unix<T: GdbTarget>(stub: &mut GdbStub<T>, path: &str) -> io::Result<()> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    println!("Waiting for gdb on {}", path);
+    let (mut stream, _) = listener.accept()?;
+    serve(stub, &mut stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A guest with 0x4000 bytes of memory that stops at its only breakpoint on `c` and after one instruction on `s`.
+    struct FakeTarget {
+        registers: [u32; I386_REGISTER_COUNT],
+        memory: Vec<u8>,
+        breakpoints: Vec<u64>
+    }
+
+    impl GdbTarget for FakeTarget {
+        fn read_registers(&mut self) -> [u32; I386_REGISTER_COUNT] {
+            self.registers
+        }
+
+        fn write_registers(&mut self, registers: &[u32; I386_REGISTER_COUNT]) {
+            self.registers = *registers;
+        }
+
+        fn read_memory(&mut self, address: u64, length: usize) -> Option<Vec<u8>> {
+            self.memory.get(address as usize..(address as usize).checked_add(length)?).map(|bytes| bytes.to_vec())
+        }
+
+        fn write_memory(&mut self, address: u64, data: &[u8]) -> bool {
+            match self.memory.get_mut(address as usize..address as usize + data.len()) {
+                Some(bytes) => {
+                    bytes.copy_from_slice(data);
+                    true
+                }
+                None => false
+            }
+        }
+
+        fn resume(&mut self, step: bool) -> StopReason {
+            match (step, self.breakpoints.first()) {
+                (true, _) => {
+                    self.registers[I386_EIP] += 1;
+                    StopReason::Step
+                }
+                (false, Some(address)) => {
+                    self.registers[I386_EIP] = *address as u32;
+                    StopReason::Breakpoint
+                }
+                (false, None) => StopReason::Halted
+            }
+        }
+
+        fn insert_breakpoint(&mut self, kind: BreakpointType, address: u64, _length: u64) -> bool {
+            if kind != BreakpointType::Software {
+                return false;
+            }
+            self.breakpoints.push(address);
+            true
+        }
+
+        fn remove_breakpoint(&mut self, _kind: BreakpointType, address: u64, _length: u64) -> bool {
+            let found = self.breakpoints.contains(&address);
+            self.breakpoints.retain(|breakpoint| *breakpoint != address);
+            found
+        }
+    }
+
+    /// What gdb sends, every reply acknowledged up front, and what the stub sent back.
+    struct ScriptedClient {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl Read for ScriptedClient {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buffer)
+        }
+    }
+
+    impl Write for ScriptedClient {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.output.write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Serves `packets` to a fresh FakeTarget and returns the replies, without the acks.
+    fn session(packets: &[&str]) -> (Vec<String>, FakeTarget) {
+        let mut input = vec!();
+        for packet in packets {
+            input.extend(frame_packet(packet));
+            input.push(b'+');
+        }
+        let mut client = ScriptedClient { input: Cursor::new(input), output: vec!() };
+
+        let mut registers = [0u32; I386_REGISTER_COUNT];
+        registers[I386_EIP] = 0x10;
+        let mut stub = GdbStub::new(FakeTarget { registers: registers, memory: (0..0x4000).map(|i| i as u8).collect(), breakpoints: vec!() });
+        serve(&mut stub, &mut client).unwrap();
+
+        let output = String::from_utf8(client.output).unwrap();
+        let replies = output.split('$').skip(1).map(|packet| String::from(packet.split('#').next().unwrap())).collect();
+        (replies, stub.target)
+    }
+
+    #[test]
+    fn query_supported() {
+        let (replies, _) = session(&["qSupported:multiprocess+;swbreak+"]);
+        assert_eq!(replies, ["PacketSize=4000;swbreak+;hwbreak+"]);
+    }
+
+    #[test]
+    fn registers() {
+        let (replies, target) = session(&["g", "p8", "P0=78563412", "p0", "p1f", &format!("G{}", "01000000".repeat(16)), "g"]);
+        assert_eq!(replies[0], format!("{}10000000{}", "00000000".repeat(8), "00000000".repeat(7)));
+        assert_eq!(replies[1..5], ["10000000", "OK", "78563412", "xxxxxxxx"]);
+        assert_eq!(replies[5..], ["OK", &"01000000".repeat(16)]);
+        assert_eq!(target.registers, [1; I386_REGISTER_COUNT]);
+    }
+
+    #[test]
+    fn unavailable_registers_have_their_size() {
+        let (replies, _) = session(&["pf", "p10", "p17", "p18", "p20", "p27", "p28", "p29"]);
+        assert_eq!(replies[0], "00000000");
+        assert_eq!(replies[1..3], ["x".repeat(20), "x".repeat(20)]);
+        assert_eq!(replies[3], "x".repeat(8));
+        assert_eq!(replies[4..6], ["x".repeat(32), "x".repeat(32)]);
+        assert_eq!(replies[6..], ["x".repeat(8), String::from("E01")]);
+    }
+
+    #[test]
+    fn memory() {
+        let (replies, target) = session(&["m10,4", "M10,2:abcd", "m10,4", "m3fff,2", "M10,2:ab", "mffffffffffffffff,4"]);
+        assert_eq!(replies, ["10111213", "OK", "abcd1213", "E0e", "E01", "E0e"]);
+        assert_eq!(target.memory[0x10..0x12], [0xab, 0xcd]);
+
+        let (replies, _) = session(&["m0,4000"]);
+        assert_eq!(replies[0].len(), MAX_READ_LENGTH * 2);
+        assert!(frame_packet(&replies[0]).len() <= PACKET_SIZE);
+    }
+
+    #[test]
+    fn breakpoints_and_resuming() {
+        let (replies, target) = session(&["Z0,40,1", "s", "c", "z0,40,1", "z0,40,1", "Z1,50,1", "Z3,50,1", "c", "c"]);
+        assert_eq!(replies, ["OK", "S05", "T05swbreak:;", "OK", "E0e", "E0e", "", "S05", "W00"]);
+        assert_eq!(target.registers[I386_EIP], 0x40);
+        assert!(target.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn bad_checksum_is_retransmitted() {
+        let mut input = b"$g#00".to_vec();
+        input.extend(frame_packet("p8"));
+        input.push(b'+');
+        let mut client = ScriptedClient { input: Cursor::new(input), output: vec!() };
+        let mut stub = GdbStub::new(FakeTarget { registers: [0; I386_REGISTER_COUNT], memory: vec!(), breakpoints: vec!() });
+        serve(&mut stub, &mut client).unwrap();
+
+        assert_eq!(String::from_utf8(client.output).unwrap(), format!("-+{}", String::from_utf8(frame_packet("00000000")).unwrap()));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+
+use crate::haxm_interface_windows::*;
+
+// Debug register bits, Intel SDM Vol. 3 17.2
+pub const DR6_B0: u64 = 1 << 0;
+pub const DR6_BS: u64 = 1 << 14;
+pub const DR7_L0: u64 = 1 << 0;
+pub const DR7_LE: u64 = 1 << 8;
+pub const DR7_RESERVED_1: u64 = 1 << 10;
+pub const DR7_RW_SHIFT: u64 = 16;
+pub const DR7_LEN_SHIFT: u64 = 18;
+
+pub const HW_BREAKPOINT_COUNT: usize = 4;
+
+pub const INT3_OPCODE: u8 = 0xCC;
+
+/// What a hardware breakpoint triggers on. Encoded in the R/Wn bits of DR7.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HwBreakpointKind {
+    Execute = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HwBreakpoint {
+    /// The linear address to watch. Data breakpoints must be aligned to `length`.
+    pub address: u64,
+    pub kind: HwBreakpointKind,
+    /// 1, 2, 4 or 8 bytes. Always 1 for HwBreakpointKind::Execute.
+    pub length: u8
+}
+
+/// The debug features to enable on a vCPU, turned into a hax_debug_t by to_hax_debug().
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct DebugConfig {
+    /// Exit after every instruction.
+    pub single_step: bool,
+    /// Exit when the guest executes INT3, instead of delivering #BP to the guest's IDT.
+    pub software_breakpoints: bool,
+    /// DR0 - DR3.
+    pub hw_breakpoints: [Option<HwBreakpoint>; HW_BREAKPOINT_COUNT]
+}
+
+impl DebugConfig {
+
+    fn dr7(&self) -> u64 {
+        let mut dr7 = DR7_RESERVED_1;
+
+        for (slot, breakpoint) in self.hw_breakpoints.iter().enumerate() {
+            if let Some(breakpoint) = breakpoint {
+                let length_bits: u64 = match breakpoint.length {
+                    2 => 0b01,
+                    8 => 0b10,
+                    4 => 0b11,
+                    _ => 0b00
+                };
+                let length_bits = if breakpoint.kind == HwBreakpointKind::Execute { 0 } else { length_bits };
+
+                dr7 |= DR7_L0 << (slot * 2);
+                dr7 |= (breakpoint.kind as u64) << (DR7_RW_SHIFT + slot as u64 * 4);
+                dr7 |= length_bits << (DR7_LEN_SHIFT + slot as u64 * 4);
+                // Exact data breakpoint matching, recommended by the SDM whenever data breakpoints are used
+                dr7 |= DR7_LE;
+            }
+        }
+
+        dr7
+    }
+
+    /// Builds the structure HAX_IOCTL_VCPU_DEBUG takes. With nothing enabled the result turns debugging off.
+    pub fn to_hax_debug(self) -> hax_debug_t {
+        let mut control = 0;
+        if self.single_step {
+            control |= HAX_DEBUG_STEP;
+        }
+        if self.software_breakpoints {
+            control |= HAX_DEBUG_USE_SW_BP;
+        }
+        if self.hw_breakpoints.iter().any(|breakpoint| breakpoint.is_some()) {
+            control |= HAX_DEBUG_USE_HW_BP;
+        }
+        if control != 0 {
+            control |= HAX_DEBUG_ENABLE;
+        }
+
+        let mut dr = [0u64; 8];
+        for (slot, breakpoint) in self.hw_breakpoints.iter().enumerate() {
+            if let Some(breakpoint) = breakpoint {
+                dr[slot] = breakpoint.address;
+            }
+        }
+        dr[7] = self.dr7();
+
+        hax_debug_t {
+            control: control,
+            reserved: 0,
+            dr: dr
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DebugExitKind {
+    /// The instruction before `rip` completed with single-stepping on.
+    SingleStep,
+    /// The guest hit an INT3 at `rip`.
+    SoftwareBreakpoint,
+    /// Execution reached the instruction breakpoint in DRn, `rip` has not executed yet.
+    HwBreakpoint(usize),
+    /// The instruction before `rip` accessed the data breakpoint in DRn.
+    Watchpoint(usize)
+}
+
+/// A HAX_EXIT_DEBUG, decoded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DebugExit {
+    pub kind: DebugExitKind,
+    /// The guest's instruction pointer (relative to CS) when it exited.
+    pub rip: u64,
+    /// The address that triggered the exit: the linear address of the watched data for a watchpoint, the
+    /// instruction pointer otherwise.
+    pub address: u64,
+    pub dr6: u64
+}
+
+impl DebugExit {
+
+    /// Decodes the tunnel's debug information.
+    ///
+    /// # Arguments
+    ///
+    /// * `debug` - The debug member of the tunnel's union after a HAX_EXIT_DEBUG.
+    /// * `debug_state` - The configuration last sent with HAX_IOCTL_VCPU_DEBUG, used to tell instruction breakpoints
+    ///   from watchpoints and to find the watched address.
+    pub fn decode(debug: &hax_tunnel_debug, debug_state: &hax_debug_t) -> Self {
+        let rip = debug.rip;
+        let dr6 = debug.dr6;
+        let dr7 = debug_state.dr[7];
+
+        let triggered = (0..HW_BREAKPOINT_COUNT).find(|slot| dr6 & (DR6_B0 << slot) != 0);
+
+        let (kind, address) = match triggered {
+            Some(slot) => {
+                let rw = (dr7 >> (DR7_RW_SHIFT + slot as u64 * 4)) & 0b11;
+                if rw == HwBreakpointKind::Execute as u64 {
+                    (DebugExitKind::HwBreakpoint(slot), rip)
+                }
+                else {
+                    (DebugExitKind::Watchpoint(slot), debug_state.dr[slot])
+                }
+            }
+            None if dr6 & DR6_BS != 0 => (DebugExitKind::SingleStep, rip),
+            // HAXM reports #BP exits with DR6 clear
+            None => (DebugExitKind::SoftwareBreakpoint, rip)
+        };
+
+        DebugExit {
+            kind: kind,
+            rip: rip,
+            address: address,
+            dr6: dr6
+        }
+    }
+}
+
+/// INT3 breakpoints patched into guest memory, remembering the bytes they replaced.
+pub struct SoftwareBreakpoints {
+    saved_bytes: BTreeMap<u64, u8>
+}
+
+impl SoftwareBreakpoints {
+
+    /// Associated function constructor. Constructs an empty breakpoint set.
+    pub fn new() -> Self {
+        SoftwareBreakpoints {
+            saved_bytes: BTreeMap::new()
+        }
+    }
+
+    /// Patches an INT3 over the byte at guest physical `address`. Returns false if it is out of range or already set.
+    pub fn insert(&mut self, memory: &mut [u8], address: u64) -> bool {
+        if address as usize >= memory.len() || self.saved_bytes.contains_key(&address) {
+            return false;
+        }
+
+        self.saved_bytes.insert(address, memory[address as usize]);
+        memory[address as usize] = INT3_OPCODE;
+        true
+    }
+
+    /// Restores the byte an INT3 replaced. Returns false if there was no breakpoint at `address`.
+    pub fn remove(&mut self, memory: &mut [u8], address: u64) -> bool {
+        match self.saved_bytes.remove(&address) {
+            Some(byte) => {
+                memory[address as usize] = byte;
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Restores every patched byte.
+    pub fn remove_all(&mut self, memory: &mut [u8]) {
+        for (address, byte) in self.saved_bytes.iter() {
+            memory[*address as usize] = *byte;
+        }
+        self.saved_bytes.clear();
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        self.saved_bytes.contains_key(&address)
+    }
+
+    /// The byte the guest would see at `address` without the breakpoint, for reads that should not show the INT3.
+    pub fn original_byte(&self, address: u64) -> Option<u8> {
+        self.saved_bytes.get(&address).copied()
+    }
+
+    pub fn addresses(&self) -> Vec<u64> {
+        self.saved_bytes.keys().copied().collect()
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+
+use crate::assembler::{Assembler, Mode};
+
+// The guest's exception handling. A GDT describing the calculator's code and data segments and an IDT with a 32 bit
+// interrupt gate for each of the 32 exception vectors live at the top of the data segment. Every gate points at a
+// stub near the end of the code segment that pushes its vector (and a zero error code when the CPU doesn't push
+// one), then joins a common handler that pops the vector, error code, faulting EIP and CS into the mailbox and
+// halts. A HLT there is how the host tells a fault from the guest finishing.
+//
+// The initial state (fixtures/initial_state.json) points GDTR and IDTR at the tables and loads the selectors below.
+
+/// DS offset (and linear address) of the GDT: a null descriptor, then CODE_SELECTOR, DATA_SELECTOR and
+/// CODE32_SELECTOR.
+pub const GDT_BASE: u32 = 0x1E00;
+/// DS offset of the mailbox the fault handler fills: vector, error code, EIP and CS, a u32 each.
+pub const MAILBOX: u32 = 0x1E20;
+/// DS offset of the IDT.
+pub const IDT_BASE: u32 = 0x1F00;
+pub const EXCEPTION_VECTORS: usize = 32;
+/// CS offset of the stubs. Guest code loaded at the start of the code segment must stay below it.
+pub const STUBS_OFFSET: u32 = 0x1C00;
+/// CS offset of the HLT the fault handler ends in, the first byte of the stubs.
+pub const FAULT_HALT: u32 = STUBS_OFFSET;
+
+pub const CODE_SELECTOR: u16 = 0x08;
+pub const DATA_SELECTOR: u16 = 0x10;
+/// The code segment again, but 32 bit, see CalcVm.use_32bit_code().
+pub const CODE32_SELECTOR: u16 = 0x18;
+
+/// The mnemonic and name of each architecturally defined exception vector.
+const EXCEPTIONS: [(&str, &str); 22] = [
+    ("#DE", "division error"), ("#DB", "debug exception"), ("NMI", "non-maskable interrupt"), ("#BP", "breakpoint"),
+    ("#OF", "overflow"), ("#BR", "bound range exceeded"), ("#UD", "invalid opcode"), ("#NM", "device not available"),
+    ("#DF", "double fault"), ("", "coprocessor segment overrun"), ("#TS", "invalid TSS"), ("#NP", "segment not present"),
+    ("#SS", "stack-segment fault"), ("#GP", "general protection fault"), ("#PF", "page fault"), ("", "reserved exception"),
+    ("#MF", "x87 floating-point exception"), ("#AC", "alignment check"), ("#MC", "machine check"),
+    ("#XM", "SIMD floating-point exception"), ("#VE", "virtualization exception"), ("#CP", "control protection exception")
+];
+
+/// The mnemonic, e.g. `#DE`, and the name of exception `vector`.
+pub fn exception_name(vector: u8) -> (&'static str, &'static str) {
+    EXCEPTIONS.get(vector as usize).copied().unwrap_or(("", "reserved exception"))
+}
+
+/// Whether the CPU pushes an error code for exception `vector`.
+pub fn has_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10 | 11 | 12 | 13 | 14 | 17 | 21)
+}
+
+/// An exception the guest took, as its handler recorded it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GuestFault {
+    pub vector: u8,
+    /// None for the exceptions that don't have one.
+    pub error_code: Option<u32>,
+    /// The EIP the CPU pushed: the faulting instruction, or the one after it for traps such as INT3 and INTO.
+    pub eip: u32,
+    pub cs: u16,
+    /// The linear address of `eip`.
+    pub address: u64
+}
+
+impl GuestFault {
+
+    /// Decodes the mailbox the fault handler filled.
+    ///
+    /// # Arguments
+    ///
+    /// * `mailbox` - The 16 mailbox bytes.
+    /// * `code_base` - The base of the code segment, to turn EIP into a linear address.
+    pub fn from_mailbox(mailbox: &[u8], code_base: u64) -> Self {
+        let word = |index: usize| u32::from_le_bytes([mailbox[index * 4], mailbox[index * 4 + 1], mailbox[index * 4 + 2], mailbox[index * 4 + 3]]);
+        let vector = word(0) as u8;
+        GuestFault {
+            vector: vector,
+            error_code: if has_error_code(vector) { Some(word(1)) } else { None },
+            eip: word(2),
+            cs: word(3) as u16,
+            address: code_base + word(2) as u64
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        exception_name(self.vector).1
+    }
+}
+
+impl fmt::Display for GuestFault {
+
+    /// E.g. `division error at 0x2004`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#x}", self.name(), self.address)?;
+        if let Some(error_code) = self.error_code {
+            write!(f, " (error code {:#x})", error_code)?;
+        }
+        Ok(())
+    }
+}
+
+/// A byte granular GDT descriptor, 32 bit if `big` (the D/B flag) and 16 bit otherwise.
+fn segment_descriptor(base: u32, limit: u32, access: u8, big: bool) -> [u8; 8] {
+    [
+        limit as u8, (limit >> 8) as u8,
+        base as u8, (base >> 8) as u8, (base >> 16) as u8,
+        access,
+        ((limit >> 16) & 0xF) as u8 | if big { 0x40 } else { 0 },
+        (base >> 24) as u8
+    ]
+}
+
+/// A 32 bit interrupt gate to `selector`:`offset`.
+fn interrupt_gate(selector: u16, offset: u32) -> [u8; 8] {
+    [
+        offset as u8, (offset >> 8) as u8,
+        selector as u8, (selector >> 8) as u8,
+        0,
+        0x8E,
+        (offset >> 16) as u8, (offset >> 24) as u8
+    ]
+}
+
+/// Assembles the fault handler and the per vector stubs at STUBS_OFFSET. On failure returns the assembler's error.
+fn assemble_stubs() -> Result<(Vec<u8>, Vec<u32>), String> {
+    let mut assembler = Assembler::new(Mode::Bits16).origin(STUBS_OFFSET as u64)
+        .label("fault_halt")
+        .line("hlt")
+        .label("fault")
+        .line(&format!("pop dword [{:#x}]", MAILBOX))
+        .line(&format!("pop dword [{:#x}]", MAILBOX + 4))
+        .line(&format!("pop dword [{:#x}]", MAILBOX + 8))
+        .line(&format!("pop dword [{:#x}]", MAILBOX + 12))
+        .line("jmp fault_halt");
+
+    for vector in 0..EXCEPTION_VECTORS {
+        assembler = assembler.label(&format!("vector{}", vector));
+        if !has_error_code(vector as u8) {
+            assembler = assembler.line("push dword 0");
+        }
+        assembler = assembler
+            .line(&format!("push dword {}", vector))
+            .line("jmp fault");
+    }
+
+    let code = assembler.assemble()?;
+    let mut entries = vec!();
+    for vector in 0..EXCEPTION_VECTORS {
+        match code.address_of(&format!("vector{}", vector)) {
+            Some(address) => entries.push(address as u32),
+            None => return Err(format!("No stub for vector {}", vector))
+        }
+    }
+    Ok((code.bytes, entries))
+}
+
+/// Writes the GDT, the IDT and the stubs into guest memory. On failure returns a description of what went wrong.
+///
+/// # Arguments
+///
+/// * `memory` - All of guest physical memory.
+/// * `data_base` - The linear address of the data segment, which holds the tables.
+/// * `code_base` - The linear address of the code segment, which holds the stubs.
+/// * `code_limit` - The code segment's limit, for its descriptor.
+/// * `data_limit` - The data segment's limit, for its descriptor.
+pub fn install(memory: &mut [u8], data_base: u32, code_base: u32, code_limit: u32, data_limit: u32) -> Result<(), String> {
+    let (stubs, entries) = assemble_stubs()?;
+
+    let gdt = (data_base + GDT_BASE) as usize;
+    memory[gdt..gdt + 8].copy_from_slice(&[0; 8]);
+    memory[gdt + CODE_SELECTOR as usize..gdt + CODE_SELECTOR as usize + 8].copy_from_slice(&segment_descriptor(code_base, code_limit, 0x9B, false));
+    memory[gdt + DATA_SELECTOR as usize..gdt + DATA_SELECTOR as usize + 8].copy_from_slice(&segment_descriptor(data_base, data_limit, 0x93, false));
+    memory[gdt + CODE32_SELECTOR as usize..gdt + CODE32_SELECTOR as usize + 8].copy_from_slice(&segment_descriptor(code_base, code_limit, 0x9B, true));
+
+    let mailbox = (data_base + MAILBOX) as usize;
+    memory[mailbox..mailbox + 16].copy_from_slice(&[0; 16]);
+
+    let idt = (data_base + IDT_BASE) as usize;
+    for (vector, entry) in entries.iter().enumerate() {
+        memory[idt + vector * 8..idt + vector * 8 + 8].copy_from_slice(&interrupt_gate(CODE_SELECTOR, *entry));
+    }
+
+    let start = (code_base + STUBS_OFFSET) as usize;
+    memory[start..start + stubs.len()].copy_from_slice(&stubs);
+    Ok(())
+}
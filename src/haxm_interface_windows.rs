@@ -1,9 +1,14 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
+#[cfg(windows)]
 use winapi::shared::minwindef::*;
+#[cfg(windows)]
 use winapi::um::winioctl::*;
+#[cfg(windows)]
 use winapi::shared::basetsd::*;
+#[cfg(not(windows))]
+use crate::win32_unsupported::*;
 use modular_bitfield::prelude::*;
 
 // Almost everything here is from: https://github.com/intel/haxm/blob/master/docs/api.md
@@ -202,6 +207,197 @@ pub struct hax_tunnel_info {
     pub pad: [UINT16; 3],
 }
 
+// Exit statuses reported in hax_tunnel._exit_status after HAX_VCPU_IOCTL_RUN returns
+pub const HAX_EXIT_IO: UINT32           = 1;
+pub const HAX_EXIT_MMIO: UINT32         = 2;
+pub const HAX_EXIT_REAL: UINT32         = 3;
+pub const HAX_EXIT_INTERRUPT: UINT32    = 4;
+pub const HAX_EXIT_UNKNOWN: UINT32      = 5;
+pub const HAX_EXIT_HLT: UINT32          = 6;
+pub const HAX_EXIT_STATECHANGE: UINT32  = 7;
+pub const HAX_EXIT_PAUSED: UINT32       = 8;
+pub const HAX_EXIT_FAST_MMIO: UINT32    = 9;
+pub const HAX_EXIT_PAGEFAULT: UINT32    = 10;
+pub const HAX_EXIT_DEBUG: UINT32        = 11;
+
+pub const HAX_IO_OUT: UINT8 = 0;
+pub const HAX_IO_IN: UINT8  = 1;
+
+// Set in hax_tunnel_io._flags for string (INS/OUTS) instructions
+pub const HAX_IO_FLAG_STRING: UINT8 = 1;
+
+// The hax_tunnel members are prefixed with an underscore in the original header as well
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct hax_tunnel_io {
+    pub _direction: UINT8,
+    pub _df: UINT8,
+    pub _size: UINT16,
+    pub _port: UINT16,
+    pub _count: UINT16,
+    pub _flags: UINT8,
+    pub _pad0: UINT8,
+    pub _pad1: UINT16,
+    pub _pad2: UINT32,
+    pub _vaddr: UINT64
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct hax_tunnel_mmio {
+    pub gla: UINT64
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct hax_tunnel_debug {
+    pub rip: UINT64,
+    pub dr6: UINT64,
+    pub dr7: UINT64
+}
+
+#[repr(C, packed)]
+pub union hax_tunnel_anon_union {
+    pub io: hax_tunnel_io,
+    pub mmio: hax_tunnel_mmio,
+    pub debug: hax_tunnel_debug,
+    pub pad: [UINT64; 4]
+}
+
+// Original structure has __attribute__ ((__packed__));
+// This lives in the page pointed to by hax_tunnel_info.va and is written by the driver on every VM-exit
+#[repr(C, packed)]
+pub struct hax_tunnel {
+    pub _exit_reason: UINT32,
+    pub pad0: UINT32,
+    pub _exit_status: UINT32,
+    pub user_event_pending: UINT32,
+    pub ready_for_interrupt_injection: INT32,
+    pub request_interrupt_window: INT32,
+    pub anon_union: hax_tunnel_anon_union,
+    pub apic_base: UINT64
+}
+
+// MSR indexes from the Intel SDM Vol. 4 which are useful to name
+pub const IA32_TIME_STAMP_COUNTER: UINT64  = 0x10;
+pub const IA32_APIC_BASE: UINT64           = 0x1B;
+pub const IA32_SYSENTER_CS: UINT64         = 0x174;
+pub const IA32_SYSENTER_ESP: UINT64        = 0x175;
+pub const IA32_SYSENTER_EIP: UINT64        = 0x176;
+pub const IA32_PAT: UINT64                 = 0x277;
+pub const IA32_EFER: UINT64                = 0xC0000080;
+
+// The most MSRs a single HAX_VCPU_IOCTL_GET_MSRS/HAX_VCPU_IOCTL_SET_MSRS can carry
+pub const HAX_MAX_MSR_ARRAY: usize = 0x20;
+
+// Original structure has __attribute__ ((__packed__));
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct vmx_msr {
+    pub entry: UINT64,
+    pub value: UINT64
+}
+
+// Original structure has __attribute__ ((__packed__));
+#[repr(C, packed)]
+pub struct hax_msr_data {
+    pub nr_msr: UINT16,
+    pub done: UINT16,
+    pub pad: [UINT16; 2],
+    pub entries: [vmx_msr; HAX_MAX_MSR_ARRAY]
+}
+
+// The FXSAVE/FXRSTOR memory image, used by HAX_VCPU_IOCTL_GET_FPU and HAX_VCPU_IOCTL_SET_FPU
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct fx_layout_anon_union_1_anon_struct {
+    pub fip: UINT32,
+    pub fcs: UINT16,
+    pub res2: UINT16
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union fx_layout_anon_union_1 {
+    pub anon_struct: fx_layout_anon_union_1_anon_struct,
+    pub fpu_ip: UINT64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct fx_layout_anon_union_2_anon_struct {
+    pub fdp: UINT32,
+    pub fds: UINT16,
+    pub res3: UINT16
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union fx_layout_anon_union_2 {
+    pub anon_struct: fx_layout_anon_union_2_anon_struct,
+    pub fpu_dp: UINT64
+}
+
+// Original structure has ALIGNED(16)
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct fx_layout {
+    pub fcw: UINT16,
+    pub fsw: UINT16,
+    pub ftw: UINT8,
+    pub res1: UINT8,
+    pub fop: UINT16,
+    pub anon_union_1: fx_layout_anon_union_1,
+    pub anon_union_2: fx_layout_anon_union_2,
+    pub mxcsr: UINT32,
+    pub mxcsr_mask: UINT32,
+    pub st_mm: [[UINT8; 16]; 8],
+    pub mmx_1: [[UINT8; 16]; 8], // XMM0 - XMM7
+    pub mmx_2: [[UINT8; 16]; 8], // XMM8 - XMM15
+    pub pad: [UINT8; 96]
+}
+
+// The most leaves a single HAX_VCPU_IOCTL_SET_CPUID/HAX_VCPU_IOCTL_GET_CPUID can carry
+pub const HAX_MAX_CPUID_ENTRIES: usize = 0x40;
+
+// Original structure has __attribute__ ((__packed__));
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct hax_cpuid_entry {
+    pub function: UINT32,
+    pub index: UINT32,
+    pub flags: UINT32,
+    pub eax: UINT32,
+    pub ebx: UINT32,
+    pub ecx: UINT32,
+    pub edx: UINT32,
+    pub pad: [UINT32; 3]
+}
+
+// Original structure has __attribute__ ((__packed__));
+// In the original the entries are a flexible array member (entries[0]), only header + total entries are sent
+#[repr(C, packed)]
+pub struct hax_cpuid {
+    pub total: UINT32,
+    pub pad: UINT32,
+    pub entries: [hax_cpuid_entry; HAX_MAX_CPUID_ENTRIES]
+}
+
+// hax_debug_t.control bits
+pub const HAX_DEBUG_ENABLE: UINT32    = 1 << 0;
+pub const HAX_DEBUG_STEP: UINT32      = 1 << 1;
+pub const HAX_DEBUG_USE_SW_BP: UINT32 = 1 << 2;
+pub const HAX_DEBUG_USE_HW_BP: UINT32 = 1 << 3;
+
+// Original structure has __attribute__ ((__packed__));
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct hax_debug_t {
+    pub control: UINT32,
+    pub reserved: UINT32,
+    pub dr: [UINT64; 8]
+}
+
 
 pub const HAX_DEVICE_TYPE: DWORD    =  0x4000;
 //
@@ -239,6 +435,6 @@ pub const HAX_VCPU_IOCTL_KICKOFF: DWORD    = CTL_CODE_MACRO!(HAX_DEVICE_TYPE, 0x
 /* API version 2.0 */
 //pub const HAX_VM_IOCTL_NOTIFY_QEMU_VERSION: DWORD  = CTL_CODE_MACRO!(HAX_DEVICE_TYPE, 0x910, METHOD_BUFFERED, FILE_ANY_ACCESS);
 //
-//const HAX_IOCTL_VCPU_DEBUG: DWORD      = CTL_CODE_MACRO(HAX_DEVICE_TYPE, 0x916, METHOD_BUFFERED, FILE_ANY_ACCESS);
-//const HAX_VCPU_IOCTL_SET_CPUID: DWORD  = CTL_CODE_MACRO(HAX_DEVICE_TYPE, 0x917, METHOD_BUFFERED, FILE_ANY_ACCESS);
-//const HAX_VCPU_IOCTL_GET_CPUID: DWORD  = CTL_CODE_MACRO(HAX_DEVICE_TYPE, 0x918, METHOD_BUFFERED, FILE_ANY_ACCESS);
\ No newline at end of file
+pub const HAX_IOCTL_VCPU_DEBUG: DWORD      = CTL_CODE_MACRO!(HAX_DEVICE_TYPE, 0x916, METHOD_BUFFERED, FILE_ANY_ACCESS);
+pub const HAX_VCPU_IOCTL_SET_CPUID: DWORD  = CTL_CODE_MACRO!(HAX_DEVICE_TYPE, 0x917, METHOD_BUFFERED, FILE_ANY_ACCESS);
+pub const HAX_VCPU_IOCTL_GET_CPUID: DWORD  = CTL_CODE_MACRO!(HAX_DEVICE_TYPE, 0x918, METHOD_BUFFERED, FILE_ANY_ACCESS);
\ No newline at end of file
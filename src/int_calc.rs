@@ -0,0 +1,349 @@
+use crate::assembler::{Assembler, Mode};
+use crate::calculator::CalcVm;
+use crate::radix;
+use crate::vcpu_regs::*;
+
+// Integer operations run as a few instructions on EAX (first operand) and ECX (second operand). Results come back
+// in EAX, or EDX:EAX for the widening multiplies and divides, and the flags in EFLAGS.
+//
+// Whether a result fitted is the guest CPU's call: CF says an unsigned result didn't, OF a signed one. The host only
+// applies the OverflowPolicy to what the flags say.
+
+/// How operands and results are read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Signedness {
+    Unsigned,
+    /// Two's complement.
+    Signed
+}
+
+impl Signedness {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unsigned" | "u" => Some(Signedness::Unsigned),
+            "signed" | "s" => Some(Signedness::Signed),
+            _ => None
+        }
+    }
+
+    /// Parses an operand, u32 or i32 depending on the signedness, to its bits. Any literal radix::parse_u64() reads
+    /// will do; hex, binary, octal and character literals give the bits directly, so `0xffffffff` is -1 when signed.
+    pub fn parse(&self, text: &str) -> Result<u32, String> {
+        match self {
+            Signedness::Unsigned => match radix::parse_u64(text) {
+                Ok(value) if value <= u32::MAX as u64 => Ok(value as u32),
+                _ => Err(format!("{} is not an unsigned 32 bit number", text))
+            },
+            Signedness::Signed => {
+                let (negative, literal) = match text.strip_prefix('-') {
+                    Some(literal) => (true, literal),
+                    None => (false, text)
+                };
+                let limit = if negative { 1 << 31 } else if radix::is_decimal(literal) { i32::MAX as u64 } else { u32::MAX as u64 };
+                match radix::parse_u64(literal) {
+                    Ok(value) if value <= limit => Ok(if negative { (value as u32).wrapping_neg() } else { value as u32 }),
+                    _ => Err(format!("{} is not a signed 32 bit number", text))
+                }
+            }
+        }
+    }
+
+    pub fn format(&self, value: u32) -> String {
+        match self {
+            Signedness::Unsigned => value.to_string(),
+            Signedness::Signed => (value as i32).to_string()
+        }
+    }
+
+    /// The EFLAGS bit the CPU sets when a result doesn't fit: CF for unsigned, OF for signed.
+    pub fn overflow_flag(&self) -> u32 {
+        match self {
+            Signedness::Unsigned => EFLAGS_CF,
+            Signedness::Signed => EFLAGS_OF
+        }
+    }
+}
+
+/// What to do with a result that doesn't fit in 32 bits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverflowPolicy {
+    /// Keep the low 32 bits, like the CPU does.
+    Wrapping,
+    /// Fail.
+    Checked,
+    /// Clamp to the largest or smallest value in the direction the result overflowed.
+    Saturating
+}
+
+impl OverflowPolicy {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wrapping" | "wrap" => Some(OverflowPolicy::Wrapping),
+            "checked" | "check" => Some(OverflowPolicy::Checked),
+            "saturating" | "sat" => Some(OverflowPolicy::Saturating),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IntOp {
+    Add,
+    Sub,
+    /// MUL, the 64 bit product in EDX:EAX.
+    Mul,
+    /// IMUL, the 64 bit signed product in EDX:EAX.
+    Imul,
+    /// DIV, the quotient in EAX and the remainder in EDX.
+    Div,
+    /// IDIV, the quotient in EAX and the remainder in EDX.
+    Idiv,
+    /// DIV, with the remainder as the result.
+    Mod,
+    /// IDIV, with the remainder as the result.
+    Imod,
+    And,
+    Or,
+    Xor,
+    /// Of the first operand, the second is ignored.
+    Not,
+    /// Of the first operand, the second is ignored.
+    Neg,
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+    /// CMP, only the flags are of interest.
+    Compare
+}
+
+impl IntOp {
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add" | "+" => Some(IntOp::Add),
+            "sub" | "-" => Some(IntOp::Sub),
+            "mul" | "*" => Some(IntOp::Mul),
+            "imul" => Some(IntOp::Imul),
+            "div" | "/" => Some(IntOp::Div),
+            "idiv" => Some(IntOp::Idiv),
+            "mod" | "%" => Some(IntOp::Mod),
+            "imod" => Some(IntOp::Imod),
+            "and" | "&" => Some(IntOp::And),
+            "or" | "|" => Some(IntOp::Or),
+            "xor" | "^" => Some(IntOp::Xor),
+            "not" | "~" => Some(IntOp::Not),
+            "neg" => Some(IntOp::Neg),
+            "shl" | "<<" => Some(IntOp::Shl),
+            "shr" | ">>" => Some(IntOp::Shr),
+            "sar" => Some(IntOp::Sar),
+            "rol" => Some(IntOp::Rol),
+            "ror" => Some(IntOp::Ror),
+            "cmp" => Some(IntOp::Compare),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            IntOp::Add => "+",
+            IntOp::Sub => "-",
+            IntOp::Mul => "*",
+            IntOp::Imul => "imul",
+            IntOp::Div => "/",
+            IntOp::Idiv => "idiv",
+            IntOp::Mod => "%",
+            IntOp::Imod => "imod",
+            IntOp::And => "&",
+            IntOp::Or => "|",
+            IntOp::Xor => "^",
+            IntOp::Not => "~",
+            IntOp::Neg => "neg",
+            IntOp::Shl => "<<",
+            IntOp::Shr => ">>",
+            IntOp::Sar => "sar",
+            IntOp::Rol => "rol",
+            IntOp::Ror => "ror",
+            IntOp::Compare => "cmp"
+        }
+    }
+
+    /// Whether the operation only takes one operand.
+    pub fn is_unary(&self) -> bool {
+        matches!(self, IntOp::Not | IntOp::Neg)
+    }
+
+    /// Whether the operands and results are two's complement.
+    pub fn is_signed(&self) -> bool {
+        matches!(self, IntOp::Imul | IntOp::Idiv | IntOp::Imod | IntOp::Sar)
+    }
+
+    /// Whether an OverflowPolicy applies: the arithmetic that sets CF and OF for results that don't fit.
+    pub fn can_overflow(&self) -> bool {
+        matches!(self, IntOp::Add | IntOp::Sub | IntOp::Mul | IntOp::Imul | IntOp::Neg)
+    }
+
+    /// The variant of the operation that reads its operands with `signedness`, e.g. IntOp::Imul for IntOp::Mul and
+    /// signed. Operations that don't care are returned as they are.
+    pub fn with_signedness(self, signedness: Signedness) -> Self {
+        let signed = signedness == Signedness::Signed;
+        match self {
+            IntOp::Mul | IntOp::Imul => if signed { IntOp::Imul } else { IntOp::Mul },
+            IntOp::Div | IntOp::Idiv => if signed { IntOp::Idiv } else { IntOp::Div },
+            IntOp::Mod | IntOp::Imod => if signed { IntOp::Imod } else { IntOp::Mod },
+            IntOp::Shr | IntOp::Sar => if signed { IntOp::Sar } else { IntOp::Shr },
+            op => op
+        }
+    }
+
+    /// The value an overflowing `a op b` saturates to. Which way it overflowed follows from the operands' signs.
+    fn saturation_bound(&self, signedness: Signedness, a: u32, b: u32) -> u32 {
+        let (a, b) = (a as i32, b as i32);
+        match (signedness, self) {
+            (Signedness::Unsigned, IntOp::Sub) | (Signedness::Unsigned, IntOp::Neg) => 0,
+            (Signedness::Unsigned, _) => u32::MAX,
+            (Signedness::Signed, IntOp::Add) => if b < 0 { i32::MIN as u32 } else { i32::MAX as u32 },
+            (Signedness::Signed, IntOp::Sub) => if b < 0 { i32::MAX as u32 } else { i32::MIN as u32 },
+            (Signedness::Signed, IntOp::Neg) => i32::MAX as u32,
+            (Signedness::Signed, _) => if (a < 0) != (b < 0) { i32::MIN as u32 } else { i32::MAX as u32 }
+        }
+    }
+}
+
+/// The guest's registers after an IntOp.
+#[derive(Debug)]
+pub struct IntResult {
+    /// EAX, or EDX (the remainder) for IntOp::Mod and IntOp::Imod.
+    pub value: u32,
+    /// EDX: the high half of a product or the remainder of a division, zero otherwise.
+    pub high: u32,
+    pub eflags: u32
+}
+
+impl IntResult {
+
+    pub fn carry(&self) -> bool {
+        self.eflags & EFLAGS_CF != 0
+    }
+
+    pub fn overflow(&self) -> bool {
+        self.eflags & EFLAGS_OF != 0
+    }
+
+    pub fn zero(&self) -> bool {
+        self.eflags & EFLAGS_ZF != 0
+    }
+
+    pub fn sign(&self) -> bool {
+        self.eflags & EFLAGS_SF != 0
+    }
+
+    /// Whether the result doesn't fit in 32 bits read with `signedness`, going by the flag the CPU set.
+    pub fn overflowed(&self, signedness: Signedness) -> bool {
+        self.eflags & signedness.overflow_flag() != 0
+    }
+
+    /// For IntOp::Compare, how the first operand relates to the second: `(unsigned, signed)` as the conditions
+    /// JB/JE/JA and JL/JE/JG test them.
+    pub fn comparison(&self) -> (&'static str, &'static str) {
+        if self.zero() {
+            return ("equal", "equal");
+        }
+        let unsigned = if self.carry() { "below" } else { "above" };
+        let signed = if self.sign() != self.overflow() { "less" } else { "greater" };
+        (unsigned, signed)
+    }
+}
+
+/// Builds the guest code for `op`: the operation on EAX and ECX (CL for shifts and rotates), then HLT.
+fn guest_code(op: IntOp) -> Result<Vec<u8>, String> {
+    let operation: &[&str] = match op {
+        IntOp::Add => &["add eax, ecx"],
+        IntOp::Sub => &["sub eax, ecx"],
+        IntOp::Mul => &["mul ecx"],
+        IntOp::Imul => &["imul ecx"],
+        IntOp::Div | IntOp::Mod => &["div ecx"],
+        IntOp::Idiv | IntOp::Imod => &["cdq", "idiv ecx"],
+        IntOp::And => &["and eax, ecx"],
+        IntOp::Or => &["or eax, ecx"],
+        IntOp::Xor => &["xor eax, ecx"],
+        IntOp::Not => &["not eax"],
+        IntOp::Neg => &["neg eax"],
+        IntOp::Shl => &["shl eax, cl"],
+        IntOp::Shr => &["shr eax, cl"],
+        IntOp::Sar => &["sar eax, cl"],
+        IntOp::Rol => &["rol eax, cl"],
+        IntOp::Ror => &["ror eax, cl"],
+        IntOp::Compare => &["cmp eax, ecx"]
+    };
+
+    let mut assembler = Assembler::new(Mode::Bits16).line("xor edx, edx");
+    for line in operation.iter() {
+        assembler = assembler.line(line);
+    }
+    Ok(assembler.line("hlt").assemble()?.bytes)
+}
+
+/// Runs `a op b` on the guest CPU.
+///
+/// # Arguments
+///
+/// * `calc_vm` - The VM to run in. Its code and registers are overwritten.
+/// * `op` - The operation.
+/// * `a` - The first operand. Two's complement for the signed operations.
+/// * `b` - The second operand, the count for shifts and rotates (the CPU masks it to 5 bits). Ignored by unary
+///   operations.
+pub fn run_int_op(calc_vm: &mut CalcVm, op: IntOp, a: u32, b: u32) -> Result<IntResult, String> {
+    calc_vm.load_code(&guest_code(op)?)?;
+    calc_vm.reset_cpu_state();
+    {
+        let cpu_state = &mut calc_vm.vcpu().cpu_state;
+        cpu_state.set_gpr32(REG_RAX, a);
+        cpu_state.set_gpr32(REG_RCX, b);
+    }
+
+    calc_vm.run()?;
+
+    let cpu_state = &calc_vm.vcpu().cpu_state;
+    let value = match op {
+        IntOp::Mod | IntOp::Imod => cpu_state.gpr32(REG_RDX),
+        _ => cpu_state.gpr32(REG_RAX)
+    };
+    Ok(IntResult {
+        value: value,
+        high: cpu_state.gpr32(REG_RDX),
+        eflags: cpu_state.eflags()
+    })
+}
+
+/// Like run_int_op(), but applies `policy` to results the guest flagged as not fitting. `op` is first switched to its
+/// variant for `signedness`. Operations that can't overflow (see IntOp::can_overflow()) are returned unchanged.
+/// On failure, including an overflow under OverflowPolicy::Checked, returns a description of what went wrong.
+pub fn run_int_op_with_policy(calc_vm: &mut CalcVm, op: IntOp, a: u32, b: u32, signedness: Signedness, policy: OverflowPolicy)
+    -> Result<IntResult, String> {
+    let op = op.with_signedness(signedness);
+    let mut result = run_int_op(calc_vm, op, a, b)?;
+
+    if !op.can_overflow() || !result.overflowed(signedness) {
+        return Ok(result);
+    }
+
+    match policy {
+        OverflowPolicy::Wrapping => {}
+        OverflowPolicy::Checked => {
+            let flag = if signedness == Signedness::Signed { "OF" } else { "CF" };
+            let expression = if op.is_unary() {
+                format!("{} {}", op.symbol(), signedness.format(a))
+            }
+            else {
+                format!("{} {} {}", signedness.format(a), op.symbol(), signedness.format(b))
+            };
+            return Err(format!("Overflow: {} doesn't fit in 32 bits {} ({} set)", expression, if signedness == Signedness::Signed { "signed" } else { "unsigned" }, flag));
+        }
+        OverflowPolicy::Saturating => result.value = op.saturation_bound(signedness, a, b)
+    }
+    Ok(result)
+}
@@ -1,27 +1,65 @@
-use std::ptr;
+use std::io::BufRead;
 
-use winapi::um::memoryapi::VirtualAlloc;
-use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE, PAGE_EXECUTE_READWRITE};
-use winapi::um::errhandlingapi::GetLastError;
-use winapi::shared::basetsd::UINT8;
-
-
-const RAM_SIZE: u32 = 0x4000;
+use assembler::{Assembler, Mode};
+use calculator::{CalcVm, RunError};
+use cmos::{Cmos, RtcClock, CMOS_NVRAM_SIZE};
+use debugger::Debugger;
+use gdbstub::GdbStub;
+use monitor::Monitor;
+use rpn::RpnCalculator;
+use session::ExpressionSession;
+use float_calc::{FloatOp, Precision};
+use int_calc::{IntOp, OverflowPolicy, Signedness};
+use bignum::{BigInt, BigOp};
+use bcd::{BcdOp, Decimal};
+use radix::Radix;
+use state_file::VcpuSnapshot;
+use snapshot::VmSnapshot;
+use trace::{TraceFilter, Tracer};
+use vcpu_regs::{EFLAGS_CF, EFLAGS_OF, EFLAGS_SF, EFLAGS_ZF, REG_RAX, REG_RCX};
 
 mod haxm_interface_windows;
+#[cfg(not(windows))]
+mod win32_unsupported;
+mod port_io;
+mod cmos;
+mod fpu;
+mod vcpu_regs;
+mod state_dump;
+mod state_file;
+mod snapshot;
+mod guest_fault;
+mod calculator;
+mod float_calc;
+mod int_calc;
+mod bignum;
+mod bcd;
+mod number_theory;
+mod radix;
+mod expression;
+mod variables;
+mod session;
+mod rpn;
+mod cpuid;
+mod guest_debug;
+mod gdbstub;
+mod debugger;
+mod monitor;
+mod disasm;
+mod assembler;
+mod trace;
 
 mod haxm {
     
     use std::mem;
     use std::ptr;
-    use winapi::um::winnt::*;
-    use winapi::um::fileapi::*;
-    use winapi::shared::minwindef::*;
-    use winapi::um::errhandlingapi::*;
-    use winapi::um::ioapiset::*;
-    use winapi::ctypes::*;
-    use winapi::shared::basetsd::*;
+    #[cfg(windows)]
+    use winapi::{um::winnt::*, um::fileapi::*, shared::minwindef::*, um::errhandlingapi::*, um::ioapiset::*, ctypes::*, shared::basetsd::*, shared::winerror::ERROR_INVALID_PARAMETER};
+    #[cfg(not(windows))]
+    use crate::win32_unsupported::*;
     use crate::haxm_interface_windows::*;
+    use crate::port_io::PortIoBus;
+    use crate::guest_debug::{DebugConfig, DebugExit};
 
     /// Helper function because winapi booleans are rust i32s.
     pub fn win_bool_eval(input: BOOL) -> bool {
@@ -33,10 +71,27 @@ mod haxm {
         }
     }
 
+    /// Why HaxmVCPU.run_until_exit() handed control back.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum VcpuExit {
+        /// The guest executed HLT.
+        Halt,
+        /// The vCPU shut down, usually because of a triple fault.
+        StateChange,
+        /// The guest touched guest physical memory that is not backed by RAM.
+        Mmio { gla: UINT64 },
+        /// A single-step, breakpoint or watchpoint enabled through set_debug() triggered.
+        Debug(DebugExit),
+        /// Any other HAX_EXIT_* status the run loop does not know how to continue from.
+        Unhandled(UINT32)
+    }
+
     pub struct HaxmVCPU {
         pub vcpu_handle: HANDLE,
         pub id: UINT32,
         pub cpu_state: vcpu_state_t,
+        pub fpu_state: fx_layout,
+        pub debug_state: hax_debug_t,
         pub tunnel: hax_tunnel_info
     }
     
@@ -50,7 +105,7 @@ mod haxm {
         /// * `vm_id` - The ID of the parent VM creating this vcpu.`
         pub fn new(id: UINT32, vm_id: UINT32) -> Result<Self, DWORD> {
             unsafe {
-                let formatted_name = format!("\\\\.\\hax_vm{:02}_vcpu{:02}\0", id, vm_id);
+                let formatted_name = format!("\\\\.\\hax_vm{:02}_vcpu{:02}\0", vm_id, id);
                 let vm_name: &str = &formatted_name;
                 let vcpu_handle = CreateFileA(vm_name as *const str as *const i8, GENERIC_READ | GENERIC_WRITE, 0, ptr::null_mut(),
                                                             OPEN_EXISTING, 0, ptr::null_mut());
@@ -58,6 +113,8 @@ mod haxm {
                     vcpu_handle: vcpu_handle,
                     id: id,
                     cpu_state: mem::zeroed::<vcpu_state_t>(),
+                    fpu_state: mem::zeroed::<fx_layout>(),
+                    debug_state: mem::zeroed::<hax_debug_t>(),
                     tunnel: mem::zeroed::<hax_tunnel_info>()
                 };
     
@@ -141,6 +198,249 @@ mod haxm {
                 }
             }
         }
+
+        /// Gets the vCPU's x87/SSE state (the FXSAVE image) into fpu_state. On success returns None, else returns the
+        /// value of GetLastError().
+        pub fn get_fpu(&mut self) -> Option<DWORD> {
+            unsafe {
+                let was_successful = DeviceIoControl(self.vcpu_handle, HAX_VCPU_IOCTL_GET_FPU, ptr::null_mut(), 0,
+                    &mut self.fpu_state as *mut fx_layout as *mut c_void,
+                    mem::size_of_val(&self.fpu_state) as u32, ptr::null_mut(), ptr::null_mut());
+
+                if win_bool_eval(was_successful) {
+                    None
+                }
+                else {
+                    Some(GetLastError())
+                }
+            }
+        }
+
+        /// Sets the vCPU's x87/SSE state from fpu_state. On success returns None, else returns the value of GetLastError().
+        pub fn set_fpu(&mut self) -> Option<DWORD> {
+            unsafe {
+                let was_successful = DeviceIoControl(self.vcpu_handle, HAX_VCPU_IOCTL_SET_FPU,
+                    &mut self.fpu_state as *mut fx_layout as *mut c_void,
+                    mem::size_of_val(&self.fpu_state) as u32, ptr::null_mut(), 0, ptr::null_mut(), ptr::null_mut());
+
+                if win_bool_eval(was_successful) {
+                    None
+                }
+                else {
+                    Some(GetLastError())
+                }
+            }
+        }
+
+        /// Gets the CPUID leaves the vCPU reports to the guest. On success returns the leaves, else returns the value of
+        /// GetLastError().
+        pub fn get_cpuid(&self) -> Result<Vec<hax_cpuid_entry>, DWORD> {
+            unsafe {
+                let mut cpuid = mem::zeroed::<hax_cpuid>();
+                cpuid.total = HAX_MAX_CPUID_ENTRIES as UINT32;
+
+                let was_successful = DeviceIoControl(self.vcpu_handle, HAX_VCPU_IOCTL_GET_CPUID,
+                    &mut cpuid as *mut hax_cpuid as *mut c_void, mem::size_of_val(&cpuid) as u32,
+                    &mut cpuid as *mut hax_cpuid as *mut c_void, mem::size_of_val(&cpuid) as u32,
+                    ptr::null_mut(), ptr::null_mut());
+
+                if win_bool_eval(was_successful) {
+                    let total = (cpuid.total as usize).min(HAX_MAX_CPUID_ENTRIES);
+                    Ok(cpuid.entries[..total].to_vec())
+                }
+                else {
+                    Err(GetLastError())
+                }
+            }
+        }
+
+        /// Overrides the CPUID leaves the vCPU reports to the guest. HAXM validates the table and will not expose
+        /// features the host or the driver lack. On success returns None, else returns the value of GetLastError().
+        ///
+        /// # Arguments
+        ///
+        /// * `entries` - The leaves. At most HAX_MAX_CPUID_ENTRIES, a longer table fails with ERROR_INVALID_PARAMETER.
+        pub fn set_cpuid(&self, entries: &[hax_cpuid_entry]) -> Option<DWORD> {
+            if entries.len() > HAX_MAX_CPUID_ENTRIES {
+                return Some(ERROR_INVALID_PARAMETER);
+            }
+
+            unsafe {
+                let total = entries.len();
+                let mut cpuid = mem::zeroed::<hax_cpuid>();
+                cpuid.total = total as UINT32;
+                cpuid.entries[..total].copy_from_slice(&entries[..total]);
+
+                // Only the header and the used entries, like the flexible array in the original structure
+                let size = mem::size_of::<hax_cpuid>() - (HAX_MAX_CPUID_ENTRIES - total) * mem::size_of::<hax_cpuid_entry>();
+
+                let was_successful = DeviceIoControl(self.vcpu_handle, HAX_VCPU_IOCTL_SET_CPUID,
+                    &mut cpuid as *mut hax_cpuid as *mut c_void, size as u32, ptr::null_mut(), 0,
+                    ptr::null_mut(), ptr::null_mut());
+
+                if win_bool_eval(was_successful) {
+                    None
+                }
+                else {
+                    Some(GetLastError())
+                }
+            }
+        }
+
+        /// Enables or disables single-stepping and breakpoints for the vCPU. On success returns None and debug_state holds
+        /// the configuration, else returns the value of GetLastError().
+        ///
+        /// # Arguments
+        ///
+        /// * `config` - What to enable. DebugConfig::default() turns debugging off.
+        pub fn set_debug(&mut self, config: &DebugConfig) -> Option<DWORD> {
+            unsafe {
+                let mut debug_state = config.to_hax_debug();
+
+                let was_successful = DeviceIoControl(self.vcpu_handle, HAX_IOCTL_VCPU_DEBUG,
+                    &mut debug_state as *mut hax_debug_t as *mut c_void,
+                    mem::size_of_val(&debug_state) as u32, ptr::null_mut(), 0, ptr::null_mut(), ptr::null_mut());
+
+                if win_bool_eval(was_successful) {
+                    self.debug_state = debug_state;
+                    None
+                }
+                else {
+                    Some(GetLastError())
+                }
+            }
+        }
+
+        /// Issues one HAX_VCPU_IOCTL_GET_MSRS or HAX_VCPU_IOCTL_SET_MSRS per HAX_MAX_MSR_ARRAY sized chunk of `msrs`.
+        /// Returns how many entries, from the start of `msrs`, the driver processed.
+        fn transfer_msrs(&self, ioctl: DWORD, msrs: &mut [vmx_msr]) -> Result<usize, DWORD> {
+            let mut total_done = 0;
+
+            for chunk in msrs.chunks_mut(HAX_MAX_MSR_ARRAY) {
+                unsafe {
+                    let mut msr_data = mem::zeroed::<hax_msr_data>();
+                    msr_data.nr_msr = chunk.len() as UINT16;
+                    msr_data.entries[..chunk.len()].copy_from_slice(chunk);
+
+                    let was_successful = DeviceIoControl(self.vcpu_handle, ioctl,
+                        &mut msr_data as *mut hax_msr_data as *mut c_void, mem::size_of_val(&msr_data) as u32,
+                        &mut msr_data as *mut hax_msr_data as *mut c_void, mem::size_of_val(&msr_data) as u32,
+                        ptr::null_mut(), ptr::null_mut());
+
+                    if !win_bool_eval(was_successful) {
+                        return Err(GetLastError());
+                    }
+
+                    let done = (msr_data.done as usize).min(chunk.len());
+                    chunk[..done].copy_from_slice(&msr_data.entries[..done]);
+                    total_done += done;
+
+                    // The driver stops at the first MSR it refuses, so later chunks would be out of order
+                    if done < chunk.len() {
+                        break;
+                    }
+                }
+            }
+
+            Ok(total_done)
+        }
+
+        /// Reads MSRs from the vCPU. On success returns how many entries, from the start of `msrs`, were read. This is
+        /// less than `msrs.len()` if HAXM refused an MSR; the entry at the returned index is the one it refused.
+        /// On failure returns the value of GetLastError().
+        ///
+        /// # Arguments
+        ///
+        /// * `msrs` - The MSRs to read. The `entry` member of each element selects the MSR (see the IA32_* constants),
+        ///   the `value` member is filled in.
+        pub fn get_msrs(&self, msrs: &mut [vmx_msr]) -> Result<usize, DWORD> {
+            self.transfer_msrs(HAX_VCPU_IOCTL_GET_MSRS, msrs)
+        }
+
+        /// Writes MSRs of the vCPU. On success returns how many entries, from the start of `msrs`, were written. This is
+        /// less than `msrs.len()` if HAXM refused an MSR; the entry at the returned index is the one it refused.
+        /// On failure returns the value of GetLastError().
+        ///
+        /// # Arguments
+        ///
+        /// * `msrs` - The MSR indexes (see the IA32_* constants) and the values to write to them.
+        pub fn set_msrs(&self, msrs: &[vmx_msr]) -> Result<usize, DWORD> {
+            let mut msrs = msrs.to_vec();
+            self.transfer_msrs(HAX_VCPU_IOCTL_SET_MSRS, &mut msrs)
+        }
+
+        /// Returns the exit status (one of the HAX_EXIT_* values) of the last VM-exit. Requires setup_vcpu_tunnel().
+        pub fn exit_status(&self) -> UINT32 {
+            unsafe {
+                let tunnel = self.tunnel.va as *const hax_tunnel;
+                ptr::read_unaligned(ptr::addr_of!((*tunnel)._exit_status))
+            }
+        }
+
+        /// Services a HAX_EXIT_IO by moving the data between the tunnel's I/O buffer and the bus.
+        fn handle_io_exit(&self, io_bus: &mut PortIoBus) {
+            unsafe {
+                let tunnel = self.tunnel.va as *const hax_tunnel;
+                let io = ptr::read_unaligned(ptr::addr_of!((*tunnel).anon_union.io));
+                let io_buffer = self.tunnel.io_va as *mut u8;
+
+                let size = io._size as usize;
+                let count = io._count as usize;
+
+                for i in 0..count {
+                    // With EFLAGS.DF set string I/O walks the buffer backwards
+                    let element = if io._df != 0 { count - 1 - i } else { i };
+                    let data = io_buffer.add(element * size);
+
+                    if io._direction == HAX_IO_OUT {
+                        let mut value: u32 = 0;
+                        ptr::copy_nonoverlapping(data, &mut value as *mut u32 as *mut u8, size);
+                        io_bus.io_out(io._port, size as u8, value);
+                    }
+                    else {
+                        let value = io_bus.io_in(io._port, size as u8);
+                        ptr::copy_nonoverlapping(&value as *const u32 as *const u8, data, size);
+                    }
+                }
+            }
+        }
+
+        /// Runs the vCPU, servicing port I/O with `io_bus`, until the guest halts or exits for a reason the loop cannot
+        /// handle. Requires setup_vcpu_tunnel(). On failure of the run IOCTL returns the value of GetLastError().
+        ///
+        /// # Arguments
+        ///
+        /// * `io_bus` - The devices that IN/OUT instructions are forwarded to.
+        pub fn run_until_exit(&mut self, io_bus: &mut PortIoBus) -> Result<VcpuExit, DWORD> {
+            loop {
+                if let Some(last_error) = self.run() {
+                    return Err(last_error);
+                }
+
+                match self.exit_status() {
+                    HAX_EXIT_IO => self.handle_io_exit(io_bus),
+                    // The host needed the physical CPU back, nothing happened to the guest
+                    HAX_EXIT_INTERRUPT | HAX_EXIT_PAUSED => {}
+                    HAX_EXIT_HLT => return Ok(VcpuExit::Halt),
+                    HAX_EXIT_STATECHANGE => return Ok(VcpuExit::StateChange),
+                    HAX_EXIT_DEBUG => {
+                        let debug = unsafe {
+                            let tunnel = self.tunnel.va as *const hax_tunnel;
+                            ptr::read_unaligned(ptr::addr_of!((*tunnel).anon_union.debug))
+                        };
+                        return Ok(VcpuExit::Debug(DebugExit::decode(&debug, &self.debug_state)));
+                    }
+                    HAX_EXIT_MMIO | HAX_EXIT_FAST_MMIO => {
+                        let gla = unsafe {
+                            let tunnel = self.tunnel.va as *const hax_tunnel;
+                            ptr::read_unaligned(ptr::addr_of!((*tunnel).anon_union.mmio.gla))
+                        };
+                        return Ok(VcpuExit::Mmio { gla: gla });
+                    }
+                    exit_status => return Ok(VcpuExit::Unhandled(exit_status))
+                }
+            }
+        }
     
     }
     
@@ -346,11 +646,64 @@ fn get_integer_input(prompt: &str) -> Result<u32, String> {
     println!("{}", prompt);
     let mut buffer = String::new();
     if let Ok(_str_len) = std::io::stdin().read_line(&mut buffer) {
-        if let Ok(int) = buffer.trim().parse::<u32>() {
-            Ok(int)
+        match radix::parse_u64(buffer.trim()) {
+            Ok(int) if int <= u32::MAX as u64 => Ok(int as u32),
+            _ => Err(String::from("Unable to parse to u32"))
+        }
+    }
+    else {
+        Err(String::from("Unable to read input"))
+    }
+}
+
+/// Like get_integer_input(), but reads a u32 or an i32 (returned as its two's complement) depending on `signedness`.
+fn get_operand_input(prompt: &str, signedness: Signedness) -> Result<u32, String> {
+    println!("{}", prompt);
+    let mut buffer = String::new();
+    if let Ok(_str_len) = std::io::stdin().read_line(&mut buffer) {
+        signedness.parse(buffer.trim())
+    }
+    else {
+        Err(String::from("Unable to read input"))
+    }
+}
+
+fn get_big_input(prompt: &str) -> Result<BigInt, String> {
+    println!("{}", prompt);
+    let mut buffer = String::new();
+    if let Ok(_str_len) = std::io::stdin().read_line(&mut buffer) {
+        BigInt::parse(&buffer)
+    }
+    else {
+        Err(String::from("Unable to read input"))
+    }
+}
+
+fn get_decimal_input(prompt: &str) -> Result<Decimal, String> {
+    println!("{}", prompt);
+    let mut buffer = String::new();
+    if let Ok(_str_len) = std::io::stdin().read_line(&mut buffer) {
+        Decimal::parse(&buffer)
+    }
+    else {
+        Err(String::from("Unable to read input"))
+    }
+}
+
+/// Reads a number at `precision`. f32 operands are parsed as f32, rounding the text once, and widened exactly.
+fn get_float_input(prompt: &str, precision: Precision) -> Result<f64, String> {
+    println!("{}", prompt);
+    let mut buffer = String::new();
+    if let Ok(_str_len) = std::io::stdin().read_line(&mut buffer) {
+        let parsed = match precision {
+            Precision::Single => buffer.trim().parse::<f32>().map(|float| float as f64),
+            Precision::Double => buffer.trim().parse::<f64>()
+        };
+        if let Ok(float) = parsed {
+            Ok(float)
         }
         else {
-            Err(String::from("Unable to parse to u32"))
+            Err(String::from("Unable to parse to a floating point number"))
         }
     }
     else {
@@ -358,142 +711,752 @@ fn get_integer_input(prompt: &str) -> Result<u32, String> {
     }
 }
 
-fn main() {
+/// `value` as short as it can be written at `precision` and still read back the same.
+fn float_text(value: f64, precision: Precision) -> String {
+    match precision {
+        Precision::Single => (value as f32).to_string(),
+        Precision::Double => value.to_string()
+    }
+}
+
+/// Prints the EFLAGS bits that tell whether a result fitted, as the guest left them.
+fn print_arithmetic_flags(eflags: u32) {
+    let flag = |mask: u32| (eflags & mask != 0) as u8;
+    println!("CF={} OF={} ZF={} SF={}", flag(EFLAGS_CF), flag(EFLAGS_OF), flag(EFLAGS_ZF), flag(EFLAGS_SF));
+}
+
+/// The guest code of the original calculator.
+fn add_program() -> Vec<u8> {
+    let code = Assembler::new(Mode::Bits16)
+        .line("add eax, ecx")
+        .line("hlt")
+        .assemble();
+
+    match code {
+        Ok(code) => code.bytes,
+        Err(error_message) => panic!("{}", error_message)
+    }
+}
 
-    unsafe {
-        let mut haxm_device = haxm::HaxmDevice::new();
+/// The original calculator: adds two u32s with `add eax, ecx`.
+fn integer_add(calc_vm: &mut CalcVm) {
+    if let Err(error_message) = calc_vm.load_code(&add_program()) {
+        panic!("{}", error_message);
+    }
+
+    // Collect first number
+    let int1 = match get_integer_input("Enter first number: ") {
+        Ok(int1) => int1,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    // Collect second number
+    let int2 = match get_integer_input("Enter second number: ") {
+        Ok(int2) => int2,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    calc_vm.vcpu().cpu_state.set_gpr32(REG_RAX, int1);
+    calc_vm.vcpu().cpu_state.set_gpr32(REG_RCX, int2);
+
+    if let Err(error_message) = calc_vm.run() {
+        panic!("{}", error_message);
+    }
 
-        if let Err(last_error) = &haxm_device.initialize() {
-            panic!("Unable to initialize HAXM device. GetLastError: {}", last_error);
+    println!("{} + {} = {}", int1, int2, calc_vm.vcpu().cpu_state.gpr32(REG_RAX));
+    print_arithmetic_flags(calc_vm.vcpu().cpu_state.eflags());
+}
+
+/// `hypercalc batch`: adds every pair of u32s on stdin (one pair per line, in any radix Signedness::parse() reads),
+/// each in a fork of one set up VM.
+fn batch(calc_vm: &mut CalcVm) {
+    if let Err(error_message) = calc_vm.load_code(&add_program()) {
+        panic!("{}", error_message);
+    }
+
+    let stdin = std::io::stdin();
+    for (line_number, line) in stdin.lock().lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => panic!("Unable to read input: {}", error)
+        };
+        if line.trim().is_empty() {
+            continue;
         }
 
-        let new_vm_result = haxm_device.new_vm(); 
-        if let Err(last_error) = new_vm_result {
-            panic!("Unable to create a new VM. GetLastError: {}", last_error);
+        let operands: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match operands.as_slice() {
+            [int1, int2] => Signedness::Unsigned.parse(int1).and_then(|int1| Ok((int1, Signedness::Unsigned.parse(int2)?))),
+            _ => Err(String::from("expected two u32s"))
+        };
+        let (int1, int2) = match parsed {
+            Ok(operands) => operands,
+            Err(error_message) => {
+                eprintln!("line {}: {}", line_number + 1, error_message);
+                continue;
+            }
+        };
+
+        let mut child = match calc_vm.fork() {
+            Ok(child) => child,
+            Err(error_message) => panic!("{}", error_message)
+        };
+        child.vcpu().cpu_state.set_gpr32(REG_RAX, int1);
+        child.vcpu().cpu_state.set_gpr32(REG_RCX, int2);
+
+        match child.run() {
+            Ok(()) => println!("{} + {} = {}", int1, int2, child.vcpu().cpu_state.gpr32(REG_RAX)),
+            Err(error_message) => eprintln!("line {}: {}", line_number + 1, error_message)
         }
-        
-        let hva = VirtualAlloc(ptr::null_mut(), RAM_SIZE as usize, MEM_COMMIT | MEM_RESERVE, PAGE_EXECUTE_READWRITE);
-        if hva == ptr::null_mut() {
-            panic!("Unable to allocate memory. Call to VirtualAlloc failed. GetLastError: {}", GetLastError());
+    }
+}
+
+/// `hypercalc int <op> [signed|unsigned] [wrapping|checked|saturating] [dec|hex|oct|bin|base<N>] [bits]`, see
+/// IntOp::from_name() for the operations. The signedness defaults to the operation's (unsigned except for imul, idiv,
+/// imod and sar), the policy to wrapping and the radix to decimal. `bits` shows the result bit by bit with the flags.
+fn int_calculation(calc_vm: &mut CalcVm, args: &[String]) {
+    let usage = "Usage: hypercalc int <add|sub|mul|imul|div|idiv|mod|imod|and|or|xor|not|neg|shl|shr|sar|rol|ror|cmp> \
+        [signed|unsigned] [wrapping|checked|saturating] [dec|hex|oct|bin|base<N>] [bits]";
+    let op = match args.first().and_then(|name| IntOp::from_name(name)) {
+        Some(op) => op,
+        None => panic!("{}", usage)
+    };
+
+    let mut signedness = if op.is_signed() { Signedness::Signed } else { Signedness::Unsigned };
+    let mut policy = OverflowPolicy::Wrapping;
+    let mut radix = Radix::DECIMAL;
+    let mut bits = false;
+    for arg in args[1..].iter() {
+        if let Some(parsed) = Signedness::from_name(arg) {
+            signedness = parsed;
+        }
+        else if let Some(parsed) = OverflowPolicy::from_name(arg) {
+            policy = parsed;
+        }
+        else if let Some(parsed) = Radix::from_name(arg) {
+            radix = parsed;
+        }
+        else if arg == "bits" {
+            bits = true;
         }
+        else {
+            panic!("{}", usage);
+        }
+    }
+    let op = op.with_signedness(signedness);
+
+    let a = match get_operand_input("Enter first number: ", signedness) {
+        Ok(a) => a,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    let b = if op.is_unary() {
+        0
+    }
+    else {
+        match get_operand_input("Enter second number: ", signedness) {
+            Ok(b) => b,
+            Err(error_message) => panic!("{}", error_message)
+        }
+    };
+
+    let result = match int_calc::run_int_op_with_policy(calc_vm, op, a, b, signedness, policy) {
+        Ok(result) => result,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    // Operands and results print the way the operation interprets them, or as bit patterns in another radix
+    let show = |value: u32| if radix.is_decimal() { signedness.format(value) } else { radix.format(value as u64) };
+    match op {
+        IntOp::Not | IntOp::Neg => println!("{} {} = {} ({:#010x})", op.symbol(), show(a), show(result.value), result.value),
+        IntOp::Mul | IntOp::Imul => {
+            println!("{} {} {} = {} (edx:eax = {:08x}:{:08x})", show(a), op.symbol(), show(b), show(result.value), result.high, result.value);
+        }
+        IntOp::Div | IntOp::Idiv => println!("{} {} {} = {} remainder {}", show(a), op.symbol(), show(b), show(result.value), show(result.high)),
+        IntOp::Compare => {
+            let (unsigned, signed) = result.comparison();
+            println!("{} cmp {}: unsigned {}, signed {}", a, b, unsigned, signed);
+        }
+        _ => println!("{} {} {} = {} ({:#010x})", show(a), op.symbol(), show(b), show(result.value), result.value)
+    }
+    if op.can_overflow() && result.overflowed(signedness) {
+        println!("The result overflowed, {}", if policy == OverflowPolicy::Saturating { "saturated" } else { "wrapped" });
+    }
+    if !bits {
+        print_arithmetic_flags(result.eflags);
+    }
+    else if op == IntOp::Mul || op == IntOp::Imul {
+        println!("{}", radix::bit_view(((result.high as u64) << 32) | result.value as u64, 64, result.eflags));
+    }
+    else {
+        println!("{}", radix::bit_view(result.value as u64, 32, result.eflags));
+    }
+}
+
+/// `hypercalc big <add|sub|mul|divmod> [hex]`
+fn big_calculation(calc_vm: &mut CalcVm, args: &[String]) {
+    let usage = "Usage: hypercalc big <add|sub|mul|divmod> [hex]";
+    let op = match args.first().and_then(|name| BigOp::from_name(name)) {
+        Some(op) => op,
+        None => panic!("{}", usage)
+    };
+    let hex = match args.get(1).map(|radix| radix.as_str()) {
+        Some("hex") => true,
+        None => false,
+        Some(_) => panic!("{}", usage)
+    };
 
-        let calc_vm = &mut haxm_device.vms[0]; 
+    let a = match get_big_input("Enter first number: ") {
+        Ok(a) => a,
+        Err(error_message) => panic!("{}", error_message)
+    };
 
-        let mut mem: [UINT8; RAM_SIZE as usize] = [0x90; RAM_SIZE as usize];
-        // add eax, ecx
-        // hlt
-        mem[0x2000] = 0x66;
-        mem[0x2001] = 0x01;
-        mem[0x2002] = 0xC8;
-        mem[0x2003] = 0xf4;
+    let b = match get_big_input("Enter second number: ") {
+        Ok(b) => b,
+        Err(error_message) => panic!("{}", error_message)
+    };
 
-        // Move our custom assembly opcodes into the memory which will become the memory of our guest
-        ptr::copy(mem.as_mut_ptr(), hva as *mut u8, mem.len());
+    let (result, remainder) = match bignum::run_big_op(calc_vm, op, &a, &b) {
+        Ok(result) => result,
+        Err(error_message) => panic!("{}", error_message)
+    };
 
+    let show = |value: &BigInt| if hex { value.to_hex() } else { value.to_decimal() };
+    match remainder {
+        Some(remainder) => println!("{} {} {} = {} remainder {}", show(&a), op.symbol(), show(&b), show(&result), show(&remainder)),
+        None => println!("{} {} {} = {}", show(&a), op.symbol(), show(&b), show(&result))
+    }
+}
+
+/// `hypercalc bcd <add|sub|mul|div>`, checking the guest's answer against the host's when the host can work it out.
+fn bcd_calculation(calc_vm: &mut CalcVm, args: &[String]) {
+    let op = match args.first().and_then(|name| BcdOp::from_name(name)) {
+        Some(op) => op,
+        None => panic!("Usage: hypercalc bcd <add|sub|mul|div>")
+    };
+
+    let a = match get_decimal_input("Enter first number: ") {
+        Ok(a) => a,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    let b = match get_decimal_input("Enter second number: ") {
+        Ok(b) => b,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    let (result, remainder) = match bcd::run_bcd_op(calc_vm, op, &a, &b) {
+        Ok(result) => result,
+        Err(error_message) => panic!("{}", error_message)
+    };
 
-        if let Some(last_error) = calc_vm.alloc_ram(hva as u64, RAM_SIZE) {
-            panic!("Unable to allocate memory for the VM. GetLastError: {}", last_error);
+    match &remainder {
+        Some(remainder) => println!("{} {} {} = {} remainder {}", a, op.symbol(), b, result, remainder),
+        None => println!("{} {} {} = {}", a, op.symbol(), b, result)
+    }
+
+    match bcd::host_result(op, &a, &b) {
+        None => println!("host check: skipped, the numbers don't fit in an i128"),
+        Some(expected) if expected == (result.clone(), remainder.clone()) => println!("host check: ok"),
+        Some((expected, expected_remainder)) => {
+            match expected_remainder {
+                Some(expected_remainder) => println!("host check: MISMATCH, expected {} remainder {}", expected, expected_remainder),
+                None => println!("host check: MISMATCH, expected {}", expected)
+            }
+            std::process::exit(1);
         }
+    }
+}
 
+/// The leading `[dec|hex|oct|bin|base<N>] [bits]` options of the expression modes, and the arguments after them.
+fn display_options(args: &[String]) -> (Option<Radix>, bool, &[String]) {
+    let mut radix = None;
+    let mut bits = false;
+    let mut args = args;
+    while let Some(arg) = args.first() {
+        if let Some(parsed) = Radix::from_name(arg) {
+            radix = Some(parsed);
+        }
+        else if arg == "bits" {
+            bits = true;
+        }
+        else {
+            break;
+        }
+        args = &args[1..];
+    }
+    (radix, bits, args)
+}
+
+/// `hypercalc expr [dec|hex|oct|bin|base<N>] [bits] [expression]`, prompting for the expression if it isn't on the
+/// command line. The result shows in decimal and hex unless a radix is given, `bits` adds the bit view.
+fn expression_calculation(calc_vm: &mut CalcVm, args: &[String]) {
+    let (radix, bits, args) = display_options(args);
 
-        if let Some(last_error) = calc_vm.set_ram(0, RAM_SIZE, hva as u64) {
-            panic!("Unable to set memory for the VM. GetLastError: {}", last_error);
+    let text = if args.is_empty() {
+        println!("Enter an expression: ");
+        let mut buffer = String::new();
+        if std::io::stdin().read_line(&mut buffer).is_err() {
+            panic!("Unable to read input");
         }
+        buffer.trim().to_string()
+    }
+    else {
+        args.join(" ")
+    };
 
-        if let Some(last_error) = calc_vm.new_cpu(0) {
-            panic!("Unable to create a vCPU for the VM. GetLastError: {}", last_error);
+    let mut session = match ExpressionSession::new(calc_vm, radix, bits) {
+        Ok(session) => session,
+        Err(error_message) => panic!("{}", error_message)
+    };
+    match session.execute(&text) {
+        Ok(()) => {}
+        Err(RunError::Fault(fault)) => {
+            println!("{}: {}", text, fault);
+            std::process::exit(1);
         }
+        Err(error_message) => panic!("{}", error_message)
+    }
+}
+
+/// `hypercalc prime <n>`, for any 64 bit n written as radix::parse_u64() reads it.
+fn prime_test(calc_vm: &mut CalcVm, args: &[String]) {
+    let n = match args.first().map(|text| radix::parse_u64(text)) {
+        Some(Ok(n)) => n,
+        Some(Err(error_message)) => panic!("{}", error_message),
+        None => panic!("Usage: hypercalc prime <n>")
+    };
+
+    match number_theory::is_prime64(calc_vm, n) {
+        Ok(true) => println!("{} is prime", n),
+        Ok(false) => println!("{} is not prime", n),
+        Err(error_message) => panic!("{}", error_message)
+    }
+}
+
+/// `hypercalc calc [dec|hex|oct|bin|base<N>] [bits]`
+fn calc_session(calc_vm: &mut CalcVm, args: &[String]) {
+    let (radix, bits, args) = display_options(args);
+    if let Some(arg) = args.first() {
+        panic!("Unknown option {}, usage: hypercalc calc [dec|hex|oct|bin|base<N>] [bits]", arg);
+    }
+    match ExpressionSession::new(calc_vm, radix, bits) {
+        Ok(mut session) => session.run(),
+        Err(error_message) => panic!("{}", error_message)
+    }
+}
+
+/// `hypercalc rpn`
+fn rpn_session(calc_vm: &mut CalcVm) {
+    RpnCalculator::new(calc_vm).run();
+}
+
+/// `hypercalc float <add|sub|mul|div|sqrt|cmp> [f32|f64]`
+fn float_calculation(calc_vm: &mut CalcVm, args: &[String]) {
+    let op = match args.first().and_then(|name| FloatOp::from_name(name)) {
+        Some(op) => op,
+        None => panic!("Usage: hypercalc float <add|sub|mul|div|sqrt|cmp> [f32|f64]")
+    };
+
+    let precision = match args.get(1).map(|name| name.as_str()) {
+        Some("f32") => Precision::Single,
+        Some("f64") | None => Precision::Double,
+        Some(other) => panic!("Unknown precision {}, expected f32 or f64", other)
+    };
 
-        if let Some(last_error) = calc_vm.vcpus[0].setup_vcpu_tunnel() {
-            panic!("Unable to setup vCPU channel for vCPU: {}. GetLastError: {}", calc_vm.vcpus[0].id, last_error);
+    let a = match get_float_input("Enter first number: ", precision) {
+        Ok(a) => a,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    let b = if op == FloatOp::Sqrt {
+        0.0
+    }
+    else {
+        match get_float_input("Enter second number: ", precision) {
+            Ok(b) => b,
+            Err(error_message) => panic!("{}", error_message)
         }
+    };
 
+    let result = match float_calc::run_float_op(calc_vm, op, precision, a, b) {
+        Ok(result) => result,
+        Err(error_message) => panic!("{}", error_message)
+    };
 
-        /*
-            Physical Memory (processor linear address space) layout for a pseudo flat model:
-            [0x0000 - 0x1fff] [Data segment]
-            [0x2000 - 0x3fff] [Code segment]
-        */
+    let (a, b, value) = (float_text(a, precision), float_text(b, precision), float_text(result.value(precision), precision));
+    match op {
+        FloatOp::Sqrt => println!("sqrt({}) = {}", a, value),
+        FloatOp::Compare => {
+            match result.ordering {
+                Some(ordering) => println!("{} cmp {} = {:?}", a, b, ordering),
+                None => println!("{} cmp {} = unordered", a, b)
+            }
+        }
+        _ => println!("{} {} {} = {}", a, op.symbol(), b, value)
+    }
 
-        // Set the register state
-        calc_vm.vcpus[0].cpu_state.cs.selector = 0;
-        calc_vm.vcpus[0].cpu_state.cs.limit = 0x3FFF;
-        calc_vm.vcpus[0].cpu_state.cs.anon_union.ar = 0x9B;
-        calc_vm.vcpus[0].cpu_state.cs.base = 0x2000;
+    let exceptions = float_calc::exception_names(result.exceptions);
+    if !exceptions.is_empty() {
+        println!("IEEE exceptions: {}", exceptions.join(", "));
+    }
 
-        calc_vm.vcpus[0].cpu_state.ds.selector = 0;
-        calc_vm.vcpus[0].cpu_state.ds.limit = 0x1FFF;
-        calc_vm.vcpus[0].cpu_state.ds.anon_union.ar = 0x93;
-        calc_vm.vcpus[0].cpu_state.ds.base = 0;
+    if !result.matches_host() {
+        println!("Guest result {:#x} differs from the host's {:#x}", result.bits, result.host_bits);
+        std::process::exit(1);
+    }
+}
 
-        calc_vm.vcpus[0].cpu_state.tr.selector = 0;
-        calc_vm.vcpus[0].cpu_state.tr.limit = 0;
-        calc_vm.vcpus[0].cpu_state.tr.anon_union.ar = 0x83;
-        calc_vm.vcpus[0].cpu_state.tr.base = 0;
+/// Loads the program at `path` into the code segment, or the integer add without a path. `.asm` files are assembled
+/// as 16 bit code with labels relative to CS, anything else is loaded as raw machine code.
+fn load_program(calc_vm: &mut CalcVm, path: Option<&String>) {
+    let code = match path {
+        Some(path) if path.ends_with(".asm") => match assembler::assemble_file(path, Mode::Bits16, 0) {
+            Ok(code) if code.origin == 0 => code.bytes,
+            Ok(code) => panic!("{} is assembled for {:#x}, but programs are loaded at CS:0", path, code.origin),
+            Err(error_message) => panic!("{}", error_message)
+        },
+        Some(path) => match std::fs::read(path) {
+            Ok(code) => code,
+            Err(error) => panic!("Unable to read {}: {}", path, error)
+        },
+        None => add_program()
+    };
+    if let Err(error_message) = calc_vm.load_code(&code) {
+        panic!("{}", error_message);
+    }
+}
 
-        calc_vm.vcpus[0].cpu_state.ldt.selector = 0;
-        calc_vm.vcpus[0].cpu_state.ldt.limit = 0;
-        calc_vm.vcpus[0].cpu_state.ldt.anon_union.ar = 0x10000;
-        calc_vm.vcpus[0].cpu_state.ldt.base = 0;
+/// `hypercalc debug [program]`
+fn debug_session(calc_vm: &mut CalcVm, args: &[String]) {
+    load_program(calc_vm, args.first());
+    Monitor::new(Debugger::new(calc_vm)).run();
+}
 
-        calc_vm.vcpus[0].cpu_state.gdt.limit = 0;
-        calc_vm.vcpus[0].cpu_state.gdt.base = 0;
-        calc_vm.vcpus[0].cpu_state.gdt.anon_union.ar = 0x10000; // Set here, but also automatically by the Haxm driver
+/// `hypercalc resume <snapshot>`: restores a snapshot saved by the monitor and runs the guest on to its HLT.
+fn resume(calc_vm: &mut CalcVm, args: &[String]) {
+    let snapshot = match args.first().map(|path| VmSnapshot::load(path)) {
+        Some(Ok(snapshot)) => snapshot,
+        Some(Err(error_message)) => panic!("{}", error_message),
+        None => panic!("Usage: hypercalc resume <snapshot>")
+    };
 
-        calc_vm.vcpus[0].cpu_state.idt.limit = 0;
-        calc_vm.vcpus[0].cpu_state.idt.base = 0;
-        calc_vm.vcpus[0].cpu_state.idt.anon_union.ar = 0x10000; // Set here, but also automatically by the Haxm driver
+    if let Err(error_message) = calc_vm.restore(&snapshot).and_then(|_| calc_vm.run().map_err(String::from)) {
+        panic!("{}", error_message);
+    }
+    println!("{}", calc_vm.vcpu().cpu_state);
+}
 
-        calc_vm.vcpus[0].cpu_state.cr0 = 0x21; // 0x21
-        calc_vm.vcpus[0].cpu_state.cr3 = 0;
-        calc_vm.vcpus[0].cpu_state.cr4 = 0x2000;
+/// `hypercalc gdb [port|socket path] [program]`
+fn gdb_session(calc_vm: &mut CalcVm, args: &[String]) {
+    load_program(calc_vm, args.get(1));
 
-        calc_vm.vcpus[0].cpu_state.dr6 = 0xFFFF0FF0; // Set here, but also automatically by the Haxm driver
-        calc_vm.vcpus[0].cpu_state.dr7 = 0x400; // Set here, but also automatically by the Haxm driver
+    let mut stub = GdbStub::new(Debugger::new(calc_vm));
+    let endpoint = args.first().map(|endpoint| endpoint.as_str()).unwrap_or("1234");
 
-        //calc_vm.vcpus[0].cpu_state.anon_union_1.anon_struct.rsp.b32 = 0x100;
-        ptr::write(&calc_vm.vcpus[0].cpu_state.anon_union_1.anon_struct.rsp.b32 as *const u32 as *mut u32, 0x1000);
+    let served = match endpoint.parse::<u16>() {
+        Ok(port) => gdbstub::serve_tcp(&mut stub, port),
+        #[cfg(unix)]
+        Err(_) => gdbstub::serve_unix(&mut stub, endpoint),
+        #[cfg(not(unix))]
+        Err(_) => panic!("Unknown port {}", endpoint)
+    };
 
-        calc_vm.vcpus[0].cpu_state.anon_union_2.rip = 0;
+    stub.target.detach();
+    if let Err(error) = served {
+        panic!("gdb session failed: {}", error);
+    }
+}
 
-        calc_vm.vcpus[0].cpu_state.anon_union_3.eflags = 0x202;
+fn parse_address(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u64>().ok()
+    }
+}
 
+/// `hypercalc trace show <file> [--text <text>] [--reg <register>] [--pc <start>[-<end>]] [--writes]`
+fn show_trace(args: &[String]) {
+    let usage = "Usage: hypercalc trace show <file> [--text <text>] [--reg <register>] [--pc <start>[-<end>]] [--writes]";
+    let path = match args.first() {
+        Some(path) => path,
+        None => panic!("{}", usage)
+    };
 
-        // Collect first number
-        let mut int1 = 0;
-        let first_input = get_integer_input("Enter first number: ");
-        if let Err(error_message) = first_input {
-            panic!("{}", error_message);
+    let mut filter = TraceFilter::default();
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--text" => filter.text = options.next().cloned(),
+            "--reg" => filter.register = options.next().cloned(),
+            "--pc" => {
+                let range = options.next().map(|range| range.as_str()).unwrap_or("");
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse_address(start), parse_address(end)),
+                    None => (parse_address(range), parse_address(range).map(|start| start + 1))
+                };
+                match (start, end) {
+                    (Some(start), Some(end)) => filter.pc_range = Some((start, end)),
+                    _ => panic!("Bad address range {}", range)
+                }
+            }
+            "--writes" => filter.writes_memory = true,
+            _ => panic!("{}", usage)
         }
-        else if let Ok(result1) = first_input {
-            //calc_vm.vcpus[0].cpu_state.anon_union_1.anon_struct.rax.b32 = result1;
-            ptr::write(&calc_vm.vcpus[0].cpu_state.anon_union_1.anon_struct.rax.b32 as *const u32 as *mut u32, result1);
-            int1 = result1;
+    }
+
+    let records = match trace::read_trace(path) {
+        Ok(records) => records,
+        Err(error_message) => panic!("{}", error_message)
+    };
+    for record in records.iter().filter(|record| filter.matches(record)) {
+        println!("{}", record);
+    }
+}
+
+/// `hypercalc trace diff <left> <right>`
+fn diff_traces(args: &[String]) {
+    let (left, right) = match (args.first(), args.get(1)) {
+        (Some(left), Some(right)) => (left, right),
+        _ => panic!("Usage: hypercalc trace diff <left> <right>")
+    };
+
+    let (left_records, right_records) = match (trace::read_trace(left), trace::read_trace(right)) {
+        (Ok(left_records), Ok(right_records)) => (left_records, right_records),
+        (Err(error_message), _) | (_, Err(error_message)) => panic!("{}", error_message)
+    };
+
+    let differences = trace::diff(&left_records, &right_records);
+    if differences.is_empty() {
+        println!("The traces match ({} steps)", left_records.len());
+        return;
+    }
+
+    for difference in differences.iter() {
+        println!("step {}: {} differ", difference.step, difference.fields.join(", "));
+        match &difference.left {
+            Some(record) => println!("  < {}", record),
+            None => println!("  < (trace ended)")
         }
-        
-        // Collect second number
-        let mut int2 = 0;
-        let second_input = get_integer_input("Enter second number: ");
-        if let Err(error_message) = second_input {
-            panic!("{}", error_message);
+        match &difference.right {
+            Some(record) => println!("  > {}", record),
+            None => println!("  > (trace ended)")
         }
-        else if let Ok(result2) = second_input {
-            //calc_vm.vcpus[0].cpu_state.anon_union_1.anon_struct.rcx.b32 = result2;
-            ptr::write(&calc_vm.vcpus[0].cpu_state.anon_union_1.anon_struct.rcx.b32 as *const u32 as *mut u32, result2);
-            int2 = result2;
+        if difference.diverges() {
+            println!("The traces diverge at step {}", difference.step);
+        }
+    }
+}
+
+/// Applies `--cpu-vendor` and `--hide-cpuid` to the CPUID table the guest sees.
+fn configure_cpuid(calc_vm: &mut CalcVm, vendor: Option<&str>, hidden_features: Option<&str>) {
+    let vendor: Option<[u8; 12]> = vendor.map(|vendor| match vendor.as_bytes().try_into() {
+        Ok(vendor) => vendor,
+        Err(_) => panic!("The CPU vendor {} isn't 12 characters long", vendor)
+    });
+    let (ecx_mask, edx_mask) = match hidden_features.map(cpuid::feature_masks) {
+        Some(Ok(masks)) => masks,
+        Some(Err(error_message)) => panic!("{}", error_message),
+        None => (0, 0)
+    };
+
+    let configured = calc_vm.update_cpuid(|config| {
+        let config = match &vendor {
+            Some(vendor) => config.vendor(vendor),
+            None => config
+        };
+        config.mask_features(ecx_mask, edx_mask)
+    });
+    if let Err(error_message) = configured {
+        panic!("{}", error_message);
+    }
+}
+
+/// Applies `--clock` and `--cmos` to the guest's RTC.
+fn configure_cmos(calc_vm: &mut CalcVm, clock: Option<&str>, nvram_path: Option<&str>) {
+    let mut cmos = Cmos::new(RtcClock::Host);
+    if let Some(clock) = clock {
+        match RtcClock::parse(clock) {
+            Ok(clock) => cmos.set_clock(clock),
+            Err(error_message) => panic!("{}", error_message)
         }
-          
-        if let Some(last_error) = calc_vm.vcpus[0].set_regs() {
-            panic!("Unable to set vCPU {} registers. GetLastError: {}", calc_vm.vcpus[0].id, last_error);
+    }
+    if let Some(path) = nvram_path {
+        match std::fs::read(path) {
+            Ok(nvram) if nvram.len() <= CMOS_NVRAM_SIZE => cmos.preseed_nvram(0, &nvram),
+            Ok(nvram) => panic!("{} has {} bytes, the CMOS NVRAM only {}", path, nvram.len(), CMOS_NVRAM_SIZE),
+            Err(error) => panic!("Unable to read {}: {}", path, error)
         }
+    }
+    calc_vm.set_cmos(cmos);
+}
 
-        calc_vm.vcpus[0].run();
+fn load_snapshot(path: &str) -> VcpuSnapshot {
+    match VcpuSnapshot::load(path) {
+        Ok(snapshot) => snapshot,
+        Err(error_message) => panic!("{}", error_message)
+    }
+}
 
-        if let Some(last_error) = calc_vm.vcpus[0].get_regs() {
-            panic!("Unable to get vCPU {} registers. GetLastError: {}", calc_vm.vcpus[0].id, last_error);
+/// `hypercalc state show <file>`
+fn show_state(args: &[String]) {
+    let snapshot = match args.first() {
+        Some(path) => load_snapshot(path),
+        None => panic!("Usage: hypercalc state show <file>")
+    };
+
+    println!("{}", snapshot.cpu.vcpu_state());
+    if let Some(fpu) = &snapshot.fpu {
+        println!("fcw={:04x} fsw={:04x} ftw={:02x} mxcsr={:08x}", fpu.fcw, fpu.fsw, fpu.ftw, fpu.mxcsr);
+        let fx_layout = fpu.to_fx_layout();
+        for index in (0..fpu::X87_REGISTER_COUNT).filter(|index| fx_layout.is_st_valid(*index)) {
+            println!("st{:<3} {}", index, fx_layout.st(index).to_f64());
+        }
+        for (index, value) in fpu.xmm.iter().enumerate().filter(|(_, value)| **value != 0) {
+            println!("xmm{:<2} {:032x}", index, value);
         }
+    }
+    for msr in snapshot.msrs.iter() {
+        println!("msr {:08x} = {:016x}", msr.index, msr.value);
+    }
+}
 
-        println!("{} + {} = {}", int1, int2, calc_vm.vcpus[0].cpu_state.anon_union_1.anon_struct.rax.b32);
-        
+/// `hypercalc state diff <left> <right>`, e.g. a golden state against the one a run saved.
+fn diff_state_files(args: &[String]) {
+    let (left, right) = match (args.first(), args.get(1)) {
+        (Some(left), Some(right)) => (load_snapshot(left), load_snapshot(right)),
+        _ => panic!("Usage: hypercalc state diff <left> <right>")
+    };
+
+    let mut differences = 0;
+    for change in state_dump::diff_states(&left.cpu.vcpu_state(), &right.cpu.vcpu_state()) {
+        println!("{}", change);
+        differences += 1;
+    }
+    if left.fpu != right.fpu {
+        println!("fpu  differs");
+        differences += 1;
+    }
+    if left.msrs != right.msrs {
+        println!("msrs differ");
+        differences += 1;
+    }
+    if differences == 0 {
+        println!("The states match");
+    }
+    else {
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+
+    // Reading traces doesn't need HAXM
+    if args.get(1).map(|mode| mode.as_str()) == Some("trace") {
+        match args.get(2).map(|command| command.as_str()) {
+            Some("show") => show_trace(&args[3..]),
+            Some("diff") => diff_traces(&args[3..]),
+            _ => panic!("Usage: hypercalc trace <show|diff> ...")
+        }
+        return;
+    }
+
+    if args.get(1).map(|mode| mode.as_str()) == Some("state") {
+        match args.get(2).map(|command| command.as_str()) {
+            Some("show") => show_state(&args[3..]),
+            Some("diff") => diff_state_files(&args[3..]),
+            _ => panic!("Usage: hypercalc state <show|diff> ...")
+        }
+        return;
+    }
+
+    // Options in front of any mode:
+    // `--trace <file>` records every instruction the calculation runs
+    // `--state <file>` starts the calculation from a saved state instead of fixtures/initial_state.json
+    // `--save-state <file>` saves the state the calculation ends in
+    // `--clock <date>` freezes the guest's RTC at a UTC time such as 2024-02-29T13:45:07
+    // `--cmos <file>` preseeds the CMOS NVRAM with a raw image of up to 128 bytes
+    // `--cpu-vendor <vendor>` makes CPUID report a 12 character vendor string such as AuthenticAMD
+    // `--hide-cpuid <features>` clears CPUID feature bits, e.g. sse4_2,popcnt, so guests take their fallback paths
+    let mut trace_path = None;
+    let mut state_path = None;
+    let mut save_state_path = None;
+    let mut clock = None;
+    let mut cmos_path = None;
+    let mut cpu_vendor = None;
+    let mut hidden_features = None;
+    while let Some(option) = args.get(1).filter(|option| option.starts_with("--")).cloned() {
+        let value = match args.get(2) {
+            Some(value) => value.clone(),
+            None => panic!("{} needs a value", option)
+        };
+        match option.as_str() {
+            "--trace" => trace_path = Some(value),
+            "--state" => state_path = Some(value),
+            "--save-state" => save_state_path = Some(value),
+            "--clock" => clock = Some(value),
+            "--cmos" => cmos_path = Some(value),
+            "--cpu-vendor" => cpu_vendor = Some(value),
+            "--hide-cpuid" => hidden_features = Some(value),
+            _ => panic!("Unknown option {}", option)
+        }
+        args.drain(1..3);
+    }
+
+    let mut calc_vm = match CalcVm::new() {
+        Ok(calc_vm) => calc_vm,
+        Err(error_message) => panic!("{}", error_message)
+    };
+
+    // Older HAXM drivers don't implement the CPUID IOCTLs, guests just won't see the signature there
+    if let Err(error_message) = calc_vm.advertise_hypervisor() {
+        eprintln!("Warning: {}", error_message);
+    }
+    if cpu_vendor.is_some() || hidden_features.is_some() {
+        configure_cpuid(&mut calc_vm, cpu_vendor.as_deref(), hidden_features.as_deref());
+    }
+    if clock.is_some() || cmos_path.is_some() {
+        configure_cmos(&mut calc_vm, clock.as_deref(), cmos_path.as_deref());
+    }
+
+    if let Some(path) = trace_path {
+        match Tracer::create(&path) {
+            Ok(tracer) => calc_vm.tracer = Some(tracer),
+            Err(error_message) => panic!("{}", error_message)
+        }
+    }
+
+    if let Some(path) = state_path {
+        let loaded = VcpuSnapshot::load(&path).and_then(|snapshot| {
+            calc_vm.initial_state = snapshot.cpu.clone();
+            calc_vm.load_state(&snapshot)
+        });
+        if let Err(error_message) = loaded {
+            panic!("{}", error_message);
+        }
+    }
+
+    match args.get(1).map(|mode| mode.as_str()) {
+        None => integer_add(&mut calc_vm),
+        Some("int") => int_calculation(&mut calc_vm, &args[2..]),
+        Some("big") => big_calculation(&mut calc_vm, &args[2..]),
+        Some("bcd") => bcd_calculation(&mut calc_vm, &args[2..]),
+        Some("expr") => expression_calculation(&mut calc_vm, &args[2..]),
+        Some("prime") => prime_test(&mut calc_vm, &args[2..]),
+        Some("calc") => calc_session(&mut calc_vm, &args[2..]),
+        Some("rpn") => rpn_session(&mut calc_vm),
+        Some("float") => float_calculation(&mut calc_vm, &args[2..]),
+        Some("debug") => debug_session(&mut calc_vm, &args[2..]),
+        Some("gdb") => gdb_session(&mut calc_vm, &args[2..]),
+        Some("resume") => resume(&mut calc_vm, &args[2..]),
+        Some("batch") => batch(&mut calc_vm),
+        Some(mode) => panic!("Unknown mode {}", mode)
+    }
+
+    if let Some(path) = save_state_path {
+        if let Err(error_message) = calc_vm.save_state().and_then(|snapshot| snapshot.save(&path)) {
+            panic!("{}", error_message);
+        }
     }
 }
@@ -0,0 +1,353 @@
+use std::io::{self, BufRead, Write};
+
+use crate::debugger::Debugger;
+use crate::disasm::MAX_INSTRUCTION_LENGTH;
+use crate::guest_debug::{HwBreakpoint, HwBreakpointKind, DebugExitKind};
+use crate::haxm::VcpuExit;
+use crate::haxm_interface_windows::vcpu_state_t;
+use crate::snapshot::VmSnapshot;
+use crate::state_dump::{self, eflags_names};
+use crate::vcpu_regs::*;
+
+const HELP: &str = "\
+regs                      General purpose registers, EIP and EFLAGS
+sregs                     Segment registers with their decoded descriptors, descriptor tables and control registers
+state                     Everything: registers, segments, control and debug registers, activity and interruptibility
+changes                   The registers the last step or cont changed
+x/<count><b|h|w> <addr>   Dump guest memory in bytes, 16 bit halfwords or 32 bit words (x/16x <addr> = 16 bytes)
+disasm [addr] [count]     Disassemble count instructions (default 8) from addr (default: the next one)
+step [count]              Execute count instructions (default 1)
+cont                      Run until a breakpoint, HLT or another exit
+break [addr]              Set an INT3 breakpoint, or list breakpoints without addr
+hbreak <addr>             Set a hardware instruction breakpoint
+watch <addr> [len]        Set a hardware write watchpoint
+awatch <addr> [len]       Set a hardware read/write watchpoint
+delete <addr>             Remove every breakpoint and watchpoint at addr
+set <reg> <value>         Set a general purpose register, eip or eflags
+snapshot <file>           Save the whole VM (vCPUs, memory, devices) to file
+restore <file>            Load a snapshot saved by snapshot, breakpoints stay where they are
+help                      This text
+quit                      Leave the monitor
+
+Addresses are linear (CS.base + EIP for code) and, like values, take decimal, 0x hex, or a register name.";
+
+/// The interactive monitor behind `hypercalc debug`.
+pub struct Monitor<'a> {
+    debugger: Debugger<'a>,
+    /// The vCPU state before the last step or cont, for `changes`.
+    previous_state: Option<vcpu_state_t>
+}
+
+impl<'a> Monitor<'a> {
+
+    /// Associated function constructor.
+    pub fn new(debugger: Debugger<'a>) -> Self {
+        Monitor {
+            debugger: debugger,
+            previous_state: None
+        }
+    }
+
+    /// Parses a number (decimal or 0x hex) or the name of a register holding one.
+    fn parse_value(&mut self, text: &str) -> Result<u64, String> {
+        let cpu_state = &self.debugger.calc_vm.vcpu().cpu_state;
+
+        if let Some(index) = GPR_NAMES_32.iter().position(|name| *name == text) {
+            return Ok(cpu_state.gpr32(index) as u64);
+        }
+        match text {
+            "eip" => return Ok(cpu_state.eip() as u64),
+            "pc" => return Ok(self.debugger.pc()),
+            "eflags" => return Ok(cpu_state.eflags() as u64),
+            _ => {}
+        }
+
+        let parsed = match text.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => text.parse::<u64>()
+        };
+        parsed.map_err(|_| format!("Not a number or register: {}", text))
+    }
+
+    fn print_regs(&mut self) {
+        let cpu_state = &self.debugger.calc_vm.vcpu().cpu_state;
+
+        for (index, name) in GPR_NAMES_32.iter().enumerate() {
+            print!("{}={:08x}", name, cpu_state.gpr32(index));
+            print!("{}", if index % 4 == 3 { "\n" } else { " " });
+        }
+        println!("eip={:08x} eflags={:08x} [{}]", cpu_state.eip(), cpu_state.eflags(), eflags_names(cpu_state.eflags()));
+        self.print_current_instruction();
+    }
+
+    fn print_sregs(&mut self) {
+        let cpu_state = &self.debugger.calc_vm.vcpu().cpu_state;
+
+        for (name, segment) in cpu_state.segments().iter() {
+            println!("{:<4} {}", name, segment);
+        }
+        println!("gdt  base={:016x} limit={:04x}", cpu_state.gdt.base, cpu_state.gdt.limit);
+        println!("idt  base={:016x} limit={:04x}", cpu_state.idt.base, cpu_state.idt.limit);
+        println!("cr0={:08x} [{}] cr2={:08x} cr3={:08x}", cpu_state.cr0, state_dump::cr0_names(cpu_state.cr0), cpu_state.cr2, cpu_state.cr3);
+        println!("cr4={:08x} [{}]", cpu_state.cr4, state_dump::cr4_names(cpu_state.cr4));
+        println!("dr6={:08x} dr7={:08x} [{}]", cpu_state.dr6, cpu_state.dr7, state_dump::dr7_names(cpu_state.dr7));
+    }
+
+    fn print_changes(&mut self) -> Result<(), String> {
+        let previous_state = self.previous_state.as_ref().ok_or("Nothing has run yet")?;
+        let changes = state_dump::diff_states(previous_state, &self.debugger.calc_vm.vcpu().cpu_state);
+        if changes.is_empty() {
+            println!("No registers changed");
+        }
+        for change in changes.iter() {
+            println!("{}", change);
+        }
+        Ok(())
+    }
+
+    /// `x/<count><format> <addr>`, the format letters being gdb's unit sizes. `x` (hex) is accepted and implied.
+    fn examine(&mut self, format: &str, address: Option<&str>) -> Result<(), String> {
+        let digits: String = format.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let count = if digits.is_empty() { 16 } else { digits.parse::<usize>().map_err(|_| format!("Bad count {}", digits))? };
+
+        let mut unit = 1;
+        for letter in format[digits.len()..].chars() {
+            unit = match letter {
+                'x' => unit,
+                'b' => 1,
+                'h' => 2,
+                'w' => 4,
+                _ => return Err(format!("Unknown format letter {}", letter))
+            };
+        }
+
+        let address = self.parse_value(address.ok_or("x needs an address")?)?;
+        let length = count.checked_mul(unit).ok_or("Count too large")?;
+        let bytes = self.debugger.read_memory(address, length).ok_or(format!("{:#x} + {:#x} is outside of guest RAM", address, length))?;
+
+        let per_line = 16 / unit;
+        for (line, chunk) in bytes.chunks(unit * per_line).enumerate() {
+            print!("{:08x}:", address as usize + line * unit * per_line);
+            for value in chunk.chunks(unit) {
+                let mut padded = [0u8; 4];
+                padded[..unit].copy_from_slice(value);
+                print!(" {:0width$x}", u32::from_le_bytes(padded), width = unit * 2);
+            }
+            println!();
+        }
+        Ok(())
+    }
+
+    fn disasm(&mut self, address: Option<&str>, count: Option<&str>) -> Result<(), String> {
+        let address = match address {
+            Some(address) => self.parse_value(address)?,
+            None => self.debugger.pc()
+        };
+        let count = match count {
+            Some(count) => self.parse_value(count)? as usize,
+            None => 8
+        };
+        if count.checked_mul(MAX_INSTRUCTION_LENGTH).is_none() {
+            return Err(String::from("Count too large"));
+        }
+
+        let instructions = self.debugger.disassemble(address, count).ok_or(format!("{:#x} is outside of guest RAM", address))?;
+        for instruction in instructions.iter() {
+            println!("{}", instruction);
+        }
+        Ok(())
+    }
+
+    fn report_exit(&mut self, exit: VcpuExit) {
+        let pc = self.debugger.pc();
+        match exit {
+            VcpuExit::Debug(debug_exit) => {
+                match debug_exit.kind {
+                    DebugExitKind::SingleStep => println!("Stepped to {:08x}", pc),
+                    DebugExitKind::SoftwareBreakpoint => println!("Breakpoint at {:08x}", pc),
+                    DebugExitKind::HwBreakpoint(slot) => println!("Hardware breakpoint {} at {:08x}", slot, pc),
+                    DebugExitKind::Watchpoint(slot) => println!("Watchpoint {} ({:#x}) hit, stopped at {:08x}", slot, debug_exit.address, pc)
+                }
+            }
+            VcpuExit::Halt => match self.debugger.calc_vm.guest_fault() {
+                Some(fault) => println!("Halted in the fault handler: {}", fault),
+                None => println!("Halted at {:08x}", pc)
+            },
+            exit => println!("Stopped at {:08x}: {:?}", pc, exit)
+        }
+        self.print_current_instruction();
+    }
+
+    fn print_current_instruction(&mut self) {
+        if let Some(instruction) = self.debugger.current_instruction() {
+            println!("{}", instruction);
+        }
+    }
+
+    fn set_register(&mut self, register: Option<&str>, value: Option<&str>) -> Result<(), String> {
+        let (register, value) = match (register, value) {
+            (Some(register), Some(value)) => (register, value),
+            _ => return Err(String::from("Usage: set <reg> <value>"))
+        };
+        let value = self.parse_value(value)? as u32;
+
+        let cpu_state = &mut self.debugger.calc_vm.vcpu().cpu_state;
+        if let Some(index) = GPR_NAMES_32.iter().position(|name| *name == register) {
+            cpu_state.set_gpr32(index, value);
+            return Ok(());
+        }
+        match register {
+            "eip" => cpu_state.set_eip(value),
+            "eflags" => cpu_state.set_eflags(value),
+            _ => return Err(format!("Unknown register {}", register))
+        }
+        Ok(())
+    }
+
+    fn watch(&mut self, kind: HwBreakpointKind, address: Option<&str>, length: Option<&str>) -> Result<(), String> {
+        let address = self.parse_value(address.ok_or("Missing address")?)?;
+        let length = match length {
+            Some(length) => self.parse_value(length)?,
+            None => 1
+        };
+        if !matches!(length, 1 | 2 | 4 | 8) || address & (length - 1) != 0 {
+            return Err(String::from("Watchpoints cover 1, 2, 4 or 8 aligned bytes"));
+        }
+
+        match self.debugger.insert_hw_breakpoint(HwBreakpoint { address: address, kind: kind, length: length as u8 }) {
+            Some(slot) => println!("Watchpoint {} at {:#x}", slot, address),
+            None => return Err(String::from("All 4 debug registers are in use"))
+        }
+        Ok(())
+    }
+
+    fn list_breakpoints(&mut self) {
+        for address in self.debugger.software_breakpoints() {
+            println!("break  {:08x}", address);
+        }
+        for (slot, breakpoint) in self.debugger.hw_breakpoints().iter().enumerate() {
+            if let Some(breakpoint) = breakpoint {
+                println!("dr{}    {:08x} {:?} len={}", slot, breakpoint.address, breakpoint.kind, breakpoint.length);
+            }
+        }
+    }
+
+    /// Runs one command line. Returns Ok(false) when the monitor should exit.
+    pub fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true)
+        };
+
+        match command {
+            "regs" => self.print_regs(),
+            "sregs" => self.print_sregs(),
+            "state" => println!("{}", self.debugger.calc_vm.vcpu().cpu_state),
+            "changes" => self.print_changes()?,
+            "x" => self.examine("", words.next())?,
+            _ if command.starts_with("x/") => self.examine(&command[2..], words.next())?,
+            "disasm" => self.disasm(words.next(), words.next())?,
+            "step" | "s" => {
+                let count = match words.next() {
+                    Some(count) => self.parse_value(count)?,
+                    None => 1
+                };
+                self.previous_state = Some(self.debugger.calc_vm.vcpu().cpu_state.clone());
+                for _ in 0..count {
+                    let exit = self.debugger.step()?;
+                    let stepped = matches!(exit, VcpuExit::Debug(ref debug_exit) if debug_exit.kind == DebugExitKind::SingleStep);
+                    if !stepped || count == 1 {
+                        self.report_exit(exit);
+                    }
+                    if !stepped {
+                        break;
+                    }
+                }
+                if count > 1 {
+                    println!("Now at {:08x}", self.debugger.pc());
+                    self.print_current_instruction();
+                }
+            }
+            "cont" | "c" => {
+                self.previous_state = Some(self.debugger.calc_vm.vcpu().cpu_state.clone());
+                let exit = self.debugger.cont()?;
+                self.report_exit(exit);
+            }
+            "break" | "b" => {
+                match words.next() {
+                    Some(address) => {
+                        let address = self.parse_value(address)?;
+                        if !self.debugger.insert_software_breakpoint(address) {
+                            return Err(format!("Can't set a breakpoint at {:#x}", address));
+                        }
+                        println!("Breakpoint at {:#x}", address);
+                    }
+                    None => self.list_breakpoints()
+                }
+            }
+            "hbreak" => {
+                let address = self.parse_value(words.next().ok_or("Missing address")?)?;
+                match self.debugger.insert_hw_breakpoint(HwBreakpoint { address: address, kind: HwBreakpointKind::Execute, length: 1 }) {
+                    Some(slot) => println!("Hardware breakpoint {} at {:#x}", slot, address),
+                    None => return Err(String::from("All 4 debug registers are in use"))
+                }
+            }
+            "watch" => self.watch(HwBreakpointKind::Write, words.next(), words.next())?,
+            "awatch" => self.watch(HwBreakpointKind::ReadWrite, words.next(), words.next())?,
+            "delete" => {
+                let address = self.parse_value(words.next().ok_or("Missing address")?)?;
+                let mut removed = self.debugger.remove_software_breakpoint(address);
+                for kind in [HwBreakpointKind::Execute, HwBreakpointKind::Write, HwBreakpointKind::ReadWrite].iter() {
+                    removed |= self.debugger.remove_hw_breakpoint(address, *kind);
+                }
+                if !removed {
+                    return Err(format!("No breakpoint at {:#x}", address));
+                }
+            }
+            "set" => self.set_register(words.next(), words.next())?,
+            "snapshot" => {
+                let path = words.next().ok_or("Missing file name")?;
+                self.debugger.snapshot()?.save(path)?;
+                println!("Saved to {}", path);
+            }
+            "restore" => {
+                let snapshot = VmSnapshot::load(words.next().ok_or("Missing file name")?)?;
+                self.debugger.restore(&snapshot)?;
+                println!("Restored, now at {:08x}", self.debugger.pc());
+                self.print_current_instruction();
+            }
+            "help" | "?" => println!("{}", HELP),
+            "quit" | "q" => return Ok(false),
+            _ => return Err(format!("Unknown command {}, try help", command))
+        }
+        Ok(true)
+    }
+
+    /// Reads commands from stdin until `quit` or end of input. An empty line repeats the previous command.
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut last_line = String::new();
+
+        loop {
+            print!("(hypercalc) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+
+            let line = if line.trim().is_empty() { last_line.clone() } else { String::from(line.trim()) };
+            match self.execute(&line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(error_message) => println!("{}", error_message)
+            }
+            last_line = line;
+        }
+
+        self.debugger.detach();
+    }
+}
@@ -0,0 +1,516 @@
+use crate::assembler::{Assembler, Mode};
+use crate::calculator::{CalcVm, RunError};
+use crate::guest_fault::STUBS_OFFSET;
+use crate::vcpu_regs::*;
+
+// Number theory routines in guest code: gcd, lcm, modular exponentiation and inverse, Miller-Rabin primality for 32
+// and 64 bit values and factorial. They are assembled once into a library for 16 bit code that sits at LIBRARY_OFFSET
+// in the code segment, out of the way of programs loaded at CS:0, and guest code uses them with a near CALL.
+//
+// Calling convention, for every routine:
+//
+// * Arguments in EAX, ECX and EBX, in that order. A 64 bit argument is EDX:EAX.
+// * The result in EAX.
+// * CF clear on return if there is a result, set if there isn't (see Routine::failure()).
+// * EBP and ESP are preserved, EBX, ECX, EDX, ESI and EDI may be changed.
+// * Up to STACK_USE bytes of stack are used, the return address included.
+//
+// Dividing by a zero modulus is left to DIV, which raises #DE like any other division by zero.
+
+/// CS offset of the library. Code loaded at the start of the code segment has to end below it to call the routines.
+pub const LIBRARY_OFFSET: u32 = 0x1000;
+/// The most stack a call into the library takes, in bytes.
+pub const STACK_USE: usize = 0x50;
+
+/// The Miller-Rabin bases that tell every 32 bit number apart: all that pass 2, 7 and 61 are prime.
+const BASES32: [u32; 3] = [2, 7, 61];
+/// The Miller-Rabin bases that tell every 64 bit number apart.
+const BASES64: [u32; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Routine {
+    /// EAX = gcd(EAX, ECX), gcd(0, 0) being 0.
+    Gcd,
+    /// EAX = lcm(EAX, ECX), CF if it doesn't fit in 32 bits.
+    Lcm,
+    /// EAX = EAX ^ ECX mod EBX.
+    ModPow,
+    /// EAX = the inverse of EAX mod ECX, CF if there is none.
+    ModInv,
+    /// EAX = 1 if EAX is prime, 0 if not.
+    IsPrime,
+    /// EAX = 1 if EDX:EAX is prime, 0 if not.
+    IsPrime64,
+    /// EAX = EAX!, CF if it doesn't fit in 32 bits.
+    Factorial
+}
+
+impl Routine {
+
+    pub const ALL: [Routine; 7] = [Routine::Gcd, Routine::Lcm, Routine::ModPow, Routine::ModInv, Routine::IsPrime, Routine::IsPrime64, Routine::Factorial];
+
+    /// The routine for a function name in an expression. IsPrime64 has none, expressions are 32 bit.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gcd" => Some(Routine::Gcd),
+            "lcm" => Some(Routine::Lcm),
+            "modpow" => Some(Routine::ModPow),
+            "modinv" => Some(Routine::ModInv),
+            "isprime" => Some(Routine::IsPrime),
+            "fact" | "factorial" => Some(Routine::Factorial),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Routine::Gcd => "gcd",
+            Routine::Lcm => "lcm",
+            Routine::ModPow => "modpow",
+            Routine::ModInv => "modinv",
+            Routine::IsPrime => "isprime",
+            Routine::IsPrime64 => "isprime64",
+            Routine::Factorial => "fact"
+        }
+    }
+
+    /// How many 32 bit arguments the routine takes.
+    pub fn arity(&self) -> usize {
+        match self {
+            Routine::IsPrime | Routine::Factorial => 1,
+            Routine::Gcd | Routine::Lcm | Routine::ModInv | Routine::IsPrime64 => 2,
+            Routine::ModPow => 3
+        }
+    }
+
+    /// Why the routine returned with CF set, None for routines that always have a result.
+    pub fn failure(&self) -> Option<&'static str> {
+        match self {
+            Routine::Lcm | Routine::Factorial => Some("the result doesn't fit in 32 bits"),
+            Routine::ModInv => Some("there is no inverse, the number and the modulus have a common factor"),
+            _ => None
+        }
+    }
+
+    /// The label of the entry point.
+    fn label(&self) -> &'static str {
+        self.name()
+    }
+}
+
+/// gcd: Euclid's algorithm with DIV. lcm: a / gcd(a, b) * b, the MUL setting CF when it overflows.
+fn gcd_lcm_routines(assembler: Assembler) -> Assembler {
+    assembler
+        .label("gcd")
+        .line("test ecx, ecx")
+        .line("jz .done")
+        .label(".remainder")
+        .line("xor edx, edx")
+        .line("div ecx")
+        .line("mov eax, ecx")
+        .line("mov ecx, edx")
+        .line("test ecx, ecx")
+        .line("jnz .remainder")
+        .label(".done")
+        .line("clc")
+        .line("ret")
+
+        .label("lcm")
+        .line("test eax, eax")
+        .line("jz .zero")
+        .line("test ecx, ecx")
+        .line("jz .zero")
+        .line("mov ebx, eax")
+        .line("mov esi, ecx")
+        .line("call gcd")
+        .line("mov ecx, eax")
+        .line("mov eax, ebx")
+        .line("xor edx, edx")
+        .line("div ecx")
+        .line("mul esi")
+        .line("ret")
+        .label(".zero")
+        // Clears CF too
+        .line("xor eax, eax")
+        .line("ret")
+}
+
+/// Right to left square and multiply. Every product is of two values below the modulus, so the DIV reducing it can't
+/// overflow.
+fn modpow_routine(assembler: Assembler) -> Assembler {
+    assembler
+        .label("modpow")
+        .line("mov esi, ecx")
+        .line("xor edx, edx")
+        .line("div ebx")
+        .line("mov edi, edx")
+        // 1 mod m, which is 0 for m = 1
+        .line("mov eax, 1")
+        .line("xor edx, edx")
+        .line("div ebx")
+        .line("mov ecx, edx")
+        .label(".bit")
+        .line("test esi, esi")
+        .line("jz .done")
+        .line("shr esi, 1")
+        .line("jnc .square")
+        .line("mov eax, ecx")
+        .line("mul edi")
+        .line("div ebx")
+        .line("mov ecx, edx")
+        .label(".square")
+        .line("mov eax, edi")
+        .line("mul edi")
+        .line("div ebx")
+        .line("mov edi, edx")
+        .line("jmp .bit")
+        .label(".done")
+        .line("mov eax, ecx")
+        .line("clc")
+        .line("ret")
+}
+
+/// The extended Euclidean algorithm, keeping the coefficient of `a` reduced mod m so it never goes negative.
+fn modinv_routine(assembler: Assembler) -> Assembler {
+    assembler
+        .label("modinv")
+        .line("test ecx, ecx")
+        .line("jz .none")
+        .line("push ebp")
+        .line("mov ebp, ecx")
+        // (r0, r1) = (m, a mod m), (t0, t1) = (0, 1) in ESI, EDI, EBX and ECX
+        .line("xor edx, edx")
+        .line("div ebp")
+        .line("mov edi, edx")
+        .line("mov esi, ebp")
+        .line("xor ebx, ebx")
+        .line("mov ecx, 1")
+        .label(".step")
+        .line("test edi, edi")
+        .line("jz .end")
+        .line("mov eax, esi")
+        .line("xor edx, edx")
+        .line("div edi")
+        .line("mov esi, edi")
+        .line("mov edi, edx")
+        // t0 - q * t1 mod m
+        .line("mul ecx")
+        .line("div ebp")
+        .line("mov eax, ebx")
+        .line("sub eax, edx")
+        .line("jnc .reduced")
+        .line("add eax, ebp")
+        .label(".reduced")
+        .line("mov ebx, ecx")
+        .line("mov ecx, eax")
+        .line("jmp .step")
+        .label(".end")
+        // r0 is the gcd, only 1 has an inverse
+        .line("mov eax, ebx")
+        .line("pop ebp")
+        .line("cmp esi, 1")
+        .line("jne .none")
+        .line("clc")
+        .line("ret")
+        .label(".none")
+        .line("stc")
+        .line("ret")
+}
+
+/// Miller-Rabin with the BASES32, each round a modpow and up to s - 1 squarings.
+fn is_prime_routine(assembler: Assembler) -> Assembler {
+    let mut assembler = assembler
+        .label("isprime")
+        .line("cmp eax, 2")
+        .line("jb .composite")
+        .line("je .prime")
+        .line("test al, 1")
+        .line("jz .composite")
+        .line("mov esi, eax")
+        .line("lea edi, [eax-1]");
+    for base in BASES32.iter() {
+        assembler = assembler
+            .line(&format!("mov eax, {}", base))
+            .line("call mr_round")
+            .line("jc .composite");
+    }
+    assembler
+        .label(".prime")
+        .line("mov eax, 1")
+        .line("clc")
+        .line("ret")
+        .label(".composite")
+        .line("xor eax, eax")
+        .line("ret")
+
+        // EAX = base, ESI = n (odd), EDI = n - 1. CF set if the base proves n composite.
+        .label("mr_round")
+        .line("xor edx, edx")
+        .line("div esi")
+        .line("test edx, edx")
+        .line("jz .inconclusive")
+        .line("push esi")
+        .line("push edi")
+        .line("mov eax, edx")
+        // n - 1 = d * 2^s
+        .line("bsf ecx, edi")
+        .line("shr edi, cl")
+        .line("push ecx")
+        .line("mov ecx, edi")
+        .line("mov ebx, esi")
+        .line("call modpow")
+        .line("pop ecx")
+        .line("pop edi")
+        .line("pop esi")
+        .line("cmp eax, 1")
+        .line("je .inconclusive")
+        .label(".square")
+        .line("cmp eax, edi")
+        .line("je .inconclusive")
+        .line("dec ecx")
+        .line("jz .witness")
+        .line("mul eax")
+        .line("div esi")
+        .line("mov eax, edx")
+        .line("jmp .square")
+        .label(".witness")
+        .line("stc")
+        .line("ret")
+        .label(".inconclusive")
+        .line("clc")
+        .line("ret")
+}
+
+/// Miller-Rabin for 64 bit values with the BASES64. Numbers that fit in 32 bits go to isprime. The 64 bit values live
+/// in a stack frame; products are reduced mod n by double and add, so nothing needs more than 64 bits.
+fn is_prime64_routine(assembler: Assembler) -> Assembler {
+    // The frame, below BP: n, n - 1, d, s, the squarings left, x, the base, the multiplier and the exponent
+    let (n, n_minus_1, d, s, left, x, base, multiplier, exponent) = (8, 16, 24, 28, 32, 40, 48, 56, 64);
+    let lo = |offset: i32| format!("[bp-{}]", offset);
+    let hi = |offset: i32| format!("[bp-{}]", offset - 4);
+
+    let mut assembler = assembler
+        .label("isprime64")
+        .line("test edx, edx")
+        .line("jz isprime")
+        .line("test al, 1")
+        .line("jnz .odd")
+        .line("xor eax, eax")
+        .line("ret")
+        .label(".odd")
+        .line("push ebp")
+        .line("mov ebp, esp")
+        .line(&format!("sub esp, {}", exponent))
+        .line(&format!("mov {}, eax", lo(n)))
+        .line(&format!("mov {}, edx", hi(n)))
+        .line("sub eax, 1")
+        .line("sbb edx, 0")
+        .line(&format!("mov {}, eax", lo(n_minus_1)))
+        .line(&format!("mov {}, edx", hi(n_minus_1)))
+        .line("xor ecx, ecx")
+        .label(".halve")
+        .line("shrd eax, edx, 1")
+        .line("shr edx, 1")
+        .line("inc ecx")
+        .line("test al, 1")
+        .line("jz .halve")
+        .line(&format!("mov {}, eax", lo(d)))
+        .line(&format!("mov {}, edx", hi(d)))
+        .line(&format!("mov {}, ecx", lo(s)));
+    for base in BASES64.iter() {
+        assembler = assembler
+            .line(&format!("mov eax, {}", base))
+            .line("call mr64_round")
+            .line("jc .composite");
+    }
+    assembler
+        .line("mov eax, 1")
+        .line("jmp .end")
+        .label(".composite")
+        .line("xor eax, eax")
+        .label(".end")
+        .line("mov esp, ebp")
+        .line("pop ebp")
+        .line("clc")
+        .line("ret")
+
+        // EAX = base. CF set if the base proves n composite.
+        .label("mr64_round")
+        .line(&format!("mov {}, eax", lo(base)))
+        .line(&format!("mov dword {}, 0", hi(base)))
+        .line("call powmod64")
+        .line("cmp eax, 1")
+        .line("jne .start")
+        .line("test edx, edx")
+        .line("jz .inconclusive")
+        .label(".start")
+        .line(&format!("mov ecx, {}", lo(s)))
+        .line(&format!("mov {}, ecx", lo(left)))
+        .label(".square")
+        .line(&format!("cmp eax, {}", lo(n_minus_1)))
+        .line("jne .next")
+        .line(&format!("cmp edx, {}", hi(n_minus_1)))
+        .line("je .inconclusive")
+        .label(".next")
+        .line(&format!("dec dword {}", lo(left)))
+        .line("jz .witness")
+        .line("mov ebx, eax")
+        .line("mov ecx, edx")
+        .line(&format!("mov {}, eax", lo(multiplier)))
+        .line(&format!("mov {}, edx", hi(multiplier)))
+        .line("call mulmod64")
+        .line("jmp .square")
+        .label(".witness")
+        .line("stc")
+        .line("ret")
+        .label(".inconclusive")
+        .line("clc")
+        .line("ret")
+
+        // x = base ^ d mod n, left to right, also returned in EDX:EAX
+        .label("powmod64")
+        .line(&format!("mov dword {}, 1", lo(x)))
+        .line(&format!("mov dword {}, 0", hi(x)))
+        .line(&format!("mov eax, {}", lo(d)))
+        .line(&format!("mov {}, eax", lo(exponent)))
+        .line(&format!("mov eax, {}", hi(d)))
+        .line(&format!("mov {}, eax", hi(exponent)))
+        .line("mov edi, 64")
+        .label(".bit")
+        .line(&format!("mov ebx, {}", lo(x)))
+        .line(&format!("mov ecx, {}", hi(x)))
+        .line(&format!("mov {}, ebx", lo(multiplier)))
+        .line(&format!("mov {}, ecx", hi(multiplier)))
+        .line("call mulmod64")
+        .line(&format!("shl dword {}, 1", lo(exponent)))
+        .line(&format!("rcl dword {}, 1", hi(exponent)))
+        .line("jnc .store")
+        .line(&format!("mov ebx, {}", lo(base)))
+        .line(&format!("mov ecx, {}", hi(base)))
+        .line(&format!("mov {}, eax", lo(multiplier)))
+        .line(&format!("mov {}, edx", hi(multiplier)))
+        .line("call mulmod64")
+        .label(".store")
+        .line(&format!("mov {}, eax", lo(x)))
+        .line(&format!("mov {}, edx", hi(x)))
+        .line("dec edi")
+        .line("jnz .bit")
+        .line("ret")
+
+        // EDX:EAX = ECX:EBX * multiplier mod n, both below n. Changes ESI and the multiplier.
+        .label("mulmod64")
+        .line("xor eax, eax")
+        .line("xor edx, edx")
+        .line("mov esi, 64")
+        .label(".bit")
+        .line("shl eax, 1")
+        .line("rcl edx, 1")
+        .line("call reduce64")
+        .line(&format!("shl dword {}, 1", lo(multiplier)))
+        .line(&format!("rcl dword {}, 1", hi(multiplier)))
+        .line("jnc .next")
+        .line("add eax, ebx")
+        .line("adc edx, ecx")
+        .line("call reduce64")
+        .label(".next")
+        .line("dec esi")
+        .line("jnz .bit")
+        .line("ret")
+
+        // EDX:EAX = EDX:EAX mod n for values below 2n, CF on entry being the 2^64 bit of the value
+        .label("reduce64")
+        .line("jc .subtract")
+        .line(&format!("cmp edx, {}", hi(n)))
+        .line("jb .done")
+        .line("ja .subtract")
+        .line(&format!("cmp eax, {}", lo(n)))
+        .line("jb .done")
+        .label(".subtract")
+        .line(&format!("sub eax, {}", lo(n)))
+        .line(&format!("sbb edx, {}", hi(n)))
+        .label(".done")
+        .line("ret")
+}
+
+/// Counts down from n, stopping with CF set at the first product that doesn't fit in 32 bits.
+fn factorial_routine(assembler: Assembler) -> Assembler {
+    assembler
+        .label("fact")
+        .line("mov ecx, eax")
+        .line("mov eax, 1")
+        .label(".multiply")
+        .line("cmp ecx, 1")
+        .line("jbe .done")
+        .line("mul ecx")
+        .line("jc .overflow")
+        .line("dec ecx")
+        .line("jmp .multiply")
+        .label(".done")
+        .line("clc")
+        .label(".overflow")
+        .line("ret")
+}
+
+/// The assembled routines.
+pub struct Library {
+    bytes: Vec<u8>,
+    /// The CS offset of each routine in Routine::ALL.
+    entries: Vec<u32>
+}
+
+impl Library {
+
+    /// Associated function constructor. Assembles the library for LIBRARY_OFFSET. On failure returns the assembler's
+    /// error, or how much the library is too large.
+    pub fn new() -> Result<Self, String> {
+        let assembler = Assembler::new(Mode::Bits16).origin(LIBRARY_OFFSET as u64);
+        let assembler = is_prime64_routine(is_prime_routine(modinv_routine(modpow_routine(gcd_lcm_routines(assembler)))));
+        let code = factorial_routine(assembler).assemble()?;
+
+        if LIBRARY_OFFSET as usize + code.bytes.len() > STUBS_OFFSET as usize {
+            return Err(format!("The routine library takes {} bytes, there are {}", code.bytes.len(), STUBS_OFFSET - LIBRARY_OFFSET));
+        }
+
+        let mut entries = vec!();
+        for routine in Routine::ALL.iter() {
+            match code.address_of(routine.label()) {
+                Some(address) => entries.push(address as u32),
+                None => return Err(format!("No entry point for {}", routine.name()))
+            }
+        }
+        Ok(Library {
+            bytes: code.bytes,
+            entries: entries
+        })
+    }
+
+    /// The CS offset to CALL for `routine`.
+    pub fn entry(&self, routine: Routine) -> u32 {
+        let index = Routine::ALL.iter().position(|candidate| *candidate == routine).unwrap();
+        self.entries[index]
+    }
+
+    /// Copies the routines into the code segment at LIBRARY_OFFSET. On failure returns a description of why they don't
+    /// fit.
+    pub fn install(&self, calc_vm: &mut CalcVm) -> Result<(), String> {
+        calc_vm.write_code(LIBRARY_OFFSET, &self.bytes)
+    }
+}
+
+/// Tells whether `n` is prime with the IsPrime64 routine. On failure returns the exception the guest took or a
+/// description of what else went wrong.
+pub fn is_prime64(calc_vm: &mut CalcVm, n: u64) -> Result<bool, RunError> {
+    let library = Library::new()?;
+    let code = Assembler::new(Mode::Bits16)
+        .line(&format!("call {:#x}", library.entry(Routine::IsPrime64)))
+        .line("hlt")
+        .assemble()?;
+
+    calc_vm.load_code(&code.bytes)?;
+    library.install(calc_vm)?;
+    calc_vm.reset_cpu_state();
+    calc_vm.vcpu().cpu_state.set_gpr32(REG_RAX, n as u32);
+    calc_vm.vcpu().cpu_state.set_gpr32(REG_RDX, (n >> 32) as u32);
+    calc_vm.run()?;
+    Ok(calc_vm.vcpu().cpu_state.gpr32(REG_RAX) == 1)
+}
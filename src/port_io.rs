@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+
+/// A device that sits on the guest's I/O port space. HAXM hands every IN/OUT instruction it does not handle
+/// itself back to us through the vCPU tunnel, and the run loop forwards it to the device owning that port.
+pub trait PortIoDevice {
+    /// Handles a guest IN instruction. `size` is the access width in bytes (1, 2 or 4).
+    fn io_in(&mut self, port: u16, size: u8) -> u32;
+
+    /// Handles a guest OUT instruction. `size` is the access width in bytes (1, 2 or 4).
+    fn io_out(&mut self, port: u16, size: u8, value: u32);
+
+    /// The device's state for a VM snapshot, in whatever encoding the device likes. None for stateless devices.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Puts back a state save_state() returned. On failure returns a description of what was wrong with it.
+    fn restore_state(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A device's saved state, tied to the port range the device is registered at.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeviceState {
+    pub first_port: u16,
+    pub count: u16,
+    pub state: Vec<u8>
+}
+
+struct PortRange {
+    first_port: u16,
+    count: u16,
+    device: Box<dyn PortIoDevice>
+}
+
+/// The set of emulated devices attached to a VM, keyed by the port ranges they claim.
+pub struct PortIoBus {
+    ranges: Vec<PortRange>
+}
+
+impl PortIoBus {
+
+    /// Associated function constructor. Constructs a bus with no devices attached.
+    pub fn new() -> Self {
+        PortIoBus {
+            ranges: vec!()
+        }
+    }
+
+    /// Attaches a device to the bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `first_port` - The first port the device responds to.
+    /// * `count` - The number of consecutive ports, starting at `first_port`, that belong to the device.
+    /// * `device` - The device. Ranges registered earlier take precedence if they overlap.
+    pub fn register(&mut self, first_port: u16, count: u16, device: Box<dyn PortIoDevice>) {
+        self.ranges.push(PortRange {
+            first_port: first_port,
+            count: count,
+            device: device
+        });
+    }
+
+    fn find(&mut self, port: u16) -> Option<&mut PortRange> {
+        self.ranges.iter_mut().find(|range| port >= range.first_port && (port - range.first_port) < range.count)
+    }
+
+    /// Forwards a guest IN to the owning device. Unclaimed ports float high like they do on real hardware.
+    pub fn io_in(&mut self, port: u16, size: u8) -> u32 {
+        match self.find(port) {
+            Some(range) => range.device.io_in(port, size),
+            None => {
+                if size >= 4 {
+                    0xFFFFFFFF
+                }
+                else {
+                    (1u32 << (size as u32 * 8)) - 1
+                }
+            }
+        }
+    }
+
+    /// Forwards a guest OUT to the owning device. Writes to unclaimed ports are dropped.
+    pub fn io_out(&mut self, port: u16, size: u8, value: u32) {
+        if let Some(range) = self.find(port) {
+            range.device.io_out(port, size, value);
+        }
+    }
+
+    /// The state of every device that has one, in registration order.
+    pub fn save_states(&self) -> Vec<DeviceState> {
+        let mut states = vec!();
+        for range in self.ranges.iter() {
+            if let Some(state) = range.device.save_state() {
+                states.push(DeviceState {
+                    first_port: range.first_port,
+                    count: range.count,
+                    state: state
+                });
+            }
+        }
+        states
+    }
+
+    /// Hands each state to the device registered at the same port range. On failure returns a description of the
+    /// state that doesn't fit the devices on this bus.
+    pub fn restore_states(&mut self, states: &[DeviceState]) -> Result<(), String> {
+        for state in states.iter() {
+            let range = self.ranges.iter_mut().find(|range| range.first_port == state.first_port && range.count == state.count);
+            match range {
+                Some(range) => range.device.restore_state(&state.state)?,
+                None => return Err(format!("No device at ports {:#x}-{:#x} to restore", state.first_port, state.first_port as u32 + state.count as u32 - 1))
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use std::num::IntErrorKind;
+
+use crate::vcpu_regs::*;
+
+// Number bases for the programmer's calculator: integer literals in any base, results formatted in any base and a
+// bit by bit view of a result next to the guest's status flags.
+
+/// The status flags the bit view decodes, in bit order.
+const STATUS_FLAGS: [(u32, &str); 6] = [
+    (EFLAGS_CF, "CF"), (EFLAGS_PF, "PF"), (EFLAGS_AF, "AF"), (EFLAGS_ZF, "ZF"), (EFLAGS_SF, "SF"), (EFLAGS_OF, "OF")
+];
+
+/// The character a character literal (the text after its opening quote) stands for, None if it isn't one.
+fn parse_char(text: &str) -> Option<char> {
+    let mut chars = text.strip_suffix('\'')?.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c @ ('\\' | '\'' | '"') => c,
+            _ => return None
+        },
+        c => c
+    };
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(c)
+}
+
+/// Parses an unsigned integer literal: decimal, hex with `0x`, binary with `0b`, octal with `0o`, `<base>#<digits>`
+/// for any base from 2 to 36 (e.g. `36#zz`), or a character literal such as `'A'` or `'\n'`, which is its code point.
+/// Digits may be separated by `_`. On failure returns a description of what is wrong with `text`.
+pub fn parse_u64(text: &str) -> Result<u64, String> {
+    if let Some(quoted) = text.strip_prefix('\'') {
+        return parse_char(quoted).map(|c| c as u64).ok_or_else(|| format!("{} is not a character literal", text));
+    }
+
+    let cleaned: String = text.chars().filter(|c| *c != '_').collect();
+    let lower = cleaned.to_ascii_lowercase();
+    let (digits, base) = if let Some(digits) = lower.strip_prefix("0x") {
+        (digits, 16)
+    }
+    else if let Some(digits) = lower.strip_prefix("0b") {
+        (digits, 2)
+    }
+    else if let Some(digits) = lower.strip_prefix("0o") {
+        (digits, 8)
+    }
+    else if let Some((base, digits)) = lower.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(base) if (2..=36).contains(&base) => (digits, base),
+            _ => return Err(format!("{} doesn't have a base from 2 to 36", text))
+        }
+    }
+    else {
+        (lower.as_str(), 10)
+    };
+
+    // from_str_radix() would take a sign
+    if digits.is_empty() || digits.starts_with('+') {
+        return Err(format!("{} is not a number", text));
+    }
+    u64::from_str_radix(digits, base).map_err(|error| match error.kind() {
+        IntErrorKind::PosOverflow => format!("{} doesn't fit in 64 bits", text),
+        _ => format!("{} is not a base {} number", text, base)
+    })
+}
+
+/// Whether `text` is a plain decimal literal, the only kind read as a signed number. The others give bit patterns.
+pub fn is_decimal(text: &str) -> bool {
+    text.chars().all(|c| c.is_ascii_digit() || c == '_')
+}
+
+/// The base results are shown in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Radix {
+    base: u32
+}
+
+impl Radix {
+
+    pub const DECIMAL: Radix = Radix { base: 10 };
+
+    /// `dec`, `hex`, `oct`, `bin` or `base<N>` for N from 2 to 36, e.g. `base3`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let base = match name {
+            "dec" => 10,
+            "hex" => 16,
+            "oct" => 8,
+            "bin" => 2,
+            _ => match name.strip_prefix("base").map(|base| base.parse::<u32>()) {
+                Some(Ok(base)) if (2..=36).contains(&base) => base,
+                _ => return None
+            }
+        };
+        Some(Radix {
+            base: base
+        })
+    }
+
+    pub fn is_decimal(&self) -> bool {
+        self.base == 10
+    }
+
+    /// `value` in this base, written the way parse_u64() reads it back: `0x1f`, `0b11111`, `0o37`, `31` or e.g.
+    /// `3#1011`.
+    pub fn format(&self, value: u64) -> String {
+        let mut digits = vec!();
+        let mut rest = value;
+        loop {
+            digits.push(std::char::from_digit((rest % self.base as u64) as u32, self.base).unwrap());
+            rest /= self.base as u64;
+            if rest == 0 {
+                break;
+            }
+        }
+        let digits: String = digits.iter().rev().collect();
+
+        match self.base {
+            10 => digits,
+            16 => format!("0x{}", digits),
+            8 => format!("0o{}", digits),
+            2 => format!("0b{}", digits),
+            base => format!("{}#{}", base, digits)
+        }
+    }
+}
+
+/// The low `width` bits (32 or 64) of `value` in rows of 32, nibbles apart and numbered by their top bit, with the
+/// status flags of `eflags` decoded next to the last row, e.g.
+///
+/// ```text
+///   31   27   23   19   15   11   7    3
+///   0000 0000 0000 0000 0000 0000 0010 1010   CF=0 PF=0 AF=0 ZF=0 SF=0 OF=0 (eflags 0x00000202)
+/// ```
+pub fn bit_view(value: u64, width: u32, eflags: u32) -> String {
+    let mut lines = vec!();
+    for row in (0..width / 32).rev() {
+        let top = row * 32 + 31;
+        let numbers: String = (0..8).map(|nibble| format!("{:<5}", top - nibble * 4)).collect();
+        let nibbles: Vec<String> = (0..8).map(|nibble| format!("{:04b}", (value >> (top - 3 - nibble * 4)) & 0xF)).collect();
+        lines.push(format!("  {}", numbers.trim_end()));
+        lines.push(format!("  {}", nibbles.join(" ")));
+    }
+
+    let flags: Vec<String> = STATUS_FLAGS.iter().map(|(mask, name)| format!("{}={}", name, (eflags & mask != 0) as u8)).collect();
+    if let Some(last) = lines.last_mut() {
+        last.push_str(&format!("   {} (eflags {:#010x})", flags.join(" "), eflags));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_and_parse_round_trip() {
+        let values = [0, 1, 31, 35, 36, 0x8000_0000, u32::MAX as u64, 0x0123_4567_89ab_cdef, u64::MAX];
+        for base in [2, 8, 10, 16, 36] {
+            let radix = Radix { base: base };
+            for value in values {
+                assert_eq!(parse_u64(&radix.format(value)), Ok(value), "{} in base {}", value, base);
+            }
+        }
+
+        assert_eq!(Radix::from_name("hex").unwrap().format(31), "0x1f");
+        assert_eq!(Radix::from_name("bin").unwrap().format(31), "0b11111");
+        assert_eq!(Radix::from_name("oct").unwrap().format(31), "0o37");
+        assert_eq!(Radix::from_name("dec").unwrap().format(31), "31");
+        assert_eq!(Radix::from_name("base36").unwrap().format(71), "36#1z");
+        assert_eq!(Radix::from_name("base3").unwrap().format(0), "3#0");
+        assert_eq!(Radix::DECIMAL.format(u64::MAX), "18446744073709551615");
+    }
+
+    #[test]
+    fn radix_names() {
+        assert_eq!(Radix::from_name("base2"), Some(Radix { base: 2 }));
+        assert_eq!(Radix::from_name("base36"), Some(Radix { base: 36 }));
+        assert_eq!(Radix::from_name("base1"), None);
+        assert_eq!(Radix::from_name("base37"), None);
+        assert_eq!(Radix::from_name("base"), None);
+        assert_eq!(Radix::from_name("HEX"), None);
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse_u64("0XFF"), Ok(255));
+        assert_eq!(parse_u64("0B101"), Ok(5));
+        assert_eq!(parse_u64("0o777"), Ok(511));
+        assert_eq!(parse_u64("36#ZZ"), Ok(1295));
+        assert_eq!(parse_u64("2#1000"), Ok(8));
+        assert_eq!(parse_u64("007"), Ok(7));
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(parse_u64("1_000_000"), Ok(1_000_000));
+        assert_eq!(parse_u64("0xdead_beef"), Ok(0xdead_beef));
+        assert_eq!(parse_u64("0b_1010_0101"), Ok(0xa5));
+        assert_eq!(parse_u64("1__2_"), Ok(12));
+        assert!(is_decimal("1_000"));
+        assert!(!is_decimal("0x10"));
+        assert_eq!(parse_u64("_"), Err(String::from("_ is not a number")));
+    }
+
+    #[test]
+    fn character_literals() {
+        assert_eq!(parse_u64("'A'"), Ok(65));
+        assert_eq!(parse_u64("'_'"), Ok(95));
+        assert_eq!(parse_u64("'\\n'"), Ok(10));
+        assert_eq!(parse_u64("'\\t'"), Ok(9));
+        assert_eq!(parse_u64("'\\r'"), Ok(13));
+        assert_eq!(parse_u64("'\\0'"), Ok(0));
+        assert_eq!(parse_u64("'\\\\'"), Ok(92));
+        assert_eq!(parse_u64("'\\''"), Ok(39));
+        assert_eq!(parse_u64("'\"'"), Ok(34));
+        assert_eq!(parse_u64("'\\\"'"), Ok(34));
+        assert_eq!(parse_u64("'€'"), Ok(0x20ac));
+
+        for text in ["'", "''", "'A", "'AB'", "'\\x'", "'\\'"] {
+            assert_eq!(parse_u64(text), Err(format!("{} is not a character literal", text)));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_u64(""), Err(String::from(" is not a number")));
+        assert_eq!(parse_u64("0x"), Err(String::from("0x is not a number")));
+        assert_eq!(parse_u64("0b"), Err(String::from("0b is not a number")));
+        assert_eq!(parse_u64("16#"), Err(String::from("16# is not a number")));
+        assert_eq!(parse_u64("+5"), Err(String::from("+5 is not a number")));
+        assert_eq!(parse_u64("0x+5"), Err(String::from("0x+5 is not a number")));
+        assert_eq!(parse_u64("-5"), Err(String::from("-5 is not a base 10 number")));
+        assert_eq!(parse_u64("0b102"), Err(String::from("0b102 is not a base 2 number")));
+        assert_eq!(parse_u64("12a"), Err(String::from("12a is not a base 10 number")));
+        assert_eq!(parse_u64("1#0"), Err(String::from("1#0 doesn't have a base from 2 to 36")));
+        assert_eq!(parse_u64("37#0"), Err(String::from("37#0 doesn't have a base from 2 to 36")));
+        assert_eq!(parse_u64("#0"), Err(String::from("#0 doesn't have a base from 2 to 36")));
+        assert_eq!(parse_u64("18446744073709551616"), Err(String::from("18446744073709551616 doesn't fit in 64 bits")));
+        assert_eq!(parse_u64("0x1_0000_0000_0000_0000"), Err(String::from("0x1_0000_0000_0000_0000 doesn't fit in 64 bits")));
+        assert_eq!(parse_u64("18446744073709551615"), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn bit_view_rows() {
+        let expected = "  31   27   23   19   15   11   7    3\n  \
+            0000 0000 0000 0000 0000 0000 0010 1010   CF=0 PF=0 AF=0 ZF=0 SF=0 OF=0 (eflags 0x00000202)";
+        assert_eq!(bit_view(42, 32, 0x202), expected);
+
+        let view = bit_view(0x8000_0001_f000_000f, 64, EFLAGS_CF | EFLAGS_ZF | EFLAGS_OF | 2);
+        let lines: Vec<&str> = view.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "  63   59   55   51   47   43   39   35");
+        assert_eq!(lines[1], "  1000 0000 0000 0000 0000 0000 0000 0001");
+        assert_eq!(lines[2], "  31   27   23   19   15   11   7    3");
+        assert_eq!(lines[3], "  1111 0000 0000 0000 0000 0000 0000 1111   CF=1 PF=0 AF=0 ZF=1 SF=0 OF=1 (eflags 0x00000843)");
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{self, BufRead, Write};
+
+use crate::assembler::{Assembler, Mode};
+use crate::calculator::CalcVm;
+use crate::expression::BinaryOp;
+use crate::guest_fault::STUBS_OFFSET;
+use crate::int_calc::Signedness;
+use crate::vcpu_regs::*;
+
+// Reverse Polish notation on the guest stack. Every line typed becomes one guest program: numbers are PUSHed, operators
+// POP their operands into EAX/ECX and PUSH the result, and the program halts with the stack left in guest RAM for the
+// next line. What the session shows as the stack is read back from guest memory between SS:ESP and the initial ESP.
+
+const HELP: &str = "\
+<number>                  Push a number: decimal and may be negative, 0x hex, 0b binary, 0o octal,
+                          <base>#<digits> or a character such as 'A'
++ - * / % & | ^ << >>     Replace the top two values with the result, / % and >> are signed
+neg not                   Replace the top value with its negation or complement
+dup                       Push a copy of the top value
+swap                      Exchange the top two values
+drop                      Remove the top value
+clear                     Empty the stack
+help                      This text
+quit                      Leave
+
+Several words can go on one line, e.g. `3 4 + 12 *`. A line that fails leaves the stack as it was.";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Word {
+    Number(u32),
+    Binary(BinaryOp),
+    Neg,
+    Not,
+    Dup,
+    Swap,
+    Drop,
+    Clear
+}
+
+impl Word {
+
+    fn parse(text: &str) -> Option<Self> {
+        if let Some(op) = BinaryOp::from_symbol(text) {
+            return Some(Word::Binary(op));
+        }
+        match text {
+            "neg" => Some(Word::Neg),
+            "not" | "~" => Some(Word::Not),
+            "dup" => Some(Word::Dup),
+            "swap" => Some(Word::Swap),
+            "drop" => Some(Word::Drop),
+            "clear" => Some(Word::Clear),
+            _ => Signedness::Unsigned.parse(text).or_else(|_| Signedness::Signed.parse(text)).ok().map(Word::Number)
+        }
+    }
+
+    /// How many values the word takes off the stack and how many it puts back.
+    fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Word::Number(_) => (0, 1),
+            Word::Binary(_) => (2, 1),
+            Word::Swap => (2, 2),
+            Word::Neg | Word::Not => (1, 1),
+            Word::Dup => (1, 2),
+            Word::Drop => (1, 0),
+            // Takes everything, checked separately
+            Word::Clear => (0, 0)
+        }
+    }
+
+    /// Appends the guest code for the word.
+    fn emit(&self, assembler: Assembler, stack_top: u32) -> Assembler {
+        match self {
+            Word::Number(value) => assembler.line(&format!("push dword {:#x}", value)),
+            Word::Binary(op) => {
+                let mut assembler = assembler.line("pop ecx").line("pop eax");
+                for line in op.instructions().iter() {
+                    assembler = assembler.line(line);
+                }
+                assembler.line("push eax")
+            }
+            Word::Neg => assembler.line("pop eax").line("neg eax").line("push eax"),
+            Word::Not => assembler.line("pop eax").line("not eax").line("push eax"),
+            Word::Dup => assembler.line("pop eax").line("push eax").line("push eax"),
+            Word::Swap => assembler.line("pop eax").line("pop ecx").line("push eax").line("push ecx"),
+            Word::Drop => assembler.line("add esp, 4"),
+            Word::Clear => assembler.line(&format!("mov esp, {:#x}", stack_top))
+        }
+    }
+}
+
+/// The interactive RPN calculator behind `hypercalc rpn`.
+pub struct RpnCalculator<'a> {
+    calc_vm: &'a mut CalcVm,
+    /// The guest's ESP after the last line, where the top of the stack is.
+    stack_pointer: u32,
+    /// ESP with the stack empty, the initial ESP.
+    stack_top: u32
+}
+
+impl<'a> RpnCalculator<'a> {
+
+    /// Associated function constructor. Starts with an empty stack at the VM's initial ESP.
+    pub fn new(calc_vm: &'a mut CalcVm) -> Self {
+        let stack_top = calc_vm.initial_state.gprs[REG_RSP] as u32;
+        RpnCalculator {
+            calc_vm: calc_vm,
+            stack_pointer: stack_top,
+            stack_top: stack_top
+        }
+    }
+
+    /// The number of values on the stack.
+    pub fn depth(&self) -> usize {
+        ((self.stack_top - self.stack_pointer) / 4) as usize
+    }
+
+    /// The values on the guest stack, read from guest memory, top first.
+    pub fn stack(&mut self) -> Vec<u32> {
+        let bytes = self.calc_vm.read_data(self.stack_pointer, (self.stack_top - self.stack_pointer) as usize);
+        bytes.chunks(4).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect()
+    }
+
+    /// Compiles the words on `line` and runs them in the guest. Nothing is run, and the stack stays as it was, unless
+    /// every word is known and has the values it needs, and the code fits below the fault handler; if the guest fails
+    /// the stack is put back too. On failure returns a description of what went wrong.
+    pub fn execute(&mut self, line: &str) -> Result<(), String> {
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+
+        let max_depth = (self.stack_top / 4) as usize;
+        let mut depth = self.depth();
+        let mut assembler = Assembler::new(Mode::Bits16);
+
+        for text in line.split_whitespace() {
+            let word = match Word::parse(text) {
+                Some(word) => word,
+                None => return Err(format!("Unknown word {}, try help", text))
+            };
+
+            let (takes, leaves) = word.stack_effect();
+            if depth < takes {
+                return Err(format!("{} needs {} values on the stack, there {} {}", text, takes, if depth == 1 { "is" } else { "are" }, depth));
+            }
+            depth = if word == Word::Clear { 0 } else { depth - takes + leaves };
+            if depth > max_depth {
+                return Err(format!("Stack overflow at {}, the guest stack holds {} values", text, max_depth));
+            }
+
+            assembler = word.emit(assembler, self.stack_top);
+        }
+        let code = assembler.line("hlt").assemble()?;
+        if code.bytes.len() > STUBS_OFFSET as usize {
+            return Err(format!("The line's code takes {} bytes and would overwrite the fault handler at {:#x}", code.bytes.len(), STUBS_OFFSET));
+        }
+
+        let saved_stack = self.calc_vm.read_data(self.stack_pointer, (self.stack_top - self.stack_pointer) as usize);
+
+        self.calc_vm.load_code(&code.bytes)?;
+        self.calc_vm.reset_cpu_state();
+        self.calc_vm.vcpu().cpu_state.set_gpr32(REG_RSP, self.stack_pointer);
+
+        if let Err(error_message) = self.calc_vm.run() {
+            self.calc_vm.write_data(self.stack_pointer, &saved_stack)?;
+            return Err(error_message.into());
+        }
+
+        self.stack_pointer = self.calc_vm.vcpu().cpu_state.gpr32(REG_RSP);
+        Ok(())
+    }
+
+    /// Prints the stack the way HP calculators show it: the top value last, as level 1.
+    pub fn print_stack(&mut self) {
+        let stack = self.stack();
+        if stack.is_empty() {
+            println!("(empty)");
+        }
+        for (index, value) in stack.iter().enumerate().rev() {
+            println!("{:>3}: {} ({:#010x})", index + 1, *value as i32, value);
+        }
+    }
+
+    /// Reads lines from stdin until `quit` or end of input, showing the stack after each.
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+
+        loop {
+            print!("(rpn) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+
+            match line.trim() {
+                "quit" | "q" => break,
+                "help" | "?" => {
+                    println!("{}", HELP);
+                    continue;
+                }
+                line => {
+                    if let Err(error_message) = self.execute(line) {
+                        println!("{}", error_message);
+                    }
+                }
+            }
+            self.print_stack();
+        }
+    }
+}
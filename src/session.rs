@@ -0,0 +1,106 @@
+use std::io::{self, BufRead, Write};
+
+use crate::calculator::{CalcVm, RunError};
+use crate::expression;
+use crate::radix::{self, Radix};
+use crate::variables::Variables;
+
+// The interactive infix calculator: expressions and assignments such as `x = 42` or `y = x * 3` evaluated one after
+// the other in the same VM, so the variables, which live in guest RAM, carry over from line to line.
+
+const HELP: &str = "\
+<expression>              Evaluate, e.g. `(3 + 4) * 12 - 7 / 2`, `gcd(84, 36)` or `ans + 1`
+<name> = <expression>     Evaluate and store the value in variable <name>, defining it if it is new
+vars                      List the variables with their guest addresses
+help                      This text
+quit                      Leave
+
+Every result is also stored in `ans`. Several statements can go on one line separated by `;`, e.g. `x = 6; x * 7`.";
+
+/// The infix calculator behind `hypercalc calc` and `hypercalc expr`.
+pub struct ExpressionSession<'a> {
+    calc_vm: &'a mut CalcVm,
+    variables: Variables,
+    /// The base results are shown in, None for decimal with hex alongside.
+    radix: Option<Radix>,
+    /// Whether each result is followed by its bit view.
+    bits: bool
+}
+
+impl<'a> ExpressionSession<'a> {
+
+    /// Associated function constructor. Starts with only `ans` defined, as 0. On failure returns a description of
+    /// what went wrong.
+    pub fn new(calc_vm: &'a mut CalcVm, radix: Option<Radix>, bits: bool) -> Result<Self, String> {
+        let variables = Variables::new(calc_vm)?;
+        Ok(ExpressionSession {
+            calc_vm: calc_vm,
+            variables: variables,
+            radix: radix,
+            bits: bits
+        })
+    }
+
+    /// Evaluates the `;` separated statements on `line` in the guest, printing each result. Stops at the first one
+    /// that fails and returns the exception the guest took or a description of what else went wrong.
+    pub fn execute(&mut self, line: &str) -> Result<(), RunError> {
+        for text in line.split(';').map(|text| text.trim()).filter(|text| !text.is_empty()) {
+            let statement = expression::parse_statement(text)?;
+            let value = expression::execute(self.calc_vm, &statement, &mut self.variables)?;
+            match &statement.target {
+                Some(target) => self.print_result(target, value),
+                None => self.print_result(text, value)
+            }
+        }
+        Ok(())
+    }
+
+    fn print_result(&mut self, text: &str, value: u32) {
+        match self.radix {
+            None => println!("{} = {} ({:#010x})", text, value as i32, value),
+            Some(radix) if radix.is_decimal() => println!("{} = {}", text, value as i32),
+            Some(radix) => println!("{} = {}", text, radix.format(value as u64))
+        }
+        if self.bits {
+            println!("{}", radix::bit_view(value as u64, 32, self.calc_vm.vcpu().cpu_state.eflags()));
+        }
+    }
+
+    /// Prints every variable with its value, read from guest memory, and where it is.
+    pub fn print_variables(&mut self) {
+        for (name, address, value) in self.variables.dump(self.calc_vm) {
+            let shown = match self.radix {
+                Some(radix) if !radix.is_decimal() => radix.format(value as u64),
+                _ => format!("{} ({:#010x})", value as i32, value)
+            };
+            println!("  {:<12} DS:{:#06x}  {}", name, address, shown);
+        }
+    }
+
+    /// Reads lines from stdin until `quit` or end of input.
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+
+        loop {
+            print!("(calc) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+
+            match line.trim() {
+                "quit" | "q" => break,
+                "help" | "?" => println!("{}", HELP),
+                "vars" => self.print_variables(),
+                line => {
+                    if let Err(error) = self.execute(line) {
+                        println!("{}", String::from(error));
+                    }
+                }
+            }
+        }
+    }
+}
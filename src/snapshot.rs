@@ -0,0 +1,63 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::port_io::DeviceState;
+use crate::state_file::VcpuSnapshot;
+
+// Whole VM snapshots: every vCPU's state (see state_file), guest memory and the devices on the port I/O bus, in one
+// bincode file. Restoring one into a freshly created CalcVm resumes the guest where it was saved.
+
+/// Bumped whenever the layout changes. Snapshots with another version are rejected.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Guest physical memory starting at `guest_physical`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub guest_physical: u64,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct VmSnapshot {
+    pub version: u32,
+    /// Indexed by vCPU id.
+    pub vcpus: Vec<VcpuSnapshot>,
+    pub memory: Vec<MemoryRegion>,
+    pub devices: Vec<DeviceState>
+}
+
+impl VmSnapshot {
+
+    /// Associated function constructor.
+    pub fn new(vcpus: Vec<VcpuSnapshot>, memory: Vec<MemoryRegion>, devices: Vec<DeviceState>) -> Self {
+        VmSnapshot {
+            version: SNAPSHOT_FORMAT_VERSION,
+            vcpus: vcpus,
+            memory: memory,
+            devices: devices
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Serializing a snapshot to bincode can't fail")
+    }
+
+    /// The version is the first four bytes (little endian) and is checked before anything else is decoded.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let version: u32 = bincode::deserialize(bytes).map_err(|e| format!("Invalid snapshot: {}", e))?;
+        if version != SNAPSHOT_FORMAT_VERSION {
+            return Err(format!("Snapshot format version {} is not supported, expected {}", version, SNAPSHOT_FORMAT_VERSION));
+        }
+        bincode::deserialize(bytes).map_err(|e| format!("Invalid snapshot: {}", e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_bytes()).map_err(|e| format!("Unable to write {}: {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        VmSnapshot::from_bytes(&bytes)
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt;
+
+use crate::guest_debug::{DR7_L0, DR7_LE, DR7_LEN_SHIFT, DR7_RW_SHIFT, HW_BREAKPOINT_COUNT};
+use crate::haxm_interface_windows::{interruptibility_state_t, segment_desc_t, vcpu_state_t};
+use crate::vcpu_regs::*;
+
+// Readable dumps of the vCPU state: Display gives the layout a person reads, Debug the struct-like form, and
+// diff_states() the registers that differ between two states.
+
+const EFLAGS_NAMES: [(u64, &str); 16] = [
+    (EFLAGS_CF as u64, "CF"), (EFLAGS_PF as u64, "PF"), (EFLAGS_AF as u64, "AF"), (EFLAGS_ZF as u64, "ZF"),
+    (EFLAGS_SF as u64, "SF"), (EFLAGS_TF as u64, "TF"), (EFLAGS_IF as u64, "IF"), (EFLAGS_DF as u64, "DF"),
+    (EFLAGS_OF as u64, "OF"), (EFLAGS_NT as u64, "NT"), (EFLAGS_RF as u64, "RF"), (EFLAGS_VM as u64, "VM"),
+    (EFLAGS_AC as u64, "AC"), (EFLAGS_VIF as u64, "VIF"), (EFLAGS_VIP as u64, "VIP"),
+    (EFLAGS_ID as u64, "ID")
+];
+
+// Control register bits, Intel SDM Vol. 3 2.5
+const CR0_NAMES: [(u64, &str); 11] = [
+    (1 << 0, "PE"), (1 << 1, "MP"), (1 << 2, "EM"), (1 << 3, "TS"), (1 << 4, "ET"), (1 << 5, "NE"),
+    (1 << 16, "WP"), (1 << 18, "AM"), (1 << 29, "NW"), (1 << 30, "CD"), (1 << 31, "PG")
+];
+
+const CR4_NAMES: [(u64, &str); 20] = [
+    (1 << 0, "VME"), (1 << 1, "PVI"), (1 << 2, "TSD"), (1 << 3, "DE"), (1 << 4, "PSE"), (1 << 5, "PAE"),
+    (1 << 6, "MCE"), (1 << 7, "PGE"), (1 << 8, "PCE"), (1 << 9, "OSFXSR"), (1 << 10, "OSXMMEXCPT"),
+    (1 << 11, "UMIP"), (1 << 13, "VMXE"), (1 << 14, "SMXE"), (1 << 16, "FSGSBASE"), (1 << 17, "PCIDE"),
+    (1 << 18, "OSXSAVE"), (1 << 20, "SMEP"), (1 << 21, "SMAP"), (1 << 22, "PKE")
+];
+
+const EFER_LMA: u64 = 1 << 10;
+
+const EFER_NAMES: [(u64, &str); 4] = [(1 << 0, "SCE"), (1 << 8, "LME"), (EFER_LMA, "LMA"), (1 << 11, "NXE")];
+
+const INTERRUPTIBILITY_NAMES: [(u64, &str); 4] = [(1 << 0, "sti"), (1 << 1, "movss"), (1 << 2, "smi"), (1 << 3, "nmi")];
+
+const GPR_NAMES_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
+];
+
+// Segment access rights as HAXM stores them (the VMX layout, SDM Vol. 3 24.4.1)
+const AR_ACCESSED: u32 = 1 << 0;
+const AR_CODE: u32 = 1 << 3;
+const AR_S: u32 = 1 << 4;
+const AR_DPL_SHIFT: u32 = 5;
+const AR_PRESENT: u32 = 1 << 7;
+const AR_LONG: u32 = 1 << 13;
+pub const AR_DB: u32 = 1 << 14;
+const AR_GRANULARITY: u32 = 1 << 15;
+const AR_UNUSABLE: u32 = 1 << 16;
+
+/// The names of the bits set in `value`, space separated, "-" when none are.
+fn flag_names(value: u64, names: &[(u64, &str)]) -> String {
+    let set: Vec<&str> = names.iter().filter(|(bit, _)| value & bit != 0).map(|(_, name)| *name).collect();
+    if set.is_empty() {
+        String::from("-")
+    }
+    else {
+        set.join(" ")
+    }
+}
+
+pub fn eflags_names(eflags: u32) -> String {
+    let mut names = flag_names(eflags as u64, &EFLAGS_NAMES);
+    let iopl = (eflags >> 12) & 0x3;
+    if iopl != 0 {
+        names.push_str(&format!(" IOPL={}", iopl));
+    }
+    names
+}
+
+pub fn cr0_names(cr0: u64) -> String {
+    flag_names(cr0, &CR0_NAMES)
+}
+
+pub fn cr4_names(cr4: u64) -> String {
+    flag_names(cr4, &CR4_NAMES)
+}
+
+pub fn efer_names(efer: u64) -> String {
+    flag_names(efer, &EFER_NAMES)
+}
+
+/// The breakpoints DR7 enables, e.g. `dr0 L execute`, `dr1 L write 4`.
+pub fn dr7_names(dr7: u64) -> String {
+    let mut slots = vec!();
+    for slot in 0..HW_BREAKPOINT_COUNT {
+        let local = dr7 & (DR7_L0 << (slot * 2)) != 0;
+        let global = dr7 & (DR7_L0 << (slot * 2 + 1)) != 0;
+        if !local && !global {
+            continue;
+        }
+
+        let rw = (dr7 >> (DR7_RW_SHIFT + slot as u64 * 4)) & 0b11;
+        let length = match (dr7 >> (DR7_LEN_SHIFT + slot as u64 * 4)) & 0b11 {
+            0b00 => 1,
+            0b01 => 2,
+            0b10 => 8,
+            _ => 4
+        };
+        let scope = match (local, global) {
+            (true, true) => "LG",
+            (true, false) => "L",
+            _ => "G"
+        };
+        slots.push(match rw {
+            0b00 => format!("dr{} {} execute", slot, scope),
+            0b01 => format!("dr{} {} write {}", slot, scope, length),
+            0b10 => format!("dr{} {} io {}", slot, scope, length),
+            _ => format!("dr{} {} read/write {}", slot, scope, length)
+        });
+    }
+    if dr7 & DR7_LE != 0 {
+        slots.push(String::from("LE"));
+    }
+    if dr7 & (DR7_LE << 1) != 0 {
+        slots.push(String::from("GE"));
+    }
+    if slots.is_empty() {
+        String::from("-")
+    }
+    else {
+        slots.join(", ")
+    }
+}
+
+/// The VMX guest activity state (SDM Vol. 3 24.4.2).
+pub fn activity_name(activity_state: u32) -> &'static str {
+    match activity_state {
+        0 => "active",
+        1 => "hlt",
+        2 => "shutdown",
+        3 => "wait-for-sipi",
+        _ => "unknown"
+    }
+}
+
+/// The access rights decoded, e.g. `code readable accessed dpl=0 16bit`.
+fn describe_access_rights(ar: u32) -> String {
+    if ar & AR_UNUSABLE != 0 {
+        return String::from("unusable");
+    }
+
+    let mut description = String::new();
+    if ar & AR_S == 0 {
+        // System segments: only the ones the calculator's state can contain are named
+        let kind = match ar & 0xF {
+            0x2 => "LDT",
+            0x3 => "TSS16 busy",
+            0xB => "TSS32 busy",
+            _ => "system"
+        };
+        description.push_str(kind);
+    }
+    else if ar & AR_CODE != 0 {
+        description.push_str("code");
+        description.push_str(if ar & 0x2 != 0 { " readable" } else { " exec-only" });
+        if ar & 0x4 != 0 {
+            description.push_str(" conforming");
+        }
+    }
+    else {
+        description.push_str("data");
+        description.push_str(if ar & 0x2 != 0 { " writable" } else { " read-only" });
+        if ar & 0x4 != 0 {
+            description.push_str(" expand-down");
+        }
+    }
+
+    if ar & AR_ACCESSED != 0 && ar & AR_S != 0 {
+        description.push_str(" accessed");
+    }
+    description.push_str(&format!(" dpl={}", (ar >> AR_DPL_SHIFT) & 0x3));
+    if ar & AR_PRESENT == 0 {
+        description.push_str(" not-present");
+    }
+    if ar & AR_LONG != 0 {
+        description.push_str(" 64bit");
+    }
+    else if ar & AR_S != 0 {
+        description.push_str(if ar & AR_DB != 0 { " 32bit" } else { " 16bit" });
+    }
+    if ar & AR_GRANULARITY != 0 {
+        description.push_str(" 4k-granular");
+    }
+    description
+}
+
+impl segment_desc_t {
+    pub fn ar(&self) -> u32 {
+        unsafe { self.anon_union.ar }
+    }
+}
+
+/// `0008 base=0000000000002000 limit=00003fff ar=0009b code readable accessed dpl=0 16bit`
+impl fmt::Display for segment_desc_t {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x} base={:016x} limit={:08x} ar={:05x} {}", self.selector, self.base, self.limit, self.ar(), describe_access_rights(self.ar()))
+    }
+}
+
+impl fmt::Debug for segment_desc_t {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("segment_desc_t")
+            .field("selector", &format_args!("{:#x}", self.selector))
+            .field("base", &format_args!("{:#x}", self.base))
+            .field("limit", &format_args!("{:#x}", self.limit))
+            .field("ar", &format_args!("{:#x} ({})", self.ar(), describe_access_rights(self.ar())))
+            .finish()
+    }
+}
+
+impl interruptibility_state_t {
+    pub fn raw(&self) -> u32 {
+        unsafe { self.raw }
+    }
+}
+
+/// The blocking conditions in effect, e.g. `sti movss`, or `-`.
+impl fmt::Display for interruptibility_state_t {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", flag_names(self.raw() as u64, &INTERRUPTIBILITY_NAMES))
+    }
+}
+
+impl fmt::Debug for interruptibility_state_t {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw = self.raw();
+        f.debug_struct("interruptibility_state_t")
+            .field("sti_blocking", &(raw & 1 != 0))
+            .field("movss_blocking", &(raw & 2 != 0))
+            .field("smi_blocking", &(raw & 4 != 0))
+            .field("nmi_blocking", &(raw & 8 != 0))
+            .finish()
+    }
+}
+
+impl vcpu_state_t {
+
+    /// True when the vCPU runs in 64 bit mode (EFER.LMA), and the upper halves of the registers matter.
+    pub fn long_mode(&self) -> bool {
+        self.efer as u64 & EFER_LMA != 0
+    }
+
+    /// The segment registers by name, in the order they are dumped.
+    pub fn segments(&self) -> [(&'static str, &segment_desc_t); 8] {
+        [
+            ("cs", &self.cs), ("ss", &self.ss), ("ds", &self.ds), ("es", &self.es), ("fs", &self.fs), ("gs", &self.gs),
+            ("ldt", &self.ldt), ("tr", &self.tr)
+        ]
+    }
+}
+
+/// Every register of the state as a name and formatted value, decoded where that helps. Used to diff two states.
+fn state_fields(cpu_state: &vcpu_state_t) -> Vec<(String, String)> {
+    let mut fields = vec!();
+
+    if cpu_state.long_mode() {
+        for (index, name) in GPR_NAMES_64.iter().enumerate() {
+            fields.push((name.to_string(), format!("{:016x}", cpu_state.gpr(index))));
+        }
+        fields.push((String::from("rip"), format!("{:016x}", unsafe { cpu_state.anon_union_2.rip })));
+    }
+    else {
+        for (index, name) in GPR_NAMES_32.iter().enumerate() {
+            fields.push((name.to_string(), format!("{:08x}", cpu_state.gpr32(index))));
+        }
+        fields.push((String::from("eip"), format!("{:08x}", cpu_state.eip())));
+    }
+    fields.push((String::from("eflags"), format!("{:08x} [{}]", cpu_state.eflags(), eflags_names(cpu_state.eflags()))));
+
+    for (name, segment) in cpu_state.segments().iter() {
+        fields.push((name.to_string(), segment.to_string()));
+    }
+    // Only the base and limit of the descriptor table registers mean anything
+    fields.push((String::from("gdt"), format!("base={:016x} limit={:04x}", cpu_state.gdt.base, cpu_state.gdt.limit)));
+    fields.push((String::from("idt"), format!("base={:016x} limit={:04x}", cpu_state.idt.base, cpu_state.idt.limit)));
+
+    fields.push((String::from("cr0"), format!("{:08x} [{}]", cpu_state.cr0, cr0_names(cpu_state.cr0))));
+    fields.push((String::from("cr2"), format!("{:08x}", cpu_state.cr2)));
+    fields.push((String::from("cr3"), format!("{:08x}", cpu_state.cr3)));
+    fields.push((String::from("cr4"), format!("{:08x} [{}]", cpu_state.cr4, cr4_names(cpu_state.cr4))));
+    fields.push((String::from("efer"), format!("{:08x} [{}]", cpu_state.efer, efer_names(cpu_state.efer as u64))));
+    let debug_registers = [cpu_state.dr0, cpu_state.dr1, cpu_state.dr2, cpu_state.dr3];
+    for (index, value) in debug_registers.iter().enumerate() {
+        fields.push((format!("dr{}", index), format!("{:08x}", value)));
+    }
+    fields.push((String::from("dr6"), format!("{:08x}", cpu_state.dr6)));
+    fields.push((String::from("dr7"), format!("{:08x} [{}]", cpu_state.dr7, dr7_names(cpu_state.dr7))));
+    fields.push((String::from("sysenter_cs"), format!("{:04x}", cpu_state.sysenter_cs)));
+    fields.push((String::from("sysenter_eip"), format!("{:08x}", cpu_state.sysenter_eip)));
+    fields.push((String::from("sysenter_esp"), format!("{:08x}", cpu_state.sysenter_esp)));
+    fields.push((String::from("activity"), String::from(activity_name(cpu_state.activity_state))));
+    fields.push((String::from("interruptibility"), cpu_state.interruptibility_state.to_string()));
+    fields
+}
+
+/// The general purpose registers, four to a line, then the rest one per line:
+///
+/// ```text
+/// eax=00000003 ecx=00000002 edx=00000000 ebx=00000000
+/// esp=00001000 ebp=00000000 esi=00000000 edi=00000000
+/// eip=00000003 eflags=00000006 [PF]
+/// cs   0000 base=0000000000002000 limit=00003fff ar=0009b code readable accessed dpl=0 16bit
+/// ...
+/// ```
+impl fmt::Display for vcpu_state_t {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = state_fields(self);
+        let gpr_count = if self.long_mode() { 16 } else { 8 };
+
+        for (index, (name, value)) in fields[..gpr_count].iter().enumerate() {
+            write!(f, "{}={}", name, value)?;
+            write!(f, "{}", if index % 4 == 3 { "\n" } else { " " })?;
+        }
+        writeln!(f, "{}={} eflags={}", fields[gpr_count].0, fields[gpr_count].1, fields[gpr_count + 1].1)?;
+
+        let rest = &fields[gpr_count + 2..];
+        for (index, (name, value)) in rest.iter().enumerate() {
+            write!(f, "{:<4} {}", name, value)?;
+            if index + 1 < rest.len() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for vcpu_state_t {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("vcpu_state_t");
+        for (name, value) in state_fields(self).iter() {
+            debug.field(name, &format_args!("{}", value));
+        }
+        debug.finish()
+    }
+}
+
+/// A register that differs between two states, formatted like the dumps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateChange {
+    pub name: String,
+    pub old: String,
+    pub new: String
+}
+
+impl fmt::Display for StateChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<4} {} -> {}", self.name, self.old, self.new)
+    }
+}
+
+/// The registers that differ from `old` to `new`, in dump order. Fields are matched by name, so entering or leaving
+/// long mode shows up as the 32 bit registers going away and the 64 bit ones appearing (`-` on the missing side).
+pub fn diff_states(old: &vcpu_state_t, new: &vcpu_state_t) -> Vec<StateChange> {
+    let old_fields = state_fields(old);
+    let new_fields = state_fields(new);
+    let value_of = |fields: &[(String, String)], name: &str| {
+        fields.iter().find(|(field, _)| field == name).map(|(_, value)| value.clone()).unwrap_or_else(|| String::from("-"))
+    };
+
+    let mut changes = vec!();
+    for (name, _) in old_fields.iter().chain(new_fields.iter().filter(|(name, _)| !old_fields.iter().any(|(old_name, _)| old_name == name))) {
+        let old_value = value_of(&old_fields, name);
+        let new_value = value_of(&new_fields, name);
+        if old_value != new_value {
+            changes.push(StateChange { name: name.clone(), old: old_value, new: new_value });
+        }
+    }
+    changes
+}
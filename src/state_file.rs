@@ -0,0 +1,413 @@
+use std::fs;
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+
+use crate::haxm_interface_windows::*;
+use crate::fpu::{XMM_REGISTER_COUNT, X87_REGISTER_COUNT};
+
+// A serde representation of the vCPU state, kept apart from the #[repr(C)] structures HAXM reads so those can follow
+// the driver's headers and this can stay stable. Stored as JSON (readable, used for fixtures) or bincode.
+
+/// Bumped whenever a field is added, removed or changes meaning. Files with another version are rejected.
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+/// The calculator's starting state: 16 bit protected mode with CS and DS covering the code and data halves of memory,
+/// paging off and EIP at the start of the code segment.
+pub const INITIAL_STATE: &str = include_str!("../fixtures/initial_state.json");
+
+/// The MSRs a saved state includes. The time stamp counter is left out, it never compares equal between runs.
+pub const SAVED_MSRS: [u64; 6] = [IA32_APIC_BASE, IA32_SYSENTER_CS, IA32_SYSENTER_ESP, IA32_SYSENTER_EIP, IA32_PAT, IA32_EFER];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SegmentState {
+    pub selector: u16,
+    pub base: u64,
+    pub limit: u32,
+    /// Access rights in the VMX layout HAXM uses.
+    pub ar: u32
+}
+
+impl SegmentState {
+    fn from_segment(segment: &segment_desc_t) -> Self {
+        SegmentState {
+            selector: segment.selector,
+            base: segment.base,
+            limit: segment.limit,
+            ar: unsafe { segment.anon_union.ar }
+        }
+    }
+
+    fn to_segment(self, segment: &mut segment_desc_t) {
+        segment.selector = self.selector;
+        segment.base = self.base;
+        segment.limit = self.limit;
+        segment.anon_union.ar = self.ar;
+    }
+}
+
+/// Everything in vcpu_state_t except its padding. Fields missing from a file are zero.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct CpuState {
+    /// RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8 - R15 (the REG_* order).
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+
+    pub cs: SegmentState,
+    pub ss: SegmentState,
+    pub ds: SegmentState,
+    pub es: SegmentState,
+    pub fs: SegmentState,
+    pub gs: SegmentState,
+    pub ldt: SegmentState,
+    pub tr: SegmentState,
+    pub gdt: SegmentState,
+    pub idt: SegmentState,
+
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+
+    pub dr0: u64,
+    pub dr1: u64,
+    pub dr2: u64,
+    pub dr3: u64,
+    pub dr6: u64,
+    pub dr7: u64,
+    pub pde: u64,
+
+    pub efer: u32,
+
+    pub sysenter_cs: u32,
+    pub sysenter_eip: u64,
+    pub sysenter_esp: u64,
+
+    pub activity_state: u32,
+    pub interruptibility_state: u32
+}
+
+impl CpuState {
+
+    pub fn from_vcpu_state(cpu_state: &vcpu_state_t) -> Self {
+        let mut gprs = [0; 16];
+        for (index, value) in gprs.iter_mut().enumerate() {
+            *value = cpu_state.gpr(index);
+        }
+
+        CpuState {
+            gprs: gprs,
+            rip: unsafe { cpu_state.anon_union_2.rip },
+            rflags: unsafe { cpu_state.anon_union_3.rflags },
+            cs: SegmentState::from_segment(&cpu_state.cs),
+            ss: SegmentState::from_segment(&cpu_state.ss),
+            ds: SegmentState::from_segment(&cpu_state.ds),
+            es: SegmentState::from_segment(&cpu_state.es),
+            fs: SegmentState::from_segment(&cpu_state.fs),
+            gs: SegmentState::from_segment(&cpu_state.gs),
+            ldt: SegmentState::from_segment(&cpu_state.ldt),
+            tr: SegmentState::from_segment(&cpu_state.tr),
+            gdt: SegmentState::from_segment(&cpu_state.gdt),
+            idt: SegmentState::from_segment(&cpu_state.idt),
+            cr0: cpu_state.cr0,
+            cr2: cpu_state.cr2,
+            cr3: cpu_state.cr3,
+            cr4: cpu_state.cr4,
+            dr0: cpu_state.dr0,
+            dr1: cpu_state.dr1,
+            dr2: cpu_state.dr2,
+            dr3: cpu_state.dr3,
+            dr6: cpu_state.dr6,
+            dr7: cpu_state.dr7,
+            pde: cpu_state.pde,
+            efer: cpu_state.efer,
+            sysenter_cs: cpu_state.sysenter_cs,
+            sysenter_eip: cpu_state.sysenter_eip,
+            sysenter_esp: cpu_state.sysenter_esp,
+            activity_state: cpu_state.activity_state,
+            interruptibility_state: unsafe { cpu_state.interruptibility_state.raw }
+        }
+    }
+
+    /// A vcpu_state_t holding this state, with zeroed padding.
+    pub fn vcpu_state(&self) -> vcpu_state_t {
+        let mut cpu_state = unsafe { mem::zeroed::<vcpu_state_t>() };
+        self.to_vcpu_state(&mut cpu_state);
+        cpu_state
+    }
+
+    /// Overwrites every field of `cpu_state` this state covers.
+    pub fn to_vcpu_state(&self, cpu_state: &mut vcpu_state_t) {
+        for (index, value) in self.gprs.iter().enumerate() {
+            cpu_state.set_gpr(index, *value);
+        }
+        cpu_state.anon_union_2.rip = self.rip;
+        cpu_state.anon_union_3.rflags = self.rflags;
+
+        self.cs.to_segment(&mut cpu_state.cs);
+        self.ss.to_segment(&mut cpu_state.ss);
+        self.ds.to_segment(&mut cpu_state.ds);
+        self.es.to_segment(&mut cpu_state.es);
+        self.fs.to_segment(&mut cpu_state.fs);
+        self.gs.to_segment(&mut cpu_state.gs);
+        self.ldt.to_segment(&mut cpu_state.ldt);
+        self.tr.to_segment(&mut cpu_state.tr);
+        self.gdt.to_segment(&mut cpu_state.gdt);
+        self.idt.to_segment(&mut cpu_state.idt);
+
+        cpu_state.cr0 = self.cr0;
+        cpu_state.cr2 = self.cr2;
+        cpu_state.cr3 = self.cr3;
+        cpu_state.cr4 = self.cr4;
+        cpu_state.dr0 = self.dr0;
+        cpu_state.dr1 = self.dr1;
+        cpu_state.dr2 = self.dr2;
+        cpu_state.dr3 = self.dr3;
+        cpu_state.dr6 = self.dr6;
+        cpu_state.dr7 = self.dr7;
+        cpu_state.pde = self.pde;
+        cpu_state.efer = self.efer;
+        cpu_state.sysenter_cs = self.sysenter_cs;
+        cpu_state.sysenter_eip = self.sysenter_eip;
+        cpu_state.sysenter_esp = self.sysenter_esp;
+        cpu_state.activity_state = self.activity_state;
+        cpu_state.interruptibility_state.raw = self.interruptibility_state;
+    }
+}
+
+/// The FXSAVE image without its reserved bytes. The 80 bit x87 registers and the XMM registers are stored as
+/// little endian 128 bit integers, the way FXSAVE lays them out.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct FpuState {
+    pub fcw: u16,
+    pub fsw: u16,
+    /// The abridged tag word FXSAVE stores, one bit per register.
+    pub ftw: u8,
+    pub fop: u16,
+    pub fpu_ip: u64,
+    pub fpu_dp: u64,
+    pub mxcsr: u32,
+    pub mxcsr_mask: u32,
+    pub st_mm: [u128; X87_REGISTER_COUNT],
+    pub xmm: [u128; XMM_REGISTER_COUNT]
+}
+
+impl FpuState {
+
+    pub fn from_fx_layout(fpu_state: &fx_layout) -> Self {
+        let mut st_mm = [0; X87_REGISTER_COUNT];
+        for (value, register) in st_mm.iter_mut().zip(fpu_state.st_mm.iter()) {
+            *value = u128::from_le_bytes(*register);
+        }
+        let mut xmm = [0; XMM_REGISTER_COUNT];
+        for (index, value) in xmm.iter_mut().enumerate() {
+            *value = fpu_state.xmm(index);
+        }
+
+        FpuState {
+            fcw: fpu_state.fcw,
+            fsw: fpu_state.fsw,
+            ftw: fpu_state.ftw,
+            fop: fpu_state.fop,
+            fpu_ip: unsafe { fpu_state.anon_union_1.fpu_ip },
+            fpu_dp: unsafe { fpu_state.anon_union_2.fpu_dp },
+            mxcsr: fpu_state.mxcsr,
+            mxcsr_mask: fpu_state.mxcsr_mask,
+            st_mm: st_mm,
+            xmm: xmm
+        }
+    }
+
+    /// Builds the FXSAVE image. The reserved bytes are zero.
+    pub fn to_fx_layout(&self) -> fx_layout {
+        let mut fpu_state = unsafe { mem::zeroed::<fx_layout>() };
+        fpu_state.fcw = self.fcw;
+        fpu_state.fsw = self.fsw;
+        fpu_state.ftw = self.ftw;
+        fpu_state.fop = self.fop;
+        fpu_state.anon_union_1.fpu_ip = self.fpu_ip;
+        fpu_state.anon_union_2.fpu_dp = self.fpu_dp;
+        fpu_state.mxcsr = self.mxcsr;
+        fpu_state.mxcsr_mask = self.mxcsr_mask;
+        for (register, value) in fpu_state.st_mm.iter_mut().zip(self.st_mm.iter()) {
+            *register = value.to_le_bytes();
+        }
+        for (index, value) in self.xmm.iter().enumerate() {
+            fpu_state.set_xmm(index, *value);
+        }
+        fpu_state
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsrState {
+    pub index: u64,
+    pub value: u64
+}
+
+/// A vCPU's complete state as stored in a file. The FPU state and MSRs are optional, so a fixture can hold only the
+/// registers it cares about.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct VcpuSnapshot {
+    pub version: u32,
+    pub cpu: CpuState,
+    // Not skipped when empty: bincode isn't self-describing, every field has to be written
+    #[serde(default)]
+    pub fpu: Option<FpuState>,
+    #[serde(default)]
+    pub msrs: Vec<MsrState>
+}
+
+/// Only the version of a snapshot, read first so a file from another version gets a clear error.
+#[derive(Deserialize)]
+struct SnapshotVersion {
+    version: u32
+}
+
+fn check_version(version: u32) -> Result<(), String> {
+    if version == STATE_FORMAT_VERSION {
+        Ok(())
+    }
+    else {
+        Err(format!("State format version {} is not supported, expected {}", version, STATE_FORMAT_VERSION))
+    }
+}
+
+impl VcpuSnapshot {
+
+    /// Associated function constructor.
+    pub fn new(cpu: CpuState, fpu: Option<FpuState>, msrs: Vec<MsrState>) -> Self {
+        VcpuSnapshot {
+            version: STATE_FORMAT_VERSION,
+            cpu: cpu,
+            fpu: fpu,
+            msrs: msrs
+        }
+    }
+
+    /// The calculator's starting state, from fixtures/initial_state.json.
+    pub fn initial() -> Self {
+        VcpuSnapshot::from_json(INITIAL_STATE).expect("fixtures/initial_state.json is invalid")
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Serializing a snapshot to JSON can't fail")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let version: SnapshotVersion = serde_json::from_str(json).map_err(|e| format!("Invalid state file: {}", e))?;
+        check_version(version.version)?;
+        serde_json::from_str(json).map_err(|e| format!("Invalid state file: {}", e))
+    }
+
+    /// bincode, with the version as the first four bytes (little endian).
+    pub fn to_binary(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Serializing a snapshot to bincode can't fail")
+    }
+
+    pub fn from_binary(bytes: &[u8]) -> Result<Self, String> {
+        let version: u32 = bincode::deserialize(bytes).map_err(|e| format!("Invalid state file: {}", e))?;
+        check_version(version)?;
+        bincode::deserialize(bytes).map_err(|e| format!("Invalid state file: {}", e))
+    }
+
+    /// Writes the snapshot to `path`, as JSON if the name ends in `.json` and as bincode otherwise.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let bytes = if path.ends_with(".json") { self.to_json().into_bytes() } else { self.to_binary() };
+        fs::write(path, bytes).map_err(|e| format!("Unable to write {}: {}", path, e))
+    }
+
+    /// Reads a snapshot written by save().
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        if path.ends_with(".json") {
+            let json = String::from_utf8(bytes).map_err(|_| format!("{} is not UTF-8", path))?;
+            VcpuSnapshot::from_json(&json)
+        }
+        else {
+            VcpuSnapshot::from_binary(&bytes)
+        }
+    }
+
+    /// The MSRs in the form HAX_VCPU_IOCTL_SET_MSRS takes.
+    pub fn vmx_msrs(&self) -> Vec<vmx_msr> {
+        self.msrs.iter().map(|msr| vmx_msr { entry: msr.index, value: msr.value }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A snapshot with every kind of field set, including the largest values each type holds.
+    fn full_snapshot() -> VcpuSnapshot {
+        let mut cpu = VcpuSnapshot::initial().cpu;
+        cpu.gprs[15] = u64::MAX;
+        cpu.rip = 0x1234;
+        cpu.fs = SegmentState { selector: 0x23, base: u64::MAX, limit: u32::MAX, ar: 0xc093 };
+        cpu.sysenter_esp = u64::MAX;
+        cpu.interruptibility_state = 3;
+
+        let fpu = FpuState {
+            fcw: 0x37f,
+            fsw: 0x3800,
+            ftw: 0x80,
+            fop: 0x7ff,
+            fpu_ip: u64::MAX,
+            fpu_dp: 0x1000,
+            mxcsr: 0x1f80,
+            mxcsr_mask: 0xffff,
+            st_mm: [u128::MAX; 8],
+            xmm: [u128::MAX; 16]
+        };
+        let msrs = SAVED_MSRS.iter().map(|index| MsrState { index: *index, value: u64::MAX }).collect();
+        VcpuSnapshot::new(cpu, Some(fpu), msrs)
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let snapshot = full_snapshot();
+        assert_eq!(VcpuSnapshot::from_json(&snapshot.to_json()), Ok(snapshot));
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let snapshot = full_snapshot();
+        let bytes = snapshot.to_binary();
+        assert_eq!(bytes[..4], STATE_FORMAT_VERSION.to_le_bytes());
+        assert_eq!(VcpuSnapshot::from_binary(&bytes), Ok(snapshot));
+    }
+
+    #[test]
+    fn vcpu_state_round_trip() {
+        let snapshot = full_snapshot();
+        assert_eq!(CpuState::from_vcpu_state(&snapshot.cpu.vcpu_state()), snapshot.cpu);
+
+        let fpu = snapshot.fpu.unwrap();
+        assert_eq!(FpuState::from_fx_layout(&fpu.to_fx_layout()), fpu);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut snapshot = full_snapshot();
+        snapshot.version = 2;
+        let expected = Err(String::from("State format version 2 is not supported, expected 1"));
+        assert_eq!(VcpuSnapshot::from_json(&snapshot.to_json()), expected);
+        assert_eq!(VcpuSnapshot::from_binary(&snapshot.to_binary()), expected);
+    }
+
+    #[test]
+    fn initial_state_fixture() {
+        let snapshot = VcpuSnapshot::initial();
+        assert_eq!(snapshot.cpu.cs, SegmentState { selector: 8, base: 0x2000, limit: 0x3fff, ar: 0x9b });
+        assert_eq!(snapshot.cpu.gprs[4], 0x1000);
+        assert_eq!(snapshot.cpu.rflags, 0x202);
+        assert_eq!(snapshot.cpu.es, SegmentState::default());
+        assert_eq!(snapshot.fpu, None);
+        assert!(snapshot.msrs.is_empty());
+    }
+}
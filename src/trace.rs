@@ -0,0 +1,507 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::calculator::RAM_SIZE;
+use crate::debugger::Debugger;
+use crate::guest_debug::{DebugExit, DebugExitKind};
+use crate::haxm::VcpuExit;
+use crate::haxm_interface_windows::{segment_desc_t, vcpu_state_t};
+use crate::vcpu_regs::GPR_NAMES_32;
+
+// Instruction level traces, one JSON object per executed instruction (JSON lines). The guest is single-stepped and
+// the registers and RAM are compared before and after every instruction.
+
+/// A trace that never reaches HLT is cut off here, rather than filling the disk.
+pub const MAX_TRACE_STEPS: u64 = 1_000_000;
+
+const SEGMENT_NAMES: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub name: String,
+    pub old: u64,
+    pub new: u64
+}
+
+/// Bytes of guest memory at a linear address.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u64,
+    /// Hex, lowest address first.
+    pub bytes: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Counts from 0 over the whole trace, across runs.
+    pub step: u64,
+    /// The linear address of the instruction, CS.base + EIP.
+    pub pc: u64,
+    /// Hex.
+    pub bytes: String,
+    pub text: String,
+    /// Registers the instruction changed, besides EIP.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub registers: Vec<RegisterChange>,
+    /// The instruction's ModRM memory operand and what it held before the instruction ran.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_operand: Option<MemoryAccess>,
+    /// Every run of bytes the instruction changed, with the new contents.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub writes: Vec<MemoryAccess>,
+    /// How the guest stopped, when it wasn't just the end of the single-step (e.g. `Halt`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit: Option<String>
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>7} {:08x}: {:<20} {:<32}", self.step, self.pc, self.bytes, self.text)?;
+        for change in self.registers.iter() {
+            write!(f, " {}={:#x}->{:#x}", change.name, change.old, change.new)?;
+        }
+        if let Some(operand) = &self.memory_operand {
+            write!(f, " [{:#x}]={}", operand.address, operand.bytes)?;
+        }
+        for write in self.writes.iter() {
+            write!(f, " [{:#x}]<-{}", write.address, write.bytes)?;
+        }
+        if let Some(exit) = &self.exit {
+            write!(f, " ({})", exit)?;
+        }
+        Ok(())
+    }
+}
+
+fn segment(cpu_state: &vcpu_state_t, number: u8) -> &segment_desc_t {
+    match number {
+        0 => &cpu_state.es,
+        1 => &cpu_state.cs,
+        2 => &cpu_state.ss,
+        3 => &cpu_state.ds,
+        4 => &cpu_state.fs,
+        _ => &cpu_state.gs
+    }
+}
+
+/// The registers a trace follows, by name.
+fn traced_registers(cpu_state: &vcpu_state_t) -> Vec<(&'static str, u64)> {
+    let mut registers = vec!();
+    for (index, name) in GPR_NAMES_32.iter().enumerate() {
+        registers.push((*name, cpu_state.gpr32(index) as u64));
+    }
+    registers.push(("eflags", cpu_state.eflags() as u64));
+    for (number, name) in SEGMENT_NAMES.iter().enumerate() {
+        registers.push((*name, segment(cpu_state, number as u8).selector as u64));
+    }
+    registers.push(("cr0", cpu_state.cr0));
+    registers
+}
+
+/// Runs of bytes that differ between `before` and `after`, as writes at their linear addresses.
+fn changed_memory(before: &[u8], after: &[u8]) -> Vec<MemoryAccess> {
+    let mut writes = vec!();
+    let mut start = None;
+
+    for address in 0..=before.len() {
+        let differs = address < before.len() && before[address] != after[address];
+        match (start, differs) {
+            (None, true) => start = Some(address),
+            (Some(run_start), false) => {
+                writes.push(MemoryAccess { address: run_start as u64, bytes: to_hex(&after[run_start..address]) });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    writes
+}
+
+/// Writes a trace of everything the guest executes through a Debugger.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    step: u64
+}
+
+impl Tracer {
+
+    /// Associated function constructor. Records go to `output`, one JSON object per line.
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Tracer {
+            output: output,
+            step: 0
+        }
+    }
+
+    /// Associated function constructor. Creates (or truncates) the trace file at `path`.
+    pub fn create(path: &str) -> Result<Self, String> {
+        match File::create(path) {
+            Ok(file) => Ok(Tracer::new(Box::new(BufWriter::new(file)))),
+            Err(error) => Err(format!("Unable to create trace file {}: {}", path, error))
+        }
+    }
+
+    /// Executes one instruction and records it. On failure returns a description of what went wrong.
+    pub fn step(&mut self, debugger: &mut Debugger) -> Result<VcpuExit, String> {
+        let instruction = debugger.current_instruction().ok_or("The instruction pointer is outside of RAM")?;
+        let memory_before = debugger.read_memory(0, RAM_SIZE as usize).unwrap_or_default();
+
+        let (registers_before, operand_address) = {
+            let cpu_state = &debugger.calc_vm.vcpu().cpu_state;
+            let mut gprs = [0u32; 8];
+            for (index, gpr) in gprs.iter_mut().enumerate() {
+                *gpr = cpu_state.gpr32(index);
+            }
+            let operand_address = instruction.memory.map(|memory| segment(cpu_state, memory.segment).base + memory.offset(&gprs));
+            (traced_registers(cpu_state), operand_address)
+        };
+        let memory_operand = match (operand_address, instruction.memory) {
+            (Some(address), Some(memory)) => debugger.read_memory(address, memory.size).map(|bytes| MemoryAccess { address: address, bytes: to_hex(&bytes) }),
+            _ => None
+        };
+
+        let exit = debugger.step()?;
+
+        let registers_after = traced_registers(&debugger.calc_vm.vcpu().cpu_state);
+        let memory_after = debugger.read_memory(0, RAM_SIZE as usize).unwrap_or_default();
+
+        let registers = registers_before.into_iter().zip(registers_after)
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((name, old), (_, new))| RegisterChange { name: String::from(name), old: old, new: new })
+            .collect();
+
+        let exit_description = match &exit {
+            VcpuExit::Debug(DebugExit { kind: DebugExitKind::SingleStep, .. }) => None,
+            exit => Some(format!("{:?}", exit))
+        };
+
+        let record = TraceRecord {
+            step: self.step,
+            pc: instruction.address,
+            bytes: to_hex(&instruction.bytes),
+            text: instruction.text,
+            registers: registers,
+            memory_operand: memory_operand,
+            writes: changed_memory(&memory_before, &memory_after),
+            exit: exit_description
+        };
+        self.step += 1;
+
+        let written = serde_json::to_writer(&mut self.output, &record).map_err(|error| error.to_string())
+            .and_then(|_| writeln!(self.output).map_err(|error| error.to_string()));
+        if let Err(error) = written {
+            return Err(format!("Unable to write trace record: {}", error));
+        }
+
+        Ok(exit)
+    }
+
+    /// Single-steps the guest until it stops for any other reason (HLT, a fault, a breakpoint) and returns that exit.
+    /// On failure returns a description of what went wrong.
+    pub fn run(&mut self, debugger: &mut Debugger) -> Result<VcpuExit, String> {
+        let result = loop {
+            if self.step >= MAX_TRACE_STEPS {
+                break Err(format!("Trace stopped after {} steps", MAX_TRACE_STEPS));
+            }
+
+            match self.step(debugger) {
+                Ok(VcpuExit::Debug(DebugExit { kind: DebugExitKind::SingleStep, .. })) => {}
+                other => break other
+            }
+        };
+
+        if let Err(error) = self.output.flush() {
+            return Err(format!("Unable to write trace: {}", error));
+        }
+        result
+    }
+}
+
+/// Reads a trace written by Tracer. On failure returns a description of the first bad line.
+pub fn read_trace(path: &str) -> Result<Vec<TraceRecord>, String> {
+    let file = File::open(path).map_err(|error| format!("Unable to open trace file {}: {}", path, error))?;
+
+    let mut records = vec!();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|error| format!("Unable to read {}: {}", path, error))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(error) => return Err(format!("{} line {}: {}", path, index + 1, error))
+        }
+    }
+    Ok(records)
+}
+
+/// Picks records out of a trace. Every condition that is set has to match.
+#[derive(Default)]
+pub struct TraceFilter {
+    /// Part of the instruction text, e.g. `mul` or `[0x1800]`.
+    pub text: Option<String>,
+    /// A register the instruction changed.
+    pub register: Option<String>,
+    /// Instructions in [start, end).
+    pub pc_range: Option<(u64, u64)>,
+    /// Instructions that wrote memory.
+    pub writes_memory: bool
+}
+
+impl TraceFilter {
+
+    pub fn matches(&self, record: &TraceRecord) -> bool {
+        if let Some(text) = &self.text {
+            if !record.text.contains(text.as_str()) {
+                return false;
+            }
+        }
+        if let Some(register) = &self.register {
+            if !record.registers.iter().any(|change| &change.name == register) {
+                return false;
+            }
+        }
+        if let Some((start, end)) = self.pc_range {
+            if record.pc < start || record.pc >= end {
+                return false;
+            }
+        }
+        !self.writes_memory || !record.writes.is_empty()
+    }
+}
+
+/// A step at which two traces disagree.
+#[derive(Debug)]
+pub struct StepDifference {
+    pub step: u64,
+    pub left: Option<TraceRecord>,
+    pub right: Option<TraceRecord>,
+    /// What differs: `pc`, `text`, register names, `memory operand`, `writes`, `exit`, or `missing`.
+    pub fields: Vec<String>
+}
+
+impl StepDifference {
+
+    /// Whether the traces went different ways here, so later steps can't be lined up.
+    pub fn diverges(&self) -> bool {
+        self.fields.iter().any(|field| field == "pc" || field == "missing")
+    }
+}
+
+/// The registers a record leaves behind, by name.
+fn register_results(record: &TraceRecord) -> BTreeMap<&str, u64> {
+    record.registers.iter().map(|change| (change.name.as_str(), change.new)).collect()
+}
+
+fn compare_records(left: &TraceRecord, right: &TraceRecord) -> Vec<String> {
+    let mut fields = vec!();
+    if left.pc != right.pc {
+        fields.push(String::from("pc"));
+    }
+    if left.text != right.text {
+        fields.push(String::from("text"));
+    }
+
+    let (left_registers, right_registers) = (register_results(left), register_results(right));
+    let mut names: Vec<&str> = left_registers.keys().chain(right_registers.keys()).copied().collect();
+    names.sort_unstable();
+    names.dedup();
+    for name in names {
+        if left_registers.get(name) != right_registers.get(name) {
+            fields.push(String::from(name));
+        }
+    }
+
+    if left.memory_operand != right.memory_operand {
+        fields.push(String::from("memory operand"));
+    }
+    if left.writes != right.writes {
+        fields.push(String::from("writes"));
+    }
+    if left.exit != right.exit {
+        fields.push(String::from("exit"));
+    }
+    fields
+}
+
+/// Compares two traces of the same calculation step by step. Stops at the first step where they run different code,
+/// since nothing after it lines up.
+pub fn diff(left: &[TraceRecord], right: &[TraceRecord]) -> Vec<StepDifference> {
+    let mut differences = vec!();
+
+    for step in 0..left.len().max(right.len()) {
+        let (left_record, right_record) = (left.get(step), right.get(step));
+        let fields = match (left_record, right_record) {
+            (Some(left_record), Some(right_record)) => compare_records(left_record, right_record),
+            _ => vec!(String::from("missing"))
+        };
+
+        if !fields.is_empty() {
+            let difference = StepDifference {
+                step: step as u64,
+                left: left_record.cloned(),
+                right: right_record.cloned(),
+                fields: fields
+            };
+            let diverges = difference.diverges();
+            differences.push(difference);
+            if diverges {
+                break;
+            }
+        }
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(step: u64, pc: u64, text: &str) -> TraceRecord {
+        TraceRecord {
+            step: step,
+            pc: pc,
+            bytes: String::from("90"),
+            text: String::from(text),
+            registers: vec!(),
+            memory_operand: None,
+            writes: vec!(),
+            exit: None
+        }
+    }
+
+    fn change(name: &str, old: u64, new: u64) -> RegisterChange {
+        RegisterChange { name: String::from(name), old: old, new: new }
+    }
+
+    fn write(address: u64, bytes: &str) -> MemoryAccess {
+        MemoryAccess { address: address, bytes: String::from(bytes) }
+    }
+
+    #[test]
+    fn identical_traces_have_no_differences() {
+        let trace = vec!(record(0, 0x1000, "nop"), record(1, 0x1001, "hlt"));
+        assert!(diff(&trace, &trace).is_empty());
+    }
+
+    #[test]
+    fn diff_stops_where_the_pc_differs() {
+        let mut left = vec!(record(0, 0x1000, "inc eax"), record(1, 0x1001, "jz 0x1010"), record(2, 0x1003, "nop"), record(3, 0x1004, "hlt"));
+        let mut right = vec!(record(0, 0x1000, "inc eax"), record(1, 0x1001, "jz 0x1010"), record(2, 0x1010, "nop"), record(3, 0x1011, "hlt"));
+        left[0].registers = vec!(change("eax", 0, 1));
+        right[0].registers = vec!(change("eax", 0, 2));
+
+        let differences = diff(&left, &right);
+        assert_eq!(differences.len(), 2);
+
+        assert_eq!(differences[0].step, 0);
+        assert_eq!(differences[0].fields, vec!(String::from("eax")));
+        assert!(!differences[0].diverges());
+
+        // Nothing is reported past the first step that runs different code
+        assert_eq!(differences[1].step, 2);
+        assert_eq!(differences[1].fields, vec!(String::from("pc")));
+        assert!(differences[1].diverges());
+        assert_eq!(differences[1].left, Some(left[2].clone()));
+        assert_eq!(differences[1].right, Some(right[2].clone()));
+    }
+
+    #[test]
+    fn diff_compares_every_field() {
+        let left = record(0, 0x1000, "mov [0x1800], eax");
+        let mut right = record(0, 0x1000, "mov [0x1800], ecx");
+        right.registers = vec!(change("eflags", 2, 0x46));
+        right.memory_operand = Some(write(0x1800, "00000000"));
+        right.writes = vec!(write(0x1800, "01000000"));
+        right.exit = Some(String::from("Halt"));
+
+        let differences = diff(&[left], &[right]);
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].fields, vec!("text", "eflags", "memory operand", "writes", "exit"));
+        assert!(!differences[0].diverges());
+    }
+
+    #[test]
+    fn a_longer_trace_has_a_missing_step() {
+        let left = vec!(record(0, 0x1000, "nop"), record(1, 0x1001, "nop"), record(2, 0x1002, "hlt"));
+        let right = vec!(record(0, 0x1000, "nop"), record(1, 0x1001, "nop"));
+
+        let differences = diff(&left, &right);
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].step, 2);
+        assert_eq!(differences[0].fields, vec!(String::from("missing")));
+        assert!(differences[0].diverges());
+        assert_eq!(differences[0].left, Some(left[2].clone()));
+        assert_eq!(differences[0].right, None);
+
+        let differences = diff(&right, &left);
+        assert_eq!(differences[0].left, None);
+        assert_eq!(differences[0].right, Some(left[2].clone()));
+    }
+
+    #[test]
+    fn adjacent_changed_bytes_are_one_write() {
+        let before = [0u8, 0, 0, 0, 0, 0, 0, 0];
+        let after = [1u8, 0, 0xab, 0xcd, 0xef, 0, 0, 0x7f];
+        assert_eq!(changed_memory(&before, &after), vec!(write(0, "01"), write(2, "abcdef"), write(7, "7f")));
+        assert_eq!(changed_memory(&before, &before), vec!());
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        let mut full = record(1, 0x1002, "add [bx+0x10], ax");
+        full.bytes = String::from("014710");
+        full.registers = vec!(change("eflags", 2, 0x46), change("eip", 0x1002, 0x1005));
+        full.memory_operand = Some(write(0x1810, "ffff"));
+        full.writes = vec!(write(0x1810, "0000"));
+        full.exit = Some(String::from("Halt"));
+        let records = vec!(record(0, 0x1000, "nop"), full);
+
+        let mut lines = String::new();
+        for record in records.iter() {
+            lines.push_str(&serde_json::to_string(record).unwrap());
+            lines.push_str("\n\n");
+        }
+        // Empty fields are left out of the record rather than written as [] or null
+        assert!(lines.starts_with("{\"step\":0,\"pc\":4096,\"bytes\":\"90\",\"text\":\"nop\"}\n"));
+
+        let path = std::env::temp_dir().join(format!("hypercalc-trace-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &lines).unwrap();
+        assert_eq!(read_trace(path), Ok(records));
+
+        std::fs::write(path, "{\"step\":0,\"pc\":4096,\"bytes\":\"90\",\"text\":\"nop\"}\n{\"step\":1}\n").unwrap();
+        let error = read_trace(path).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert!(error.starts_with(&format!("{} line 2: missing field `pc`", path)), "{}", error);
+    }
+
+    #[test]
+    fn filter_conditions_all_have_to_match() {
+        let mut record = record(0, 0x1000, "mov [0x1800], eax");
+        record.registers = vec!(change("eflags", 2, 0x46));
+        record.writes = vec!(write(0x1800, "01000000"));
+
+        assert!(TraceFilter::default().matches(&record));
+        assert!(TraceFilter { text: Some(String::from("[0x1800]")), ..Default::default() }.matches(&record));
+        assert!(!TraceFilter { text: Some(String::from("mul")), ..Default::default() }.matches(&record));
+        assert!(TraceFilter { register: Some(String::from("eflags")), ..Default::default() }.matches(&record));
+        assert!(!TraceFilter { register: Some(String::from("eax")), ..Default::default() }.matches(&record));
+        assert!(TraceFilter { pc_range: Some((0x1000, 0x1001)), ..Default::default() }.matches(&record));
+        assert!(!TraceFilter { pc_range: Some((0xf00, 0x1000)), ..Default::default() }.matches(&record));
+        assert!(TraceFilter { writes_memory: true, ..Default::default() }.matches(&record));
+
+        let filter = TraceFilter { text: Some(String::from("mov")), register: Some(String::from("eflags")), pc_range: Some((0, 0x2000)), writes_memory: true };
+        assert!(filter.matches(&record));
+        record.writes.clear();
+        assert!(!filter.matches(&record));
+    }
+}
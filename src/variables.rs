@@ -0,0 +1,65 @@
+use crate::calculator::CalcVm;
+use crate::guest_fault::GDT_BASE;
+
+// Named variables for expressions. Each name gets a dword in the data segment when it is first assigned and keeps it
+// for the rest of the session; guest code reads and writes the value there as a memory operand, so the host only
+// keeps the table of names and addresses. `ans` is always defined and holds the last result.
+
+/// The data segment range the variables are placed in, below the descriptor tables.
+pub const VARIABLES_BASE: u32 = 0x1C00;
+pub const VARIABLES_END: u32 = GDT_BASE;
+/// The variable every statement stores its result in.
+pub const ANS: &str = "ans";
+
+/// The variables of a session, by name. The values are in guest memory.
+pub struct Variables {
+    /// Variable i is at VARIABLES_BASE + 4 * i.
+    names: Vec<String>
+}
+
+impl Variables {
+
+    /// Associated function constructor. Starts with `ans`, set to 0 in `calc_vm`. On failure returns a description of
+    /// what went wrong.
+    pub fn new(calc_vm: &mut CalcVm) -> Result<Self, String> {
+        calc_vm.write_data(VARIABLES_BASE, &0u32.to_le_bytes())?;
+        Ok(Variables {
+            names: vec!(String::from(ANS))
+        })
+    }
+
+    /// The DS offset of variable `name`, None if it isn't defined.
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.names.iter().position(|candidate| candidate == name).map(|index| VARIABLES_BASE + 4 * index as u32)
+    }
+
+    /// The DS offset `name` has, or gets when define()d. On failure returns a description of why it can't have one.
+    pub fn address_for(&self, name: &str) -> Result<u32, String> {
+        if let Some(address) = self.address(name) {
+            return Ok(address);
+        }
+        let address = VARIABLES_BASE + 4 * self.names.len() as u32;
+        if address >= VARIABLES_END {
+            return Err(format!("No room for {}, all {} variables are in use", name, (VARIABLES_END - VARIABLES_BASE) / 4));
+        }
+        Ok(address)
+    }
+
+    /// Defines `name` if it isn't already and returns its DS offset. On failure returns a description of why it can't
+    /// be defined.
+    pub fn define(&mut self, name: &str) -> Result<u32, String> {
+        let address = self.address_for(name)?;
+        if self.address(name).is_none() {
+            self.names.push(String::from(name));
+        }
+        Ok(address)
+    }
+
+    /// Every variable with its DS offset and its value, read from `calc_vm`'s memory, in the order they were defined.
+    pub fn dump(&self, calc_vm: &mut CalcVm) -> Vec<(String, u32, u32)> {
+        let bytes = calc_vm.read_data(VARIABLES_BASE, self.names.len() * 4);
+        self.names.iter().zip(bytes.chunks(4)).enumerate()
+            .map(|(index, (name, value))| (name.clone(), VARIABLES_BASE + 4 * index as u32, u32::from_le_bytes([value[0], value[1], value[2], value[3]])))
+            .collect()
+    }
+}
@@ -0,0 +1,74 @@
+use crate::haxm_interface_windows::vcpu_state_t;
+
+// Indexes into vcpu_state_t's register array, in the order the hardware numbers them (and ModRM encodes them)
+pub const REG_RAX: usize = 0;
+pub const REG_RCX: usize = 1;
+pub const REG_RDX: usize = 2;
+pub const REG_RSP: usize = 4;
+
+pub const GPR_NAMES_32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+
+// EFLAGS bits
+pub const EFLAGS_CF: u32 = 1 << 0;
+pub const EFLAGS_PF: u32 = 1 << 2;
+pub const EFLAGS_AF: u32 = 1 << 4;
+pub const EFLAGS_ZF: u32 = 1 << 6;
+pub const EFLAGS_SF: u32 = 1 << 7;
+pub const EFLAGS_TF: u32 = 1 << 8;
+pub const EFLAGS_IF: u32 = 1 << 9;
+pub const EFLAGS_DF: u32 = 1 << 10;
+pub const EFLAGS_OF: u32 = 1 << 11;
+pub const EFLAGS_NT: u32 = 1 << 14;
+pub const EFLAGS_RF: u32 = 1 << 16;
+pub const EFLAGS_VM: u32 = 1 << 17;
+pub const EFLAGS_AC: u32 = 1 << 18;
+pub const EFLAGS_VIF: u32 = 1 << 19;
+pub const EFLAGS_VIP: u32 = 1 << 20;
+pub const EFLAGS_ID: u32 = 1 << 21;
+
+// vcpu_state_t is plain data for the driver (unions of integers and bitfields), so a bitwise copy is a full copy
+impl Clone for vcpu_state_t {
+    fn clone(&self) -> Self {
+        unsafe { std::ptr::read(self) }
+    }
+}
+
+// Accessors that hide the unions in vcpu_state_t, so callers don't need unsafe for every register access
+impl vcpu_state_t {
+
+    /// Returns general purpose register `index` (see the REG_* constants), all 64 bits.
+    pub fn gpr(&self, index: usize) -> u64 {
+        unsafe { self.anon_union_1.regs[index] }
+    }
+
+    /// Sets general purpose register `index` (see the REG_* constants), all 64 bits.
+    pub fn set_gpr(&mut self, index: usize, value: u64) {
+        unsafe { self.anon_union_1.regs[index] = value; }
+    }
+
+    /// Returns the low 32 bits of general purpose register `index`.
+    pub fn gpr32(&self, index: usize) -> u32 {
+        self.gpr(index) as u32
+    }
+
+    /// Sets general purpose register `index` to a zero extended 32 bit value.
+    pub fn set_gpr32(&mut self, index: usize, value: u32) {
+        self.set_gpr(index, value as u64);
+    }
+
+    pub fn eip(&self) -> u32 {
+        unsafe { self.anon_union_2.eip }
+    }
+
+    pub fn set_eip(&mut self, value: u32) {
+        self.anon_union_2.rip = value as u64;
+    }
+
+    pub fn eflags(&self) -> u32 {
+        unsafe { self.anon_union_3.eflags }
+    }
+
+    pub fn set_eflags(&mut self, value: u32) {
+        self.anon_union_3.rflags = value as u64;
+    }
+}
@@ -0,0 +1,66 @@
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(clippy::upper_case_acronyms)]
+#![allow(clippy::too_many_arguments)]
+
+// Stand-ins for the Win32 types and calls HyperCalc uses, for building on hosts other than Windows. There is no HAXM
+// there, so every call fails with ERROR_NOT_SUPPORTED and CalcVm::new() reports that the device couldn't be opened.
+// Whatever doesn't run a guest (the assembler, the disassembler, the gdb stub, state files, the CMOS model) builds
+// and is tested anywhere.
+
+pub use std::ffi::c_void;
+
+pub type BOOL = i32;
+pub type UINT = u32;
+pub type UINT8 = u8;
+pub type UINT16 = u16;
+pub type UINT32 = u32;
+pub type UINT64 = u64;
+pub type INT32 = i32;
+pub type DWORD = u32;
+pub type HANDLE = *mut c_void;
+
+pub const ERROR_NOT_SUPPORTED: DWORD = 50;
+pub const ERROR_INVALID_PARAMETER: DWORD = 87;
+pub const INVALID_HANDLE_VALUE: HANDLE = -1isize as HANDLE;
+
+pub const GENERIC_READ: DWORD = 0x80000000;
+pub const GENERIC_WRITE: DWORD = 0x40000000;
+pub const OPEN_EXISTING: DWORD = 3;
+pub const PAGE_READWRITE: DWORD = 0x04;
+pub const FILE_MAP_COPY: DWORD = 0x0001;
+pub const FILE_MAP_ALL_ACCESS: DWORD = 0xF001F;
+pub const METHOD_BUFFERED: DWORD = 0;
+pub const FILE_ANY_ACCESS: DWORD = 0;
+
+pub unsafe fn GetLastError() -> DWORD {
+    ERROR_NOT_SUPPORTED
+}
+
+pub unsafe fn CreateFileA(_file_name: *const i8, _desired_access: DWORD, _share_mode: DWORD, _security_attributes: *mut c_void,
+    _creation_disposition: DWORD, _flags_and_attributes: DWORD, _template_file: HANDLE) -> HANDLE {
+    INVALID_HANDLE_VALUE
+}
+
+pub unsafe fn DeviceIoControl(_device: HANDLE, _io_control_code: DWORD, _in_buffer: *mut c_void, _in_buffer_size: DWORD,
+    _out_buffer: *mut c_void, _out_buffer_size: DWORD, _bytes_returned: *mut DWORD, _overlapped: *mut c_void) -> BOOL {
+    0
+}
+
+pub unsafe fn CloseHandle(_object: HANDLE) -> BOOL {
+    0
+}
+
+pub unsafe fn CreateFileMappingW(_file: HANDLE, _attributes: *mut c_void, _protect: DWORD, _maximum_size_high: DWORD,
+    _maximum_size_low: DWORD, _name: *const u16) -> HANDLE {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn MapViewOfFile(_file_mapping: HANDLE, _desired_access: DWORD, _file_offset_high: DWORD, _file_offset_low: DWORD,
+    _number_of_bytes_to_map: usize) -> *mut c_void {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn UnmapViewOfFile(_base_address: *const c_void) -> BOOL {
+    0
+}